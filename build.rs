@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Generates the gRPC service of the `grpc` feature from `proto/analysis.proto`.
+    #[cfg(feature = "grpc")]
+    {
+        if std::env::var_os("PROTOC").is_none() {
+            let protoc = protoc_bin_vendored::protoc_bin_path().expect("no bundled protoc");
+            std::env::set_var("PROTOC", protoc);
+        }
+        tonic_build::compile_protos("proto/analysis.proto").expect("failed to compile protos");
+    }
+}
@@ -0,0 +1,359 @@
+//! Analysis of many positions with a single engine.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::{EngineConfig, ThinkParams, ThreadedEngine};
+//!
+//! let mut engine = ThreadedEngine::spawn(EngineConfig {
+//!     path: "/path/to/engine".to_string(),
+//!     ..Default::default()
+//! })
+//! .unwrap();
+//!
+//! let positions = vec![
+//!     "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1",
+//!     "lnsgkgsnl/1r5b1/ppppppppp/9/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL w - 2",
+//! ];
+//! let limits = ThinkParams::new().byoyomi(Duration::from_secs(1));
+//! let batch = engine.analyze_batch(positions, limits).on_progress(|progress| {
+//!     println!("{}/{} depth {:?}", progress.completed, progress.total, progress.depth);
+//! });
+//! for (position, analysis) in batch {
+//!     let analysis = analysis.unwrap();
+//!     println!("{}: {:?} {:?}", position, analysis.outcome, analysis.info.score);
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::error::Error;
+use crate::protocol::{SearchInfo, ThinkParams};
+use crate::threaded::{SearchOutcome, ThreadedEngine};
+
+/// How often a running search checks for cancellation.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a search may take to answer `stop` before the analysis gives up on it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The result of analyzing a single position.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Analysis {
+    /// The final response of the engine.
+    pub outcome: SearchOutcome,
+    /// The last reported search information for the principal variation.
+    pub info: SearchInfo,
+    /// Time between sending `go` and receiving the result.
+    pub elapsed: Duration,
+}
+
+/// Progress of a batch analysis, reported through `on_progress` callbacks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnalysisProgress {
+    /// Number of positions analyzed so far.
+    pub completed: usize,
+    /// Number of positions in the batch.
+    pub total: usize,
+    /// The position this update is about.
+    pub position: String,
+    /// Depth reached on `position`, or `None` once it was analyzed.
+    pub depth: Option<i32>,
+    /// Time since the batch was created.
+    pub elapsed: Duration,
+    /// Estimated time until the batch completes, once a position was analyzed.
+    pub eta: Option<Duration>,
+}
+
+/// A boxed callback receiving `AnalysisProgress` updates.
+pub type ProgressCallback = Box<dyn FnMut(&AnalysisProgress) + Send>;
+
+/// Counts analyzed positions and reports progress to an optional callback.
+pub(crate) struct ProgressTracker {
+    started: Instant,
+    completed: usize,
+    total: usize,
+    callback: Option<ProgressCallback>,
+}
+
+impl fmt::Debug for ProgressTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProgressTracker")
+            .field("completed", &self.completed)
+            .field("total", &self.total)
+            .finish()
+    }
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(total: usize) -> Self {
+        ProgressTracker {
+            started: Instant::now(),
+            completed: 0,
+            total,
+            callback: None,
+        }
+    }
+
+    pub(crate) fn set_callback(&mut self, callback: ProgressCallback) {
+        self.callback = Some(callback);
+    }
+
+    /// Reports a new depth reached while analyzing `position`.
+    pub(crate) fn depth_reached(&mut self, position: &str, depth: i32) {
+        self.report(position, Some(depth));
+    }
+
+    /// Reports that `position` has been analyzed.
+    pub(crate) fn completed(&mut self, position: &str) {
+        self.completed += 1;
+        self.report(position, None);
+    }
+
+    fn report(&mut self, position: &str, depth: Option<i32>) {
+        if self.callback.is_none() {
+            return;
+        }
+        let progress = self.progress(position, depth, self.started.elapsed());
+        if let Some(callback) = &mut self.callback {
+            callback(&progress);
+        }
+    }
+
+    fn progress(&self, position: &str, depth: Option<i32>, elapsed: Duration) -> AnalysisProgress {
+        let eta = (self.completed > 0).then(|| {
+            let remaining = self.total.saturating_sub(self.completed);
+            elapsed.mul_f64(remaining as f64 / self.completed as f64)
+        });
+        AnalysisProgress {
+            completed: self.completed,
+            total: self.total,
+            position: position.to_string(),
+            depth,
+            elapsed,
+            eta,
+        }
+    }
+}
+
+/// Runs a single search on `position` and collects its information.
+///
+/// `on_depth` is called whenever the principal variation reaches a new depth.
+/// If `cancelled` returns `true` while searching, the search is stopped and
+/// `Error::Cancelled` is returned once the engine has answered, or
+/// `Error::Timeout` if it does not answer `stop` in time.
+/// Returns `Error::Terminated` if the engine terminates while searching.
+pub(crate) fn analyze_position(
+    engine: &mut ThreadedEngine,
+    position: &str,
+    limits: &ThinkParams,
+    cancelled: impl Fn() -> bool,
+    mut on_depth: impl FnMut(i32),
+) -> Result<Analysis, Error> {
+    let mut depth = None;
+    analyze_position_with(engine, position, limits, cancelled, |info, _| {
+        if let Some(d) = info.depth.filter(|d| Some(*d) != depth) {
+            depth = Some(d);
+            on_depth(d);
+        }
+    })
+}
+
+/// Like `analyze_position`, but calls `on_info` after every `info` command
+/// with the accumulated information and the time the command arrived at.
+pub(crate) fn analyze_position_with(
+    engine: &mut ThreadedEngine,
+    position: &str,
+    limits: &ThinkParams,
+    cancelled: impl Fn() -> bool,
+    mut on_info: impl FnMut(&SearchInfo, Duration),
+) -> Result<Analysis, Error> {
+    if cancelled() {
+        return Err(Error::Cancelled);
+    }
+
+    engine.set_position(position);
+    let search = engine.go(limits.clone())?;
+    let mut info = SearchInfo::default();
+    // Set once the search has been stopped, to the time it has to answer by.
+    let mut stopped: Option<Instant> = None;
+
+    loop {
+        while let Some(timed) = search.try_next_timed_info() {
+            info.update(&timed.params);
+            on_info(&info, timed.elapsed);
+        }
+        match search.wait(CANCEL_POLL_INTERVAL) {
+            Ok(result) => {
+                if stopped.is_some() {
+                    return Err(Error::Cancelled);
+                }
+                while let Some(timed) = search.try_next_timed_info() {
+                    info.update(&timed.params);
+                    on_info(&info, timed.elapsed);
+                }
+                return Ok(Analysis {
+                    outcome: result.outcome,
+                    info,
+                    elapsed: search.elapsed(),
+                });
+            }
+            Err(Error::Timeout) => match stopped {
+                Some(deadline) if Instant::now() >= deadline => {
+                    return Err(Error::Timeout.with_command("stop"));
+                }
+                Some(_) => {}
+                None if cancelled() => {
+                    search.stop();
+                    stopped = Some(Instant::now() + STOP_TIMEOUT);
+                }
+                None => {}
+            },
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// An iterator analyzing positions one by one, returned by `ThreadedEngine::analyze_batch`.
+///
+/// Each call to `next` runs one search and yields the position with its result.
+/// Iteration ends when all positions were analyzed or the batch was cancelled.
+#[derive(Debug)]
+pub struct BatchAnalysis<'a> {
+    engine: &'a mut ThreadedEngine,
+    positions: VecDeque<String>,
+    limits: ThinkParams,
+    cancel: CancellationToken,
+    progress: ProgressTracker,
+}
+
+impl<'a> BatchAnalysis<'a> {
+    pub(crate) fn new(
+        engine: &'a mut ThreadedEngine,
+        positions: VecDeque<String>,
+        limits: ThinkParams,
+    ) -> Self {
+        BatchAnalysis {
+            engine,
+            progress: ProgressTracker::new(positions.len()),
+            positions,
+            limits,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Sets a callback receiving progress updates while iterating.
+    #[must_use]
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&AnalysisProgress) + Send + 'static,
+    {
+        self.progress.set_callback(Box::new(callback));
+        self
+    }
+
+    /// Returns a token which cancels the batch, stopping the running search.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Returns the number of positions not analyzed yet.
+    pub fn remaining(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+impl Iterator for BatchAnalysis<'_> {
+    type Item = (String, Result<Analysis, Error>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let position = self.positions.pop_front()?;
+        let cancel = &self.cancel;
+        let progress = &mut self.progress;
+        let result = analyze_position(
+            self.engine,
+            &position,
+            &self.limits,
+            || cancel.is_cancelled(),
+            |depth| progress.depth_reached(&position, depth),
+        );
+        match result {
+            Err(Error::Cancelled) => {
+                self.positions.clear();
+                None
+            }
+            res => {
+                self.progress.completed(&position);
+                Some((position, res))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn progress() {
+        let mut tracker = ProgressTracker::new(4);
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&reports);
+        tracker.set_callback(Box::new(move |p: &AnalysisProgress| {
+            sink.lock().unwrap().push((p.completed, p.depth));
+        }));
+
+        tracker.depth_reached("a", 3);
+        tracker.completed("a");
+        assert_eq!(vec![(0, Some(3)), (1, None)], *reports.lock().unwrap());
+
+        let p = tracker.progress("b", None, Duration::from_secs(2));
+        assert_eq!(Some(Duration::from_secs(6)), p.eta);
+        assert_eq!(
+            None,
+            ProgressTracker::new(4)
+                .progress("a", None, Duration::ZERO)
+                .eta
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn engine_exits_after_stop() {
+        use crate::threaded::EngineConfig;
+        use std::cell::Cell;
+
+        // Exits instead of answering `stop` with a best move.
+        let script = "while read -r cmd rest; do case $cmd in \
+            usi) echo usiok;; \
+            isready) echo readyok;; \
+            stop) exit 0;; \
+            esac; done";
+        let config = EngineConfig {
+            path: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            working_dir: Some(".".to_string()),
+            ..Default::default()
+        };
+        let mut engine = ThreadedEngine::spawn(config).unwrap();
+        // Cancels once the search has started.
+        let calls = Cell::new(0);
+        let cancelled = || {
+            calls.set(calls.get() + 1);
+            calls.get() > 1
+        };
+        let res = analyze_position(
+            &mut engine,
+            "startpos",
+            &ThinkParams::new().infinite(),
+            cancelled,
+            |_| {},
+        );
+        assert!(matches!(res, Err(Error::Terminated)));
+    }
+}
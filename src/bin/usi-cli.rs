@@ -0,0 +1,494 @@
+//! An interactive session with a USI engine.
+//!
+//! ```text
+//! usi-cli [--transcript FILE] ENGINE [ARGS...]
+//! ```
+//!
+//! USI commands typed at the prompt are sent to the engine, with `info` lines
+//! of searches printed in a readable form. Tab completes commands, option names
+//! and option values, and `setoption` accepts a unique prefix of an option name.
+//! `help` lists the other commands.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+use usi::{
+    EngineConfig, GuiCommand, InfoParams, OptionKind, OptionParams, ScoreKind, SearchHandle,
+    SearchOutcome, ThreadedEngine,
+};
+
+/// How often a running search is checked for output while waiting for input.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USAGE: &str = "usage: usi-cli [--transcript FILE] ENGINE [ARGS...]";
+
+const COMMANDS: &[&str] = &[
+    "gameover",
+    "go",
+    "help",
+    "isready",
+    "options",
+    "ponderhit",
+    "position",
+    "quit",
+    "record",
+    "setoption",
+    "stop",
+    "usinewgame",
+];
+
+const HELP: &str = "\
+USI commands are sent to the engine: position, go, stop, ponderhit, setoption,
+usinewgame, isready, gameover and quit.
+  setoption NAME [VALUE]  shorthand for setoption name NAME value VALUE
+  options                 list the options of the engine
+  record FILE | off       start or stop recording a transcript
+  help                    show this message
+Tab completes commands, option names and option values.";
+
+struct Session {
+    engine: ThreadedEngine,
+    options: Vec<OptionParams>,
+    search: Option<SearchHandle>,
+    transcript: Option<File>,
+}
+
+impl Session {
+    /// Prints a line of output, recording it in the transcript.
+    fn print(&mut self, line: &str) {
+        println!("{}", line);
+        if let Some(file) = &mut self.transcript {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    /// Runs a line of input. Returns `false` once the session should end.
+    fn run(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if let Some(file) = &mut self.transcript {
+            let _ = writeln!(file, "> {}", line);
+        }
+        let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "" => {}
+            "help" => self.print(HELP),
+            "options" => {
+                for option in self.options.clone() {
+                    self.print(&option.to_string());
+                }
+            }
+            "record" => self.record(args.trim()),
+            "setoption" if !args.trim_start().starts_with("name ") => {
+                let (name, value) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+                self.set_option(name, Some(value.trim()).filter(|v| !v.is_empty()));
+            }
+            _ => match GuiCommand::parse(line) {
+                Ok(GuiCommand::SetOption(name, value)) => self.set_option(&name, value.as_deref()),
+                Ok(command) => return self.send(command),
+                Err(err) => self.print(&format!("error: {}", err)),
+            },
+        }
+        true
+    }
+
+    fn send(&mut self, command: GuiCommand) -> bool {
+        match command {
+            GuiCommand::Go(params) => match self.engine.go(params) {
+                Ok(handle) => self.search = Some(handle),
+                Err(err) => self.print(&format!("error: {}", err)),
+            },
+            GuiCommand::Position(sfen) => self.engine.set_position(&sfen),
+            GuiCommand::Stop => self.engine.stop(),
+            GuiCommand::Ponderhit => self.engine.ponderhit(),
+            GuiCommand::UsiNewGame => {
+                self.engine.new_game(None);
+            }
+            GuiCommand::GameOver(result) => self.engine.game_over(result),
+            GuiCommand::IsReady => match self.engine.wait_ready(READY_TIMEOUT) {
+                Ok(()) => self.print("readyok"),
+                Err(err) => self.print(&format!("error: {}", err)),
+            },
+            GuiCommand::Usi => {
+                let name = self.engine.name().to_string();
+                self.print(&format!("id name {}", name));
+            }
+            GuiCommand::SetOption(name, value) => self.set_option(&name, value.as_deref()),
+            GuiCommand::Quit => return false,
+        }
+        true
+    }
+
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        match expand_option(name, &self.options) {
+            Ok(name) => {
+                self.engine.set_option(&name, value);
+                self.print(&format!("{} = {}", name, value.unwrap_or("<button>")));
+            }
+            Err(msg) => self.print(&format!("error: {}", msg)),
+        }
+    }
+
+    fn record(&mut self, path: &str) {
+        match path {
+            "" => self.print("error: record needs a file name or off"),
+            "off" => self.transcript = None,
+            path => match File::create(path) {
+                Ok(file) => self.transcript = Some(file),
+                Err(err) => self.print(&format!("error: {}", err)),
+            },
+        }
+    }
+
+    /// Prints the output of the running search. Returns `true` once it has finished.
+    fn poll_search(&mut self) -> bool {
+        let Some(handle) = self.search.take() else {
+            return true;
+        };
+        while let Some(params) = handle.try_next_info() {
+            if let Some(line) = format_info(&params) {
+                self.print(&line);
+            }
+        }
+        match handle.poll() {
+            Some(result) => {
+                self.print(&format_outcome(&result.outcome));
+                true
+            }
+            None if !self.engine.is_alive() => {
+                self.print("error: the engine terminated");
+                true
+            }
+            None => {
+                self.search = Some(handle);
+                false
+            }
+        }
+    }
+}
+
+/// Formats an `info` command, with scores from the side to move in pawns.
+fn format_info(params: &[InfoParams]) -> Option<String> {
+    let mut parts = Vec::new();
+    for p in params {
+        match p {
+            InfoParams::Depth(depth, Some(seldepth)) => {
+                parts.push(format!("depth {}/{}", depth, seldepth))
+            }
+            InfoParams::Depth(depth, None) => parts.push(format!("depth {}", depth)),
+            InfoParams::MultiPv(n) => parts.push(format!("#{}", n)),
+            InfoParams::Score(v, kind) => {
+                let bound = match kind {
+                    ScoreKind::CpLowerbound | ScoreKind::MateLowerbound => " (lower)",
+                    ScoreKind::CpUpperbound | ScoreKind::MateUpperbound => " (upper)",
+                    _ => "",
+                };
+                let score = match kind {
+                    ScoreKind::MateSignOnly if *v < 0 => "mated".to_string(),
+                    ScoreKind::MateSignOnly => "mate".to_string(),
+                    kind if kind.is_mate() => format!("mate {}", v),
+                    _ => format!("{:+.2}", f64::from(*v) / 100.0),
+                };
+                parts.push(format!("score {}{}", score, bound));
+            }
+            InfoParams::Nodes(n) => parts.push(format!("nodes {}", abbreviate(*n))),
+            InfoParams::Nps(n) => parts.push(format!("nps {}", abbreviate(*n))),
+            InfoParams::Time(t) => parts.push(format!("{:.1}s", t.as_secs_f64())),
+            InfoParams::HashFull(n) => parts.push(format!("hash {}%", n / 10)),
+            InfoParams::Pv(pv) => parts.push(format!("pv {}", pv.join(" "))),
+            InfoParams::Text(s) => parts.push(format!("string {}", s)),
+            _ => {}
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("  "))
+}
+
+fn abbreviate(n: u64) -> String {
+    match n {
+        0..=9_999 => n.to_string(),
+        10_000..=999_999 => format!("{}k", n / 1000),
+        _ => format!("{:.1}M", n as f64 / 1e6),
+    }
+}
+
+fn format_outcome(outcome: &SearchOutcome) -> String {
+    match outcome {
+        SearchOutcome::BestMove(params) => format!("bestmove {}", params),
+        SearchOutcome::Checkmate(params) => format!("checkmate {}", params),
+        SearchOutcome::DeclareWin => "bestmove win".to_string(),
+    }
+}
+
+/// Returns the completions of the last word of `line`.
+fn complete(line: &str, options: &[OptionParams]) -> Vec<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let partial = if line.ends_with(char::is_whitespace) {
+        ""
+    } else {
+        words.last().copied().unwrap_or_default()
+    };
+    let preceding = words.len() - usize::from(!partial.is_empty());
+    let candidates: Vec<&str> = match words.first() {
+        _ if preceding == 0 => COMMANDS.to_vec(),
+        Some(&"setoption") if preceding == 1 || words.get(1) == Some(&"name") && preceding == 2 => {
+            options.iter().map(|o| o.name.as_str()).collect()
+        }
+        Some(&"setoption") => match option_kind(words[preceding - 1], options) {
+            Some(OptionKind::Check { .. }) => vec!["true", "false"],
+            Some(OptionKind::Combo { vars, .. }) => vars.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        },
+        Some(&"position") if preceding == 1 => vec!["startpos", "sfen"],
+        Some(&"go") => vec![
+            "btime", "wtime", "byoyomi", "binc", "winc", "depth", "nodes", "infinite", "mate",
+            "ponder",
+        ],
+        _ => Vec::new(),
+    };
+    let partial = partial.to_lowercase();
+    candidates
+        .into_iter()
+        .filter(|c| c.to_lowercase().starts_with(&partial))
+        .map(str::to_string)
+        .collect()
+}
+
+fn option_kind<'a>(name: &str, options: &'a [OptionParams]) -> Option<&'a OptionKind> {
+    options.iter().find(|o| o.name == name).map(|o| &o.value)
+}
+
+/// Expands a unique, case-insensitive prefix of an option name.
+fn expand_option(name: &str, options: &[OptionParams]) -> Result<String, String> {
+    if options.is_empty() || options.iter().any(|o| o.name == name) {
+        return Ok(name.to_string());
+    }
+    let prefix = name.to_lowercase();
+    let matches: Vec<&str> = options
+        .iter()
+        .map(|o| o.name.as_str())
+        .filter(|n| n.to_lowercase().starts_with(&prefix))
+        .collect();
+    match matches[..] {
+        [name] => Ok(name.to_string()),
+        [] => Err(format!("unknown option {}", name)),
+        _ => Err(format!("ambiguous option {}: {}", name, matches.join(" "))),
+    }
+}
+
+/// Completes the word before the cursor with `complete`.
+struct CliHelper {
+    options: Vec<OptionParams>,
+}
+
+impl Completer for CliHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.trim_end_matches(|c: char| !c.is_whitespace()).len();
+        Ok((start, complete(line, &self.options)))
+    }
+}
+
+impl Hinter for CliHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CliHelper {}
+
+impl Validator for CliHelper {}
+
+impl Helper for CliHelper {}
+
+/// Reads input on a separate thread, so that searches are printed while waiting for input.
+///
+/// A line is only read once the previous one was acknowledged through the returned
+/// sender, so that the terminal is not left in raw mode when the session ends.
+fn read_lines(options: Vec<OptionParams>) -> (Receiver<String>, Sender<()>) {
+    let (sender, receiver) = channel();
+    let (next, acknowledged) = channel();
+    thread::spawn(move || {
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .auto_add_history(true)
+            .build();
+        let mut editor: Editor<CliHelper, DefaultHistory> = match Editor::with_config(config) {
+            Ok(editor) => editor,
+            Err(err) => {
+                eprintln!("failed to read input: {}", err);
+                return;
+            }
+        };
+        editor.set_helper(Some(CliHelper { options }));
+        while let Ok(line) = editor.readline("") {
+            if sender.send(line).is_err() || acknowledged.recv().is_err() {
+                break;
+            }
+        }
+    });
+    (receiver, next)
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    if matches!(args.peek().map(String::as_str), Some("-h" | "--help")) {
+        println!("{}", USAGE);
+        println!();
+        println!("Runs an interactive session with a USI engine.");
+        println!("{}", HELP);
+        return;
+    }
+    let mut transcript = None;
+    if args.peek().map(String::as_str) == Some("--transcript") {
+        args.next();
+        transcript = args.next();
+    }
+    let Some(path) = args.next() else {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    let config = args.fold(EngineConfig::new(&path), |config, arg| config.arg(&arg));
+    let engine = match ThreadedEngine::spawn(config) {
+        Ok(engine) => engine,
+        Err(err) => {
+            eprintln!("failed to start {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut session = Session {
+        options: engine
+            .info()
+            .map(|info| info.declared_options().to_vec())
+            .unwrap_or_default(),
+        engine,
+        search: None,
+        transcript: None,
+    };
+    if let Some(path) = transcript {
+        session.record(&path);
+    }
+    let name = session.engine.name().to_string();
+    session.print(&format!("connected to {}, type help for commands", name));
+
+    let (lines, next) = read_lines(session.options.clone());
+    loop {
+        let line = if session.poll_search() {
+            match lines.recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            }
+        } else {
+            match lines.recv_timeout(POLL_INTERVAL) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        };
+        if !session.run(&line) {
+            break;
+        }
+        let _ = next.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Vec<OptionParams> {
+        vec![
+            OptionParams {
+                name: "USI_Hash".to_string(),
+                value: OptionKind::Spin {
+                    default: Some(256),
+                    min: Some(1),
+                    max: Some(1024),
+                },
+            },
+            OptionParams {
+                name: "USI_Ponder".to_string(),
+                value: OptionKind::Check {
+                    default: Some(false),
+                },
+            },
+            OptionParams {
+                name: "Threads".to_string(),
+                value: OptionKind::Spin {
+                    default: Some(1),
+                    min: Some(1),
+                    max: Some(64),
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn completion() {
+        let options = options();
+        assert_eq!(vec!["setoption", "stop"], complete("s", &options));
+        assert_eq!(
+            vec!["USI_Hash", "USI_Ponder"],
+            complete("setoption usi", &options)
+        );
+        assert_eq!(vec!["Threads"], complete("setoption name th", &options));
+        assert_eq!(
+            vec!["true", "false"],
+            complete("setoption USI_Ponder ", &options)
+        );
+        assert_eq!(vec!["startpos", "sfen"], complete("position ", &options));
+
+        let helper = CliHelper { options };
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+        assert_eq!(
+            (10, vec!["Threads".to_string()]),
+            Completer::complete(&helper, "setoption thr 4", 13, &ctx).unwrap()
+        );
+        let options = helper.options;
+
+        assert_eq!(Ok("USI_Hash".to_string()), expand_option("usi_h", &options));
+        assert!(expand_option("USI", &options)
+            .unwrap_err()
+            .contains("ambiguous"));
+        assert!(expand_option("Hash", &options).is_err());
+    }
+
+    #[test]
+    fn info_lines() {
+        assert_eq!(
+            Some(
+                "depth 12/18  score +0.35 (lower)  nodes 1.2M  nps 800k  pv 7g7f 3c3d".to_string()
+            ),
+            format_info(&[
+                InfoParams::Depth(12, Some(18)),
+                InfoParams::Score(35, ScoreKind::CpLowerbound),
+                InfoParams::Nodes(1_234_567),
+                InfoParams::Nps(800_000),
+                InfoParams::Pv(vec!["7g7f".to_string(), "3c3d".to_string()]),
+            ])
+        );
+        assert_eq!(
+            Some("score mate -3".to_string()),
+            format_info(&[InfoParams::Score(-3, ScoreKind::MateExact)])
+        );
+        assert_eq!(None, format_info(&[InfoParams::CurrMoveNumber(3)]));
+    }
+}
@@ -0,0 +1,245 @@
+//! Side-by-side comparison of several engines on the same positions.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::compare::Comparison;
+//! use usi::{EngineConfig, ThinkParams};
+//!
+//! let report = Comparison::new(ThinkParams::new().byoyomi(Duration::from_secs(1)))
+//!     .engine("avx2", EngineConfig::new("/path/to/engine-avx2"))
+//!     .engine("nnue", EngineConfig::new("/path/to/engine-nnue"))
+//!     .position("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1")
+//!     .run()
+//!     .unwrap();
+//! println!("{}", report);
+//! ```
+
+use std::fmt;
+
+use crate::analysis::{analyze_position, Analysis};
+use crate::error::Error;
+use crate::protocol::{InfoParams, ThinkParams};
+use crate::threaded::{EngineConfig, ThreadedEngine};
+
+/// Runs a set of positions through several engines with the same limits.
+///
+/// The engines run one after another, so that they don't compete for the CPU.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    engines: Vec<(String, EngineConfig)>,
+    positions: Vec<String>,
+    limits: ThinkParams,
+}
+
+impl Comparison {
+    /// Creates a comparison searching each position with `limits`.
+    pub fn new(limits: ThinkParams) -> Self {
+        Comparison {
+            engines: Vec::new(),
+            positions: Vec::new(),
+            limits,
+        }
+    }
+
+    /// Adds an engine, labelled `label` in the report.
+    #[must_use]
+    pub fn engine(mut self, label: &str, config: EngineConfig) -> Self {
+        self.engines.push((label.to_string(), config));
+        self
+    }
+
+    /// Adds a position in SFEN notation.
+    #[must_use]
+    pub fn position(mut self, position: &str) -> Self {
+        self.positions.push(position.to_string());
+        self
+    }
+
+    /// Spawns the engines in turn and analyzes every position with each of them.
+    ///
+    /// Returns the first error of spawning an engine or of a search.
+    pub fn run(&self) -> Result<ComparisonReport, Error> {
+        let mut rows: Vec<ComparisonRow> = self
+            .positions
+            .iter()
+            .map(|position| ComparisonRow {
+                position: position.clone(),
+                analyses: Vec::with_capacity(self.engines.len()),
+            })
+            .collect();
+        for (_, config) in &self.engines {
+            let mut engine = ThreadedEngine::spawn(config.clone())?;
+            for row in &mut rows {
+                let analysis =
+                    analyze_position(&mut engine, &row.position, &self.limits, || false, |_| {})?;
+                row.analyses.push(analysis);
+            }
+        }
+        Ok(ComparisonReport {
+            engines: self
+                .engines
+                .iter()
+                .map(|(label, _)| label.clone())
+                .collect(),
+            rows,
+        })
+    }
+}
+
+/// The analyses of one position by every engine of a `Comparison`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ComparisonRow {
+    pub position: String,
+    /// The analyses in the order the engines were added.
+    pub analyses: Vec<Analysis>,
+}
+
+impl ComparisonRow {
+    /// Returns `true` if all engines chose the same move.
+    pub fn best_moves_agree(&self) -> bool {
+        let mut moves = self.analyses.iter().map(|a| a.outcome.move_str());
+        match moves.next() {
+            Some(first) => moves.all(|mv| mv == first),
+            None => true,
+        }
+    }
+}
+
+/// Results of `Comparison::run`, displayed as one table per position.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ComparisonReport {
+    /// Labels of the engines.
+    pub engines: Vec<String>,
+    pub rows: Vec<ComparisonRow>,
+}
+
+impl ComparisonReport {
+    /// Returns the rows where the engines chose different moves.
+    pub fn disagreements(&self) -> impl Iterator<Item = &ComparisonRow> {
+        self.rows.iter().filter(|row| !row.best_moves_agree())
+    }
+}
+
+impl fmt::Display for ComparisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const HEADER: [&str; 5] = ["engine", "bestmove", "score", "depth", "pv"];
+
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "position {}", row.position)?;
+            let cells: Vec<[String; 5]> = self
+                .engines
+                .iter()
+                .zip(&row.analyses)
+                .map(|(label, analysis)| cells(label, analysis))
+                .collect();
+            let mut widths = HEADER.map(str::len);
+            for line in &cells {
+                for (width, cell) in widths.iter_mut().zip(line) {
+                    *width = (*width).max(cell.len());
+                }
+            }
+            let header = HEADER.map(str::to_string);
+            for line in std::iter::once(&header).chain(&cells) {
+                writeln!(f)?;
+                let last = line.len() - 1;
+                for (j, (cell, width)) in line.iter().zip(widths).enumerate() {
+                    if j == last {
+                        write!(f, "{cell}")?;
+                    } else {
+                        write!(f, "{cell:<width$}  ")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats the columns of an analysis, using `-` for missing values.
+fn cells(label: &str, analysis: &Analysis) -> [String; 5] {
+    let info = &analysis.info;
+    let score = info
+        .score
+        .clone()
+        .map(|(v, kind)| InfoParams::Score(v, kind).to_string())
+        .map(|s| s.trim_start_matches("score ").to_string());
+    [
+        label.to_string(),
+        analysis
+            .outcome
+            .move_str()
+            .unwrap_or_else(|| "win".to_string()),
+        score.unwrap_or_else(|| "-".to_string()),
+        info.depth
+            .map_or_else(|| "-".to_string(), |d| d.to_string()),
+        info.pv.join(" "),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::protocol::{BestMoveParams, ScoreKind, SearchInfo};
+    use crate::threaded::SearchOutcome;
+
+    fn analysis(mv: &str, score: (i32, ScoreKind), depth: i32, pv: &[&str]) -> Analysis {
+        Analysis {
+            outcome: SearchOutcome::BestMove(BestMoveParams::MakeMove(mv.to_string(), None)),
+            info: SearchInfo {
+                depth: Some(depth),
+                score: Some(score),
+                pv: pv.iter().map(|m| m.to_string()).collect(),
+                ..Default::default()
+            },
+            elapsed: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn report() {
+        let report = ComparisonReport {
+            engines: vec!["a".to_string(), "engine-b".to_string()],
+            rows: vec![
+                ComparisonRow {
+                    position: "startpos".to_string(),
+                    analyses: vec![
+                        analysis("7g7f", (35, ScoreKind::CpExact), 20, &["7g7f", "3c3d"]),
+                        analysis("2g2f", (-12, ScoreKind::CpExact), 8, &["2g2f"]),
+                    ],
+                },
+                ComparisonRow {
+                    position: "sfen 4k4/9/4P4/9/9/9/9/9/9 b G 1".to_string(),
+                    analyses: vec![
+                        analysis("G*5b", (1, ScoreKind::MateExact), 1, &["G*5b"]),
+                        analysis("G*5b", (1, ScoreKind::MateExact), 2, &["G*5b"]),
+                    ],
+                },
+            ],
+        };
+        assert_eq!(
+            vec!["startpos"],
+            report
+                .disagreements()
+                .map(|row| row.position.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "position startpos\n\
+             engine    bestmove  score   depth  pv\n\
+             a         7g7f      cp 35   20     7g7f 3c3d\n\
+             engine-b  2g2f      cp -12  8      2g2f\n\
+             position sfen 4k4/9/4P4/9/9/9/9/9/9 b G 1\n\
+             engine    bestmove  score   depth  pv\n\
+             a         G*5b      mate 1  1      G*5b\n\
+             engine-b  G*5b      mate 1  2      G*5b",
+            report.to_string()
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Latency measurements validating an engine installation.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::diagnostics::Diagnostics;
+//! use usi::{EngineConfig, ThinkParams};
+//!
+//! let report = Diagnostics::new()
+//!     .hash(4096)
+//!     .limits(ThinkParams::new().byoyomi(Duration::from_millis(200)))
+//!     .run(EngineConfig::new("/path/to/engine"))
+//!     .unwrap();
+//! println!("{}", report);
+//! ```
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::game::STARTPOS;
+use crate::protocol::ThinkParams;
+use crate::threaded::{EngineConfig, ThreadedEngine};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Measures the handshake time of an engine, its `readyok` latency
+/// after a large `USI_Hash` setting and its `bestmove` turnaround
+/// over a set of probe positions.
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+    hash: Option<u32>,
+    probes: Vec<String>,
+    limits: ThinkParams,
+    timeout: Duration,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Diagnostics {
+            hash: None,
+            probes: Vec::new(),
+            limits: ThinkParams::new().byoyomi(Duration::from_millis(100)),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics::default()
+    }
+
+    /// Sets `USI_Hash` to `mb` megabytes before measuring the `readyok` latency.
+    #[must_use]
+    pub fn hash(mut self, mb: u32) -> Self {
+        self.hash = Some(mb);
+        self
+    }
+
+    /// Adds a probe position in SFEN notation.
+    ///
+    /// The initial position is probed if no position was added.
+    #[must_use]
+    pub fn probe(mut self, position: &str) -> Self {
+        self.probes.push(position.to_string());
+        self
+    }
+
+    /// Sets the limits of the probe searches, 100ms of byoyomi by default.
+    #[must_use]
+    pub fn limits(mut self, limits: ThinkParams) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how long to wait for each `readyok` and `bestmove`, 30 seconds by default.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Spawns the engine described by `config` and measures it.
+    ///
+    /// Returns the error of spawning the engine or of the handshake if it did not start,
+    /// or `Error::Timeout` if the engine did not answer `isready` or a probe in time.
+    pub fn run(&self, config: EngineConfig) -> Result<DiagnosticsReport, Error> {
+        let started = Instant::now();
+        let mut engine = ThreadedEngine::spawn(config)?;
+        let handshake = started.elapsed();
+        if engine.info().is_none() {
+            return Err(engine.poll_error().unwrap_or(Error::Timeout));
+        }
+
+        let mut report = DiagnosticsReport {
+            engine_name: engine.name().to_string(),
+            handshake,
+            ready_latency: timed(|| engine.wait_ready(self.timeout))?,
+            hash: None,
+            turnarounds: Vec::new(),
+        };
+        if let Some(mb) = self.hash {
+            let value = mb.to_string();
+            let latency =
+                timed(|| engine.set_option_checked("USI_Hash", Some(&value), self.timeout))?;
+            report.hash = Some((mb, latency));
+        }
+
+        let default_probe = [STARTPOS.to_string()];
+        let probes = if self.probes.is_empty() {
+            &default_probe[..]
+        } else {
+            &self.probes[..]
+        };
+        for position in probes {
+            engine.set_position(position);
+            let handle = engine.go(self.limits.clone())?;
+            let result = handle.wait(self.timeout)?;
+            report.turnarounds.push(result.elapsed);
+        }
+        Ok(report)
+    }
+}
+
+fn timed<F: FnOnce() -> Result<(), Error>>(f: F) -> Result<Duration, Error> {
+    let started = Instant::now();
+    f().map(|_| started.elapsed())
+}
+
+/// Measurements taken by `Diagnostics::run`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiagnosticsReport {
+    /// Name of the engine reported during the handshake.
+    pub engine_name: String,
+    /// Time from spawning the process to the end of the handshake.
+    pub handshake: Duration,
+    /// Time between `isready` and `readyok` with the default settings.
+    pub ready_latency: Duration,
+    /// `USI_Hash` size in megabytes and the `readyok` latency after setting it.
+    pub hash: Option<(u32, Duration)>,
+    /// Time between `go` and `bestmove` for each probe position.
+    pub turnarounds: Vec<Duration>,
+}
+
+impl DiagnosticsReport {
+    /// Returns the average `bestmove` turnaround, or `None` without probes.
+    pub fn average_turnaround(&self) -> Option<Duration> {
+        let n = u32::try_from(self.turnarounds.len())
+            .ok()
+            .filter(|&n| n > 0)?;
+        Some(self.turnarounds.iter().sum::<Duration>() / n)
+    }
+}
+
+impl fmt::Display for DiagnosticsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "engine: {}", self.engine_name)?;
+        writeln!(f, "handshake: {}ms", self.handshake.as_millis())?;
+        write!(f, "readyok: {}ms", self.ready_latency.as_millis())?;
+        if let Some((mb, latency)) = self.hash {
+            write!(
+                f,
+                "\nreadyok after USI_Hash {}: {}ms",
+                mb,
+                latency.as_millis()
+            )?;
+        }
+        if let Some(average) = self.average_turnaround() {
+            write!(
+                f,
+                "\nbestmove: {}ms average over {} positions",
+                average.as_millis(),
+                self.turnarounds.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = DiagnosticsReport {
+            engine_name: "engine".to_string(),
+            handshake: Duration::from_millis(120),
+            ready_latency: Duration::from_millis(3),
+            hash: Some((4096, Duration::from_millis(900))),
+            turnarounds: vec![Duration::from_millis(100), Duration::from_millis(140)],
+        };
+        assert_eq!(
+            Some(Duration::from_millis(120)),
+            report.average_turnaround()
+        );
+        assert_eq!(
+            "engine: engine\nhandshake: 120ms\nreadyok: 3ms\n\
+             readyok after USI_Hash 4096: 900ms\n\
+             bestmove: 120ms average over 2 positions",
+            report.to_string()
+        );
+
+        report.hash = None;
+        report.turnarounds.clear();
+        assert_eq!(None, report.average_turnaround());
+        assert_eq!(
+            "engine: engine\nhandshake: 120ms\nreadyok: 3ms",
+            report.to_string()
+        );
+    }
+}
@@ -1,6 +1,13 @@
+use std::fmt;
+
 use thiserror::Error;
 
+/// Errors of the crate.
+///
+/// Errors may be wrapped in `Error::Context` describing where they occurred,
+/// so match on `class` or `root` rather than on the variants directly.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("illegal USI command syntax")]
     IllegalSyntax,
@@ -11,9 +18,259 @@ pub enum Error {
     #[error("the engine already started listening")]
     IllegalOperation,
 
+    #[error("a search is already in progress")]
+    SearchInProgress,
+
+    #[error("the operation was cancelled")]
+    Cancelled,
+
+    #[error("timed out waiting for the engine")]
+    Timeout,
+
+    #[error("an engine output line exceeded {0} bytes")]
+    LineTooLong(usize),
+
+    #[error("more than {0} bytes of engine output were buffered")]
+    OutputOverflow(usize),
+
     #[error("IO error occurred when communicating with the engine")]
     EngineIo(#[from] std::io::Error),
 
+    #[error("the engine rejected the option {0}")]
+    UnknownOption(String),
+
+    #[error("the engine played an illegal move: {0}")]
+    IllegalEngineMove(String),
+
+    #[error("illegal move: {0}")]
+    IllegalMove(String),
+
+    #[error("illegal configuration: {0}")]
+    IllegalConfig(String),
+
+    #[error("invalid evaluation file: {0}")]
+    InvalidEvalFile(String),
+
+    #[error("a hash size of {0} MiB exceeds the {1} MiB of available memory")]
+    HashTooLarge(u64, u64),
+
     #[error("An error occurred inside the external handler")]
     HandlerError(#[from] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("the external handler panicked: {0}")]
+    HandlerPanic(String),
+
+    #[error("failed to spawn the engine {path}: {source}")]
+    Spawn {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("the USI handshake failed: {0}")]
+    Handshake(Box<Error>),
+
+    #[error("the engine has terminated")]
+    Terminated,
+
+    #[error("{source} ({context})")]
+    Context {
+        context: Box<ErrorContext>,
+        source: Box<Error>,
+    },
+}
+
+/// Where an error occurred, attached with `Error::with_engine`, `Error::with_command`
+/// and `Error::with_line`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ErrorContext {
+    /// Name of the engine.
+    pub engine: Option<String>,
+    /// The command being sent to the engine.
+    pub command: Option<String>,
+    /// The line of engine output being handled.
+    pub line: Option<String>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts = [
+            self.engine.as_ref().map(|e| format!("engine {e}")),
+            self.command.as_ref().map(|c| format!("command `{c}`")),
+            self.line.as_ref().map(|l| format!("line `{l}`")),
+        ];
+        let parts: Vec<String> = parts.into_iter().flatten().collect();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Classes of failures, returned by `Error::class`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorClass {
+    /// The engine process could not be started.
+    Spawn,
+    /// The engine did not complete the `usi` handshake.
+    Handshake,
+    /// A command or engine output could not be parsed.
+    Parse,
+    /// The engine did not answer in time.
+    Timeout,
+    /// The engine terminated.
+    Terminated,
+    /// Communicating with the engine failed.
+    Io,
+    /// The configuration or an option was rejected.
+    Config,
+    /// An operation was used in a state which does not allow it.
+    Usage,
+    Cancelled,
+    /// The engine misbehaved, e.g. by playing an illegal move.
+    Engine,
+    /// A callback failed.
+    Handler,
+}
+
+impl Error {
+    /// Returns the class of the failure, looking through `Error::Context`.
+    pub fn class(&self) -> ErrorClass {
+        match self.root() {
+            Error::Spawn { .. } => ErrorClass::Spawn,
+            Error::Handshake(_) => ErrorClass::Handshake,
+            Error::IllegalSyntax
+            | Error::IllegalNumberFormat(_)
+            | Error::LineTooLong(_)
+            | Error::OutputOverflow(_) => ErrorClass::Parse,
+            Error::Timeout => ErrorClass::Timeout,
+            Error::Terminated => ErrorClass::Terminated,
+            Error::EngineIo(_) => ErrorClass::Io,
+            Error::IllegalConfig(_)
+            | Error::InvalidEvalFile(_)
+            | Error::HashTooLarge(..)
+            | Error::UnknownOption(_) => ErrorClass::Config,
+            Error::IllegalOperation | Error::SearchInProgress | Error::IllegalMove(_) => {
+                ErrorClass::Usage
+            }
+            Error::Cancelled => ErrorClass::Cancelled,
+            Error::IllegalEngineMove(_) => ErrorClass::Engine,
+            Error::HandlerError(_) | Error::HandlerPanic(_) => ErrorClass::Handler,
+            Error::Context { source, .. } => source.class(),
+        }
+    }
+
+    /// Returns the error without the `Error::Context` attached to it.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    /// Returns the context attached to the error, if any.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Attaches the name of the engine, unless one is attached already.
+    pub fn with_engine(self, engine: &str) -> Error {
+        self.with_context(|c| {
+            c.engine.get_or_insert_with(|| engine.to_string());
+        })
+    }
+
+    /// Attaches the command being sent, unless one is attached already.
+    pub fn with_command(self, command: &str) -> Error {
+        self.with_context(|c| {
+            c.command.get_or_insert_with(|| command.to_string());
+        })
+    }
+
+    /// Attaches the line of engine output being handled, unless one is attached already.
+    pub fn with_line(self, line: &str) -> Error {
+        self.with_context(|c| {
+            c.line.get_or_insert_with(|| line.trim_end().to_string());
+        })
+    }
+
+    fn with_context(self, f: impl FnOnce(&mut ErrorContext)) -> Error {
+        let (mut context, source) = match self {
+            Error::Context { context, source } => (context, source),
+            err => (Box::default(), Box::new(err)),
+        };
+        f(&mut context);
+        Error::Context { context, source }
+    }
+
+    /// Returns `true` for failures which may not recur when spawning the engine again,
+    /// such as an executable locked by another process, a network file system error,
+    /// or an engine exiting or timing out during the handshake.
+    pub fn is_transient(&self) -> bool {
+        use std::io::ErrorKind;
+
+        match self.root() {
+            Error::Timeout | Error::Terminated => true,
+            Error::Handshake(err) => err.is_transient(),
+            Error::EngineIo(err) | Error::Spawn { source: err, .. } => matches!(
+                err.kind(),
+                ErrorKind::PermissionDenied
+                    | ErrorKind::ExecutableFileBusy
+                    | ErrorKind::ResourceBusy
+                    | ErrorKind::StaleNetworkFileHandle
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UsiEngineHandler;
+
+    #[test]
+    fn context() {
+        let err = Error::Timeout.with_engine("Engine").with_command("isready");
+        assert_eq!(ErrorClass::Timeout, err.class());
+        assert!(matches!(err.root(), Error::Timeout));
+        assert!(err.is_transient());
+        assert_eq!(
+            "timed out waiting for the engine (engine Engine, command `isready`)",
+            err.to_string()
+        );
+
+        let err = err.with_engine("Other").with_line("bestmove\n");
+        let context = err.context().unwrap();
+        assert_eq!(Some("Engine"), context.engine.as_deref());
+        assert_eq!(Some("bestmove"), context.line.as_deref());
+        assert!(matches!(
+            err,
+            Error::Context { ref source, .. } if matches!(**source, Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn class() {
+        let err =
+            UsiEngineHandler::spawn("/nonexistent/engine", ".", Vec::<&str>::new()).unwrap_err();
+        assert_eq!(ErrorClass::Spawn, err.class());
+        assert!(err.to_string().contains("/nonexistent/engine"));
+        assert!(!err.is_transient());
+
+        let err = Error::Handshake(Box::new(Error::Terminated));
+        assert_eq!(ErrorClass::Handshake, err.class());
+        assert!(err.is_transient());
+        assert_eq!(ErrorClass::Parse, Error::LineTooLong(10).class());
+        assert_eq!(
+            ErrorClass::Config,
+            Error::UnknownOption("x".to_string()).class()
+        );
+    }
 }
@@ -0,0 +1,252 @@
+//! Falling back to a backup engine when the primary one fails.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::failover::FailoverEngine;
+//! use usi::{EngineConfig, ThinkParams};
+//!
+//! let mut engine = FailoverEngine::spawn(
+//!     EngineConfig::new("/path/to/experimental-engine"),
+//!     EngineConfig::new("/path/to/stable-engine"),
+//! )
+//! .unwrap();
+//! engine.set_position("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1");
+//! let params = ThinkParams::new()
+//!     .btime(Duration::from_secs(60))
+//!     .wtime(Duration::from_secs(60))
+//!     .byoyomi(Duration::from_secs(10));
+//! let result = engine.search(params).unwrap();
+//! while let Some(event) = engine.poll_event() {
+//!     println!("failed over: {}", event.reason);
+//! }
+//! println!("{:?}", result.outcome);
+//! ```
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, ErrorClass};
+use crate::game::Color;
+use crate::protocol::{GameOverKind, InfoParams, OptionParams, ThinkParams};
+use crate::server::{follow, Backend, SearchControl};
+use crate::threaded::{EngineConfig, SearchOutcome, SearchResult, ThreadedEngine};
+
+/// Time allowed beyond the time limit of a search before the engine is considered hung.
+const DEFAULT_HANG_MARGIN: Duration = Duration::from_secs(1);
+
+/// Which engine of a `FailoverEngine` is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EngineRole {
+    Primary,
+    Backup,
+}
+
+/// Reported by `FailoverEngine::poll_event` when switching to the backup engine.
+#[derive(Debug)]
+pub struct FailoverEvent {
+    /// Why the primary engine was given up: `Error::Timeout` if it hung,
+    /// `Error::Terminated` if it terminated, or the error of spawning it.
+    pub reason: Error,
+    /// The position sent to the backup engine.
+    pub position: Option<String>,
+    /// The search handed over to the backup engine with the time left,
+    /// or `None` if the primary engine failed outside of a search.
+    pub params: Option<ThinkParams>,
+    /// Time spent on the search before handing it over.
+    pub elapsed: Duration,
+}
+
+/// An engine which switches to a backup engine if the primary one hangs or crashes.
+///
+/// A search interrupted by the failure is repeated by the backup engine on
+/// the same position, with the time used so far taken from the side to move.
+/// Once failed over, the backup engine is used for the following searches.
+///
+/// Searches without a time limit are only failed over if the engine terminates.
+#[derive(Debug)]
+pub struct FailoverEngine {
+    backup: EngineConfig,
+    engine: ThreadedEngine,
+    role: EngineRole,
+    position: Option<String>,
+    /// Options set through `set_option`, applied to the backup engine too.
+    options: Vec<(String, Option<String>)>,
+    hang_margin: Duration,
+    events: VecDeque<FailoverEvent>,
+}
+
+impl FailoverEngine {
+    /// Spawns the primary engine, or the backup engine if that fails.
+    pub fn spawn(primary: EngineConfig, backup: EngineConfig) -> Result<Self, Error> {
+        let mut events = VecDeque::new();
+        let (engine, role) = match ThreadedEngine::spawn(primary) {
+            Ok(engine) => (engine, EngineRole::Primary),
+            Err(err) => {
+                let engine = ThreadedEngine::spawn(backup.clone())?;
+                events.push_back(FailoverEvent {
+                    reason: err,
+                    position: None,
+                    params: None,
+                    elapsed: Duration::ZERO,
+                });
+                (engine, EngineRole::Backup)
+            }
+        };
+        Ok(FailoverEngine {
+            backup,
+            engine,
+            role,
+            position: None,
+            options: Vec::new(),
+            hang_margin: DEFAULT_HANG_MARGIN,
+            events,
+        })
+    }
+
+    /// Sets how long the primary engine may exceed the time limit of a search
+    /// before it is considered hung. Defaults to one second.
+    ///
+    /// As a hang is only detected once the time limit is exceeded, the backup
+    /// engine then searches within what is left of the byoyomi and this margin.
+    pub fn set_hang_margin(&mut self, margin: Duration) {
+        self.hang_margin = margin;
+    }
+
+    /// Returns which engine is running.
+    pub fn role(&self) -> EngineRole {
+        self.role
+    }
+
+    /// Returns the running engine.
+    ///
+    /// Positions set directly on the engine are not known to the backup engine.
+    pub fn engine(&mut self) -> &mut ThreadedEngine {
+        &mut self.engine
+    }
+
+    /// Sets an option of the running engine, and of the backup engine once failed over.
+    pub fn set_option(&mut self, name: &str, value: Option<&str>) {
+        self.engine.set_option(name, value);
+        self.options.retain(|(n, _)| n != name);
+        self.options
+            .push((name.to_string(), value.map(str::to_string)));
+    }
+
+    /// Set the current position using SFEN notation.
+    pub fn set_position(&mut self, sfen: &str) {
+        self.engine.set_position(sfen);
+        self.position = Some(sfen.to_string());
+    }
+
+    /// Searches the current position and waits for the result.
+    ///
+    /// If the primary engine hangs or terminates, the search is repeated by
+    /// the backup engine and a `FailoverEvent` is reported.
+    /// Returns `Error::Timeout` if the backup engine hangs too.
+    pub fn search(&mut self, params: ThinkParams) -> Result<SearchResult, Error> {
+        self.search_with(params, &SearchControl::new(), &mut |_| {})
+    }
+
+    /// Like `search`, passing on the `info` commands of the search and the requests of `control`.
+    pub fn search_with(
+        &mut self,
+        params: ThinkParams,
+        control: &SearchControl,
+        on_info: &mut dyn FnMut(&[InfoParams]),
+    ) -> Result<SearchResult, Error> {
+        let started = Instant::now();
+        let limit = params
+            .time_limit(self.black_to_move())
+            .map(|l| l + self.hang_margin);
+        let result = self
+            .engine
+            .go(params.clone())
+            .and_then(|handle| follow(&handle, limit, control, on_info));
+        let reason = match result {
+            Err(err)
+                if self.role == EngineRole::Primary
+                    && matches!(
+                        err.class(),
+                        ErrorClass::Timeout | ErrorClass::Terminated | ErrorClass::Io
+                    ) =>
+            {
+                err
+            }
+            result => return result,
+        };
+
+        self.engine = ThreadedEngine::spawn(self.backup.clone())?;
+        self.role = EngineRole::Backup;
+        for (name, value) in &self.options {
+            self.engine.set_option(name, value.as_deref());
+        }
+        if let Some(position) = &self.position {
+            self.engine.set_position(position);
+        }
+        let elapsed = started.elapsed();
+        let remaining = params.after_elapsed(self.black_to_move(), elapsed);
+        self.events.push_back(FailoverEvent {
+            reason,
+            position: self.position.clone(),
+            params: Some(remaining.clone()),
+            elapsed,
+        });
+        let handle = self.engine.go(remaining.clone())?;
+        let limit = remaining
+            .time_limit(self.black_to_move())
+            .map(|l| l + self.hang_margin);
+        follow(&handle, limit, control, on_info)
+    }
+
+    /// Takes the next failover event (non-blocking).
+    pub fn poll_event(&mut self) -> Option<FailoverEvent> {
+        self.events.pop_front()
+    }
+
+    fn black_to_move(&self) -> bool {
+        self.position
+            .as_deref()
+            .and_then(Color::to_move)
+            .is_none_or(|color| color == Color::Black)
+    }
+}
+
+impl Backend for FailoverEngine {
+    fn name(&self) -> String {
+        self.engine.name().to_string()
+    }
+
+    fn options(&self) -> Vec<OptionParams> {
+        self.engine.options()
+    }
+
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        FailoverEngine::set_option(self, name, value);
+    }
+
+    fn new_game(&mut self) {
+        self.engine.new_game(None);
+    }
+
+    fn game_over(&mut self, result: GameOverKind) {
+        self.engine.game_over(result);
+    }
+
+    fn is_ready(&mut self) -> Result<(), Error> {
+        Backend::is_ready(&mut self.engine)
+    }
+
+    fn search(
+        &mut self,
+        position: &str,
+        params: ThinkParams,
+        control: &SearchControl,
+        on_info: &mut dyn FnMut(&[InfoParams]),
+    ) -> Result<SearchOutcome, Error> {
+        self.set_position(position);
+        self.search_with(params, control, on_info)
+            .map(|result| result.outcome)
+    }
+}
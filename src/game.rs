@@ -0,0 +1,1076 @@
+//! Games between two engines.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::game::{play_game, TimeControl, STARTPOS};
+//! use usi::{EngineConfig, ThreadedEngine};
+//!
+//! let spawn = |path: &str| {
+//!     ThreadedEngine::spawn(EngineConfig {
+//!         path: path.to_string(),
+//!         ..Default::default()
+//!     })
+//!     .unwrap()
+//! };
+//! let mut black = spawn("/path/to/engine_a");
+//! let mut white = spawn("/path/to/engine_b");
+//!
+//! let time_control = TimeControl::new()
+//!     .initial(Duration::from_secs(60))
+//!     .byoyomi(Duration::from_secs(1));
+//! let record = play_game(&mut black, &mut white, time_control, STARTPOS).unwrap();
+//! println!("{:?} after {} moves", record.result, record.moves.len());
+//! ```
+
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::error::Error;
+use crate::process::{EngineInfo, Fingerprint};
+use crate::protocol::{BestMoveParams, GameOverKind, ThinkParams};
+use crate::rules::Position;
+use crate::threaded::{EngineConfig, ReadyToken, SearchHandle, SearchOutcome, ThreadedEngine};
+
+/// The initial position of a regular game.
+pub const STARTPOS: &str = "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1";
+
+/// A standard handicap, removing pieces of the stronger player, who plays White
+/// and moves first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Handicap {
+    /// Without the lance on 1a (kyō-ochi).
+    Lance,
+    /// Without the lance on 9a (migi kyō-ochi).
+    RightLance,
+    /// Without the bishop (kaku-ochi).
+    Bishop,
+    /// Without the rook (hisha-ochi).
+    Rook,
+    /// Without the rook and the lance on 1a (hikyō-ochi).
+    RookLance,
+    /// Without the rook and the bishop (nimai-ochi).
+    TwoPieces,
+    /// Also without both lances (yonmai-ochi).
+    FourPieces,
+    /// Also without both knights (rokumai-ochi).
+    SixPieces,
+    /// Also without both silvers (hachimai-ochi).
+    EightPieces,
+    /// Also without both golds, leaving the king alone (jūmai-ochi).
+    TenPieces,
+}
+
+impl Handicap {
+    /// All handicaps, from the smallest to the largest.
+    pub const ALL: [Handicap; 10] = [
+        Handicap::Lance,
+        Handicap::RightLance,
+        Handicap::Bishop,
+        Handicap::Rook,
+        Handicap::RookLance,
+        Handicap::TwoPieces,
+        Handicap::FourPieces,
+        Handicap::SixPieces,
+        Handicap::EightPieces,
+        Handicap::TenPieces,
+    ];
+
+    /// Returns the initial position in SFEN.
+    pub fn sfen(self) -> &'static str {
+        match self {
+            Handicap::Lance => "lnsgkgsn1/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::RightLance => {
+                "1nsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1"
+            }
+            Handicap::Bishop => "lnsgkgsnl/1r7/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::Rook => "lnsgkgsnl/7b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::RookLance => "lnsgkgsn1/7b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::TwoPieces => "lnsgkgsnl/9/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::FourPieces => "1nsgkgsn1/9/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::SixPieces => "2sgkgs2/9/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::EightPieces => "3gkg3/9/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            Handicap::TenPieces => "4k4/9/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+        }
+    }
+
+    /// Returns the handicap whose initial position is `sfen`, if any.
+    pub fn from_sfen(sfen: &str) -> Option<Handicap> {
+        let sfen = sfen.trim();
+        Handicap::ALL.into_iter().find(|h| h.sfen() == sfen)
+    }
+}
+
+/// Prepares `engine` for a new game from a handicap position.
+///
+/// Starts a new game like `ThreadedEngine::new_game` and tracks the position
+/// from the handicap's initial position, in which White moves first, so that
+/// every `go` sends the handicap position followed by the moves pushed since.
+/// The returned token completes once the engine is ready.
+pub fn start_handicap_game(engine: &mut ThreadedEngine, handicap: Handicap) -> ReadyToken {
+    let ready = engine.new_game(None);
+    // Handicap positions are always valid.
+    let _ = engine.track_position(handicap.sfen());
+    ready
+}
+
+/// Number of moves after which `play_game` declares a draw.
+pub const MAX_MOVES: usize = 256;
+
+/// Time allowed beyond the clock before an engine loses on time,
+/// covering the latency of the pipes, unless set with `TimeControl::margin`.
+const TIME_MARGIN: Duration = Duration::from_secs(1);
+
+/// Time allowed per move when moves are limited by nodes, unless set with `TimeControl::margin`.
+const NODES_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long engines are given to answer `isready` before the game.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A side of the board. Black moves first in a regular game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Color {
+    Black,
+    White,
+}
+
+impl Color {
+    pub fn opponent(self) -> Color {
+        match self {
+            Color::Black => Color::White,
+            Color::White => Color::Black,
+        }
+    }
+
+    /// Returns the side to move in an SFEN position.
+    pub fn to_move(sfen: &str) -> Option<Color> {
+        match sfen.split_whitespace().nth(1)? {
+            "b" => Some(Color::Black),
+            "w" => Some(Color::White),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn index(self) -> usize {
+        match self {
+            Color::Black => 0,
+            Color::White => 1,
+        }
+    }
+}
+
+/// Time allowed to each side.
+///
+/// The runner keeps the clocks: engines are charged the time from sending `go`
+/// to receiving their answer, and are sent their remaining times with every `go`.
+///
+/// With a byoyomi, a side which used up its main time must move within the byoyomi.
+/// With an increment, the increment is added to the main time after each move.
+/// A side loses on time once it exceeds its time by more than the margin.
+///
+/// With a node limit, moves are searched with `go nodes` and no clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TimeControl {
+    initial: Duration,
+    byoyomi: Duration,
+    increment: Duration,
+    margin: Option<Duration>,
+    nodes: Option<u64>,
+}
+
+impl TimeControl {
+    pub fn new() -> Self {
+        TimeControl::default()
+    }
+
+    #[must_use]
+    pub fn initial(mut self, t: Duration) -> Self {
+        self.initial = t;
+        self
+    }
+
+    #[must_use]
+    pub fn byoyomi(mut self, t: Duration) -> Self {
+        self.byoyomi = t;
+        self
+    }
+
+    #[must_use]
+    pub fn increment(mut self, t: Duration) -> Self {
+        self.increment = t;
+        self
+    }
+
+    /// Sets the grace period allowed beyond the clock, 1 second by default.
+    ///
+    /// With a node limit, this is the time allowed per move, 60 seconds by default.
+    #[must_use]
+    pub fn margin(mut self, t: Duration) -> Self {
+        self.margin = Some(t);
+        self
+    }
+
+    /// Limits each move to `nodes` nodes instead of the clock, so that engines
+    /// searching deterministically play the same games on every run.
+    #[must_use]
+    pub fn nodes(mut self, nodes: u64) -> Self {
+        self.nodes = Some(nodes);
+        self
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.initial.is_zero()
+            && self.byoyomi.is_zero()
+            && self.increment.is_zero()
+            && self.nodes.is_none()
+    }
+}
+
+/// Remaining main time of both sides.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Clock {
+    time_control: TimeControl,
+    remaining: [Duration; 2],
+}
+
+impl Clock {
+    fn new(time_control: TimeControl) -> Self {
+        Clock {
+            time_control,
+            remaining: [time_control.initial; 2],
+        }
+    }
+
+    /// Returns the `go` parameters for the current clocks.
+    fn think_params(&self) -> ThinkParams {
+        let tc = &self.time_control;
+        if let Some(nodes) = tc.nodes {
+            return ThinkParams::new().nodes(nodes);
+        }
+        let mut params = ThinkParams::new()
+            .btime(self.remaining[Color::Black.index()])
+            .wtime(self.remaining[Color::White.index()]);
+        if !tc.byoyomi.is_zero() {
+            params = params.byoyomi(tc.byoyomi);
+        }
+        if !tc.increment.is_zero() {
+            params = params.binc(tc.increment).winc(tc.increment);
+        }
+        params
+    }
+
+    /// Returns the time `color` may think for before losing on time.
+    fn allowed(&self, color: Color) -> Duration {
+        if self.time_control.nodes.is_some() {
+            return self.time_control.margin.unwrap_or(NODES_MARGIN);
+        }
+        let margin = self.time_control.margin.unwrap_or(TIME_MARGIN);
+        self.remaining[color.index()] + self.time_control.byoyomi + margin
+    }
+
+    /// Charges `elapsed` to `color`, returning `false` if it ran out of time.
+    fn charge(&mut self, color: Color, elapsed: Duration) -> bool {
+        if elapsed > self.allowed(color) {
+            return false;
+        }
+        let remaining = &mut self.remaining[color.index()];
+        *remaining = remaining.saturating_sub(elapsed) + self.time_control.increment;
+        true
+    }
+}
+
+/// A move played in a game.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecordedMove {
+    /// The move in USI notation.
+    pub mv: String,
+    pub color: Color,
+    /// Time taken to play the move, from sending `go` to receiving `bestmove` for engines.
+    pub elapsed: Duration,
+    /// Main time left to the player after the move.
+    pub remaining: Duration,
+    /// Time the engine reported having searched in its last `info time`, if any.
+    pub reported: Option<Duration>,
+}
+
+impl RecordedMove {
+    /// Returns the time charged for the move beyond the time the engine reported,
+    /// e.g. spent in the pipes or before the engine started its own clock.
+    ///
+    /// Returns `None` if the engine reported no time.
+    pub fn overhead(&self) -> Option<Duration> {
+        self.reported.map(|r| self.elapsed.saturating_sub(r))
+    }
+}
+
+/// Time used by one player over a game, aggregated from the `RecordedMove`s.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TimeUsage {
+    /// Number of moves played.
+    pub moves: usize,
+    /// Total time spent on the moves.
+    pub total: Duration,
+    /// Longest time spent on a single move.
+    pub max: Duration,
+    /// Total time charged beyond the times reported by the engine, see `RecordedMove::overhead`.
+    pub overhead: Duration,
+}
+
+impl TimeUsage {
+    /// Aggregates the time spent by `color` on `moves`.
+    pub fn of(moves: &[RecordedMove], color: Color) -> TimeUsage {
+        moves
+            .iter()
+            .filter(|m| m.color == color)
+            .fold(TimeUsage::default(), |usage, m| TimeUsage {
+                moves: usage.moves + 1,
+                total: usage.total + m.elapsed,
+                max: usage.max.max(m.elapsed),
+                overhead: usage.overhead + m.overhead().unwrap_or_default(),
+            })
+    }
+
+    /// Returns the average time spent per move, or zero if no move was played.
+    pub fn average(&self) -> Duration {
+        match u32::try_from(self.moves) {
+            Ok(0) => Duration::ZERO,
+            Ok(n) => self.total / n,
+            Err(_) => self.total.div_f64(self.moves as f64),
+        }
+    }
+}
+
+/// Why a game ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GameEndReason {
+    /// The loser answered `bestmove resign`.
+    Resign,
+    /// The winner answered `bestmove win`, declaring a win by entering king.
+    /// The declaration is only verified with the `shogi-rules` feature.
+    Declaration,
+    /// The loser declared a win without meeting the 27-point rule.
+    /// Only detected with the `shogi-rules` feature.
+    IllegalDeclaration,
+    /// The loser did not answer in time.
+    Timeout,
+    /// The loser terminated or answered something other than a move.
+    EngineFailure,
+    /// The game reached `MAX_MOVES` moves and is a draw.
+    MaxMoves,
+    /// The loser played an illegal move. Only detected with the `shogi-rules` feature.
+    IllegalMove,
+    /// The same position occurred four times and the game is a draw (sennichite).
+    Repetition,
+    /// The same position occurred four times while the loser checked with every move.
+    /// Only detected with the `shogi-rules` feature.
+    PerpetualCheck,
+}
+
+/// The outcome of a game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GameResult {
+    /// The winner, or `None` for a draw.
+    pub winner: Option<Color>,
+    pub reason: GameEndReason,
+}
+
+impl GameResult {
+    /// Returns the `gameover` result to send to the engine playing `color`.
+    pub fn game_over_kind(&self, color: Color) -> GameOverKind {
+        match self.winner {
+            None => GameOverKind::Draw,
+            Some(w) if w == color => GameOverKind::Win,
+            Some(_) => GameOverKind::Lose,
+        }
+    }
+}
+
+/// A complete game returned by `play_game`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GameRecord {
+    /// The initial position in SFEN.
+    pub start_position: String,
+    pub moves: Vec<RecordedMove>,
+    pub result: GameResult,
+    /// Fingerprint of the black engine's executable, if it could be read.
+    pub black_engine: Option<Fingerprint>,
+    /// Fingerprint of the white engine's executable, if it could be read.
+    pub white_engine: Option<Fingerprint>,
+}
+
+impl GameRecord {
+    /// Returns the final position as the argument of `position sfen`,
+    /// i.e. the initial position followed by the moves played.
+    pub fn position(&self) -> String {
+        position_with_moves(&self.start_position, &self.moves)
+    }
+
+    /// Returns the time used by `color` over the game.
+    pub fn time_usage(&self, color: Color) -> TimeUsage {
+        TimeUsage::of(&self.moves, color)
+    }
+
+    /// Returns the game as a single line without times: the final position,
+    /// the winner and the reason the game ended, separated by tabs.
+    pub fn transcript(&self) -> String {
+        let winner = match self.result.winner {
+            Some(Color::Black) => "black",
+            Some(Color::White) => "white",
+            None => "draw",
+        };
+        format!("{}\t{}\t{:?}", self.position(), winner, self.result.reason)
+    }
+}
+
+/// Number of occurrences of a position which ends the game by repetition.
+const REPETITIONS: usize = 4;
+
+/// Positions reached in a game, for detecting repetitions.
+#[derive(Clone, Debug)]
+struct Repetitions {
+    /// The current position, or `None` once it cannot be tracked.
+    position: Option<Position>,
+    /// Each position reached, as its SFEN without the move number,
+    /// the side to move and whether it is in check.
+    history: Vec<(String, Color, bool)>,
+}
+
+impl Repetitions {
+    fn new(start_position: &str) -> Self {
+        let mut repetitions = Repetitions {
+            position: Position::from_sfen(start_position).ok(),
+            history: Vec::new(),
+        };
+        repetitions.record();
+        repetitions
+    }
+
+    fn record(&mut self) {
+        if let Some(position) = &self.position {
+            let sfen = position.to_sfen();
+            let key = sfen.rsplit_once(' ').map_or(sfen.as_str(), |(k, _)| k);
+            self.history.push((
+                key.to_string(),
+                position.side_to_move(),
+                position.in_check(),
+            ));
+        }
+    }
+
+    /// Plays `mv`, returning the result if the game ends by repetition.
+    fn play(&mut self, mv: &str) -> Option<GameResult> {
+        if !self.position.as_mut()?.play(mv) {
+            self.position = None;
+            return None;
+        }
+        self.record();
+
+        let (key, _, _) = self.history.last()?;
+        let occurrences: Vec<usize> = (0..self.history.len())
+            .filter(|i| self.history[*i].0 == *key)
+            .collect();
+        if occurrences.len() < REPETITIONS {
+            return None;
+        }
+        let cycle = &self.history[occurrences[occurrences.len() - REPETITIONS] + 1..];
+        if cfg!(feature = "shogi-rules") {
+            for color in [Color::Black, Color::White] {
+                // Positions reached by the moves of `color`.
+                let mut reached = cycle.iter().filter(|(_, c, _)| *c != color);
+                if reached.all(|(_, _, check)| *check) {
+                    return Some(GameResult {
+                        winner: Some(color.opponent()),
+                        reason: GameEndReason::PerpetualCheck,
+                    });
+                }
+            }
+        }
+        Some(GameResult {
+            winner: None,
+            reason: GameEndReason::Repetition,
+        })
+    }
+}
+
+/// Returns `false` if `mv` is known to be illegal after `moves`.
+///
+/// Positions which cannot be tracked, e.g. after an unchecked human move, are not checked.
+#[cfg(feature = "shogi-rules")]
+fn is_playable(start: &str, moves: &[RecordedMove], mv: &str) -> bool {
+    Position::from_sfen(&position_with_moves(start, moves))
+        .map_or(true, |position| position.is_legal(mv))
+}
+
+#[cfg(not(feature = "shogi-rules"))]
+fn is_playable(_start: &str, _moves: &[RecordedMove], _mv: &str) -> bool {
+    true
+}
+
+/// Returns `false` if the side to move after `moves` is known not to meet
+/// the requirements of an entering-king declaration.
+#[cfg(feature = "shogi-rules")]
+fn is_declarable(start: &str, moves: &[RecordedMove]) -> bool {
+    Position::from_sfen(&position_with_moves(start, moves))
+        .map_or(true, |position| position.can_declare_win())
+}
+
+#[cfg(not(feature = "shogi-rules"))]
+fn is_declarable(_start: &str, _moves: &[RecordedMove]) -> bool {
+    true
+}
+
+fn position_with_moves(start: &str, moves: &[RecordedMove]) -> String {
+    if moves.is_empty() {
+        return start.to_string();
+    }
+    let mut position = format!("{start} moves");
+    for m in moves {
+        position.push(' ');
+        position.push_str(&m.mv);
+    }
+    position
+}
+
+/// Plays a game between `black` and `white` from `start_position`, an SFEN string,
+/// and returns its record.
+///
+/// Both engines must have completed the handshake, which `ThreadedEngine::spawn`
+/// does along with sending `usinewgame`. The engines are sent the position and
+/// the clocks before each move, and `gameover` once the game ends.
+///
+/// A game ends when an engine resigns, declares a win, runs out of time, fails,
+/// after `MAX_MOVES` moves, or when the same position occurs for the fourth time.
+/// A repetition is a draw, except that with the `shogi-rules` feature a side
+/// which checked with every move of the repetition loses, and an engine playing
+/// an illegal move loses. Without the feature moves are not checked.
+///
+/// Returns `Error::IllegalConfig` if `start_position` has no side to move or
+/// `time_control` allows no time, and an error if an engine is not ready to play.
+pub fn play_game(
+    black: &mut ThreadedEngine,
+    white: &mut ThreadedEngine,
+    time_control: TimeControl,
+    start_position: &str,
+) -> Result<GameRecord, Error> {
+    play_game_with_cancel(
+        black,
+        white,
+        time_control,
+        start_position,
+        &CancellationToken::new(),
+    )
+}
+
+/// Like `play_game`, but aborts the game once `cancel` is cancelled.
+///
+/// The running search is stopped and both engines are sent `gameover draw`.
+/// Returns `Error::Cancelled` without a record.
+pub fn play_game_with_cancel(
+    black: &mut ThreadedEngine,
+    white: &mut ThreadedEngine,
+    time_control: TimeControl,
+    start_position: &str,
+    cancel: &CancellationToken,
+) -> Result<GameRecord, Error> {
+    let mut color = Color::to_move(start_position)
+        .ok_or_else(|| Error::IllegalConfig(format!("no side to move in {start_position}")))?;
+    if time_control.is_unlimited() {
+        return Err(Error::IllegalConfig(
+            "the time control allows no time".to_string(),
+        ));
+    }
+    black.wait_ready(READY_TIMEOUT)?;
+    white.wait_ready(READY_TIMEOUT)?;
+
+    let mut clock = Clock::new(time_control);
+    let mut moves = Vec::new();
+    let mut repetitions = Repetitions::new(start_position);
+    let result = loop {
+        if moves.len() >= MAX_MOVES {
+            break GameResult {
+                winner: None,
+                reason: GameEndReason::MaxMoves,
+            };
+        }
+        let engine = match color {
+            Color::Black => &mut *black,
+            Color::White => &mut *white,
+        };
+        let lost = |reason| GameResult {
+            winner: Some(color.opponent()),
+            reason,
+        };
+
+        engine.set_position(&position_with_moves(start_position, &moves));
+        let handle = engine.go(clock.think_params())?;
+        let result = match handle.wait_or_cancel(clock.allowed(color), cancel) {
+            Ok(result) => result,
+            Err(Error::Timeout) => {
+                handle.stop();
+                break lost(GameEndReason::Timeout);
+            }
+            Err(Error::Cancelled) => {
+                black.game_over(GameOverKind::Draw);
+                white.game_over(GameOverKind::Draw);
+                return Err(Error::Cancelled);
+            }
+            Err(_) => break lost(GameEndReason::EngineFailure),
+        };
+        if !clock.charge(color, result.engine_elapsed) {
+            break lost(GameEndReason::Timeout);
+        }
+        match result.outcome {
+            SearchOutcome::BestMove(BestMoveParams::MakeMove(mv, _)) => {
+                if !is_playable(start_position, &moves, &mv) {
+                    break lost(GameEndReason::IllegalMove);
+                }
+                let repeated = repetitions.play(&mv);
+                moves.push(RecordedMove {
+                    mv,
+                    color,
+                    elapsed: result.engine_elapsed,
+                    remaining: clock.remaining[color.index()],
+                    reported: result.reported_time,
+                });
+                color = color.opponent();
+                if let Some(result) = repeated {
+                    break result;
+                }
+            }
+            SearchOutcome::BestMove(BestMoveParams::Resign) => break lost(GameEndReason::Resign),
+            SearchOutcome::DeclareWin | SearchOutcome::BestMove(BestMoveParams::Win) => {
+                if !is_declarable(start_position, &moves) {
+                    break lost(GameEndReason::IllegalDeclaration);
+                }
+                break GameResult {
+                    winner: Some(color),
+                    reason: GameEndReason::Declaration,
+                };
+            }
+            SearchOutcome::Checkmate(_) => break lost(GameEndReason::EngineFailure),
+        }
+    };
+
+    let black_engine = black.info().and_then(EngineInfo::fingerprint);
+    let white_engine = white.info().and_then(EngineInfo::fingerprint);
+    for (engine, color) in [(black, Color::Black), (white, Color::White)] {
+        engine.game_over(result.game_over_kind(color));
+    }
+
+    Ok(GameRecord {
+        start_position: start_position.to_string(),
+        moves,
+        result,
+        black_engine,
+        white_engine,
+    })
+}
+
+/// Plays a game from each of `openings` with `nodes` nodes per move
+/// and returns their transcripts, one line per game.
+///
+/// Engines searching deterministically, e.g. with a single thread, play the
+/// same games on every run, so the transcript changes only when the behavior
+/// of an engine does. See `GameRecord::transcript` for the format.
+pub fn play_regression(
+    black: &mut ThreadedEngine,
+    white: &mut ThreadedEngine,
+    nodes: u64,
+    openings: &[&str],
+) -> Result<String, Error> {
+    let time_control = TimeControl::new().nodes(nodes);
+    let mut transcript = String::new();
+    for (i, opening) in openings.iter().enumerate() {
+        if i > 0 {
+            black.new_game(None);
+            white.new_game(None);
+        }
+        let record = play_game(black, white, time_control, opening)?;
+        transcript.push_str(&record.transcript());
+        transcript.push('\n');
+    }
+    Ok(transcript)
+}
+
+/// A game between a human and an engine.
+///
+/// The session tracks the moves and both clocks. The human's clock runs from
+/// the end of the engine's move until `play` is called.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use usi::game::{Color, GameSession, TimeControl, STARTPOS};
+/// use usi::{EngineConfig, ThreadedEngine};
+///
+/// let mut engine = ThreadedEngine::spawn(EngineConfig {
+///     path: "/path/to/engine".to_string(),
+///     ..Default::default()
+/// })
+/// .unwrap();
+/// let time_control = TimeControl::new().byoyomi(Duration::from_secs(10));
+/// let mut session = GameSession::new(&mut engine, STARTPOS, Color::White, time_control)
+///     .unwrap()
+///     .ponder(true);
+///
+/// session.play("7g7f").unwrap();
+/// println!("engine: {:?}", session.engine_move().unwrap());
+/// session.takeback();
+/// ```
+#[derive(Debug)]
+pub struct GameSession<'a> {
+    engine: &'a mut ThreadedEngine,
+    start_position: String,
+    first: Color,
+    engine_color: Color,
+    clock: Clock,
+    moves: Vec<RecordedMove>,
+    ponder: bool,
+    pondering: Option<SearchHandle>,
+    turn_started: Instant,
+    cancel: CancellationToken,
+}
+
+impl<'a> GameSession<'a> {
+    /// Starts a session from `start_position`, an SFEN string,
+    /// with the engine playing `engine_color`.
+    ///
+    /// Returns `Error::IllegalConfig` if `start_position` has no side to move.
+    pub fn new(
+        engine: &'a mut ThreadedEngine,
+        start_position: &str,
+        engine_color: Color,
+        time_control: TimeControl,
+    ) -> Result<Self, Error> {
+        let first = Color::to_move(start_position)
+            .ok_or_else(|| Error::IllegalConfig(format!("no side to move in {start_position}")))?;
+        Ok(GameSession {
+            engine,
+            start_position: start_position.to_string(),
+            first,
+            engine_color,
+            clock: Clock::new(time_control),
+            moves: Vec::new(),
+            ponder: false,
+            pondering: None,
+            turn_started: Instant::now(),
+            cancel: CancellationToken::new(),
+        })
+    }
+
+    /// Lets the engine think on the human's time, searching the position after
+    /// its expected reply. Requires `USI_Ponder` to be enabled on the engine.
+    #[must_use]
+    pub fn ponder(mut self, enabled: bool) -> Self {
+        self.ponder = enabled;
+        self
+    }
+
+    /// Returns a token which aborts the engine's thinking when cancelled,
+    /// making `engine_move` stop the search and return `Error::Cancelled`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Returns the side to move.
+    pub fn side_to_move(&self) -> Color {
+        self.moves.last().map_or(self.first, |m| m.color.opponent())
+    }
+
+    /// Returns the moves played so far.
+    pub fn moves(&self) -> &[RecordedMove] {
+        &self.moves
+    }
+
+    /// Returns the current position as the argument of `position sfen`.
+    pub fn position(&self) -> String {
+        position_with_moves(&self.start_position, &self.moves)
+    }
+
+    /// Returns the main time left to `color`.
+    pub fn remaining(&self, color: Color) -> Duration {
+        self.clock.remaining[color.index()]
+    }
+
+    /// Returns the time used by `color` so far.
+    pub fn time_usage(&self, color: Color) -> TimeUsage {
+        TimeUsage::of(&self.moves, color)
+    }
+
+    /// Plays the human's move, given in USI notation.
+    ///
+    /// Returns `Error::IllegalOperation` if it is the engine's turn, and
+    /// `Error::Timeout` without playing the move if the human ran out of time.
+    pub fn play(&mut self, mv: &str) -> Result<(), Error> {
+        let color = self.side_to_move();
+        if color == self.engine_color {
+            return Err(Error::IllegalOperation);
+        }
+        let elapsed = self.turn_started.elapsed();
+        if !self.clock.charge(color, elapsed) {
+            return Err(Error::Timeout);
+        }
+        self.moves.push(RecordedMove {
+            mv: mv.to_string(),
+            color,
+            elapsed,
+            remaining: self.clock.remaining[color.index()],
+            reported: None,
+        });
+        Ok(())
+    }
+
+    /// Asks the engine for its move and plays it if it is a move.
+    ///
+    /// Returns `Error::IllegalOperation` if it is the human's turn,
+    /// `Error::Timeout` if the engine did not answer in time, and
+    /// `Error::Cancelled` after stopping the search if the session was cancelled. With the
+    /// `shogi-rules` feature, returns `Error::IllegalEngineMove` without
+    /// playing the move if the engine played an illegal move or declared
+    /// a win by entering king without meeting the 27-point rule.
+    pub fn engine_move(&mut self) -> Result<SearchOutcome, Error> {
+        let color = self.side_to_move();
+        if color != self.engine_color {
+            return Err(Error::IllegalOperation);
+        }
+        self.stop_pondering();
+
+        self.engine.set_position(&self.position());
+        let handle = self.engine.go(self.clock.think_params())?;
+        let result = match handle.wait_or_cancel(self.clock.allowed(color), &self.cancel) {
+            Err(Error::Timeout) => {
+                handle.stop();
+                return Err(Error::Timeout);
+            }
+            result => result?,
+        };
+        if !self.clock.charge(color, result.engine_elapsed) {
+            return Err(Error::Timeout);
+        }
+        if result.outcome == SearchOutcome::DeclareWin
+            && !is_declarable(&self.start_position, &self.moves)
+        {
+            return Err(Error::IllegalEngineMove("win".to_string()));
+        }
+
+        if let SearchOutcome::BestMove(BestMoveParams::MakeMove(mv, ponder_move)) = &result.outcome
+        {
+            if !is_playable(&self.start_position, &self.moves, mv) {
+                return Err(Error::IllegalEngineMove(mv.clone()));
+            }
+            self.moves.push(RecordedMove {
+                mv: mv.clone(),
+                color,
+                elapsed: result.engine_elapsed,
+                remaining: self.clock.remaining[color.index()],
+                reported: result.reported_time,
+            });
+            if let Some(ponder_move) = ponder_move.as_ref().filter(|_| self.ponder) {
+                self.engine
+                    .set_position(&format!("{} {}", self.position(), ponder_move));
+                self.pondering = self.engine.go(self.clock.think_params().ponder()).ok();
+            }
+        }
+        self.turn_started = Instant::now();
+        Ok(result.outcome)
+    }
+
+    /// Replaces the engine with one spawned from `config`, keeping the moves
+    /// and the clocks. See `ThreadedEngine::replace`.
+    pub fn replace_engine(&mut self, config: EngineConfig) -> Result<(), Error> {
+        self.stop_pondering();
+        self.engine.replace(config)
+    }
+
+    /// Takes back moves until it is the human's turn again with one human move
+    /// fewer, i.e. the engine's last reply and the human's move before it.
+    /// Time already spent is not given back.
+    ///
+    /// Returns the number of moves taken back.
+    pub fn takeback(&mut self) -> usize {
+        self.stop_pondering();
+        let mut undone = 0;
+        while let Some(last) = self.moves.pop() {
+            undone += 1;
+            if last.color != self.engine_color {
+                break;
+            }
+        }
+        self.turn_started = Instant::now();
+        undone
+    }
+
+    fn stop_pondering(&mut self) {
+        if let Some(handle) = self.pondering.take() {
+            handle.stop();
+            let _ = handle.wait(READY_TIMEOUT);
+        }
+    }
+}
+
+impl Drop for GameSession<'_> {
+    fn drop(&mut self) {
+        self.stop_pondering();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock() {
+        let tc = TimeControl::new()
+            .initial(Duration::from_secs(10))
+            .byoyomi(Duration::from_secs(5));
+        let mut clock = Clock::new(tc);
+        assert_eq!(
+            ThinkParams::new()
+                .btime(Duration::from_secs(10))
+                .wtime(Duration::from_secs(10))
+                .byoyomi(Duration::from_secs(5)),
+            clock.think_params()
+        );
+
+        assert!(clock.charge(Color::Black, Duration::from_secs(12)));
+        assert_eq!(Duration::ZERO, clock.remaining[0]);
+        assert!(clock.charge(Color::Black, Duration::from_secs(5)));
+        assert!(!clock.charge(Color::Black, Duration::from_secs(7)));
+        assert_eq!(Duration::from_secs(10), clock.remaining[1]);
+
+        let mut clock = Clock::new(TimeControl::new().increment(Duration::from_secs(2)));
+        assert!(clock.charge(Color::White, Duration::from_secs(1)));
+        assert_eq!(Duration::from_secs(2), clock.remaining[1]);
+        assert!(!clock.charge(Color::White, Duration::from_secs(4)));
+
+        let mut clock = Clock::new(
+            TimeControl::new()
+                .byoyomi(Duration::from_secs(1))
+                .margin(Duration::from_millis(100)),
+        );
+        assert_eq!(Duration::from_millis(1100), clock.allowed(Color::Black));
+        assert!(!clock.charge(Color::Black, Duration::from_millis(1200)));
+
+        let tc = TimeControl::new().nodes(10_000);
+        assert!(!tc.is_unlimited());
+        let mut clock = Clock::new(tc);
+        assert_eq!(ThinkParams::new().nodes(10_000), clock.think_params());
+        assert!(clock.charge(Color::Black, Duration::from_secs(59)));
+        assert!(!clock.charge(Color::Black, Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn handicaps() {
+        for handicap in Handicap::ALL {
+            let position = Position::from_sfen(handicap.sfen()).unwrap();
+            assert_eq!(handicap.sfen(), position.to_sfen());
+            assert_eq!(Some(Color::White), Color::to_move(handicap.sfen()));
+            assert_eq!(Some(handicap), Handicap::from_sfen(handicap.sfen()));
+        }
+        assert_eq!(None, Handicap::from_sfen(STARTPOS));
+    }
+
+    #[test]
+    fn repetitions() {
+        let mut repetitions = Repetitions::new(STARTPOS);
+        let cycle = ["2h3h", "8b7b", "3h2h", "7b8b"];
+        for mv in cycle.iter().cycle().take(11) {
+            assert_eq!(None, repetitions.play(mv));
+        }
+        assert_eq!(
+            Some(GameResult {
+                winner: None,
+                reason: GameEndReason::Repetition,
+            }),
+            repetitions.play("7b8b")
+        );
+
+        // Black checks with every move.
+        let mut repetitions = Repetitions::new("4k4/9/9/9/5R3/9/9/9/K8 b - 1");
+        let cycle = ["4e5e", "5a4a", "5e4e", "4a5a"];
+        for mv in cycle.iter().cycle().take(11) {
+            assert_eq!(None, repetitions.play(mv));
+        }
+        let expected = if cfg!(feature = "shogi-rules") {
+            GameResult {
+                winner: Some(Color::White),
+                reason: GameEndReason::PerpetualCheck,
+            }
+        } else {
+            GameResult {
+                winner: None,
+                reason: GameEndReason::Repetition,
+            }
+        };
+        assert_eq!(Some(expected), repetitions.play("4a5a"));
+    }
+
+    #[test]
+    fn record() {
+        assert_eq!(Some(Color::Black), Color::to_move(STARTPOS));
+        assert_eq!(None, Color::to_move("startpos"));
+
+        let record = GameRecord {
+            start_position: STARTPOS.to_string(),
+            moves: vec![
+                RecordedMove {
+                    mv: "7g7f".to_string(),
+                    color: Color::Black,
+                    elapsed: Duration::from_secs(3),
+                    remaining: Duration::ZERO,
+                    reported: Some(Duration::from_millis(2900)),
+                },
+                RecordedMove {
+                    mv: "3c3d".to_string(),
+                    color: Color::White,
+                    elapsed: Duration::ZERO,
+                    remaining: Duration::ZERO,
+                    reported: None,
+                },
+                RecordedMove {
+                    mv: "2g2f".to_string(),
+                    color: Color::Black,
+                    elapsed: Duration::from_secs(1),
+                    remaining: Duration::ZERO,
+                    reported: Some(Duration::from_millis(950)),
+                },
+            ],
+            result: GameResult {
+                winner: Some(Color::White),
+                reason: GameEndReason::Resign,
+            },
+            black_engine: Some(Fingerprint::of_bytes(b"black")),
+            white_engine: None,
+        };
+        assert_eq!(
+            format!("{STARTPOS} moves 7g7f 3c3d 2g2f"),
+            record.position()
+        );
+        let usage = record.time_usage(Color::Black);
+        assert_eq!(
+            TimeUsage {
+                moves: 2,
+                total: Duration::from_secs(4),
+                max: Duration::from_secs(3),
+                overhead: Duration::from_millis(150),
+            },
+            usage
+        );
+        assert_eq!(Duration::from_secs(2), usage.average());
+        assert_eq!(
+            format!("{STARTPOS} moves 7g7f 3c3d 2g2f\twhite\tResign"),
+            record.transcript()
+        );
+        assert_eq!(Duration::ZERO, TimeUsage::default().average());
+        assert_eq!(
+            GameOverKind::Lose,
+            record.result.game_over_kind(Color::Black)
+        );
+        assert_eq!(
+            GameOverKind::Win,
+            record.result.game_over_kind(Color::White)
+        );
+    }
+}
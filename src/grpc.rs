@@ -0,0 +1,354 @@
+//! A gRPC server analyzing positions with an `EnginePool`.
+//!
+//! The service is defined in `proto/analysis.proto` and mirrors the HTTP server of
+//! the `http` feature:
+//!
+//! - `Analyze` searches a position. The `info` updates of the search are streamed
+//!   as `SearchInfo` messages as they arrive, followed by a single `AnalyzeResult`.
+//!   Dropping the stream cancels the search. `SearchInfo` messages are skipped
+//!   while a slow client has 64 updates pending; the result is always sent.
+//! - `Health` returns the number of `engines`, `live` engines and `queued` jobs.
+//!
+//! Failed searches end the stream with `UNAVAILABLE` if the engine terminated,
+//! `DEADLINE_EXCEEDED` if it did not answer in time and `INTERNAL` otherwise.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use usi::grpc::AnalysisService;
+//! use usi::{EngineConfig, EnginePool};
+//!
+//! let pool = EnginePool::spawn(&EngineConfig::new("/path/to/engine"), 4).unwrap();
+//! let runtime = tokio::runtime::Runtime::new().unwrap();
+//! runtime
+//!     .block_on(AnalysisService::new(pool).serve("127.0.0.1:50051".parse().unwrap()))
+//!     .unwrap();
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::analysis::Analysis;
+use crate::error::{Error, ErrorClass};
+use crate::pool::{EnginePool, Priority};
+use crate::protocol::{BestMoveParams, ScoreBound, SearchInfo, ThinkParams};
+use crate::threaded::SearchOutcome;
+
+use self::proto::analysis_server::{self, AnalysisServer};
+use self::proto::{analyze_update, score};
+
+/// Messages and service stubs generated from `proto/analysis.proto`.
+#[allow(missing_docs, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("usi.analysis.v1");
+}
+
+const DEFAULT_MOVETIME: Duration = Duration::from_secs(1);
+const MAX_MOVETIME: Duration = Duration::from_secs(60);
+
+/// Number of updates buffered for a slow client before `info` updates are dropped.
+const UPDATE_BUFFER: usize = 64;
+
+/// Serves analysis requests over gRPC.
+#[derive(Debug)]
+pub struct AnalysisService {
+    pool: Arc<EnginePool>,
+    default_movetime: Duration,
+    max_movetime: Duration,
+}
+
+impl AnalysisService {
+    pub fn new(pool: EnginePool) -> Self {
+        AnalysisService {
+            pool: Arc::new(pool),
+            default_movetime: DEFAULT_MOVETIME,
+            max_movetime: MAX_MOVETIME,
+        }
+    }
+
+    /// Sets the search time of requests without `movetime_ms`. Defaults to one second.
+    #[must_use]
+    pub fn default_movetime(mut self, movetime: Duration) -> Self {
+        self.default_movetime = movetime;
+        self
+    }
+
+    /// Sets the longest search time a request may ask for. Defaults to one minute.
+    #[must_use]
+    pub fn max_movetime(mut self, movetime: Duration) -> Self {
+        self.max_movetime = movetime;
+        self
+    }
+
+    /// Wraps the service for adding it to a `tonic` server along other services.
+    pub fn into_server(self) -> AnalysisServer<Self> {
+        AnalysisServer::new(self)
+    }
+
+    /// Listens on `addr` and serves requests until the server fails.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+        Server::builder()
+            .add_service(self.into_server())
+            .serve(addr)
+            .await
+    }
+
+    /// Serves requests on a bound listener until the server fails.
+    pub async fn serve_on(self, listener: TcpListener) -> Result<(), tonic::transport::Error> {
+        Server::builder()
+            .add_service(self.into_server())
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+    }
+
+    fn limits(&self, request: &proto::AnalyzeRequest) -> ThinkParams {
+        let movetime = request
+            .movetime_ms
+            .map(Duration::from_millis)
+            .unwrap_or(self.default_movetime)
+            .min(self.max_movetime);
+        let mut limits = ThinkParams::new().byoyomi(movetime);
+        if let Some(depth) = request.depth {
+            limits = limits.depth(depth);
+        }
+        if let Some(nodes) = request.nodes {
+            limits = limits.nodes(nodes);
+        }
+        limits
+    }
+}
+
+type UpdateStream = ReceiverStream<Result<proto::AnalyzeUpdate, Status>>;
+
+#[tonic::async_trait]
+impl analysis_server::Analysis for AnalysisService {
+    type AnalyzeStream = UpdateStream;
+
+    async fn analyze(
+        &self,
+        request: Request<proto::AnalyzeRequest>,
+    ) -> Result<Response<UpdateStream>, Status> {
+        let request = request.into_inner();
+        let sfen = request.sfen.trim();
+        if sfen.is_empty() {
+            return Err(Status::invalid_argument("sfen must not be empty"));
+        }
+        // A line break would let the client send arbitrary commands to the engine.
+        if sfen.chars().any(char::is_control) {
+            return Err(Status::invalid_argument(
+                "sfen must not contain control characters",
+            ));
+        }
+        let priority = match request.priority() {
+            proto::Priority::Low => Priority::Low,
+            proto::Priority::Normal => Priority::Normal,
+            proto::Priority::High => Priority::High,
+        };
+
+        let (sender, receiver) = mpsc::channel(UPDATE_BUFFER);
+        let infos = sender.clone();
+        let job = self.pool.submit_with_info(
+            sfen,
+            self.limits(&request),
+            priority,
+            move |info, elapsed| {
+                // Updates carry the accumulated information, so a dropped one
+                // is superseded by the next that fits.
+                let update = analyze_update::Update::Info(search_info(info, elapsed));
+                let _ = infos.try_send(Ok(proto::AnalyzeUpdate {
+                    update: Some(update),
+                }));
+            },
+        );
+        let cancel = job.cancellation_token();
+        let result = tokio::task::spawn_blocking(move || job.wait());
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sender.closed() => cancel.cancel(),
+                result = result => {
+                    let update = match result {
+                        Ok(Ok(analysis)) => Ok(result_update(&analysis)),
+                        Ok(Err(err)) => Err(status(&err)),
+                        Err(err) => Err(Status::internal(err.to_string())),
+                    };
+                    let _ = sender.send(update).await;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        let count = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+        Ok(Response::new(proto::HealthResponse {
+            engines: count(self.pool.len()),
+            live: count(self.pool.live_engines()),
+            queued: count(self.pool.queued()),
+        }))
+    }
+}
+
+/// Wraps the result of a search into the last message of an `Analyze` stream.
+fn result_update(analysis: &Analysis) -> proto::AnalyzeUpdate {
+    proto::AnalyzeUpdate {
+        update: Some(analyze_update::Update::Result(analysis_result(analysis))),
+    }
+}
+
+fn status(err: &Error) -> Status {
+    let message = err.to_string();
+    match err.class() {
+        ErrorClass::Terminated | ErrorClass::Io => Status::unavailable(message),
+        ErrorClass::Timeout => Status::deadline_exceeded(message),
+        ErrorClass::Cancelled => Status::cancelled(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn search_info(info: &SearchInfo, elapsed: Duration) -> proto::SearchInfo {
+    let score = info.score.as_ref().map(|&(value, ref kind)| proto::Score {
+        value: Some(if kind.is_mate() {
+            score::Value::Mate(value)
+        } else {
+            score::Value::Cp(value)
+        }),
+        bound: match kind.bound() {
+            ScoreBound::Exact => score::Bound::Exact,
+            ScoreBound::Lowerbound => score::Bound::Lower,
+            ScoreBound::Upperbound => score::Bound::Upper,
+        } as i32,
+    });
+    proto::SearchInfo {
+        depth: info.depth,
+        seldepth: info.seldepth,
+        score,
+        nodes: info.nodes,
+        nps: info.nps,
+        pv: info.pv.clone(),
+        elapsed_ms: elapsed.as_millis() as u64,
+    }
+}
+
+fn analysis_result(analysis: &Analysis) -> proto::AnalyzeResult {
+    let (bestmove, ponder) = match &analysis.outcome {
+        SearchOutcome::BestMove(BestMoveParams::MakeMove(mv, ponder)) => {
+            (mv.clone(), ponder.clone())
+        }
+        outcome => (
+            outcome.move_str().unwrap_or_else(|| "win".to_string()),
+            None,
+        ),
+    };
+    let elapsed = analysis.elapsed;
+    proto::AnalyzeResult {
+        bestmove,
+        ponder,
+        info: Some(search_info(&analysis.info, elapsed)),
+        elapsed_ms: elapsed.as_millis() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ScoreKind;
+    use proto::analysis_client::AnalysisClient;
+
+    #[test]
+    fn result_conversion() {
+        let analysis = Analysis {
+            outcome: SearchOutcome::DeclareWin,
+            info: SearchInfo {
+                depth: Some(10),
+                score: Some((5, ScoreKind::MateLowerbound)),
+                pv: vec!["7g7f".to_string()],
+                ..Default::default()
+            },
+            elapsed: Duration::from_millis(1500),
+        };
+        let result = analysis_result(&analysis);
+        assert_eq!("win", result.bestmove);
+        assert_eq!(1500, result.elapsed_ms);
+        let info = result.info.unwrap();
+        assert_eq!(Some(10), info.depth);
+        let score = info.score.unwrap();
+        assert_eq!(Some(score::Value::Mate(5)), score.value);
+        assert_eq!(score::Bound::Lower, score.bound());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn analyze() {
+        use crate::threaded::EngineConfig;
+
+        let script = "while read -r cmd rest; do case $cmd in \
+            usi) echo usiok;; \
+            isready) echo readyok;; \
+            go) echo info depth 1 score cp 10 pv 7g7f; \
+                echo info depth 2 score cp 20 pv 7g7f 3c3d; \
+                echo bestmove 7g7f ponder 3c3d;; \
+            quit) exit 0;; \
+            esac; done";
+        let config = EngineConfig::new("sh")
+            .working_dir(".")
+            .arg("-c")
+            .arg(script);
+        let pool = EnginePool::spawn(&config, 1).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(AnalysisService::new(pool).serve_on(listener));
+
+        let mut client = AnalysisClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let health = client
+            .health(proto::HealthRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((1, 1, 0), (health.engines, health.live, health.queued));
+
+        let err = client
+            .analyze(proto::AnalyzeRequest {
+                sfen: "startpos\nquit".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+
+        let mut stream = client
+            .analyze(proto::AnalyzeRequest {
+                sfen: "startpos".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut depths = Vec::new();
+        let result = loop {
+            match stream.message().await.unwrap().unwrap().update.unwrap() {
+                analyze_update::Update::Info(info) => depths.push(info.depth),
+                analyze_update::Update::Result(result) => break result,
+            }
+        };
+        assert_eq!(vec![Some(1), Some(2)], depths);
+        assert_eq!("7g7f", result.bestmove);
+        assert_eq!(Some("3c3d".to_string()), result.ponder);
+        assert_eq!(
+            Some(score::Value::Cp(20)),
+            result.info.unwrap().score.unwrap().value
+        );
+        assert!(stream.message().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,529 @@
+//! An HTTP server analyzing positions with an `EnginePool`.
+//!
+//! Requests and responses are JSON objects:
+//!
+//! - `POST /analyze` with `{"sfen": "...", "movetime": 1000}` searches a position.
+//!   `movetime` is in milliseconds; the optional `depth` and `nodes` limit the
+//!   search further and `priority` is one of `"low"`, `"normal"` or `"high"`.
+//!   The response holds `bestmove`, `ponder`, `score` (`{"cp": 35}` or
+//!   `{"mate": 5}`, with `"bound": "lower"` or `"upper"` for bounds), `depth`,
+//!   `seldepth`, `nodes`, `nps`, `pv` and `elapsed_ms`. Unknown fields are null.
+//! - `GET /health` returns the number of `engines`, `live` engines and `queued` jobs.
+//!
+//! Errors are returned as `{"error": "..."}` with a 4xx or 5xx status.
+//! Each connection serves a single request; connections beyond
+//! `AnalysisServer::max_connections` are answered with 503.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use usi::http::AnalysisServer;
+//! use usi::{EngineConfig, EnginePool};
+//!
+//! let pool = EnginePool::spawn(&EngineConfig::new("/path/to/engine"), 4).unwrap();
+//! AnalysisServer::new(pool).serve("127.0.0.1:8080").unwrap();
+//! ```
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::analysis::Analysis;
+use crate::error::{Error, ErrorClass};
+use crate::pool::{EnginePool, Priority};
+use crate::protocol::{BestMoveParams, ScoreKind, ThinkParams};
+use crate::threaded::SearchOutcome;
+
+/// Largest request body accepted.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Largest request line and headers accepted, in total.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// Time a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_MOVETIME: Duration = Duration::from_secs(1);
+const MAX_MOVETIME: Duration = Duration::from_secs(60);
+const MAX_CONNECTIONS: usize = 64;
+
+/// Serves analysis requests over HTTP, one thread per connection.
+#[derive(Debug)]
+pub struct AnalysisServer {
+    pool: Arc<EnginePool>,
+    default_movetime: Duration,
+    max_movetime: Duration,
+    max_connections: usize,
+    connections: AtomicUsize,
+}
+
+impl AnalysisServer {
+    pub fn new(pool: EnginePool) -> Self {
+        AnalysisServer {
+            pool: Arc::new(pool),
+            default_movetime: DEFAULT_MOVETIME,
+            max_movetime: MAX_MOVETIME,
+            max_connections: MAX_CONNECTIONS,
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the search time of requests without `movetime`. Defaults to one second.
+    #[must_use]
+    pub fn default_movetime(mut self, movetime: Duration) -> Self {
+        self.default_movetime = movetime;
+        self
+    }
+
+    /// Sets the longest search time a request may ask for. Defaults to one minute.
+    #[must_use]
+    pub fn max_movetime(mut self, movetime: Duration) -> Self {
+        self.max_movetime = movetime;
+        self
+    }
+
+    /// Sets the number of connections served at once. Defaults to 64.
+    #[must_use]
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Listens on `addr` and serves requests until accepting a connection fails.
+    pub fn serve<A: ToSocketAddrs>(self, addr: A) -> Result<(), Error> {
+        self.serve_on(TcpListener::bind(addr)?)
+    }
+
+    /// Serves requests on a bound listener until accepting a connection fails.
+    pub fn serve_on(self, listener: TcpListener) -> Result<(), Error> {
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            match Connection::open(&server) {
+                Some(connection) => {
+                    thread::spawn(move || connection.server.handle(stream));
+                }
+                None => {
+                    let _ = stream.set_write_timeout(Some(READ_TIMEOUT));
+                    let _ = Response::error(503, "too many connections").write_to(&stream);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let response = match read_request(&stream) {
+            Ok((method, path, body)) => self.respond(&method, &path, &body),
+            Err(response) => response,
+        };
+        let _ = response.write_to(&stream);
+    }
+
+    fn respond(&self, method: &str, path: &str, body: &str) -> Response {
+        let path = path.split('?').next().unwrap_or_default();
+        match (method, path) {
+            ("POST", "/analyze") => match self.analyze(body) {
+                Ok(json) => Response::ok(json),
+                Err(response) => response,
+            },
+            ("GET", "/health") => Response::ok(
+                json!({
+                    "engines": self.pool.len(),
+                    "live": self.pool.live_engines(),
+                    "queued": self.pool.queued(),
+                })
+                .to_string(),
+            ),
+            (_, "/analyze" | "/health") => Response::error(405, "method not allowed"),
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    fn analyze(&self, body: &str) -> Result<String, Response> {
+        let request = AnalyzeRequest::parse(body).map_err(|err| Response::error(400, &err))?;
+        let movetime = request
+            .movetime
+            .unwrap_or(self.default_movetime)
+            .min(self.max_movetime);
+        let mut limits = ThinkParams::new().byoyomi(movetime);
+        if let Some(depth) = request.depth {
+            limits = limits.depth(depth);
+        }
+        if let Some(nodes) = request.nodes {
+            limits = limits.nodes(nodes);
+        }
+        let analysis = self
+            .pool
+            .submit(request.sfen.as_str(), limits, request.priority)
+            .wait()
+            .map_err(|err| {
+                let status = match err.class() {
+                    ErrorClass::Terminated | ErrorClass::Io => 503,
+                    ErrorClass::Timeout => 504,
+                    _ => 500,
+                };
+                Response::error(status, &err.to_string())
+            })?;
+        Ok(analysis_json(&request.sfen, &analysis))
+    }
+}
+
+/// A connection counted against `AnalysisServer::max_connections` until dropped.
+struct Connection {
+    server: Arc<AnalysisServer>,
+}
+
+impl Connection {
+    fn open(server: &Arc<AnalysisServer>) -> Option<Self> {
+        server
+            .connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < server.max_connections).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Connection {
+            server: Arc::clone(server),
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.server.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The body of a `POST /analyze` request, before validation.
+#[derive(Deserialize)]
+struct AnalyzeBody {
+    sfen: Option<String>,
+    movetime: Option<u64>,
+    depth: Option<u64>,
+    nodes: Option<u64>,
+    priority: Option<String>,
+}
+
+/// The fields of a `POST /analyze` request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct AnalyzeRequest {
+    sfen: String,
+    movetime: Option<Duration>,
+    depth: Option<u32>,
+    nodes: Option<u64>,
+    priority: Priority,
+}
+
+impl AnalyzeRequest {
+    fn parse(body: &str) -> Result<Self, String> {
+        let body = match serde_json::from_str(body).map_err(|err| err.to_string())? {
+            value @ serde_json::Value::Object(_) => {
+                AnalyzeBody::deserialize(value).map_err(|err| err.to_string())?
+            }
+            _ => return Err("expected an object".to_string()),
+        };
+
+        let sfen = match body.sfen {
+            Some(sfen) if !sfen.trim().is_empty() => sfen.trim().to_string(),
+            _ => return Err("sfen must be a non-empty string".to_string()),
+        };
+        // A line break would let the client send arbitrary commands to the engine.
+        if sfen.chars().any(char::is_control) {
+            return Err("sfen must not contain control characters".to_string());
+        }
+        let priority = match body.priority.as_deref() {
+            None | Some("normal") => Priority::Normal,
+            Some("low") => Priority::Low,
+            Some("high") => Priority::High,
+            Some(_) => return Err("priority must be low, normal or high".to_string()),
+        };
+        Ok(AnalyzeRequest {
+            sfen,
+            movetime: body.movetime.map(Duration::from_millis),
+            depth: body.depth.map(|d| d.min(u32::MAX as u64) as u32),
+            nodes: body.nodes,
+            priority,
+        })
+    }
+}
+
+/// The body of a `POST /analyze` response.
+#[derive(Serialize)]
+struct AnalysisBody<'a> {
+    sfen: &'a str,
+    bestmove: Option<String>,
+    ponder: Option<String>,
+    score: Option<Score>,
+    depth: Option<i32>,
+    seldepth: Option<i32>,
+    nodes: Option<u64>,
+    nps: Option<u64>,
+    pv: &'a [String],
+    elapsed_ms: u64,
+}
+
+#[derive(Serialize)]
+struct Score {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cp: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mate: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bound: Option<&'static str>,
+}
+
+/// Formats the result of `POST /analyze`.
+fn analysis_json(sfen: &str, analysis: &Analysis) -> String {
+    let (bestmove, ponder) = match &analysis.outcome {
+        SearchOutcome::BestMove(BestMoveParams::MakeMove(mv, ponder)) => {
+            (Some(mv.clone()), ponder.clone())
+        }
+        SearchOutcome::BestMove(BestMoveParams::Win) | SearchOutcome::DeclareWin => {
+            (Some("win".to_string()), None)
+        }
+        outcome => (outcome.move_str(), None),
+    };
+    let info = &analysis.info;
+    let score = info.score.as_ref().map(|(value, kind)| Score {
+        cp: (!kind.is_mate()).then_some(*value),
+        mate: kind.is_mate().then_some(*value),
+        bound: match kind {
+            ScoreKind::CpLowerbound | ScoreKind::MateLowerbound => Some("lower"),
+            ScoreKind::CpUpperbound | ScoreKind::MateUpperbound => Some("upper"),
+            _ => None,
+        },
+    });
+    let body = AnalysisBody {
+        sfen,
+        bestmove,
+        ponder,
+        score,
+        depth: info.depth,
+        seldepth: info.seldepth,
+        nodes: info.nodes,
+        nps: info.nps,
+        pv: &info.pv,
+        elapsed_ms: analysis.elapsed.as_millis().try_into().unwrap_or(u64::MAX),
+    };
+    serde_json::to_string(&body).unwrap_or_default()
+}
+
+/// Reads the request line, headers and body of a request.
+fn read_request(stream: &TcpStream) -> Result<(String, String, String), Response> {
+    let bad_request = |msg: &str| Response::error(400, msg);
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut head_left = MAX_HEAD;
+    read_head_line(&mut reader, &mut line, &mut head_left)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(bad_request("malformed request line")),
+    };
+
+    let mut length = 0;
+    loop {
+        if read_head_line(&mut reader, &mut line, &mut head_left)? == 0 {
+            return Err(bad_request("incomplete headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| bad_request("invalid content-length"))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(Response::error(413, "request body too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| bad_request("incomplete body"))?;
+    let body = String::from_utf8(body).map_err(|_| bad_request("body is not UTF-8"))?;
+    Ok((method, path, body))
+}
+
+/// Reads a line of the request line or headers into `line`, charging its length
+/// to `left` so that a client cannot make the server buffer an endless header.
+fn read_head_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    left: &mut usize,
+) -> Result<usize, Response> {
+    line.clear();
+    let read = (&mut *reader)
+        .take(*left as u64)
+        .read_line(line)
+        .map_err(|_| Response::error(400, "unreadable request"))?;
+    *left -= read;
+    if *left == 0 && !line.ends_with('\n') {
+        return Err(Response::error(431, "request header too large"));
+    }
+    Ok(read)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Response {
+            status,
+            body: json!({ "error": msg }).to_string(),
+        }
+    }
+
+    fn write_to(&self, mut stream: &TcpStream) -> std::io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Internal Server Error",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason,
+            self.body.len(),
+            self.body
+        )?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SearchInfo;
+
+    #[test]
+    fn analyze_request() {
+        let request = AnalyzeRequest::parse(
+            r#"{"sfen": "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1",
+                "movetime": 500, "depth": 12, "nodes": null, "priority": "high",
+                "tags": ["a", {"b": [true, false]}], "note": "\"q\" \u00e9"}"#,
+        )
+        .unwrap();
+        assert_eq!(Some(Duration::from_millis(500)), request.movetime);
+        assert_eq!(Some(12), request.depth);
+        assert_eq!(None, request.nodes);
+        assert_eq!(Priority::High, request.priority);
+
+        assert!(AnalyzeRequest::parse(r#"{"movetime": 500}"#).is_err());
+        assert!(AnalyzeRequest::parse(r#"{"sfen": "startpos", "movetime": 1.5}"#).is_err());
+        assert!(AnalyzeRequest::parse(r#"{"sfen": "startpos", "priority": "urgent"}"#).is_err());
+        assert!(AnalyzeRequest::parse(r#"{"sfen": "startpos"} x"#).is_err());
+        assert!(AnalyzeRequest::parse(r#"["startpos"]"#).is_err());
+        assert!(AnalyzeRequest::parse(&"[".repeat(100)).is_err());
+        assert!(AnalyzeRequest::parse(
+            r#"{"sfen": "startpos\nsetoption name EvalDir value /x\nisready"}"#
+        )
+        .is_err());
+        assert!(AnalyzeRequest::parse(r#"{"sfen": "start\u0000pos"}"#).is_err());
+    }
+
+    #[test]
+    fn request_head() {
+        let mut left = MAX_HEAD;
+        let mut line = String::new();
+        let mut reader = "GET / HTTP/1.1\r\n\r\n".as_bytes();
+        assert_eq!(Ok(16), read_head_line(&mut reader, &mut line, &mut left));
+        assert_eq!("GET / HTTP/1.1\r\n", line);
+        assert_eq!(MAX_HEAD - 16, left);
+
+        let endless = "x".repeat(2 * MAX_HEAD);
+        let mut reader = endless.as_bytes();
+        let mut left = MAX_HEAD;
+        assert_eq!(
+            431,
+            read_head_line(&mut reader, &mut line, &mut left)
+                .unwrap_err()
+                .status
+        );
+        assert_eq!(MAX_HEAD, line.len());
+    }
+
+    #[test]
+    fn analysis_json_format() {
+        let analysis = Analysis {
+            outcome: SearchOutcome::BestMove(BestMoveParams::MakeMove(
+                "7g7f".to_string(),
+                Some("3c3d".to_string()),
+            )),
+            info: SearchInfo {
+                depth: Some(10),
+                score: Some((-35, ScoreKind::CpLowerbound)),
+                pv: vec!["7g7f".to_string(), "3c3d".to_string()],
+                ..Default::default()
+            },
+            elapsed: Duration::from_millis(1002),
+        };
+        assert_eq!(
+            "{\"sfen\":\"startpos\",\"bestmove\":\"7g7f\",\"ponder\":\"3c3d\",\
+             \"score\":{\"cp\":-35,\"bound\":\"lower\"},\"depth\":10,\"seldepth\":null,\
+             \"nodes\":null,\"nps\":null,\"pv\":[\"7g7f\",\"3c3d\"],\"elapsed_ms\":1002}",
+            analysis_json("startpos", &analysis)
+        );
+        assert_eq!(
+            "{\"error\":\"a \\\"b\\\"\\n\"}",
+            Response::error(400, "a \"b\"\n").body
+        );
+    }
+
+    #[test]
+    fn routes() {
+        let server = AnalysisServer::new(EnginePool::from_engines(Vec::new()));
+        assert_eq!(
+            Response::ok("{\"engines\":0,\"live\":0,\"queued\":0}".to_string()),
+            server.respond("GET", "/health", "")
+        );
+        assert_eq!(404, server.respond("GET", "/", "").status);
+        assert_eq!(405, server.respond("GET", "/analyze", "").status);
+        assert_eq!(400, server.respond("POST", "/analyze", "{}").status);
+        assert_eq!(
+            503,
+            server
+                .respond("POST", "/analyze?x=1", r#"{"sfen": "startpos"}"#)
+                .status
+        );
+    }
+
+    #[test]
+    fn connection_limit() {
+        let server =
+            Arc::new(AnalysisServer::new(EnginePool::from_engines(Vec::new())).max_connections(2));
+        let first = Connection::open(&server).unwrap();
+        let _second = Connection::open(&server).unwrap();
+        assert!(Connection::open(&server).is_none());
+        drop(first);
+        assert!(Connection::open(&server).is_some());
+    }
+}
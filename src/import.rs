@@ -0,0 +1,259 @@
+//! Importers for engine definitions stored by popular shogi GUIs.
+//!
+//! Both ShogiGUI (`settings.xml`) and Shogidokoro (`Shogidokoro.xml`) keep their
+//! registered engines in XML files. The importers here read every `Engine` element
+//! from such a file and turn it into an `EngineEntry` holding a ready-to-spawn
+//! `EngineConfig` and the stored option values.
+//!
+//! The readers are deliberately lenient about the exact layout since it differs
+//! between GUI versions: names, paths and option values are accepted either as
+//! attributes or as child elements.
+//!
+//! # Example
+//!
+//! ```
+//! use usi::import::import_shogigui_str;
+//!
+//! let registry = import_shogigui_str(r#"
+//! <Settings>
+//!   <EngineList>
+//!     <Engine Name="YaneuraOu NNUE" Path="C:\Engines\YaneuraOu.exe">
+//!       <Option Name="USI_Hash" Value="1024" />
+//!     </Engine>
+//!   </EngineList>
+//! </Settings>
+//! "#).unwrap();
+//!
+//! let entry = registry.get("YaneuraOu NNUE").unwrap();
+//! assert_eq!(r"C:\Engines\YaneuraOu.exe", entry.config.path);
+//! assert_eq!(Some("1024"), entry.options.get("USI_Hash"));
+//! ```
+
+use std::path::Path;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::error::Error;
+use crate::registry::{EngineEntry, EngineRegistry, OptionProfile};
+use crate::threaded::EngineConfig;
+
+/// Imports engines from a ShogiGUI `settings.xml` file.
+pub fn import_shogigui<P: AsRef<Path>>(path: P) -> Result<EngineRegistry, Error> {
+    import_shogigui_str(&read_xml(path.as_ref())?)
+}
+
+/// Imports engines from the contents of a ShogiGUI `settings.xml` file.
+///
+/// Engines are expected as `<Engine Name=".." Path="..">` elements with
+/// `<Option Name=".." Value=".." />` (or `<EngineOption>`) children.
+pub fn import_shogigui_str(s: &str) -> Result<EngineRegistry, Error> {
+    import_engines(&parse_xml(s)?)
+}
+
+/// Imports engines from a Shogidokoro `Shogidokoro.xml` file.
+pub fn import_shogidokoro<P: AsRef<Path>>(path: P) -> Result<EngineRegistry, Error> {
+    import_shogidokoro_str(&read_xml(path.as_ref())?)
+}
+
+/// Imports engines from the contents of a Shogidokoro `Shogidokoro.xml` file.
+///
+/// Engines are expected as `<Engine>` elements with `<Name>`, `<Path>` and
+/// `<Option><Name>..</Name><Value>..</Value></Option>` children.
+pub fn import_shogidokoro_str(s: &str) -> Result<EngineRegistry, Error> {
+    import_engines(&parse_xml(s)?)
+}
+
+fn read_xml(path: &Path) -> Result<String, Error> {
+    let s = std::fs::read_to_string(path)?;
+    Ok(s.trim_start_matches('\u{feff}').to_string())
+}
+
+fn import_engines(root: &Element) -> Result<EngineRegistry, Error> {
+    let mut engines = Vec::new();
+    root.collect(&["Engine"], &mut engines);
+
+    let mut registry = EngineRegistry::new();
+    for engine in engines {
+        let path = engine
+            .field(&["Path", "FileName", "Command"])
+            .ok_or_else(|| Error::IllegalConfig("engine definition without a path".to_string()))?;
+        let name = engine
+            .field(&["Name", "DisplayName"])
+            .unwrap_or_else(|| path.clone());
+
+        let mut option_elems = Vec::new();
+        engine.collect(&["Option", "EngineOption"], &mut option_elems);
+        let mut options = OptionProfile::new();
+        for opt in option_elems {
+            if let (Some(n), Some(v)) = (opt.field(&["Name"]), opt.field(&["Value"])) {
+                options.insert(&n, &v);
+            }
+        }
+
+        let config = EngineConfig {
+            path,
+            ..Default::default()
+        };
+        registry.insert(&name, EngineEntry::new(config).options(options));
+    }
+
+    Ok(registry)
+}
+
+/// A minimal DOM built from the XML event stream.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn from_start(e: &BytesStart) -> Result<Self, Error> {
+        let mut elem = Element {
+            name: String::from_utf8_lossy(e.local_name().as_ref()).to_string(),
+            ..Default::default()
+        };
+        for attr in e.attributes() {
+            let attr = attr.map_err(|e| Error::IllegalConfig(e.to_string()))?;
+            let value = attr
+                .unescape_value()
+                .map_err(|e| Error::IllegalConfig(e.to_string()))?;
+            elem.attrs.push((
+                String::from_utf8_lossy(attr.key.local_name().as_ref()).to_string(),
+                value.to_string(),
+            ));
+        }
+        Ok(elem)
+    }
+
+    /// Returns the first matching attribute or child element text.
+    fn field(&self, names: &[&str]) -> Option<String> {
+        names.iter().find_map(|n| {
+            self.attrs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(n))
+                .map(|(_, v)| v.clone())
+                .or_else(|| {
+                    self.children
+                        .iter()
+                        .find(|c| c.name.eq_ignore_ascii_case(n))
+                        .map(|c| c.text.trim().to_string())
+                })
+        })
+    }
+
+    /// Collects descendants with one of the given names, without descending into matches.
+    fn collect<'a>(&'a self, names: &[&str], out: &mut Vec<&'a Element>) {
+        for child in &self.children {
+            if names.iter().any(|n| child.name.eq_ignore_ascii_case(n)) {
+                out.push(child);
+            } else {
+                child.collect(names, out);
+            }
+        }
+    }
+}
+
+fn parse_xml(s: &str) -> Result<Element, Error> {
+    let mut reader = Reader::from_str(s);
+    let mut stack = vec![Element::default()];
+
+    loop {
+        match reader
+            .read_event()
+            .map_err(|e| Error::IllegalConfig(e.to_string()))?
+        {
+            Event::Start(e) => stack.push(Element::from_start(&e)?),
+            Event::Empty(e) => {
+                let elem = Element::from_start(&e)?;
+                stack.last_mut().unwrap().children.push(elem);
+            }
+            Event::End(_) => {
+                let elem = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(elem),
+                    None => return Err(Error::IllegalConfig("unbalanced XML".to_string())),
+                }
+            }
+            Event::Text(t) => {
+                let text = t
+                    .unescape()
+                    .map_err(|e| Error::IllegalConfig(e.to_string()))?;
+                stack.last_mut().unwrap().text.push_str(&text);
+            }
+            Event::CData(t) => {
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .text
+                    .push_str(&String::from_utf8_lossy(&t));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(root), true) => Ok(root),
+        _ => Err(Error::IllegalConfig("unbalanced XML".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shogigui() {
+        let registry = import_shogigui_str(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <Settings>
+              <EngineList>
+                <Engine Name="Engine &amp; Co" Path="C:\a.exe">
+                  <Options>
+                    <EngineOption Name="USI_Hash" Value="256" />
+                    <EngineOption Name="Threads" Value="4" />
+                  </Options>
+                </Engine>
+                <Engine Path="C:\b.exe" />
+              </EngineList>
+            </Settings>"#,
+        )
+        .expect("failed to import");
+
+        assert_eq!(2, registry.len());
+        let a = registry.get("Engine & Co").unwrap();
+        assert_eq!(r"C:\a.exe", a.config.path);
+        assert_eq!(Some("256"), a.options.get("USI_Hash"));
+        assert_eq!(Some("4"), a.options.get("Threads"));
+        assert!(registry.get(r"C:\b.exe").unwrap().options.is_empty());
+    }
+
+    #[test]
+    fn shogidokoro() {
+        let registry = import_shogidokoro_str(
+            r#"<Shogidokoro>
+              <Engines>
+                <Engine>
+                  <Name>Lesserkai</Name>
+                  <Path>C:\Lesserkai.exe</Path>
+                  <Option><Name>BookFile</Name><Value>public.bin</Value></Option>
+                </Engine>
+              </Engines>
+            </Shogidokoro>"#,
+        )
+        .expect("failed to import");
+
+        let entry = registry.get("Lesserkai").unwrap();
+        assert_eq!(r"C:\Lesserkai.exe", entry.config.path);
+        assert_eq!(Some("public.bin"), entry.options.get("BookFile"));
+
+        assert!(
+            import_shogidokoro_str("<Engines><Engine><Name>x</Name></Engine></Engines>").is_err()
+        );
+        assert!(import_shogidokoro_str("<Engines><Engine>").is_err());
+    }
+}
@@ -38,7 +38,7 @@
 //! ```no_run
 //! use usi::{BestMoveParams, Error, EngineCommand, GuiCommand, UsiEngineHandler};
 //!
-//! let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", "/path/to/working_dir").unwrap();
+//! let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", "/path/to/working_dir", &[] as &[&str]).unwrap();
 //!
 //! // Get the USI engine information.
 //! let info = handler.get_info().unwrap();
@@ -75,7 +75,7 @@
 //! ```no_run
 //! use usi::{GuiCommand, UsiEngineHandler};
 //!
-//! let mut handler = UsiEngineHandler::spawn("/path/to/fairy-stockfish", ".").unwrap();
+//! let mut handler = UsiEngineHandler::spawn("/path/to/fairy-stockfish", ".", &[] as &[&str]).unwrap();
 //!
 //! // Configure before handshake (required for Fairy-Stockfish)
 //! handler.send_command_before_handshake(&GuiCommand::SetOption(
@@ -90,12 +90,49 @@
 //! // Now proceed with normal handshake
 //! let info = handler.get_info().unwrap();
 //! ```
+pub mod analysis;
+mod cancel;
+pub mod compare;
+pub mod diagnostics;
 mod error;
+pub mod failover;
+pub mod game;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "import")]
+pub mod import;
+mod memory;
+pub mod pool;
 mod process;
 mod protocol;
+pub mod registry;
+pub mod rules;
+pub mod server;
+pub mod suite;
 pub mod threaded;
+pub mod tsume;
+pub mod tuning;
 
+pub use self::analysis::{Analysis, AnalysisProgress, BatchAnalysis, ProgressCallback};
+pub use self::cancel::CancellationToken;
 pub use self::error::*;
+pub use self::game::{
+    play_game, play_game_with_cancel, play_regression, start_handicap_game, Color, GameEndReason,
+    GameRecord, GameResult, GameSession, Handicap, RecordedMove, TimeControl, TimeUsage,
+};
+pub use self::memory::available_memory;
+pub use self::pool::{EnginePool, InfoCallback, PoolBatch, PoolJob, PooledEngine, Priority};
 pub use self::process::*;
 pub use self::protocol::*;
-pub use self::threaded::{EngineConfig, EngineRequest, ThreadedEngine};
+pub use self::registry::{
+    canonical_option_name, AppliedOptions, EngineEntry, EngineRegistry, OptionChange, OptionDiff,
+    OptionProfile,
+};
+pub use self::threaded::{
+    EngineConfig, EngineRequest, GoPolicy, Overhead, ReadyToken, RequestKind, SearchHandle,
+    SearchInfos, SearchOutcome, SearchResult, SearchSnapshot, StderrMode, ThreadedEngine,
+    TimedInfo,
+};
+pub use self::tuning::{MatchScore, Spsa, SpsaParam, SpsaState, Sweep, SweepResult};
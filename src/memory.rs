@@ -0,0 +1,63 @@
+use crate::error::Error;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Returns the memory available for new allocations in bytes, or `None`
+/// if it cannot be determined.
+///
+/// It is read from `MemAvailable` in `/proc/meminfo`, and is only known on Linux.
+pub fn available_memory() -> Option<u64> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    parse_meminfo(&meminfo)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let value = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kb: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kb.saturating_mul(1024))
+}
+
+/// Checks that `instances` hash tables of `hash_mb` MiB fit into the available memory.
+///
+/// Passes if the available memory is unknown.
+pub(crate) fn check_hash_size(hash_mb: u64, instances: usize) -> Result<(), Error> {
+    match available_memory() {
+        Some(available) => check_hash_within(hash_mb, instances, available),
+        None => Ok(()),
+    }
+}
+
+fn check_hash_within(hash_mb: u64, instances: usize, available: u64) -> Result<(), Error> {
+    let requested = hash_mb.saturating_mul(instances as u64);
+    if requested.saturating_mul(MIB) > available {
+        return Err(Error::HashTooLarge(requested, available / MIB));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_size() {
+        let meminfo = "MemTotal:       16315084 kB\n\
+                       MemFree:         1022448 kB\n\
+                       MemAvailable:    8388608 kB\n";
+        let available = parse_meminfo(meminfo).unwrap();
+        assert_eq!(8192 * MIB, available);
+        assert_eq!(None, parse_meminfo("MemTotal: 16315084 kB\n"));
+
+        assert!(check_hash_within(2048, 4, available).is_ok());
+        assert!(matches!(
+            check_hash_within(4096, 3, available),
+            Err(Error::HashTooLarge(12288, 8192))
+        ));
+        assert!(check_hash_within(u64::MAX, 2, available).is_err());
+    }
+}
@@ -0,0 +1,886 @@
+//! A pool of engine instances for analyzing positions in parallel.
+//!
+//! Work is distributed by a scheduler shared by all engines of the pool:
+//! idle engines take the queued job with the highest `Priority`, jobs of
+//! equal priority run in submission order. Submitting a job with a higher
+//! priority than some running job while no engine is idle preempts the
+//! running job, which is put back in front of the queue.
+//! Jobs of an engine which terminates are moved to the remaining engines.
+//!
+//! Engines can also be taken out of the pool with `EnginePool::acquire`, for
+//! example to serve a GUI with `UsiServer`, which gets one per search.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::{EngineConfig, EnginePool, Priority, ThinkParams};
+//!
+//! let config = EngineConfig {
+//!     path: "/path/to/engine".to_string(),
+//!     ..Default::default()
+//! };
+//! let pool = EnginePool::spawn(&config, 4).unwrap();
+//!
+//! let limits = ThinkParams::new().byoyomi(Duration::from_secs(1));
+//! let positions = vec!["lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1"];
+//! let batch = pool.analyze_batch_with_priority(positions, limits.clone(), Priority::Low);
+//!
+//! // Runs before the remaining positions of the batch.
+//! let job = pool.submit(
+//!     "lnsgkgsnl/1r5b1/ppppppppp/9/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL w - 2",
+//!     limits,
+//!     Priority::High,
+//! );
+//! println!("{:?}", job.wait().map(|a| a.outcome));
+//!
+//! for (position, analysis) in batch {
+//!     println!("{}: {:?}", position, analysis.map(|a| a.outcome));
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::analysis::{analyze_position_with, Analysis, AnalysisProgress, ProgressTracker};
+use crate::cancel::CancellationToken;
+use crate::error::Error;
+use crate::protocol::{GameOverKind, OptionParams, SearchInfo, ThinkParams};
+use crate::threaded::{EngineConfig, ThreadedEngine};
+
+/// Number of times a job is retried after the engine running it terminated.
+const MAX_JOB_FAILURES: u32 = 2;
+
+type JobResult = (String, Result<Analysis, Error>);
+
+/// A boxed callback receiving the search information of a running job.
+pub type InfoCallback = Box<dyn FnMut(&SearchInfo, Duration) + Send>;
+
+/// Priority of a job submitted to an `EnginePool`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Bulk work, run when nothing else is queued.
+    Low,
+    #[default]
+    Normal,
+    /// Interactive work, preempting running jobs of lower priority.
+    High,
+}
+
+struct Job {
+    priority: Priority,
+    position: String,
+    limits: ThinkParams,
+    cancel: CancellationToken,
+    failures: u32,
+    progress: Arc<Mutex<ProgressTracker>>,
+    sender: Sender<JobResult>,
+    on_info: Option<InfoCallback>,
+}
+
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("priority", &self.priority)
+            .field("position", &self.position)
+            .field("limits", &self.limits)
+            .field("failures", &self.failures)
+            .finish()
+    }
+}
+
+impl Job {
+    /// Delivers the result of the job.
+    fn finish(self, result: Result<Analysis, Error>) {
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.completed(&self.position);
+        }
+        let _ = self.sender.send((self.position, result));
+    }
+}
+
+/// A command sent to every engine of the pool between searches.
+#[derive(Clone, Debug)]
+enum PoolCommand {
+    SetOption(String, Option<String>),
+    NewGame,
+    GameOver(GameOverKind),
+}
+
+impl PoolCommand {
+    fn apply(self, engine: &mut ThreadedEngine) {
+        match self {
+            PoolCommand::SetOption(name, value) => engine.set_option(&name, value.as_deref()),
+            PoolCommand::NewGame => {
+                engine.new_game(None);
+            }
+            PoolCommand::GameOver(result) => engine.game_over(result),
+        }
+    }
+}
+
+/// What an engine does next.
+enum Work {
+    Commands(Vec<PoolCommand>),
+    Lease(Sender<PooledEngine>),
+    Job(Box<Job>, CancellationToken),
+}
+
+/// A job currently analyzed by one of the engines.
+#[derive(Debug)]
+struct Running {
+    priority: Priority,
+    preempt: CancellationToken,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    queue: VecDeque<Job>,
+    /// Callers of `EnginePool::acquire` waiting for an engine.
+    leases: VecDeque<Sender<PooledEngine>>,
+    /// The job running on each engine, indexed by worker.
+    running: Vec<Option<Running>>,
+    /// Commands not yet sent to each engine, indexed by worker; `None` once
+    /// the engine terminated.
+    pending: Vec<Option<Vec<PoolCommand>>>,
+    /// Number of engines still running.
+    live: usize,
+    shutdown: bool,
+}
+
+#[derive(Debug, Default)]
+struct Scheduler {
+    state: Mutex<SchedulerState>,
+    cond: Condvar,
+}
+
+impl SchedulerState {
+    /// Removes the next job to run: the oldest job of the highest priority.
+    /// Cancelled jobs are discarded.
+    fn pop(&mut self) -> Option<Job> {
+        self.queue.retain(|job| !job.cancel.is_cancelled());
+        let mut next: Option<(usize, Priority)> = None;
+        for (i, job) in self.queue.iter().enumerate() {
+            if next.is_none_or(|(_, p)| job.priority > p) {
+                next = Some((i, job.priority));
+            }
+        }
+        self.queue.remove(next?.0)
+    }
+
+    /// Adds a job, preempting a running job of lower priority if no engine is idle.
+    fn push(&mut self, job: Job) {
+        self.preempt_below(job.priority);
+        self.queue.push_back(job);
+    }
+
+    /// Preempts the running job of the lowest priority below `priority` if no engine is idle.
+    fn preempt_below(&self, priority: Priority) {
+        let busy = self.running.iter().flatten().count();
+        if busy >= self.live {
+            let victim = self
+                .running
+                .iter()
+                .flatten()
+                .filter(|r| r.priority < priority && !r.preempt.is_cancelled())
+                .min_by_key(|r| r.priority);
+            if let Some(victim) = victim {
+                victim.preempt.cancel();
+            }
+        }
+    }
+
+    /// Records the termination of the engine of worker `index`, returning the
+    /// jobs left without engine.
+    fn terminated(&mut self, index: usize) -> Vec<Job> {
+        self.pending[index] = None;
+        self.live -= 1;
+        if self.live > 0 {
+            return Vec::new();
+        }
+        self.leases.clear();
+        self.queue.drain(..).collect()
+    }
+}
+
+/// A fixed set of `ThreadedEngine`s sharing work through a job scheduler.
+///
+/// Dropping the pool discards queued jobs and quits the engines once their
+/// running jobs have finished.
+#[derive(Debug)]
+pub struct EnginePool {
+    scheduler: Arc<Scheduler>,
+    size: usize,
+    name: String,
+    options: Vec<OptionParams>,
+}
+
+impl EnginePool {
+    /// Spawns `size` instances of the engine described by `config`.
+    ///
+    /// Each instance is respawned on transient failures as configured by
+    /// `EngineConfig::spawn_retry`.
+    ///
+    /// With `EngineConfig::check_memory`, returns `Error::HashTooLarge` if the
+    /// hash tables of all instances don't fit into the available memory.
+    pub fn spawn(config: &EngineConfig, size: usize) -> Result<Self, Error> {
+        config.verify_memory(size)?;
+        let engines = (0..size)
+            .map(|_| ThreadedEngine::spawn(config.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_engines(engines))
+    }
+
+    /// Creates a pool from already spawned engines.
+    pub fn from_engines(engines: Vec<ThreadedEngine>) -> Self {
+        let size = engines.len();
+        let name = engines
+            .first()
+            .map(|engine| engine.name().to_string())
+            .unwrap_or_default();
+        let options = engines
+            .first()
+            .and_then(|engine| engine.info())
+            .map(|info| info.declared_options().to_vec())
+            .unwrap_or_default();
+        let scheduler = Arc::new(Scheduler {
+            state: Mutex::new(SchedulerState {
+                running: (0..size).map(|_| None).collect(),
+                pending: (0..size).map(|_| Some(Vec::new())).collect(),
+                live: size,
+                ..Default::default()
+            }),
+            cond: Condvar::new(),
+        });
+        for (index, engine) in engines.into_iter().enumerate() {
+            let scheduler = Arc::clone(&scheduler);
+            thread::spawn(move || worker(engine, index, &scheduler));
+        }
+        EnginePool {
+            scheduler,
+            size,
+            name,
+            options,
+        }
+    }
+
+    /// Returns the name of the engines, as reported by the first one.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the options declared by the first engine.
+    pub fn options(&self) -> &[OptionParams] {
+        &self.options
+    }
+
+    /// Returns the number of engines in the pool.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns the number of engines which have not terminated.
+    pub fn live_engines(&self) -> usize {
+        self.scheduler.state.lock().map(|s| s.live).unwrap_or(0)
+    }
+
+    /// Returns the number of jobs waiting for an engine.
+    pub fn queued(&self) -> usize {
+        self.scheduler
+            .state
+            .lock()
+            .map(|s| {
+                s.queue
+                    .iter()
+                    .filter(|job| !job.cancel.is_cancelled())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Sets an option on every engine, each before its next search.
+    pub fn set_option(&self, name: &str, value: Option<&str>) {
+        self.broadcast(PoolCommand::SetOption(
+            name.to_string(),
+            value.map(|v| v.to_string()),
+        ));
+    }
+
+    /// Sends `usinewgame` to every engine, each before its next search.
+    pub fn new_game(&self) {
+        self.broadcast(PoolCommand::NewGame);
+    }
+
+    /// Sends `gameover` to every engine, each before its next search.
+    pub fn game_over(&self, result: GameOverKind) {
+        self.broadcast(PoolCommand::GameOver(result));
+    }
+
+    fn broadcast(&self, command: PoolCommand) {
+        if let Ok(mut state) = self.scheduler.state.lock() {
+            for pending in state.pending.iter_mut().flatten() {
+                pending.push(command.clone());
+            }
+        }
+        self.scheduler.cond.notify_all();
+    }
+
+    /// Takes an engine out of the pool for exclusive use until the returned
+    /// `PooledEngine` is dropped, waiting for one to become idle.
+    ///
+    /// Waiting callers are served before queued jobs and preempt running jobs
+    /// of a lower priority than `Priority::High`.
+    /// Returns `Error::Terminated` if no engine is left.
+    pub fn acquire(&self) -> Result<PooledEngine, Error> {
+        let (sender, receiver) = channel();
+        match self.scheduler.state.lock() {
+            Ok(mut state) if state.live > 0 => {
+                state.preempt_below(Priority::High);
+                state.leases.push_back(sender);
+            }
+            _ => return Err(Error::Terminated),
+        }
+        self.scheduler.cond.notify_all();
+        receiver.recv().map_err(|_| Error::Terminated)
+    }
+
+    /// Queues the analysis of a single position.
+    pub fn submit<S: Into<String>>(
+        &self,
+        position: S,
+        limits: ThinkParams,
+        priority: Priority,
+    ) -> PoolJob {
+        self.submit_job(position.into(), limits, priority, None)
+    }
+
+    /// Queues the analysis of a single position, calling `on_info` after every
+    /// `info` command with the accumulated information and the time since `go`.
+    ///
+    /// The callback is called from the threads of the pool. Information starts
+    /// over if the job is preempted or moved to another engine.
+    pub fn submit_with_info<S, F>(
+        &self,
+        position: S,
+        limits: ThinkParams,
+        priority: Priority,
+        on_info: F,
+    ) -> PoolJob
+    where
+        S: Into<String>,
+        F: FnMut(&SearchInfo, Duration) + Send + 'static,
+    {
+        self.submit_job(position.into(), limits, priority, Some(Box::new(on_info)))
+    }
+
+    fn submit_job(
+        &self,
+        position: String,
+        limits: ThinkParams,
+        priority: Priority,
+        on_info: Option<InfoCallback>,
+    ) -> PoolJob {
+        let (sender, receiver) = channel();
+        let cancel = CancellationToken::new();
+        self.schedule(vec![Job {
+            priority,
+            position,
+            limits,
+            cancel: cancel.clone(),
+            failures: 0,
+            progress: Arc::new(Mutex::new(ProgressTracker::new(1))),
+            sender,
+            on_info,
+        }]);
+        PoolJob { receiver, cancel }
+    }
+
+    /// Analyzes `positions` in parallel, one search per engine at a time.
+    ///
+    /// Results are yielded in completion order through the returned `PoolBatch`.
+    pub fn analyze_batch<I, S>(&self, positions: I, limits: ThinkParams) -> PoolBatch
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.analyze_batch_with_priority(positions, limits, Priority::Normal)
+    }
+
+    /// Analyzes `positions` in parallel, scheduling them with the given priority.
+    pub fn analyze_batch_with_priority<I, S>(
+        &self,
+        positions: I,
+        limits: ThinkParams,
+        priority: Priority,
+    ) -> PoolBatch
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let positions = positions.into_iter().map(Into::into).collect::<Vec<_>>();
+        let total = positions.len();
+        let (sender, receiver) = channel();
+        let cancel = CancellationToken::new();
+        let progress = Arc::new(Mutex::new(ProgressTracker::new(total)));
+        self.enqueue(positions, &limits, priority, (&cancel, &progress), sender);
+        PoolBatch {
+            receiver,
+            cancel,
+            progress,
+            total,
+        }
+    }
+
+    fn enqueue(
+        &self,
+        positions: Vec<String>,
+        limits: &ThinkParams,
+        priority: Priority,
+        (cancel, progress): (&CancellationToken, &Arc<Mutex<ProgressTracker>>),
+        sender: Sender<JobResult>,
+    ) {
+        let jobs = positions
+            .into_iter()
+            .map(|position| Job {
+                priority,
+                position,
+                limits: limits.clone(),
+                cancel: cancel.clone(),
+                failures: 0,
+                progress: Arc::clone(progress),
+                sender: sender.clone(),
+                on_info: None,
+            })
+            .collect();
+        self.schedule(jobs);
+    }
+
+    /// Queues `jobs`, or finishes them with `Error::Terminated` if no engine is left.
+    fn schedule(&self, jobs: Vec<Job>) {
+        let rejected = match self.scheduler.state.lock() {
+            Ok(mut state) if state.live > 0 => {
+                jobs.into_iter().for_each(|job| state.push(job));
+                Vec::new()
+            }
+            _ => jobs,
+        };
+        self.scheduler.cond.notify_all();
+        for job in rejected {
+            job.finish(Err(Error::Terminated));
+        }
+    }
+}
+
+impl Drop for EnginePool {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.scheduler.state.lock() {
+            state.shutdown = true;
+            state.queue.clear();
+        }
+        self.scheduler.cond.notify_all();
+    }
+}
+
+/// Runs queued jobs on `engine` until the pool is dropped or the engine terminates.
+fn worker(mut engine: ThreadedEngine, index: usize, scheduler: &Scheduler) {
+    loop {
+        let work = {
+            let mut state = match scheduler.state.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            let work = loop {
+                if state.shutdown {
+                    return;
+                }
+                match &mut state.pending[index] {
+                    Some(pending) if !pending.is_empty() => {
+                        break Work::Commands(mem::take(pending));
+                    }
+                    _ => {}
+                }
+                if let Some(lease) = state.leases.pop_front() {
+                    break Work::Lease(lease);
+                }
+                if let Some(job) = state.pop() {
+                    break Work::Job(Box::new(job), CancellationToken::new());
+                }
+                state = match scheduler.cond.wait(state) {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+            };
+            state.running[index] = match &work {
+                Work::Commands(_) => None,
+                Work::Lease(_) => Some(Running {
+                    priority: Priority::High,
+                    preempt: CancellationToken::new(),
+                }),
+                Work::Job(job, preempt) => Some(Running {
+                    priority: job.priority,
+                    preempt: preempt.clone(),
+                }),
+            };
+            work
+        };
+
+        let (mut job, preempt) = match work {
+            Work::Commands(commands) => {
+                for command in commands {
+                    command.apply(&mut engine);
+                }
+                continue;
+            }
+            Work::Lease(lease) => {
+                let returned = lend(engine, lease);
+                let orphans = {
+                    let mut state = match scheduler.state.lock() {
+                        Ok(state) => state,
+                        Err(_) => return,
+                    };
+                    state.running[index] = None;
+                    scheduler.cond.notify_all();
+                    match returned {
+                        Some(returned) if returned.is_alive() => {
+                            engine = returned;
+                            continue;
+                        }
+                        _ => state.terminated(index),
+                    }
+                };
+                for job in orphans {
+                    job.finish(Err(Error::Terminated));
+                }
+                return;
+            }
+            Work::Job(job, preempt) => (*job, preempt),
+        };
+
+        // Engines may terminate while idle; jobs are not blamed for that.
+        let started_alive = engine.is_alive();
+        let mut depth = None;
+        let result = analyze_position_with(
+            &mut engine,
+            &job.position,
+            &job.limits,
+            || job.cancel.is_cancelled() || preempt.is_cancelled(),
+            |info, elapsed| {
+                if let Some(on_info) = &mut job.on_info {
+                    on_info(info, elapsed);
+                }
+                if let Some(d) = info.depth.filter(|d| Some(*d) != depth) {
+                    depth = Some(d);
+                    if let Ok(mut progress) = job.progress.lock() {
+                        progress.depth_reached(&job.position, d);
+                    }
+                }
+            },
+        );
+
+        // Results are delivered after releasing the lock, as progress callbacks may call into the pool.
+        let mut finished = Vec::new();
+        let alive = {
+            let mut state = match scheduler.state.lock() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            state.running[index] = None;
+            let alive = match result {
+                Err(Error::Cancelled) if !job.cancel.is_cancelled() => {
+                    state.queue.push_front(job);
+                    true
+                }
+                Err(Error::Cancelled) => true,
+                Err(err @ (Error::Terminated | Error::EngineIo(_))) if !engine.is_alive() => {
+                    if started_alive {
+                        job.failures += 1;
+                    }
+                    if job.failures >= MAX_JOB_FAILURES {
+                        finished.push((job, Err(err)));
+                    } else {
+                        state.queue.push_front(job);
+                    }
+                    finished.extend(
+                        state
+                            .terminated(index)
+                            .into_iter()
+                            .map(|job| (job, Err(Error::Terminated))),
+                    );
+                    false
+                }
+                result => {
+                    finished.push((job, result));
+                    true
+                }
+            };
+            scheduler.cond.notify_all();
+            alive
+        };
+        for (job, result) in finished {
+            job.finish(result);
+        }
+        if !alive {
+            return;
+        }
+    }
+}
+
+/// Lends `engine` to the caller waiting on `lease`, and waits for it to be
+/// returned. Returns `None` if it was leaked.
+fn lend(engine: ThreadedEngine, lease: Sender<PooledEngine>) -> Option<ThreadedEngine> {
+    let (sender, receiver) = channel();
+    // If the caller stopped waiting, dropping the lease returns the engine right away.
+    let _ = lease.send(PooledEngine {
+        engine: Some(engine),
+        sender,
+    });
+    receiver.recv().ok()
+}
+
+/// An engine taken out of an `EnginePool` with `EnginePool::acquire`.
+///
+/// The engine goes back to the pool when this is dropped; commands sent to
+/// the whole pool in the meantime are applied then.
+#[derive(Debug)]
+pub struct PooledEngine {
+    engine: Option<ThreadedEngine>,
+    sender: Sender<ThreadedEngine>,
+}
+
+impl Deref for PooledEngine {
+    type Target = ThreadedEngine;
+
+    fn deref(&self) -> &ThreadedEngine {
+        self.engine.as_ref().expect("engine already returned")
+    }
+}
+
+impl DerefMut for PooledEngine {
+    fn deref_mut(&mut self) -> &mut ThreadedEngine {
+        self.engine.as_mut().expect("engine already returned")
+    }
+}
+
+impl Drop for PooledEngine {
+    fn drop(&mut self) {
+        if let Some(engine) = self.engine.take() {
+            let _ = self.sender.send(engine);
+        }
+    }
+}
+
+/// A single job submitted to an `EnginePool`.
+///
+/// Dropping the handle does not cancel the job.
+#[derive(Debug)]
+pub struct PoolJob {
+    receiver: Receiver<JobResult>,
+    cancel: CancellationToken,
+}
+
+impl PoolJob {
+    /// Removes the job from the queue, or stops its search if already running.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Returns a token which cancels the job, like `cancel`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Returns the result if the job has finished (non-blocking).
+    ///
+    /// Returns `Error::Cancelled` if the job was cancelled.
+    pub fn try_result(&self) -> Option<Result<Analysis, Error>> {
+        match self.receiver.try_recv() {
+            Ok((_, result)) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(Error::Cancelled)),
+        }
+    }
+
+    /// Blocks until the job has finished.
+    ///
+    /// Returns `Error::Cancelled` if the job was cancelled.
+    pub fn wait(self) -> Result<Analysis, Error> {
+        self.receiver
+            .recv()
+            .map_err(|_| Error::Cancelled)
+            .and_then(|(_, result)| result)
+    }
+}
+
+/// Results of a batch analysis running on an `EnginePool`.
+///
+/// Iterating blocks until the next result is available and ends when
+/// all positions were analyzed or the batch was cancelled.
+/// Dropping the batch cancels it.
+#[derive(Debug)]
+pub struct PoolBatch {
+    receiver: Receiver<JobResult>,
+    cancel: CancellationToken,
+    progress: Arc<Mutex<ProgressTracker>>,
+    total: usize,
+}
+
+impl PoolBatch {
+    /// Sets a callback receiving progress updates.
+    ///
+    /// The callback is called from the threads of the pool as positions
+    /// reach new depths and complete.
+    #[must_use]
+    pub fn on_progress<F>(self, callback: F) -> Self
+    where
+        F: FnMut(&AnalysisProgress) + Send + 'static,
+    {
+        if let Ok(mut progress) = self.progress.lock() {
+            progress.set_callback(Box::new(callback));
+        }
+        self
+    }
+
+    /// Returns a token which cancels the batch, discarding queued positions
+    /// and stopping running searches.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Returns the number of positions in the batch.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+impl Iterator for PoolBatch {
+    type Item = JobResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Drop for PoolBatch {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduling() {
+        let (sender, _receiver) = channel();
+        let job = |position: &str, priority, cancel: &CancellationToken| Job {
+            priority,
+            position: position.to_string(),
+            limits: ThinkParams::new(),
+            cancel: cancel.clone(),
+            failures: 0,
+            progress: Arc::new(Mutex::new(ProgressTracker::new(0))),
+            sender: sender.clone(),
+            on_info: None,
+        };
+        let cancel = CancellationToken::new();
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+
+        let running = Running {
+            priority: Priority::Low,
+            preempt: CancellationToken::new(),
+        };
+        let preempt = running.preempt.clone();
+        let mut state = SchedulerState {
+            running: vec![Some(running)],
+            live: 1,
+            ..Default::default()
+        };
+
+        state.push(job("a", Priority::Low, &cancel));
+        state.push(job("b", Priority::Normal, &cancel));
+        state.push(job("c", Priority::Normal, &cancelled));
+        state.push(job("d", Priority::Normal, &cancel));
+        assert!(preempt.is_cancelled());
+
+        state.push(job("e", Priority::High, &cancel));
+        let order = std::iter::from_fn(|| state.pop())
+            .map(|job| job.position)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["e", "b", "d", "a"], order);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn submit_with_info() {
+        let script = "while read -r cmd rest; do case $cmd in \
+            usi) echo usiok;; \
+            isready) echo readyok;; \
+            go) echo info depth 1 score cp 10 pv 7g7f; \
+                echo info depth 2 score cp 20 pv 7g7f 3c3d; \
+                echo bestmove 7g7f;; \
+            quit) exit 0;; \
+            esac; done";
+        let config = EngineConfig::new("sh")
+            .working_dir(".")
+            .arg("-c")
+            .arg(script);
+        let pool = EnginePool::spawn(&config, 1).unwrap();
+        let (sender, receiver) = channel();
+        let job = pool.submit_with_info(
+            "startpos",
+            ThinkParams::new(),
+            Priority::Normal,
+            move |info, _| {
+                let _ = sender.send((info.depth, info.pv.clone()));
+            },
+        );
+        let analysis = job.wait().unwrap();
+        assert_eq!(Some((20, crate::ScoreKind::CpExact)), analysis.info.score);
+        assert_eq!(
+            vec![
+                (Some(1), vec!["7g7f".to_string()]),
+                (Some(2), vec!["7g7f".to_string(), "3c3d".to_string()]),
+            ],
+            receiver.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn acquire() {
+        let script = "while read -r cmd rest; do case $cmd in \
+            usi) echo usiok;; \
+            isready) echo readyok;; \
+            go) echo bestmove 7g7f;; \
+            quit) exit 0;; \
+            esac; done";
+        let config = EngineConfig::new("sh")
+            .working_dir(".")
+            .arg("-c")
+            .arg(script);
+        let pool = EnginePool::spawn(&config, 1).unwrap();
+
+        let engine = pool.acquire().unwrap();
+        let job = pool.submit("startpos", ThinkParams::new(), Priority::High);
+        thread::sleep(Duration::from_millis(100));
+        assert!(job.try_result().is_none());
+        assert_eq!(1, pool.queued());
+        drop(engine);
+        assert!(job.wait().is_ok());
+
+        let engine = pool.acquire().unwrap();
+        assert!(engine.is_alive());
+    }
+}
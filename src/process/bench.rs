@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// The summary printed by an engine's `bench` command.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use usi::BenchResult;
+///
+/// let output = [
+///     "===========================",
+///     "Total time (ms) : 2000",
+///     "Nodes searched  : 4000000",
+///     "Nodes/second    : 2000000",
+/// ];
+/// let result = BenchResult::parse(output).unwrap();
+/// assert_eq!(4_000_000, result.nodes);
+/// assert_eq!(2_000_000, result.nps);
+/// assert_eq!(Some(Duration::from_secs(2)), result.time);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BenchResult {
+    /// Total number of nodes searched.
+    pub nodes: u64,
+    /// Nodes searched per second.
+    pub nps: u64,
+    /// Total time taken, if reported.
+    pub time: Option<Duration>,
+    /// Every line printed while benchmarking.
+    pub output: Vec<String>,
+}
+
+impl BenchResult {
+    /// Extracts the summary from the lines printed by `bench`, which engines
+    /// derived from Stockfish write as `Nodes searched : N`, `Nodes/second : N`
+    /// and `Total time (ms) : N`.
+    ///
+    /// Returns `Error::IllegalSyntax` if the node count or speed is missing.
+    pub fn parse<I, S>(lines: I) -> Result<BenchResult, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut nodes = None;
+        let mut nps = None;
+        let mut time = None;
+        let mut output = Vec::new();
+
+        for line in lines {
+            let line = line.as_ref().trim_end();
+            output.push(line.to_string());
+
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let Some(value) = value
+                .split_whitespace()
+                .next()
+                .and_then(|v| v.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let key = key.trim().to_ascii_lowercase();
+            if key.starts_with("nodes/second") || key == "nps" {
+                nps = Some(value);
+            } else if key == "nodes searched" || key == "nodes" {
+                nodes = Some(value);
+            } else if key.starts_with("total time") {
+                time = Some(Duration::from_millis(value));
+            }
+        }
+
+        match (nodes, nps) {
+            (Some(nodes), Some(nps)) => Ok(BenchResult {
+                nodes,
+                nps,
+                time,
+                output,
+            }),
+            _ => Err(Error::IllegalSyntax),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let result = BenchResult::parse([
+            "info depth 13 seldepth 18 score cp 45 nodes 1200 nps 600 time 2 pv 7g7f",
+            "Position: 1/4",
+            "",
+            "Total time (ms) : 1500",
+            "Nodes searched  : 3000",
+            "Nodes/second    : 2000",
+        ])
+        .unwrap();
+        assert_eq!(3000, result.nodes);
+        assert_eq!(2000, result.nps);
+        assert_eq!(Some(Duration::from_millis(1500)), result.time);
+        assert_eq!(6, result.output.len());
+
+        assert!(BenchResult::parse(["Unknown command: bench"]).is_err());
+    }
+}
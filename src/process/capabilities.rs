@@ -0,0 +1,124 @@
+use super::engine::EngineInfo;
+use crate::protocol::{OptionKind, OptionParams};
+use crate::registry::canonical_option_name;
+
+/// Features supported by an engine, derived from the options it declared.
+///
+/// Options are matched by their canonical names, so `USI_Hash` and `Hash`
+/// are the same option. `ThreadedEngine::probe_capabilities` additionally
+/// probes whether the engine answers mate searches.
+///
+/// # Examples
+///
+/// ```
+/// use usi::{Capabilities, EngineCommand};
+///
+/// let lines = [
+///     "option name MultiPV type spin default 1 min 1 max 500",
+///     "option name USI_Ponder type check default false",
+/// ];
+/// let options: Vec<_> = lines
+///     .iter()
+///     .filter_map(|line| match EngineCommand::parse(line) {
+///         Ok(EngineCommand::Option(o)) => Some(o),
+///         _ => None,
+///     })
+///     .collect();
+/// let capabilities = Capabilities::from_options(&options);
+/// assert!(capabilities.ponder);
+/// assert_eq!(Some(500), capabilities.max_multi_pv);
+/// assert_eq!(None, capabilities.max_threads);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    /// Whether the engine declares `USI_Ponder`.
+    pub ponder: bool,
+    /// The maximum of `MultiPV`, if declared as a spin option.
+    pub max_multi_pv: Option<i32>,
+    /// The maximum of `Threads`, if declared as a spin option.
+    pub max_threads: Option<i32>,
+    /// The maximum of `USI_Hash` in MiB, if declared as a spin option.
+    pub max_hash: Option<i32>,
+    /// Whether the engine answers `go mate` with `checkmate`, or `None` if not probed.
+    pub mate_search: Option<bool>,
+}
+
+impl Capabilities {
+    /// Derives the capabilities from declared options.
+    pub fn from_options(options: &[OptionParams]) -> Self {
+        let find = |name: &str| {
+            let canonical = canonical_option_name(name);
+            options
+                .iter()
+                .find(|o| canonical_option_name(&o.name) == canonical)
+                .map(|o| &o.value)
+        };
+        let max = |name: &str| match find(name) {
+            Some(OptionKind::Spin { max, .. }) => *max,
+            _ => None,
+        };
+        Capabilities {
+            ponder: find("USI_Ponder").is_some(),
+            max_multi_pv: max("MultiPV"),
+            max_threads: max("Threads"),
+            max_hash: max("USI_Hash"),
+            mate_search: None,
+        }
+    }
+
+    /// Whether more than one principal variation can be requested.
+    pub fn multi_pv(&self) -> bool {
+        self.max_multi_pv.is_some_and(|m| m > 1)
+    }
+}
+
+impl EngineInfo {
+    /// Returns the capabilities derived from the declared options.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_options(self.declared_options())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_options() {
+        let spin = |name: &str, max| OptionParams {
+            name: name.to_string(),
+            value: OptionKind::Spin {
+                default: Some(1),
+                min: Some(1),
+                max,
+            },
+        };
+        let options = vec![
+            spin("Hash", Some(33554432)),
+            spin("Threads", Some(512)),
+            spin("MultiPV", None),
+            OptionParams {
+                name: "USI_Ponder".to_string(),
+                value: OptionKind::Check {
+                    default: Some(false),
+                },
+            },
+        ];
+        let capabilities = Capabilities::from_options(&options);
+        assert_eq!(
+            Capabilities {
+                ponder: true,
+                max_multi_pv: None,
+                max_threads: Some(512),
+                max_hash: Some(33554432),
+                mate_search: None,
+            },
+            capabilities
+        );
+        assert!(!capabilities.multi_pv());
+        assert_eq!(Capabilities::default(), Capabilities::from_options(&[]));
+
+        let capabilities = Capabilities::from_options(&[spin("multi_pv", Some(4))]);
+        assert!(capabilities.multi_pv());
+    }
+}
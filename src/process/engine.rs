@@ -1,12 +1,24 @@
+use std::any::Any;
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::io::BufReader;
-use std::path::Path;
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
-use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use super::reader::{EngineCommandReader, EngineOutput};
-use super::writer::GuiCommandWriter;
+use super::bench::BenchResult;
+use super::fingerprint::Fingerprint;
+use super::observer::{Observer, Observers};
+use super::policy::{DropPolicy, HookPanicPolicy, RetryPolicy, StdioBuffers};
+use super::reader::{
+    spawn_reader_thread, EngineCommandReader, EngineOutput, Interrupter, OutputLimits,
+    OutputReceiver,
+};
+use super::writer::{GuiCommandWriter, LineEnding};
 use crate::error::Error;
 use crate::protocol::*;
 
@@ -14,7 +26,10 @@ use crate::protocol::*;
 #[derive(Clone, Debug, Default)]
 pub struct EngineInfo {
     name: String,
+    author: String,
     options: HashMap<String, String>,
+    declared: Vec<OptionParams>,
+    fingerprint: Option<Fingerprint>,
 }
 
 impl EngineInfo {
@@ -23,10 +38,192 @@ impl EngineInfo {
         &self.name
     }
 
+    /// Returns the engine author, or an empty string if the engine did not report it.
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    /// Returns the fingerprint of the executable taken when it was spawned,
+    /// or `None` if the executable could not be read.
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.fingerprint
+    }
+
+    /// Returns the engine name split into its base name and version.
+    pub fn parsed_name(&self) -> EngineName {
+        EngineName::parse(&self.name)
+    }
+
     /// Returns available engine options.
     pub fn options(&self) -> &HashMap<String, String> {
         &self.options
     }
+
+    /// Returns the options declared by the engine, in declaration order.
+    pub fn declared_options(&self) -> &[OptionParams] {
+        &self.declared
+    }
+
+    /// Returns the declared kind of the given option.
+    pub fn option_kind(&self, name: &str) -> Option<&OptionKind> {
+        self.declared
+            .iter()
+            .find(|o| o.name == name)
+            .map(|o| &o.value)
+    }
+
+    /// Returns a copy with the engine name, option names and option values
+    /// NFKC-normalized as `normalize_width` does.
+    ///
+    /// Engines built for Japanese environments sometimes report `Ｈａｓｈ`
+    /// or names containing ideographic spaces; normalizing them lets such
+    /// options match profiles and other engines.
+    pub fn normalized(&self) -> EngineInfo {
+        let opt = |s: &Option<String>| s.as_deref().map(normalize_width);
+        EngineInfo {
+            name: normalize_width(&self.name),
+            author: normalize_width(&self.author),
+            options: self
+                .options
+                .iter()
+                .map(|(k, v)| (normalize_width(k), normalize_width(v)))
+                .collect(),
+            declared: self
+                .declared
+                .iter()
+                .map(|o| OptionParams {
+                    name: normalize_width(&o.name),
+                    value: match &o.value {
+                        OptionKind::Combo { default, vars } => OptionKind::Combo {
+                            default: opt(default),
+                            vars: vars.iter().map(|v| normalize_width(v)).collect(),
+                        },
+                        OptionKind::Button { default } => OptionKind::Button {
+                            default: opt(default),
+                        },
+                        OptionKind::String { default } => OptionKind::String {
+                            default: opt(default),
+                        },
+                        OptionKind::Filename { default } => OptionKind::Filename {
+                            default: opt(default),
+                        },
+                        kind => kind.clone(),
+                    },
+                })
+                .collect(),
+            fingerprint: self.fingerprint,
+        }
+    }
+}
+
+/// An engine name reported by `id name`, split into its parts.
+///
+/// The version is the first word after the base name which looks like a version
+/// number, such as `7.63`, `v2.1` or `2024-05-03`. Words following it, typically
+/// naming the build target, are kept in `build`.
+///
+/// # Examples
+///
+/// ```
+/// use usi::EngineName;
+///
+/// let name = EngineName::parse("YaneuraOu NNUE 7.63 64AVX2");
+/// assert_eq!("YaneuraOu NNUE", name.base);
+/// assert_eq!(Some("7.63"), name.version.as_deref());
+/// assert_eq!(Some("64AVX2"), name.build.as_deref());
+///
+/// let name = EngineName::parse("Suisho5");
+/// assert_eq!("Suisho5", name.base);
+/// assert_eq!(None, name.version);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct EngineName {
+    pub base: String,
+    pub version: Option<String>,
+    pub build: Option<String>,
+}
+
+impl EngineName {
+    pub fn parse(name: &str) -> EngineName {
+        let words: Vec<&str> = name.split_whitespace().collect();
+        let pos = words
+            .iter()
+            .skip(1)
+            .position(|w| is_version(w))
+            .map(|p| p + 1);
+        match pos {
+            Some(pos) => EngineName {
+                base: words[..pos].join(" "),
+                version: Some(words[pos].to_string()),
+                build: Some(words[pos + 1..].join(" ")).filter(|b| !b.is_empty()),
+            },
+            None => EngineName {
+                base: words.join(" "),
+                version: None,
+                build: None,
+            },
+        }
+    }
+}
+
+/// Returns `true` if `word` looks like a version number, e.g. `7.63`, `v2.1`,
+/// `1.0.0b` or `2024-05-03`.
+fn is_version(word: &str) -> bool {
+    let digits = word.strip_prefix(['v', 'V']).unwrap_or(word);
+    let digits = digits
+        .strip_suffix(|c: char| c.is_ascii_lowercase())
+        .unwrap_or(digits);
+    !digits.is_empty()
+        && digits.starts_with(|c: char| c.is_ascii_digit())
+        && digits.ends_with(|c: char| c.is_ascii_digit())
+        && digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-'))
+}
+
+/// Represents a startup milestone reached while spawning and preparing an engine.
+///
+/// Engines loading large evaluation files can take a long time to answer `usiok`
+/// or `readyok`; these events allow GUIs to show progress in the meantime.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StartupEvent {
+    /// The engine process has been spawned.
+    ProcessStarted,
+    /// The `usi` command has been sent.
+    UsiSent,
+    /// An `id` command has been received.
+    IdReceived(IdParams),
+    /// The engine completed the handshake with `usiok`.
+    UsiOk,
+    /// The `isready` command has been sent.
+    IsReadySent,
+    /// The engine answered `readyok`.
+    ReadyOk,
+    /// Spawning the executable at `path` or the handshake failed. `attempt`
+    /// counts from 1, and `retry_in` is the delay before the next attempt, if any.
+    SpawnFailed {
+        path: PathBuf,
+        attempt: u32,
+        reason: String,
+        retry_in: Option<Duration>,
+    },
+}
+
+/// A boxed callback receiving `StartupEvent`s.
+pub type StartupCallback = Box<dyn FnMut(&StartupEvent) + Send>;
+
+struct StartupProgress(StartupCallback);
+
+impl std::fmt::Debug for StartupProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("StartupProgress")
+    }
+}
+
+fn notify(progress: &mut Option<StartupProgress>, event: StartupEvent) {
+    if let Some(p) = progress {
+        (p.0)(&event);
+    }
 }
 
 /// `UsiEngineHandler` provides a type-safe interface to the USI engine process.
@@ -66,14 +263,123 @@ impl EngineInfo {
 #[derive(Debug)]
 pub struct UsiEngineHandler {
     process: Child,
-    reader: Option<EngineCommandReader<BufReader<ChildStdout>>>,
-    writer: GuiCommandWriter<ChildStdin>,
+    reader: Option<OutputReceiver>,
+    output_limits: Arc<Mutex<OutputLimits>>,
+    extensions: Arc<Mutex<ProtocolExtensions>>,
+    observers: Arc<Mutex<Observers>>,
+    sender: CommandSender,
     handshake_started: bool,
+    progress: Option<StartupProgress>,
+    applied_options: HashMap<String, String>,
+    info: Option<EngineInfo>,
+    hook_panic_policy: HookPanicPolicy,
+    listener: Option<Listener>,
+    error_sender: Option<Sender<Error>>,
+    warnings: Arc<Mutex<WarningFilter>>,
+    /// Name of the option most recently sent with `setoption`.
+    last_option: Arc<Mutex<Option<String>>>,
+    working_dir: PathBuf,
+    /// Fingerprint of the executable taken before spawning it.
+    fingerprint: Option<Fingerprint>,
+    drop_policy: DropPolicy,
+}
+
+/// Forwards `info string` warnings to a channel.
+#[derive(Debug, Default)]
+struct WarningFilter {
+    patterns: WarningPatterns,
+    sender: Option<Sender<EngineWarning>>,
+}
+
+/// Returns the option rejected by the engine in `output`, if any.
+///
+/// Rejections not naming the option are attributed to the option sent last.
+fn rejected(output: &EngineOutput, last_option: &Mutex<Option<String>>) -> Option<String> {
+    let name = rejected_option(output.raw_str())?;
+    if !name.is_empty() {
+        return Some(name);
+    }
+    last_option.lock().ok()?.clone()
+}
+
+/// Resolves a path option value against the engine's working directory.
+pub(crate) fn resolve_option_path(working_dir: &Path, path: &str) -> PathBuf {
+    working_dir.join(path)
+}
+
+/// Returns a warning if a non-empty path option refers to a missing file.
+pub(crate) fn check_option_path(name: &str, path: &str, resolved: &Path) -> Option<EngineWarning> {
+    if path.is_empty() || resolved.exists() {
+        return None;
+    }
+    Some(EngineWarning {
+        message: format!(
+            "{name} refers to {} which does not exist",
+            resolved.display()
+        ),
+    })
+}
+
+/// Smallest plausible size of an evaluation file, which rules out
+/// truncated downloads and placeholders such as Git LFS pointers.
+const MIN_EVAL_FILE_SIZE: u64 = 64 * 1024;
+
+/// Checks that an evaluation option refers to a file of plausible size,
+/// or to a directory containing one.
+pub(crate) fn check_eval_path(name: &str, resolved: &Path) -> Result<(), Error> {
+    let invalid = |reason: String| {
+        Err(Error::InvalidEvalFile(format!(
+            "{name} refers to {} which {reason}",
+            resolved.display()
+        )))
+    };
+    let metadata = match std::fs::metadata(resolved) {
+        Ok(metadata) => metadata,
+        Err(_) => return invalid("does not exist".to_string()),
+    };
+    if metadata.is_dir() {
+        let largest = std::fs::read_dir(resolved)?
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .max();
+        return match largest {
+            Some(size) if size >= MIN_EVAL_FILE_SIZE => Ok(()),
+            Some(size) => invalid(format!("only contains files up to {size} bytes")),
+            None => invalid("contains no file".to_string()),
+        };
+    }
+    if metadata.len() < MIN_EVAL_FILE_SIZE {
+        return invalid(format!("is only {} bytes", metadata.len()));
+    }
+    Ok(())
+}
+
+fn check_warning(warnings: &Mutex<WarningFilter>, output: &EngineOutput) {
+    let filter = match warnings.lock() {
+        Ok(filter) => filter,
+        Err(_) => return,
+    };
+    if let (Some(sender), Some(command)) = (&filter.sender, output.response()) {
+        if let Some(warning) = filter.patterns.classify(command) {
+            let _ = sender.send(warning);
+        }
+    }
+}
+
+/// The thread started by `listen`, which hands the reader back when finished.
+#[derive(Debug)]
+struct Listener {
+    thread: JoinHandle<(OutputReceiver, Result<(), Error>)>,
+    interrupter: Interrupter,
 }
 
 impl Drop for UsiEngineHandler {
     fn drop(&mut self) {
-        self.kill().unwrap();
+        if self.drop_policy == DropPolicy::Kill {
+            // The engine may already have exited.
+            let _ = self.kill();
+        }
     }
 }
 impl UsiEngineHandler {
@@ -85,24 +391,181 @@ impl UsiEngineHandler {
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
-        let mut process = Command::new(engine_path)
-            .args(args)
-            .current_dir(working_dir)
+        let mut command = Command::new(engine_path);
+        command.args(args).current_dir(working_dir);
+        Self::spawn_command(&mut command)
+    }
+
+    /// Spawns a new process from a prepared `Command`, e.g. one with environment
+    /// variables set or stderr redirected.
+    ///
+    /// Stdin and stdout are replaced with pipes to the engine.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::process::{Command, Stdio};
+    /// use usi::UsiEngineHandler;
+    ///
+    /// let mut command = Command::new("/path/to/usi_engine");
+    /// command
+    ///     .current_dir("/path/to/working_dir")
+    ///     .env("OMP_NUM_THREADS", "1")
+    ///     .stderr(Stdio::null());
+    /// let mut handler = UsiEngineHandler::spawn_command(&mut command).unwrap();
+    /// ```
+    pub fn spawn_command(command: &mut Command) -> Result<Self, Error> {
+        Self::spawn_command_with_buffers(command, StdioBuffers::default())
+    }
+
+    /// Spawns a new process from a prepared `Command`, with the pipe buffering of `buffers`.
+    pub fn spawn_command_with_buffers(
+        command: &mut Command,
+        buffers: StdioBuffers,
+    ) -> Result<Self, Error> {
+        let working_dir = command
+            .get_current_dir()
+            .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+        let program = Path::new(command.get_program());
+        let fingerprint = Fingerprint::of_file(program)
+            .or_else(|_| Fingerprint::of_file(working_dir.join(program)))
+            .ok();
+        let path = program.display().to_string();
+        let mut process = command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .spawn()?;
+            .spawn()
+            .map_err(|source| Error::Spawn { path, source })?;
 
         let stdin = process.stdin.take().unwrap();
         let stdout = process.stdout.take().unwrap();
 
+        let output_limits = Arc::new(Mutex::new(OutputLimits::default()));
+        let reader = EngineCommandReader::new(BufReader::with_capacity(
+            buffers.read_capacity.max(1),
+            stdout,
+        ));
+        let mut writer = GuiCommandWriter::new(stdin);
+        writer.set_buffering(buffers.write);
+        let extensions = reader.shared_extensions();
+        let observers = Arc::new(Mutex::new(Observers::default()));
+        let last_option = Arc::default();
         Ok(UsiEngineHandler {
             process,
-            reader: Some(EngineCommandReader::new(BufReader::new(stdout))),
-            writer: GuiCommandWriter::new(stdin),
+            reader: Some(spawn_reader_thread(
+                reader,
+                Arc::clone(&output_limits),
+                Arc::clone(&observers),
+            )),
+            output_limits,
+            extensions,
+            sender: CommandSender {
+                writer: Arc::new(Mutex::new(writer)),
+                observers: Arc::clone(&observers),
+                last_option: Arc::clone(&last_option),
+            },
+            observers,
             handshake_started: false,
+            progress: None,
+            applied_options: HashMap::new(),
+            info: None,
+            hook_panic_policy: HookPanicPolicy::default(),
+            listener: None,
+            error_sender: None,
+            warnings: Arc::default(),
+            last_option,
+            working_dir,
+            fingerprint,
+            drop_policy: DropPolicy::default(),
         })
     }
 
+    /// Spawns a new process of the specific USI engine, reporting startup progress.
+    ///
+    /// `progress` is called with `StartupEvent::ProcessStarted` once the process is
+    /// running, and later with each milestone reached by `get_info` and `prepare`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use usi::{StartupEvent, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn_with_progress(
+    ///     "/path/to/usi_engine",
+    ///     "/path/to/working_dir",
+    ///     &[] as &[&str],
+    ///     |event: &StartupEvent| println!("{:?}", event),
+    /// )
+    /// .unwrap();
+    /// handler.get_info().unwrap();
+    /// handler.prepare().unwrap();
+    /// ```
+    pub fn spawn_with_progress<P, Q, I, S, F>(
+        engine_path: P,
+        working_dir: Q,
+        args: I,
+        progress: F,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<OsStr>,
+        Q: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+        F: FnMut(&StartupEvent) + Send + 'static,
+    {
+        let mut handler = Self::spawn(engine_path, working_dir, args)?;
+        handler.set_progress(Box::new(progress));
+        Ok(handler)
+    }
+
+    /// Reports startup progress to `progress`, starting with `StartupEvent::ProcessStarted`.
+    pub(crate) fn set_progress(&mut self, progress: StartupCallback) {
+        self.progress = Some(StartupProgress(progress));
+        notify(&mut self.progress, StartupEvent::ProcessStarted);
+    }
+
+    /// Stops reporting startup progress, returning the callback.
+    pub(crate) fn take_progress(&mut self) -> Option<StartupCallback> {
+        self.progress.take().map(|p| p.0)
+    }
+
+    pub(crate) fn notify_progress(&mut self, event: StartupEvent) {
+        notify(&mut self.progress, event);
+    }
+
+    /// Sets limits on the output read from the engine.
+    ///
+    /// The limits apply from the line after the one currently being read.
+    /// Lines discarded because of the limits are skipped by `get_info`, `prepare` and `listen`.
+    pub fn set_output_limits(&mut self, limits: OutputLimits) {
+        if let Ok(mut l) = self.output_limits.lock() {
+            *l = limits;
+        }
+    }
+
+    /// Returns the limits on the output read from the engine.
+    pub fn output_limits(&self) -> OutputLimits {
+        self.output_limits.lock().map(|l| *l).unwrap_or_default()
+    }
+
+    /// Sets the extensions parsing engine-specific lines which are not USI commands.
+    ///
+    /// The events they produce are available through `EngineOutput::extension`
+    /// in the hook passed to `listen`. Set them before `get_info` to cover
+    /// the lines sent during the handshake.
+    pub fn set_protocol_extensions(&mut self, extensions: ProtocolExtensions) {
+        if let Ok(mut e) = self.extensions.lock() {
+            *e = extensions;
+        }
+    }
+
+    /// Installs an observer notified of every command sent to and received from the engine.
+    ///
+    /// Lines read from the engine before the observer was installed are not observed.
+    pub fn add_observer<O: Observer + 'static>(&mut self, observer: O) {
+        if let Ok(mut observers) = self.observers.lock() {
+            observers.push(Box::new(observer));
+        }
+    }
+
     /// Sends a command to the engine BEFORE the USI handshake.
     ///
     /// This is useful for engines like Fairy-Stockfish that require
@@ -133,7 +596,7 @@ impl UsiEngineHandler {
         if self.handshake_started {
             return Err(Error::IllegalOperation);
         }
-        self.writer.send(command)
+        self.send(command)
     }
 
     /// Request metadata such as a name and available options.
@@ -141,51 +604,84 @@ impl UsiEngineHandler {
     /// records `id` and `option` commands until `usiok` is received.
     /// Returns `Error::IllegalOperation` when called after `listen` method.
     pub fn get_info(&mut self) -> Result<EngineInfo, Error> {
+        self.get_info_until(None)
+    }
+
+    /// Like `get_info`, but fails with `Error::Timeout` if the engine
+    /// does not complete the handshake within `timeout`.
+    pub fn get_info_with_timeout(&mut self, timeout: Duration) -> Result<EngineInfo, Error> {
+        self.get_info_until(Some(Instant::now() + timeout))
+    }
+
+    fn get_info_until(&mut self, deadline: Option<Instant>) -> Result<EngineInfo, Error> {
+        if self.reader.is_none() {
+            return Err(Error::IllegalOperation);
+        }
+        self.handshake_started = true;
+        self.handshake(deadline)
+            .map_err(|err| Error::Handshake(Box::new(err)))
+    }
+
+    fn handshake(&mut self, deadline: Option<Instant>) -> Result<EngineInfo, Error> {
         let reader = match &mut self.reader {
             Some(r) => Ok(r),
             None => Err(Error::IllegalOperation),
         }?;
 
-        self.handshake_started = true;
-
         let mut info = EngineInfo::default();
-        self.writer.send(&GuiCommand::Usi)?;
+        self.sender.write(|w| w.send(&GuiCommand::Usi))?;
+        notify(&mut self.progress, StartupEvent::UsiSent);
 
         loop {
-            match reader.next_command() {
-                Ok(output) => {
-                    match output.response() {
-                        Some(EngineCommand::Id(IdParams::Name(name))) => {
-                            info.name = name.to_string();
-                        }
-                        Some(EngineCommand::Option(OptionParams {
-                            ref name,
-                            ref value,
-                        })) => {
-                            info.options.insert(
-                                name.to_string(),
-                                match value {
-                                    OptionKind::Check { default: Some(f) } => {
-                                        if *f { "true" } else { "false" }.to_string()
-                                    }
-                                    OptionKind::Spin {
-                                        default: Some(n), ..
-                                    } => n.to_string(),
-                                    OptionKind::Combo {
-                                        default: Some(s), ..
-                                    } => s.to_string(),
-                                    OptionKind::Button { default: Some(s) } => s.to_string(),
-                                    OptionKind::String { default: Some(s) } => s.to_string(),
-                                    OptionKind::Filename { default: Some(s) } => s.to_string(),
-                                    _ => String::new(),
-                                },
-                            );
+            let output = next_output(reader, deadline);
+            if let Ok(output) = &output {
+                check_warning(&self.warnings, output);
+            }
+            match output {
+                Ok(output) => match output.response() {
+                    Some(EngineCommand::Id(id)) => {
+                        match id {
+                            IdParams::Name(name) => info.name = name.to_string(),
+                            IdParams::Author(author) => info.author = author.to_string(),
                         }
-                        Some(EngineCommand::UsiOk) => break,
-                        _ => {}
+                        notify(&mut self.progress, StartupEvent::IdReceived(id.clone()));
                     }
-                }
-                Err(Error::IllegalSyntax) => {
+                    Some(EngineCommand::Option(OptionParams {
+                        ref name,
+                        ref value,
+                    })) => {
+                        info.options.insert(
+                            name.to_string(),
+                            match value {
+                                OptionKind::Check { default: Some(f) } => {
+                                    if *f { "true" } else { "false" }.to_string()
+                                }
+                                OptionKind::Spin {
+                                    default: Some(n), ..
+                                } => n.to_string(),
+                                OptionKind::Combo {
+                                    default: Some(s), ..
+                                } => s.to_string(),
+                                OptionKind::Button { default: Some(s) } => s.to_string(),
+                                OptionKind::String { default: Some(s) } => s.to_string(),
+                                OptionKind::Filename { default: Some(s) } => s.to_string(),
+                                _ => String::new(),
+                            },
+                        );
+                        info.declared.push(OptionParams {
+                            name: name.clone(),
+                            value: value.clone(),
+                        });
+                    }
+                    Some(EngineCommand::UsiOk) => {
+                        notify(&mut self.progress, StartupEvent::UsiOk);
+                        break;
+                    }
+                    // EOF reached (engine closed) before `usiok`.
+                    None => return Err(Error::Terminated),
+                    _ => {}
+                },
+                Err(Error::IllegalSyntax | Error::LineTooLong(_) | Error::OutputOverflow(_)) => {
                     // Ignore lines that don't parse as valid USI commands
                     // (e.g., UCI-style output from Fairy-Stockfish)
                     continue;
@@ -194,81 +690,1042 @@ impl UsiEngineHandler {
             }
         }
 
+        info.fingerprint = self.fingerprint;
+        self.info = Some(info.clone());
         Ok(info)
     }
 
+    /// Returns the engine information obtained by the last `get_info` call.
+    pub fn info(&self) -> Option<&EngineInfo> {
+        self.info.as_ref()
+    }
+
     /// Prepare the engine to be ready to start a new game.
     /// Internally, `prepare()` sends `isready` command and waits until `readyok` is received.
     /// Returns `Error::IllegalOperation` when called after `listen` method.
     pub fn prepare(&mut self) -> Result<(), Error> {
+        self.prepare_with_policy(&RetryPolicy::default())
+    }
+
+    /// Prepare the engine, waiting for `readyok` according to `policy`.
+    ///
+    /// Each attempt sends `isready` and waits up to the policy's timeout.
+    /// Returns `Error::Timeout` with the `isready` command attached if no attempt
+    /// received `readyok`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use usi::{RetryPolicy, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    ///
+    /// let policy = RetryPolicy::new()
+    ///     .attempts(3)
+    ///     .timeout(Duration::from_secs(10))
+    ///     .backoff(Duration::from_millis(500));
+    /// handler.prepare_with_policy(&policy).unwrap();
+    /// ```
+    pub fn prepare_with_policy(&mut self, policy: &RetryPolicy) -> Result<(), Error> {
         let reader = match &mut self.reader {
             Some(r) => Ok(r),
             None => Err(Error::IllegalOperation),
         }?;
 
-        self.writer.send(&GuiCommand::IsReady)?;
+        for attempt in 0..policy.max_attempts() {
+            if let Some(delay) = policy.delay_before(attempt) {
+                thread::sleep(delay);
+            }
+
+            self.sender.write(|w| w.send(&GuiCommand::IsReady))?;
+            notify(&mut self.progress, StartupEvent::IsReadySent);
+
+            let deadline = policy.attempt_timeout().map(|t| Instant::now() + t);
+            loop {
+                match next_output(reader, deadline) {
+                    Ok(output) => {
+                        check_warning(&self.warnings, &output);
+                        if let (Some(name), Some(sender)) =
+                            (rejected(&output, &self.last_option), &self.error_sender)
+                        {
+                            let _ =
+                                sender.send(Error::UnknownOption(name).with_line(output.raw_str()));
+                        }
+                        match output.response() {
+                            Some(EngineCommand::ReadyOk) => {
+                                notify(&mut self.progress, StartupEvent::ReadyOk);
+                                return Ok(());
+                            }
+                            // EOF reached (engine closed) before `readyok`.
+                            None => return Err(Error::Terminated),
+                            _ => {}
+                        }
+                    }
+                    Err(
+                        Error::IllegalSyntax | Error::LineTooLong(_) | Error::OutputOverflow(_),
+                    ) => {
+                        // Ignore lines that don't parse as valid USI commands
+                        continue;
+                    }
+                    Err(Error::Timeout) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Err(Error::Timeout.with_command("isready"))
+    }
+    /// Sends a command to the engine.
+    pub fn send_command(&mut self, command: &GuiCommand) -> Result<(), Error> {
+        self.send(command)
+    }
+
+    /// Sends a line as is, for engine-specific commands such as YaneuraOu's `bench`.
+    /// Replies are received like any other engine output, e.g. by the `listen` hook.
+    ///
+    /// Returns `Error::IllegalSyntax` if `line` contains a line break.
+    pub fn send_raw(&mut self, line: &str) -> Result<(), Error> {
+        self.sender.send_raw(line)
+    }
+
+    /// Returns a sender which can be moved into the hook passed to `listen`
+    /// or to other threads, e.g. to send `stop` once a target depth is reached.
+    ///
+    /// Options set through the sender are not reflected by `current_options`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use usi::{EngineCommand, Error, GuiCommand, InfoParams, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    /// let sender = handler.command_sender();
+    /// handler.listen(move |output| -> Result<(), Error> {
+    ///     if let Some(EngineCommand::Info(params)) = output.response() {
+    ///         if params.iter().any(|p| matches!(p, InfoParams::Depth(d, _) if *d >= 20)) {
+    ///             sender.send_command(&GuiCommand::Stop)?;
+    ///         }
+    ///     }
+    ///     Ok(())
+    /// }).unwrap();
+    /// ```
+    pub fn command_sender(&self) -> CommandSender {
+        self.sender.clone()
+    }
+
+    /// Sets an option and waits for `readyok`, checking that the engine accepted it.
+    ///
+    /// Returns `Error::UnknownOption` if the engine reported the option as unknown,
+    /// as engines derived from Stockfish do, and `Error::IllegalOperation` when
+    /// called after `listen` method.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use usi::{Error, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    /// match handler.set_option_checked("EvalDir", Some("eval")) {
+    ///     Err(Error::UnknownOption(name)) => eprintln!("{} is not supported", name),
+    ///     res => res.unwrap(),
+    /// }
+    /// ```
+    pub fn set_option_checked(&mut self, name: &str, value: Option<&str>) -> Result<(), Error> {
+        if self.reader.is_none() {
+            return Err(Error::IllegalOperation);
+        }
+        self.send(&GuiCommand::SetOption(
+            name.to_string(),
+            value.map(|v| v.to_string()),
+        ))?;
+        self.sender.write(|w| w.send(&GuiCommand::IsReady))?;
+
+        let reader = self.reader.as_ref().ok_or(Error::IllegalOperation)?;
+        let mut rejected_name = None;
+        loop {
+            match next_output(reader, None) {
+                Ok(output) => {
+                    check_warning(&self.warnings, &output);
+                    match output.response() {
+                        Some(EngineCommand::ReadyOk) => break,
+                        None => return Err(Error::Terminated),
+                        _ => {
+                            if let Some(n) = rejected(&output, &self.last_option) {
+                                rejected_name = Some(n);
+                            }
+                        }
+                    }
+                }
+                Err(Error::IllegalSyntax | Error::LineTooLong(_) | Error::OutputOverflow(_)) => {
+                    continue
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        match rejected_name {
+            Some(n) if n.eq_ignore_ascii_case(name) => {
+                self.applied_options.remove(name);
+                Err(Error::UnknownOption(n))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the engine's `bench` command with the given arguments and returns its summary.
+    ///
+    /// `isready` is sent after `bench`, and the output is collected until `readyok`.
+    /// Returns `Error::IllegalSyntax` if the engine printed no summary, e.g. because
+    /// it does not support `bench`, and `Error::IllegalOperation` when called after
+    /// `listen` method.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use usi::UsiEngineHandler;
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.prepare().unwrap();
+    /// let result = handler.run_bench(&["64", "1", "15"]).unwrap();
+    /// println!("{} nodes, {} nps", result.nodes, result.nps);
+    /// ```
+    pub fn run_bench(&mut self, args: &[&str]) -> Result<BenchResult, Error> {
+        if self.reader.is_none() {
+            return Err(Error::IllegalOperation);
+        }
+        let mut line = "bench".to_string();
+        for arg in args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        self.sender.write(|w| {
+            w.send_raw(&line)?;
+            w.send(&GuiCommand::IsReady)
+        })?;
+
+        let reader = self.reader.as_ref().ok_or(Error::IllegalOperation)?;
+        let mut lines = Vec::new();
         loop {
-            match reader.next_command() {
+            match next_output(reader, None) {
                 Ok(output) => {
                     if let Some(EngineCommand::ReadyOk) = output.response() {
                         break;
                     }
+                    check_warning(&self.warnings, &output);
+                    lines.push(output.raw_str().to_string());
                 }
-                Err(Error::IllegalSyntax) => {
-                    // Ignore lines that don't parse as valid USI commands
-                    continue;
-                }
+                Err(
+                    Error::IllegalSyntax
+                    | Error::IllegalNumberFormat(_)
+                    | Error::LineTooLong(_)
+                    | Error::OutputOverflow(_),
+                ) => continue,
                 Err(err) => return Err(err),
             }
         }
+        BenchResult::parse(lines)
+    }
 
-        Ok(())
+    /// Returns every option value sent via `setoption` so far, keyed by option name.
+    ///
+    /// Later values replace earlier ones. Options sent without a value
+    /// (e.g. `button` options) are not recorded.
+    pub fn current_options(&self) -> &HashMap<String, String> {
+        &self.applied_options
     }
-    /// Sends a command to the engine.
-    pub fn send_command(&mut self, command: &GuiCommand) -> Result<(), Error> {
-        self.writer.send(command)
+
+    /// Returns the current value of an option, parsed according to its declared kind.
+    ///
+    /// The last value sent via `setoption` takes precedence over the default
+    /// declared during the handshake. `USI_Hash` and `USI_Ponder` are typed as
+    /// `spin` and `check` even when the engine does not declare them. Values which
+    /// do not match the declared kind are returned as `OptionValue::String`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use usi::{GuiCommand, OptionValue, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    /// handler.send_command(&GuiCommand::SetOption("USI_Hash".to_string(), Some("1024".to_string()))).unwrap();
+    /// assert_eq!(Some(OptionValue::Spin(1024)), handler.effective_option("USI_Hash"));
+    /// ```
+    pub fn effective_option(&self, name: &str) -> Option<OptionValue> {
+        let kind = self
+            .info
+            .as_ref()
+            .and_then(|info| info.option_kind(name).cloned())
+            .or_else(|| implicit_option_kind(name));
+
+        match (self.applied_options.get(name), kind) {
+            (Some(v), Some(kind)) => kind
+                .parse_value(v)
+                .or_else(|| Some(OptionValue::String(v.clone()))),
+            (Some(v), None) => Some(OptionValue::String(v.clone())),
+            (None, Some(kind)) => kind.default_value(),
+            (None, None) => None,
+        }
+    }
+
+    fn send(&mut self, command: &GuiCommand) -> Result<(), Error> {
+        self.sender.send_command(command)?;
+        if let GuiCommand::SetOption(name, Some(value)) = command {
+            self.applied_options.insert(name.clone(), value.clone());
+        }
+        Ok(())
     }
 
     /// Terminates the engine.
     pub fn kill(&mut self) -> Result<(), Error> {
-        self.writer.send(&GuiCommand::Quit)?;
+        self.send(&GuiCommand::Quit)?;
         self.process.kill()?;
         Ok(())
     }
 
+    /// Returns the OS-assigned process identifier of the engine.
+    pub fn pid(&self) -> u32 {
+        self.process.id()
+    }
+
+    /// Returns the working directory the engine was started in.
+    pub fn working_dir(&self) -> &Path {
+        &self.working_dir
+    }
+
+    /// Sets a `filename` or `string` option holding a path, such as `EvalDir` or `BookFile`.
+    ///
+    /// Relative paths are resolved against the engine's working directory, where
+    /// the engine looks for them. If the path does not exist, a warning is sent
+    /// through the channel set by `set_warning_sender`; the option is set anyway,
+    /// as engines may create the file later.
+    ///
+    /// Returns the resolved path.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use usi::UsiEngineHandler;
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", "/path/to/working_dir", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    /// let eval_dir = handler.set_path_option("EvalDir", "eval").unwrap();
+    /// assert_eq!(std::path::Path::new("/path/to/working_dir/eval"), eval_dir);
+    /// ```
+    pub fn set_path_option(&mut self, name: &str, path: &str) -> Result<PathBuf, Error> {
+        let resolved = resolve_option_path(&self.working_dir, path);
+        if let Some(warning) = check_option_path(name, path, &resolved) {
+            if let Some(sender) = self.warnings.lock().ok().and_then(|f| f.sender.clone()) {
+                let _ = sender.send(warning);
+            }
+        }
+        self.send(&GuiCommand::set_option(
+            name,
+            &OptionValue::Filename(path.to_string()),
+        ))?;
+        Ok(resolved)
+    }
+
+    /// Sets an evaluation option, such as `EvalDir`, `EvalFile` or `DNN_Model`,
+    /// after checking that it refers to a file of plausible size,
+    /// or to a directory containing one.
+    ///
+    /// Relative paths are resolved against the working directory.
+    /// Returns `Error::InvalidEvalFile` without setting the option if the
+    /// check fails, instead of the engine failing on `isready`.
+    /// Otherwise returns the resolved path.
+    pub fn set_eval_option(&mut self, name: &str, path: &str) -> Result<PathBuf, Error> {
+        let resolved = resolve_option_path(&self.working_dir, path);
+        check_eval_path(name, &resolved)?;
+        self.send(&GuiCommand::set_option(
+            name,
+            &OptionValue::Filename(path.to_string()),
+        ))?;
+        Ok(resolved)
+    }
+
+    /// Sets the line terminator written after each command, `\n` by default.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        let _ = self.sender.write(|w| {
+            w.set_line_ending(line_ending);
+            Ok(())
+        });
+    }
+
+    /// Sets what happens to the engine process when the handler is dropped.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// Sets what happens when the hook passed to `listen` panics.
+    /// Must be called before `listen` to take effect.
+    pub fn set_hook_panic_policy(&mut self, policy: HookPanicPolicy) {
+        self.hook_panic_policy = policy;
+    }
+
+    /// Sets a channel receiving errors which occur in the thread started by `listen`.
+    /// Must be called before `listen` to take effect.
+    ///
+    /// Besides the error stopping the thread, this includes errors which do not
+    /// stop it, such as discarded lines (see `set_output_limits`) and panics
+    /// ignored according to `HookPanicPolicy::Continue`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::sync::mpsc::channel;
+    /// use usi::{Error, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// let (sender, errors) = channel();
+    /// handler.set_error_sender(sender);
+    /// handler.listen(|_output| -> Result<(), Error> { Ok(()) }).unwrap();
+    ///
+    /// for err in errors {
+    ///     eprintln!("engine error: {}", err);
+    /// }
+    /// ```
+    pub fn set_error_sender(&mut self, sender: Sender<Error>) {
+        self.error_sender = Some(sender);
+    }
+
+    /// Sets a channel receiving the `info string` lines classified as warnings,
+    /// both during the handshake and in the thread started by `listen`.
+    pub fn set_warning_sender(&mut self, sender: Sender<EngineWarning>) {
+        if let Ok(mut filter) = self.warnings.lock() {
+            filter.sender = Some(sender);
+        }
+    }
+
+    /// Sets the patterns classifying `info string` lines as warnings.
+    pub fn set_warning_patterns(&mut self, patterns: WarningPatterns) {
+        if let Ok(mut filter) = self.warnings.lock() {
+            filter.patterns = patterns;
+        }
+    }
+
     /// Spanws a new thread to monitor outputs from the engine.
     /// `hook` will be called for each USI command received.
-    /// `prepare` method can only be called before `listen` method.
+    /// `prepare` method can only be called before `listen` method, or after `stop_listening`.
+    ///
+    /// The thread finishes when the engine closes its output, or when `hook`
+    /// returns an error or panics. See `listener_result` for its outcome.
+    /// The error stopping the thread is sent through the channel set by
+    /// `set_error_sender` instead, if any.
     pub fn listen<F, E>(&mut self, mut hook: F) -> Result<(), Error>
     where
         F: FnMut(&EngineOutput) -> Result<(), E> + Send + 'static,
         E: std::error::Error + Send + Sync + 'static,
     {
-        let mut reader = self.reader.take().ok_or(Error::IllegalOperation)?;
+        let reader = self.reader.take().ok_or(Error::IllegalOperation)?;
+        let interrupter = reader.interrupter();
+        let listen_loop = self.listen_loop();
 
-        thread::spawn(move || -> Result<(), Error> {
-            loop {
-                match reader.next_command() {
-                    Ok(output) => {
-                        // EOF reached (engine closed) - exit the loop
-                        if output.response().is_none() {
-                            return Ok(());
-                        }
-                        if let Err(e) = hook(&output) {
-                            return Err(Error::HandlerError(Box::new(e)));
-                        }
+        let thread = thread::spawn(move || {
+            let res = listen_loop.run(&reader, &mut hook);
+            (reader, res)
+        });
+        self.listener = Some(Listener {
+            thread,
+            interrupter,
+        });
+
+        Ok(())
+    }
+
+    /// Like `listen`, but the hook runs on a scoped thread for as long as `body` runs,
+    /// so that it can borrow from the caller's stack instead of being `'static`.
+    ///
+    /// `body` is called on the current thread with the handler, e.g. to send commands.
+    /// Once it returns, the hook processes the outputs already read and the thread
+    /// finishes as with `stop_listening`. Returns the value of `body`, or the error
+    /// which stopped the thread unless it was sent through the channel set by
+    /// `set_error_sender`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use usi::{EngineCommand, Error, GuiCommand, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    ///
+    /// let mut lines = Vec::new();
+    /// handler
+    ///     .listen_scoped(
+    ///         |output| -> Result<(), Error> {
+    ///             if let Some(EngineCommand::Info(_)) = output.response() {
+    ///                 lines.push(output.raw_str().to_string());
+    ///             }
+    ///             Ok(())
+    ///         },
+    ///         |handler| {
+    ///             handler.send_command(&GuiCommand::Position("startpos".to_string()))?;
+    ///             handler.send_command(&GuiCommand::Go(usi::ThinkParams::blitz()))?;
+    ///             std::thread::sleep(Duration::from_secs(2));
+    ///             Ok::<_, Error>(())
+    ///         },
+    ///     )
+    ///     .unwrap()
+    ///     .unwrap();
+    /// println!("{} info lines", lines.len());
+    /// ```
+    pub fn listen_scoped<F, E, B, R>(&mut self, mut hook: F, body: B) -> Result<R, Error>
+    where
+        F: FnMut(&EngineOutput) -> Result<(), E> + Send,
+        E: std::error::Error + Send + Sync + 'static,
+        B: FnOnce(&mut UsiEngineHandler) -> R,
+    {
+        let reader = self.reader.take().ok_or(Error::IllegalOperation)?;
+        let interrupter = reader.interrupter();
+        let listen_loop = self.listen_loop();
+
+        let (reader, res, value) = thread::scope(|scope| {
+            let thread = scope.spawn(move || {
+                let res = listen_loop.run(&reader, &mut hook);
+                (reader, res)
+            });
+            let value = body(self);
+            interrupter.interrupt();
+            match thread.join() {
+                Ok((reader, res)) => (Some(reader), res, value),
+                Err(payload) => (
+                    None,
+                    Err(Error::HandlerPanic(panic_message(&*payload))),
+                    value,
+                ),
+            }
+        });
+        self.reader = reader;
+        res.map(|_| value)
+    }
+
+    /// Captures the settings applied by the thread started by `listen`.
+    fn listen_loop(&self) -> ListenLoop {
+        ListenLoop {
+            panic_policy: self.hook_panic_policy,
+            errors: self.error_sender.clone(),
+            warnings: Arc::clone(&self.warnings),
+            last_option: Arc::clone(&self.last_option),
+        }
+    }
+
+    /// Stops the thread started by `listen` after it has processed the outputs
+    /// already read, and waits for it to finish.
+    ///
+    /// Afterwards the handler can be used as before `listen`, e.g. to call
+    /// `prepare` or `listen` again. Returns the outcome of the thread, or
+    /// `Error::IllegalOperation` if it was not started.
+    pub fn stop_listening(&mut self) -> Result<(), Error> {
+        let listener = self.listener.take().ok_or(Error::IllegalOperation)?;
+        listener.interrupter.interrupt();
+        self.join_listener(listener)
+    }
+
+    /// Returns the outcome of the thread started by `listen` once it has finished,
+    /// or `None` while it is running or if `listen` was not called.
+    pub fn listener_result(&mut self) -> Option<Result<(), Error>> {
+        if !self.listener.as_ref()?.thread.is_finished() {
+            return None;
+        }
+        let listener = self.listener.take()?;
+        Some(self.join_listener(listener))
+    }
+
+    /// Splits the handler into a writer half sending commands and a reader half
+    /// receiving the engine output, which can be moved to different threads,
+    /// e.g. to stream the output while another thread sends commands.
+    ///
+    /// The writer half owns the process and applies the `DropPolicy` when dropped.
+    /// Returns `Error::IllegalOperation` while listening.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::thread;
+    /// use usi::{GuiCommand, UsiEngineHandler};
+    ///
+    /// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+    /// handler.get_info().unwrap();
+    /// let (mut writer, reader) = handler.split().unwrap();
+    ///
+    /// let printer = thread::spawn(move || {
+    ///     while let Ok(Some(output)) = reader.recv() {
+    ///         println!("{}", output.raw_str());
+    ///     }
+    /// });
+    /// writer.send_command(&GuiCommand::IsReady).unwrap();
+    /// writer.send_command(&GuiCommand::Quit).unwrap();
+    /// printer.join().unwrap();
+    /// ```
+    pub fn split(mut self) -> Result<(EngineWriteHalf, EngineReadHalf), Error> {
+        let reader = self.reader.take().ok_or(Error::IllegalOperation)?;
+        let read_half = EngineReadHalf {
+            reader,
+            warnings: Arc::clone(&self.warnings),
+        };
+        Ok((EngineWriteHalf { handler: self }, read_half))
+    }
+
+    fn join_listener(&mut self, listener: Listener) -> Result<(), Error> {
+        match listener.thread.join() {
+            Ok((reader, res)) => {
+                self.reader = Some(reader);
+                res
+            }
+            Err(payload) => Err(Error::HandlerPanic(panic_message(&*payload))),
+        }
+    }
+}
+
+/// Sends commands to an engine from any thread, returned by `UsiEngineHandler::command_sender`.
+///
+/// Clones send to the same engine, and commands from different threads are not interleaved.
+#[derive(Clone, Debug)]
+pub struct CommandSender {
+    writer: Arc<Mutex<GuiCommandWriter<ChildStdin>>>,
+    observers: Arc<Mutex<Observers>>,
+    /// Name of the option most recently sent with `setoption`.
+    last_option: Arc<Mutex<Option<String>>>,
+}
+
+impl CommandSender {
+    /// Sends a command to the engine.
+    pub fn send_command(&self, command: &GuiCommand) -> Result<(), Error> {
+        self.write(|w| w.send(command))?;
+        if let Ok(mut observers) = self.observers.lock() {
+            observers.sent(command);
+        }
+        if let GuiCommand::SetOption(name, _) = command {
+            if let Ok(mut last) = self.last_option.lock() {
+                *last = Some(name.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a line as is, see `UsiEngineHandler::send_raw`.
+    pub fn send_raw(&self, line: &str) -> Result<(), Error> {
+        self.write(|w| w.send_raw(line))?;
+        if let Ok(mut observers) = self.observers.lock() {
+            observers.sent_raw(line);
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with the writer locked, so that the commands it sends are not interleaved.
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut GuiCommandWriter<ChildStdin>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut writer = self.writer.lock().map_err(|_| Error::IllegalOperation)?;
+        f(&mut writer)
+    }
+}
+
+/// The loop run by the thread started by `listen`.
+struct ListenLoop {
+    panic_policy: HookPanicPolicy,
+    errors: Option<Sender<Error>>,
+    warnings: Arc<Mutex<WarningFilter>>,
+    last_option: Arc<Mutex<Option<String>>>,
+}
+
+impl ListenLoop {
+    /// Calls `hook` for each output until the engine closes its output, the loop
+    /// is interrupted or `hook` fails.
+    fn run<F, E>(&self, reader: &OutputReceiver, hook: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(&EngineOutput) -> Result<(), E>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let res = loop {
+            match next_output(reader, None) {
+                Ok(output) => {
+                    // EOF reached (engine closed) - exit the loop
+                    if output.response().is_none() {
+                        break Ok(());
                     }
-                    Err(Error::IllegalSyntax) => {
-                        // Ignore illegal commands.
-                        continue;
+                    check_warning(&self.warnings, &output);
+                    if let Some(name) = rejected(&output, &self.last_option) {
+                        let _ = self.report(Error::UnknownOption(name).with_line(output.raw_str()));
                     }
-                    Err(err) => {
-                        return Err(err);
+                    match call_hook(hook, &output) {
+                        Err(err @ Error::HandlerPanic(_))
+                            if self.panic_policy == HookPanicPolicy::Continue =>
+                        {
+                            let _ = self.report(err);
+                        }
+                        Err(err) => break Err(err),
+                        Ok(()) => {}
                     }
                 }
+                Err(Error::IllegalSyntax) => {
+                    // Ignore illegal commands.
+                    continue;
+                }
+                Err(Error::Cancelled) => {
+                    // Interrupted by `stop_listening`.
+                    break Ok(());
+                }
+                Err(err @ (Error::LineTooLong(_) | Error::OutputOverflow(_))) => {
+                    let _ = self.report(err);
+                }
+                Err(err) => {
+                    break Err(err);
+                }
+            }
+        };
+        res.or_else(|err| self.report(err))
+    }
+
+    /// Sends `err` through the error channel if there is one, returning it otherwise.
+    fn report(&self, err: Error) -> Result<(), Error> {
+        match &self.errors {
+            Some(sender) => {
+                let _ = sender.send(err);
+                Ok(())
             }
+            None => Err(err),
+        }
+    }
+}
+
+/// The half of a `UsiEngineHandler` sending commands, returned by `UsiEngineHandler::split`.
+#[derive(Debug)]
+pub struct EngineWriteHalf {
+    handler: UsiEngineHandler,
+}
+
+impl EngineWriteHalf {
+    /// Sends a command to the engine.
+    pub fn send_command(&mut self, command: &GuiCommand) -> Result<(), Error> {
+        self.handler.send_command(command)
+    }
+
+    /// Sends a line as is, see `UsiEngineHandler::send_raw`.
+    pub fn send_raw(&mut self, line: &str) -> Result<(), Error> {
+        self.handler.send_raw(line)
+    }
+
+    /// Returns the OS-assigned process identifier of the engine.
+    pub fn pid(&self) -> u32 {
+        self.handler.pid()
+    }
+
+    /// Terminates the engine.
+    pub fn kill(&mut self) -> Result<(), Error> {
+        self.handler.kill()
+    }
+
+    /// Returns a sender which can be moved to other threads, see `UsiEngineHandler::command_sender`.
+    pub fn command_sender(&self) -> CommandSender {
+        self.handler.command_sender()
+    }
+
+    /// Sets what happens to the engine process when this half is dropped.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.handler.set_drop_policy(policy);
+    }
+}
+
+/// The half of a `UsiEngineHandler` receiving the engine output, returned by `UsiEngineHandler::split`.
+#[derive(Debug)]
+pub struct EngineReadHalf {
+    reader: OutputReceiver,
+    warnings: Arc<Mutex<WarningFilter>>,
+}
+
+impl EngineReadHalf {
+    /// Blocks until the next output of the engine arrives.
+    ///
+    /// Malformed commands are skipped, and warnings are sent
+    /// through the channel set by `UsiEngineHandler::set_warning_sender`.
+    /// Returns `Ok(None)` once the engine has closed its output, and
+    /// `Error::Terminated` afterwards.
+    pub fn recv(&self) -> Result<Option<EngineOutput>, Error> {
+        self.recv_until(None)
+    }
+
+    /// Like `recv`, but fails with `Error::Timeout` if no output arrives within `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<EngineOutput>, Error> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<Option<EngineOutput>, Error> {
+        loop {
+            match next_output(&self.reader, deadline) {
+                // EOF reached (engine closed)
+                Ok(output) if output.response().is_none() => return Ok(None),
+                Ok(output) => {
+                    check_warning(&self.warnings, &output);
+                    return Ok(Some(output));
+                }
+                Err(Error::IllegalSyntax) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Calls a listen hook, converting errors and panics into `Error`.
+fn call_hook<F, E>(hook: &mut F, output: &EngineOutput) -> Result<(), Error>
+where
+    F: FnMut(&EngineOutput) -> Result<(), E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| hook(output))) {
+        Ok(res) => res.map_err(|e| Error::HandlerError(Box::new(e))),
+        Err(payload) => Err(Error::HandlerPanic(panic_message(&*payload))),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Returns the kind of options defined by the USI protocol itself,
+/// which engines are not required to declare.
+fn implicit_option_kind(name: &str) -> Option<OptionKind> {
+    match name {
+        "USI_Hash" => Some(OptionKind::Spin {
+            default: None,
+            min: None,
+            max: None,
+        }),
+        "USI_Ponder" => Some(OptionKind::Check { default: None }),
+        _ => None,
+    }
+}
+
+/// Receives the next output from the reader thread, waiting until `deadline` if given.
+///
+/// An output without a response marks EOF; it is returned once, after which
+/// the disconnected channel is reported as `Error::Terminated`.
+fn next_output(reader: &OutputReceiver, deadline: Option<Instant>) -> Result<EngineOutput, Error> {
+    let res = match deadline {
+        Some(d) => reader
+            .recv_timeout(d.saturating_duration_since(Instant::now()))
+            .map_err(|e| match e {
+                RecvTimeoutError::Timeout => Error::Timeout,
+                RecvTimeoutError::Disconnected => Error::Terminated,
+            }),
+        None => reader.recv().map_err(|_| Error::Terminated),
+    };
+    res?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_path() {
+        let dir = std::env::temp_dir().join(format!("usi-eval-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let nn = dir.join("nn.bin");
+        std::fs::write(&nn, vec![0u8; MIN_EVAL_FILE_SIZE as usize]).unwrap();
+        let pointer = dir.join("model.onnx");
+        std::fs::write(&pointer, b"version https://git-lfs.github.com/spec/v1").unwrap();
+
+        assert!(check_eval_path("EvalFile", &nn).is_ok());
+        assert!(check_eval_path("EvalDir", &dir).is_ok());
+        assert!(matches!(
+            check_eval_path("DNN_Model", &pointer),
+            Err(Error::InvalidEvalFile(m)) if m.contains("is only 42 bytes")
+        ));
+        assert!(matches!(
+            check_eval_path("EvalFile", &dir.join("missing.bin")),
+            Err(Error::InvalidEvalFile(m)) if m.contains("does not exist")
+        ));
+        std::fs::remove_file(&nn).unwrap();
+        assert!(matches!(
+            check_eval_path("EvalDir", &dir),
+            Err(Error::InvalidEvalFile(m)) if m.contains("only contains files up to 42 bytes")
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn engine_name() {
+        let cases = [
+            ("Lesserkai 1.5.0", "Lesserkai", Some("1.5.0"), None),
+            (
+                "YaneuraOu NNUE 7.63 64AVX2",
+                "YaneuraOu NNUE",
+                Some("7.63"),
+                Some("64AVX2"),
+            ),
+            ("dlshogi v0.9.1b", "dlshogi", Some("v0.9.1b"), None),
+            (
+                "tanuki- 2018-06-02 TOURNAMENT",
+                "tanuki-",
+                Some("2018-06-02"),
+                Some("TOURNAMENT"),
+            ),
+            ("7.63", "7.63", None, None),
+            ("Apery_WCSC28", "Apery_WCSC28", None, None),
+            ("", "", None, None),
+        ];
+        for (name, base, version, build) in cases {
+            let parsed = EngineName::parse(name);
+            assert_eq!(base, parsed.base, "{name}");
+            assert_eq!(version, parsed.version.as_deref(), "{name}");
+            assert_eq!(build, parsed.build.as_deref(), "{name}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn split() {
+        fn assert_send<T: Send>() {}
+        assert_send::<EngineWriteHalf>();
+
+        // `cat` echoes the commands back as its output.
+        let handler = UsiEngineHandler::spawn("cat", ".", &[] as &[&str]).unwrap();
+        let (mut writer, reader) = handler.split().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut received = Vec::new();
+            while let Ok(Some(output)) = reader.recv_timeout(Duration::from_secs(10)) {
+                let response = output.response().clone();
+                let done = matches!(response, Some(EngineCommand::BestMove(_)));
+                received.push(response);
+                if done {
+                    break;
+                }
+            }
+            received
         });
+        writer.send_raw("readyok").unwrap();
+        writer.send_raw("not a command").unwrap();
+        writer.send_raw("bestmove resign").unwrap();
+        assert_eq!(
+            vec![
+                Some(EngineCommand::ReadyOk),
+                Some(EngineCommand::Unknown("not a command".to_string())),
+                Some(EngineCommand::BestMove(BestMoveParams::Resign)),
+            ],
+            receiver.join().unwrap()
+        );
+        writer.kill().unwrap();
 
-        Ok(())
+        // EOF is received once after the engine exits, and later calls fail.
+        let handler = UsiEngineHandler::spawn("sh", ".", ["-c", "exit 0"]).unwrap();
+        let (_writer, reader) = handler.split().unwrap();
+        assert!(matches!(reader.recv(), Ok(None)));
+        assert!(matches!(reader.recv(), Err(Error::Terminated)));
+        assert!(matches!(
+            reader.recv_timeout(Duration::from_secs(10)),
+            Err(Error::Terminated)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_scoped() {
+        let mut handler = UsiEngineHandler::spawn("cat", ".", &[] as &[&str]).unwrap();
+        let mut received = Vec::new();
+        let (signal, hooked) = std::sync::mpsc::channel();
+        let sent = handler
+            .listen_scoped(
+                |output| -> Result<(), std::io::Error> {
+                    received.push(output.response().clone());
+                    let _ = signal.send(());
+                    Ok(())
+                },
+                |handler| {
+                    handler.send_raw("readyok").unwrap();
+                    // Wait for the echo to reach the hook before the listener is stopped.
+                    hooked.recv_timeout(Duration::from_secs(10)).unwrap();
+                    1
+                },
+            )
+            .unwrap();
+        assert_eq!(1, sent);
+        assert_eq!(vec![Some(EngineCommand::ReadyOk)], received);
+        // The handler can listen again afterwards.
+        assert!(handler.reader.is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_sender() {
+        let mut handler = UsiEngineHandler::spawn("cat", ".", &[] as &[&str]).unwrap();
+        let sender = handler.command_sender();
+        let (results, received) = std::sync::mpsc::channel();
+        handler
+            .listen(move |output| -> Result<(), Error> {
+                match output.response() {
+                    // Answer the echoed `isready` with a move, which is echoed as well.
+                    Some(EngineCommand::Unknown(line)) if line == "isready" => {
+                        sender.send_raw("bestmove resign")?;
+                    }
+                    Some(EngineCommand::BestMove(params)) => {
+                        let _ = results.send(params.clone());
+                    }
+                    _ => {}
+                }
+                Ok(())
+            })
+            .unwrap();
+        handler.send_command(&GuiCommand::IsReady).unwrap();
+        assert_eq!(
+            BestMoveParams::Resign,
+            received.recv_timeout(Duration::from_secs(10)).unwrap()
+        );
+    }
+
+    #[test]
+    fn hook_panic() {
+        let output = EngineCommandReader::new("readyok\n".as_bytes())
+            .next_command()
+            .unwrap();
+
+        let mut hook = |_: &EngineOutput| -> Result<(), std::io::Error> { panic!("boom") };
+        assert!(matches!(
+            call_hook(&mut hook, &output),
+            Err(Error::HandlerPanic(msg)) if msg == "boom"
+        ));
+
+        let mut hook = |_: &EngineOutput| -> Result<(), std::io::Error> {
+            Err(std::io::Error::other("failed"))
+        };
+        assert!(matches!(
+            call_hook(&mut hook, &output),
+            Err(Error::HandlerError(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn engine_exits() {
+        fn spawn(script: &str) -> UsiEngineHandler {
+            UsiEngineHandler::spawn("sh", ".", ["-c", script]).unwrap()
+        }
+        // Runs `f` on another thread so that a hang fails the test.
+        fn within_timeout<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            thread::spawn(move || sender.send(f()));
+            receiver
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap()
+        }
+
+        // The engine exits before `usiok`.
+        let res = within_timeout(|| spawn("exit 0").get_info());
+        assert!(res.is_err());
+        // The engine exits before `readyok`.
+        let res = within_timeout(|| spawn("exit 0").prepare());
+        assert!(res.is_err());
+        let res = within_timeout(|| {
+            let mut handler = spawn("read line; echo usiok");
+            handler.get_info().unwrap();
+            handler.prepare()
+        });
+        assert!(res.is_err());
+        let res = within_timeout(|| spawn("exit 0").set_option_checked("USI_Hash", Some("16")));
+        assert!(res.is_err());
     }
 }
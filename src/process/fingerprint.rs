@@ -0,0 +1,122 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Identifies an exact engine build by the size and the SHA-256 digest of its executable.
+///
+/// `UsiEngineHandler` takes the fingerprint of the executable when spawning it,
+/// and reports it through `EngineInfo::fingerprint`.
+///
+/// # Examples
+///
+/// ```
+/// use usi::Fingerprint;
+///
+/// let fingerprint = Fingerprint::of_bytes(b"engine");
+/// assert_eq!(6, fingerprint.size);
+/// assert_eq!(fingerprint, fingerprint.to_string().parse().unwrap());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fingerprint {
+    /// Size of the executable in bytes.
+    pub size: u64,
+    /// SHA-256 digest of the executable.
+    pub digest: [u8; 32],
+}
+
+impl Fingerprint {
+    pub fn of_bytes(bytes: &[u8]) -> Self {
+        Fingerprint {
+            size: bytes.len() as u64,
+            digest: Sha256::digest(bytes).into(),
+        }
+    }
+
+    /// Reads the file at `path` and returns its fingerprint.
+    pub fn of_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => {
+                    return Ok(Fingerprint {
+                        size,
+                        digest: hasher.finalize().into(),
+                    })
+                }
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            size += n as u64;
+            hasher.update(&buf[..n]);
+        }
+    }
+}
+
+/// Formats the fingerprint as `{digest}-{size}`, the digest in 64 hexadecimal digits.
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.digest {
+            write!(f, "{b:02x}")?;
+        }
+        write!(f, "-{}", self.size)
+    }
+}
+
+impl std::str::FromStr for Fingerprint {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hex, size) = s.split_once('-').ok_or(crate::Error::IllegalSyntax)?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(crate::Error::IllegalSyntax);
+        }
+        let mut digest = [0u8; 32];
+        for (i, b) in digest.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+        }
+        Ok(Fingerprint {
+            size: size.parse()?,
+            digest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint() {
+        // Test vectors of SHA-256.
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855-0",
+            Fingerprint::of_bytes(b"").to_string()
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad-3",
+            Fingerprint::of_bytes(b"abc").to_string()
+        );
+
+        let path = std::env::temp_dir().join(format!("usi-fingerprint-{}", std::process::id()));
+        let bytes: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &bytes).unwrap();
+        let fingerprint = Fingerprint::of_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Fingerprint::of_bytes(&bytes), fingerprint);
+
+        let fingerprint = Fingerprint::of_bytes(b"a");
+        assert_eq!(fingerprint, fingerprint.to_string().parse().unwrap());
+        assert!("af63dc4c8601ec8c-1".parse::<Fingerprint>().is_err());
+        assert!("e3b0c44298fc1c149afbf4c8996fb924"
+            .parse::<Fingerprint>()
+            .is_err());
+    }
+}
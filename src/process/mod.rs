@@ -1,7 +1,26 @@
+mod bench;
+mod capabilities;
 mod engine;
+mod fingerprint;
+mod observer;
+
+pub(crate) use self::engine::{check_eval_path, check_option_path, resolve_option_path};
+mod policy;
 mod reader;
+#[cfg(feature = "sandbox")]
+mod sandbox;
 mod writer;
 
-pub use self::engine::{EngineInfo, UsiEngineHandler};
-pub use self::reader::{EngineCommandReader, EngineOutput};
-pub use self::writer::GuiCommandWriter;
+pub use self::bench::BenchResult;
+pub use self::capabilities::Capabilities;
+pub use self::engine::{
+    CommandSender, EngineInfo, EngineName, EngineReadHalf, EngineWriteHalf, StartupCallback,
+    StartupEvent, UsiEngineHandler,
+};
+pub use self::fingerprint::Fingerprint;
+pub use self::observer::Observer;
+pub use self::policy::{DropPolicy, HookPanicPolicy, RetryPolicy, StdioBuffers, WriteBuffering};
+pub use self::reader::{EngineCommandReader, EngineOutput, OutputLimits};
+#[cfg(feature = "sandbox")]
+pub use self::sandbox::Sandbox;
+pub use self::writer::{GuiCommandWriter, LineEnding};
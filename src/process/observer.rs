@@ -0,0 +1,82 @@
+use std::fmt;
+
+use super::reader::EngineOutput;
+use crate::protocol::GuiCommand;
+
+/// Hooks observing the commands exchanged between a `UsiEngineHandler` and
+/// its engine, e.g. for logging, metrics or debugging.
+///
+/// `on_receive` is called from the thread reading the engine output for every
+/// line read, before it is handled, including lines read during the handshake.
+///
+/// # Examples
+///
+/// ```no_run
+/// use usi::{EngineOutput, GuiCommand, Observer, UsiEngineHandler};
+///
+/// struct Logger;
+///
+/// impl Observer for Logger {
+///     fn on_send(&mut self, _command: &GuiCommand, line: &str) {
+///         println!("> {line}");
+///     }
+///
+///     fn on_receive(&mut self, output: &EngineOutput) {
+///         println!("< {}", output.raw_str().trim_end());
+///     }
+/// }
+///
+/// let mut handler = UsiEngineHandler::spawn("/path/to/usi_engine", ".", &[] as &[&str]).unwrap();
+/// handler.add_observer(Logger);
+/// handler.get_info().unwrap();
+/// ```
+pub trait Observer: Send {
+    /// Called after `command` was written to the engine as `line`, without the line break.
+    fn on_send(&mut self, _command: &GuiCommand, _line: &str) {}
+
+    /// Called after a line was written with `UsiEngineHandler::send_raw`.
+    fn on_send_raw(&mut self, _line: &str) {}
+
+    /// Called for each line read from the engine.
+    fn on_receive(&mut self, _output: &EngineOutput) {}
+}
+
+/// The observers installed on a handler.
+#[derive(Default)]
+pub(crate) struct Observers(Vec<Box<dyn Observer>>);
+
+impl Observers {
+    pub fn push(&mut self, observer: Box<dyn Observer>) {
+        self.0.push(observer);
+    }
+
+    pub fn sent(&mut self, command: &GuiCommand) {
+        if self.0.is_empty() {
+            return;
+        }
+        let line = command.to_string();
+        for o in &mut self.0 {
+            o.on_send(command, &line);
+        }
+    }
+
+    pub fn sent_raw(&mut self, line: &str) {
+        for o in &mut self.0 {
+            o.on_send_raw(line);
+        }
+    }
+
+    pub fn received(&mut self, output: &EngineOutput) {
+        for o in &mut self.0 {
+            o.on_receive(output);
+        }
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
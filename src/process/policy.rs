@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+/// `RetryPolicy` controls how long the handler waits for an engine response
+/// and how often the request is re-sent before giving up.
+///
+/// The default policy sends the request once and waits indefinitely.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use usi::RetryPolicy;
+///
+/// // Wait up to 5 seconds for each attempt, re-sending up to twice more,
+/// // sleeping 100ms and then 200ms between the attempts.
+/// let policy = RetryPolicy::new()
+///     .attempts(3)
+///     .timeout(Duration::from_secs(5))
+///     .backoff(Duration::from_millis(100));
+/// assert_eq!(Some(Duration::from_millis(200)), policy.delay_before(2));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+    attempts: u32,
+    timeout: Option<Duration>,
+    backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            timeout: None,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        RetryPolicy::default()
+    }
+
+    /// Sets the total number of attempts. Values below 1 are treated as 1.
+    #[must_use]
+    pub fn attempts(mut self, n: u32) -> Self {
+        self.attempts = n.max(1);
+        self
+    }
+
+    /// Sets how long each attempt waits for the response.
+    #[must_use]
+    pub fn timeout(mut self, t: Duration) -> Self {
+        self.timeout = Some(t);
+        self
+    }
+
+    /// Sets the delay before the first retry. The delay doubles after every retry.
+    #[must_use]
+    pub fn backoff(mut self, t: Duration) -> Self {
+        self.backoff = t;
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn attempt_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns the delay before the given zero-based attempt,
+    /// or `None` for the first attempt and attempts beyond the limit.
+    pub fn delay_before(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt >= self.attempts {
+            return None;
+        }
+        Some(self.backoff.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+}
+
+/// `HookPanicPolicy` decides what happens when the hook passed to
+/// `UsiEngineHandler::listen` panics.
+///
+/// In both cases the panic is caught and converted into `Error::HandlerPanic`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HookPanicPolicy {
+    /// Stop listening; the listen thread finishes with the error.
+    #[default]
+    Stop,
+    /// Drop the output which caused the panic and keep listening.
+    Continue,
+}
+
+/// `DropPolicy` decides what happens to the engine process when its handle is dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DropPolicy {
+    /// Send `quit` and kill the process.
+    #[default]
+    Kill,
+    /// Leave the process running, e.g. to let another component supervise it.
+    ///
+    /// The pipes to the engine are closed unless a listen thread is still
+    /// reading its output; engines which quit once their input is closed
+    /// will exit on their own.
+    Detach,
+}
+
+/// `WriteBuffering` decides when commands written to an engine are flushed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum WriteBuffering {
+    /// Flush every command.
+    #[default]
+    Command,
+    /// Hold back `setoption` and `position` until the next other command,
+    /// writing them together with it, e.g. a position with a `go`.
+    Batch,
+}
+
+/// Buffering of the pipes to an engine process.
+///
+/// # Examples
+///
+/// ```
+/// use usi::{StdioBuffers, WriteBuffering};
+///
+/// // Read long MultiPV lines with fewer reads.
+/// let buffers = StdioBuffers::new()
+///     .read_capacity(64 * 1024)
+///     .write(WriteBuffering::Batch);
+/// assert_eq!(65536, buffers.read_capacity);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct StdioBuffers {
+    /// Capacity of the buffer reading the engine output in bytes, 8 KiB by default.
+    pub read_capacity: usize,
+    pub write: WriteBuffering,
+}
+
+impl Default for StdioBuffers {
+    fn default() -> Self {
+        StdioBuffers {
+            read_capacity: 8 * 1024,
+            write: WriteBuffering::default(),
+        }
+    }
+}
+
+impl StdioBuffers {
+    pub fn new() -> Self {
+        StdioBuffers::default()
+    }
+
+    #[must_use]
+    pub fn read_capacity(mut self, n: usize) -> Self {
+        self.read_capacity = n;
+        self
+    }
+
+    #[must_use]
+    pub fn write(mut self, buffering: WriteBuffering) -> Self {
+        self.write = buffering;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let policy = RetryPolicy::default();
+        assert_eq!(1, policy.max_attempts());
+        assert_eq!(None, policy.attempt_timeout());
+        assert_eq!(None, policy.delay_before(0));
+        assert_eq!(None, policy.delay_before(1));
+
+        let policy = RetryPolicy::new()
+            .attempts(4)
+            .backoff(Duration::from_millis(10));
+        assert_eq!(None, policy.delay_before(0));
+        assert_eq!(Some(Duration::from_millis(10)), policy.delay_before(1));
+        assert_eq!(Some(Duration::from_millis(20)), policy.delay_before(2));
+        assert_eq!(Some(Duration::from_millis(40)), policy.delay_before(3));
+        assert_eq!(None, policy.delay_before(4));
+
+        assert_eq!(1, RetryPolicy::new().attempts(0).max_attempts());
+    }
+}
@@ -1,15 +1,23 @@
-use std::io::BufRead;
-use std::time::Instant;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{RecvError, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use super::observer::Observers;
 use crate::error::Error;
-use crate::EngineCommand;
+use crate::{EngineCommand, ExtensionEvent, ProtocolExtensions};
 
 /// A struct to represent each output produced from a USI engine process.
 #[derive(Debug)]
 pub struct EngineOutput {
     response: Option<EngineCommand>,
+    extension: Option<ExtensionEvent>,
     raw_str: String,
     timestamp: Instant,
+    system_time: SystemTime,
 }
 
 impl EngineOutput {
@@ -21,9 +29,26 @@ impl EngineOutput {
         &self.raw_str
     }
 
+    /// Returns the event produced by a `ProtocolExtension` for this line
+    /// if it is of type `T`.
+    pub fn extension<T: Any>(&self) -> Option<&T> {
+        self.extension.as_ref()?.downcast_ref()
+    }
+
+    /// Returns the event produced by a `ProtocolExtension` for this line, if any.
+    pub fn extension_event(&self) -> Option<&ExtensionEvent> {
+        self.extension.as_ref()
+    }
+
+    /// Returns the monotonic time the line was read at, for measuring latencies.
     pub fn timestamp(&self) -> &Instant {
         &self.timestamp
     }
+
+    /// Returns the wall-clock time the line was read at, for logs and transcripts.
+    pub fn system_time(&self) -> SystemTime {
+        self.system_time
+    }
 }
 
 /// `EngineCommandReader<R>` produces a structured output from a reader.
@@ -42,23 +67,51 @@ impl EngineOutput {
 #[derive(Debug)]
 pub struct EngineCommandReader<R: BufRead> {
     receive: R,
+    max_line_length: Option<usize>,
+    extensions: Arc<Mutex<ProtocolExtensions>>,
 }
 
 impl<R: BufRead> EngineCommandReader<R> {
     pub fn new(receive: R) -> Self {
-        EngineCommandReader { receive }
+        EngineCommandReader {
+            receive,
+            max_line_length: None,
+            extensions: Arc::default(),
+        }
+    }
+
+    /// Limits the length of a line in bytes, excluding the line terminator.
+    ///
+    /// Longer lines are skipped without being buffered, and `next_command`
+    /// returns `Error::LineTooLong` for them.
+    pub fn set_max_line_length(&mut self, limit: Option<usize>) {
+        self.max_line_length = limit;
+    }
+
+    /// Sets the extensions parsing lines which are not USI commands.
+    pub fn set_extensions(&mut self, extensions: ProtocolExtensions) {
+        if let Ok(mut e) = self.extensions.lock() {
+            *e = extensions;
+        }
+    }
+
+    /// Returns the extensions used by this reader, which can be replaced from another thread.
+    pub(crate) fn shared_extensions(&self) -> Arc<Mutex<ProtocolExtensions>> {
+        Arc::clone(&self.extensions)
     }
 
     pub fn next_command(&mut self) -> Result<EngineOutput, Error> {
         let mut buf = String::new();
 
         loop {
-            let bytes_read = self.receive.read_line(&mut buf)?;
+            let bytes_read = self.read_line(&mut buf)?;
             if bytes_read == 0 {
                 return Ok(EngineOutput {
                     response: None,
+                    extension: None,
                     raw_str: buf,
                     timestamp: Instant::now(),
+                    system_time: SystemTime::now(),
                 });
             }
 
@@ -68,19 +121,324 @@ impl<R: BufRead> EngineCommandReader<R> {
             buf.clear();
         }
 
-        let res = EngineCommand::parse(&buf)?;
+        let (res, extension) = match self.extensions.lock() {
+            Ok(extensions) => extensions.parse_command(&buf)?,
+            Err(_) => (EngineCommand::parse(&buf)?, None),
+        };
         Ok(EngineOutput {
             response: Some(res),
+            extension,
             raw_str: buf,
             timestamp: Instant::now(),
+            system_time: SystemTime::now(),
         })
     }
+
+    /// Like `BufRead::read_line`, but enforcing `max_line_length` and also ending
+    /// lines at `\r`, which some engines built for Windows emit on its own.
+    ///
+    /// The `\n` of a `\r\n` is read as an empty line.
+    fn read_line(&mut self, buf: &mut String) -> Result<usize, Error> {
+        let limit = self.max_line_length.unwrap_or(usize::MAX);
+        let mut line = Vec::new();
+        let mut bytes_read = 0;
+        let mut too_long = false;
+        loop {
+            let available = self.receive.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let (len, done) = match available.iter().position(|b| matches!(b, b'\n' | b'\r')) {
+                Some(i) => (i + 1, true),
+                None => (available.len(), false),
+            };
+            if !too_long {
+                let content = line.len() + len - usize::from(done);
+                if content > limit {
+                    too_long = true;
+                    line = Vec::new();
+                } else {
+                    line.extend_from_slice(&available[..len]);
+                }
+            }
+            self.receive.consume(len);
+            bytes_read += len;
+            if done {
+                break;
+            }
+        }
+
+        if too_long {
+            return Err(Error::LineTooLong(limit));
+        }
+        let line = String::from_utf8(line).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        })?;
+        buf.push_str(&line);
+        Ok(bytes_read)
+    }
+}
+
+/// Limits protecting the host application from engines producing excessive output.
+///
+/// By default nothing is limited.
+///
+/// # Examples
+///
+/// ```
+/// use usi::OutputLimits;
+///
+/// let limits = OutputLimits::new()
+///     .max_line_length(64 * 1024)
+///     .max_buffered_bytes(16 * 1024 * 1024);
+/// assert_eq!(Some(65536), limits.line_length_limit());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OutputLimits {
+    max_line_length: Option<usize>,
+    max_buffered_bytes: Option<usize>,
+}
+
+impl OutputLimits {
+    pub fn new() -> Self {
+        OutputLimits::default()
+    }
+
+    /// Sets the maximum length of a line in bytes.
+    /// Longer lines are discarded and reported as `Error::LineTooLong`.
+    #[must_use]
+    pub fn max_line_length(mut self, n: usize) -> Self {
+        self.max_line_length = Some(n);
+        self
+    }
+
+    /// Sets the maximum number of bytes read from the engine but not consumed yet.
+    /// `info` lines exceeding the limit are discarded and reported once as
+    /// `Error::OutputOverflow`, while other responses are always delivered.
+    #[must_use]
+    pub fn max_buffered_bytes(mut self, n: usize) -> Self {
+        self.max_buffered_bytes = Some(n);
+        self
+    }
+
+    pub fn line_length_limit(&self) -> Option<usize> {
+        self.max_line_length
+    }
+
+    pub fn buffered_limit(&self) -> Option<usize> {
+        self.max_buffered_bytes
+    }
+}
+
+/// Outputs read by the reader thread and not received yet.
+#[derive(Debug, Default)]
+struct Queue {
+    outputs: VecDeque<Result<EngineOutput, Error>>,
+    /// Number of bytes sent by the reader thread but not received yet.
+    buffered: usize,
+    /// Number of outputs to receive before reporting a pending interrupt.
+    interrupt_after: Option<usize>,
+    /// Set once the reader thread has finished.
+    closed: bool,
+    /// Set once the `OutputReceiver` has been dropped.
+    dropped: bool,
+}
+
+/// The queue shared by the reader thread, its `OutputReceiver` and `Interrupter`s.
+#[derive(Debug, Default)]
+struct Channel {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+impl Channel {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // The lock is never held while calling code which could panic.
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queues an output, returning `false` if the receiver has been dropped.
+    fn send(&self, res: Result<EngineOutput, Error>) -> bool {
+        let mut queue = self.lock();
+        if queue.dropped {
+            return false;
+        }
+        if let Ok(output) = &res {
+            queue.buffered += output.raw_str.len();
+        }
+        queue.outputs.push_back(res);
+        self.available.notify_all();
+        true
+    }
+}
+
+/// Closes the channel when the reader thread finishes, even by panicking.
+struct ChannelSender(Arc<Channel>);
+
+impl Drop for ChannelSender {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.available.notify_all();
+    }
+}
+
+/// Receives outputs read by a background reader thread.
+///
+/// Once the thread has finished and every output has been received,
+/// receiving fails as with a disconnected channel.
+#[derive(Debug)]
+pub(crate) struct OutputReceiver {
+    channel: Arc<Channel>,
+}
+
+/// Interrupts a thread waiting on an `OutputReceiver`.
+#[derive(Debug)]
+pub(crate) struct Interrupter(Arc<Channel>);
+
+impl Interrupter {
+    /// Makes the receiver return `Error::Cancelled` after the outputs already received.
+    pub(crate) fn interrupt(&self) {
+        let mut queue = self.0.lock();
+        if queue.interrupt_after.is_none() {
+            queue.interrupt_after = Some(queue.outputs.len());
+        }
+        self.0.available.notify_all();
+    }
+}
+
+impl OutputReceiver {
+    pub(crate) fn interrupter(&self) -> Interrupter {
+        Interrupter(Arc::clone(&self.channel))
+    }
+
+    pub(crate) fn recv(&self) -> Result<Result<EngineOutput, Error>, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub(crate) fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Result<EngineOutput, Error>, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<Result<EngineOutput, Error>, RecvTimeoutError> {
+        let mut queue = self.channel.lock();
+        loop {
+            if queue.interrupt_after == Some(0) {
+                queue.interrupt_after = None;
+                return Ok(Err(Error::Cancelled));
+            }
+            if let Some(res) = queue.outputs.pop_front() {
+                if let Some(n) = &mut queue.interrupt_after {
+                    *n -= 1;
+                }
+                if let Ok(output) = &res {
+                    queue.buffered -= output.raw_str.len();
+                }
+                return Ok(res);
+            }
+            if queue.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            queue = match deadline {
+                None => self
+                    .channel
+                    .available
+                    .wait(queue)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.channel
+                        .available
+                        .wait_timeout(queue, d - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+    }
+}
+
+impl Drop for OutputReceiver {
+    fn drop(&mut self) {
+        self.channel.lock().dropped = true;
+    }
+}
+
+/// Moves `reader` into a new thread which forwards every output to the returned receiver,
+/// applying the current `limits` to each line.
+///
+/// The thread exits after forwarding EOF or an IO error, or once the receiver is dropped.
+pub(crate) fn spawn_reader_thread<R>(
+    mut reader: EngineCommandReader<R>,
+    limits: Arc<Mutex<OutputLimits>>,
+    observers: Arc<Mutex<Observers>>,
+) -> OutputReceiver
+where
+    R: BufRead + Send + 'static,
+{
+    let channel = Arc::new(Channel::default());
+    let sender = ChannelSender(Arc::clone(&channel));
+
+    thread::spawn(move || {
+        let channel = &sender.0;
+        let mut overflowing = false;
+        loop {
+            let limits = limits.lock().map(|l| *l).unwrap_or_default();
+            reader.set_max_line_length(limits.max_line_length);
+
+            let res = reader.next_command();
+            let finished = match &res {
+                Ok(output) => output.response().is_none(),
+                Err(err) => matches!(err, Error::EngineIo(_)),
+            };
+            let size = res.as_ref().map(|o| o.raw_str.len()).unwrap_or(0);
+            // Only `info` lines are dropped, as waiting for any other response
+            // such as `bestmove` or `readyok` would never finish without it.
+            let droppable =
+                matches!(&res, Ok(o) if matches!(o.response(), Some(EngineCommand::Info(_))));
+            if let (Some(max), true) = (limits.max_buffered_bytes, droppable) {
+                if channel.lock().buffered + size > max {
+                    if !overflowing {
+                        overflowing = true;
+                        if !channel.send(Err(Error::OutputOverflow(max))) {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                overflowing = false;
+            }
+
+            if let (Ok(output), Ok(mut observers)) = (&res, observers.lock()) {
+                observers.received(output);
+            }
+            if !channel.send(res) || finished {
+                break;
+            }
+        }
+    });
+
+    OutputReceiver { channel }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::process::Observer;
     use crate::BestMoveParams;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver, Sender};
 
     #[test]
     fn it_works() {
@@ -110,4 +468,182 @@ mod tests {
         }
         assert_eq!("  bestmove 5e5f\n", output.raw_str());
     }
+
+    #[test]
+    fn line_endings() {
+        let buf = "usiok\r\nreadyok\rbestmove 5e5f\r\n";
+        let mut reader = EngineCommandReader::new(buf.as_bytes());
+        let output = reader.next_command().unwrap();
+        assert_eq!(Some(EngineCommand::UsiOk), *output.response());
+        assert_eq!("usiok\r", output.raw_str());
+        let output = reader.next_command().unwrap();
+        assert_eq!(Some(EngineCommand::ReadyOk), *output.response());
+        let output = reader.next_command().unwrap();
+        assert_eq!("bestmove 5e5f\r", output.raw_str());
+        assert_eq!(None, *reader.next_command().unwrap().response());
+    }
+
+    #[test]
+    fn reader_thread() {
+        let buf = "usiok\nfoo bar\ninfo depth x\nreadyok\n";
+
+        struct Counter(Arc<AtomicUsize>);
+        impl Observer for Counter {
+            fn on_receive(&mut self, _output: &EngineOutput) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut observers = Observers::default();
+        observers.push(Box::new(Counter(Arc::clone(&received))));
+
+        let receiver = spawn_reader_thread(
+            EngineCommandReader::new(buf.as_bytes()),
+            Arc::new(Mutex::new(OutputLimits::default())),
+            Arc::new(Mutex::new(observers)),
+        );
+        let outputs = (0..5).map(|_| receiver.recv().unwrap()).collect::<Vec<_>>();
+        // Every line but the one failing to parse, and the end of the output.
+        assert_eq!(4, received.load(Ordering::SeqCst));
+
+        assert_eq!(
+            Some(EngineCommand::UsiOk),
+            *outputs[0].as_ref().unwrap().response()
+        );
+        assert_eq!(
+            Some(EngineCommand::Unknown("foo bar".to_string())),
+            *outputs[1].as_ref().unwrap().response()
+        );
+        assert!(outputs[2].is_err());
+        assert_eq!(
+            Some(EngineCommand::ReadyOk),
+            *outputs[3].as_ref().unwrap().response()
+        );
+        assert_eq!(None, *outputs[4].as_ref().unwrap().response());
+
+        receiver.interrupter().interrupt();
+        assert!(matches!(receiver.recv(), Ok(Err(Error::Cancelled))));
+        // The reader thread has finished.
+        assert!(receiver.recv().is_err());
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(10)),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn interrupt() {
+        let (mut writer, pipe) = pipe();
+        let receiver = spawn_reader_thread(
+            EngineCommandReader::new(io::BufReader::new(pipe)),
+            Arc::default(),
+            Arc::default(),
+        );
+        writer.send("usiok\n");
+        assert!(matches!(receiver.recv(), Ok(Ok(_))));
+        writer.send("readyok\n");
+        while receiver.channel.lock().outputs.is_empty() {
+            thread::yield_now();
+        }
+        receiver.interrupter().interrupt();
+        // Outputs read before the interrupt are received first.
+        assert_eq!(
+            Some(EngineCommand::ReadyOk),
+            *receiver.recv().unwrap().unwrap().response()
+        );
+        assert!(matches!(receiver.recv(), Ok(Err(Error::Cancelled))));
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        drop(writer);
+        assert_eq!(None, *receiver.recv().unwrap().unwrap().response());
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn output_limits() {
+        let buf = "usiok\ninfo string 0123456789\nreadyok\n";
+        let mut reader = EngineCommandReader::new(buf.as_bytes());
+        reader.set_max_line_length(Some(12));
+        assert!(reader.next_command().is_ok());
+        assert!(matches!(reader.next_command(), Err(Error::LineTooLong(12))));
+        assert_eq!(
+            Some(EngineCommand::ReadyOk),
+            *reader.next_command().unwrap().response()
+        );
+
+        // Only `info` lines are dropped, even if other responses exceed the limit.
+        let buf = "usiok\ninfo string 0123456789\nreadyok\ninfo depth 1\nbestmove 7g7f\n";
+        let (eof_sender, eof) = channel();
+        let mut observers = Observers::default();
+        observers.push(Box::new(EofSignal(eof_sender)));
+        let receiver = spawn_reader_thread(
+            EngineCommandReader::new(buf.as_bytes()),
+            Arc::new(Mutex::new(OutputLimits::new().max_buffered_bytes(20))),
+            Arc::new(Mutex::new(observers)),
+        );
+        // Receive nothing until the reader thread has reached EOF, so that every
+        // line is checked against the limit with the previous ones still buffered.
+        eof.recv_timeout(Duration::from_secs(10)).unwrap();
+        let outputs = (0..5).map(|_| receiver.recv().unwrap()).collect::<Vec<_>>();
+        assert_eq!("usiok\n", outputs[0].as_ref().unwrap().raw_str());
+        assert!(matches!(outputs[1], Err(Error::OutputOverflow(20))));
+        assert_eq!("readyok\n", outputs[2].as_ref().unwrap().raw_str());
+        assert_eq!("bestmove 7g7f\n", outputs[3].as_ref().unwrap().raw_str());
+        assert_eq!(None, *outputs[4].as_ref().unwrap().response());
+        assert!(receiver.recv().is_err());
+    }
+
+    /// Signals once the reader thread has forwarded EOF.
+    struct EofSignal(Sender<()>);
+
+    impl Observer for EofSignal {
+        fn on_receive(&mut self, output: &EngineOutput) {
+            if output.response().is_none() {
+                let _ = self.0.send(());
+            }
+        }
+    }
+
+    /// The writing end of a pipe standing in for the output of an engine.
+    struct PipeWriter(Sender<Vec<u8>>);
+
+    impl PipeWriter {
+        fn send(&mut self, s: &str) {
+            self.0.send(s.as_bytes().to_vec()).unwrap();
+        }
+    }
+
+    /// The reading end of a pipe, which reaches EOF once the `PipeWriter` is dropped.
+    struct PipeReader {
+        receiver: Receiver<Vec<u8>>,
+        buf: Vec<u8>,
+    }
+
+    impl io::Read for PipeReader {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            if self.buf.is_empty() {
+                match self.receiver.recv() {
+                    Ok(buf) => self.buf = buf,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = out.len().min(self.buf.len());
+            out[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            Ok(n)
+        }
+    }
+
+    fn pipe() -> (PipeWriter, PipeReader) {
+        let (sender, receiver) = channel();
+        (
+            PipeWriter(sender),
+            PipeReader {
+                receiver,
+                buf: Vec::new(),
+            },
+        )
+    }
 }
@@ -0,0 +1,390 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::Error;
+
+/// Reduced privileges for an engine process, for running engine binaries
+/// which are not fully trusted.
+///
+/// On Linux, the filesystem is made read-only with Landlock, except for the
+/// working directory and the paths listed as writable, and IPv4 and IPv6 sockets
+/// are denied with a seccomp filter. Both are inherited by the processes the
+/// engine spawns and cannot be lifted by them.
+///
+/// Only Linux on x86_64 and aarch64 is supported. In particular, engines are not
+/// run with restricted tokens on Windows; `apply` fails with `Error::IllegalConfig`
+/// on other platforms.
+///
+/// Spawning fails with `Error::Spawn` if the kernel does not support
+/// the restrictions, rather than running the engine unrestricted.
+///
+/// # Examples
+///
+/// ```no_run
+/// use usi::{EngineConfig, Sandbox, ThreadedEngine};
+///
+/// let config = EngineConfig::new("/path/to/engine")
+///     .sandbox(Sandbox::new().writable("/var/cache/engine"));
+/// let engine = ThreadedEngine::spawn(config).unwrap();
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct Sandbox {
+    /// Allow the engine to open IPv4 and IPv6 sockets.
+    pub network: bool,
+    /// Directories and files the engine may modify besides its working directory.
+    pub writable: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Creates a sandbox without network access and with a read-only filesystem
+    /// except for the working directory.
+    pub fn new() -> Self {
+        Sandbox::default()
+    }
+
+    #[must_use]
+    pub fn allow_network(mut self) -> Self {
+        self.network = true;
+        self
+    }
+
+    #[must_use]
+    pub fn writable<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.writable.push(path.into());
+        self
+    }
+
+    /// Applies the restrictions to the process spawned by `command`.
+    ///
+    /// The current directory of `command`, if set, is writable.
+    /// Returns `Error::IllegalConfig` on unsupported platforms.
+    pub fn apply(&self, command: &mut Command) -> Result<(), Error> {
+        let work_dir = command.get_current_dir().map(Path::to_path_buf);
+        let mut writable: Vec<&Path> = self.writable.iter().map(PathBuf::as_path).collect();
+        writable.extend(work_dir.as_deref());
+        writable.push(Path::new("/dev/null"));
+        sys::apply(command, &writable, self.network)
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod sys {
+    use std::ffi::{c_int, c_long, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::Command;
+
+    use libc::{sock_filter, sock_fprog};
+
+    use crate::error::Error;
+
+    /// Landlock is not covered by the `libc` crate beyond its system call numbers.
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    /// `REMOVE_DIR` through `MAKE_SYM`.
+    const ACCESS_FS_MODIFY_DIR: u64 = 0x1ff << 4;
+    /// Available from Landlock ABI 3.
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    const EACCES: u32 = libc::EACCES as u32;
+    const AF_INET: u32 = libc::AF_INET as u32;
+    const AF_INET6: u32 = libc::AF_INET6 as u32;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    const SYS_SOCKET: u32 = libc::SYS_socket as u32;
+    /// Sockets can also be created through io_uring.
+    const SYS_IO_URING_SETUP: u32 = libc::SYS_io_uring_setup as u32;
+    /// System calls of the x32 ABI have this bit set on x86_64.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+        handled_access_net: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    const BPF_LD_W_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const BPF_JEQ_K: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const BPF_JGE_K: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+    const BPF_RET_K: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    /// Offsets of the fields of `struct seccomp_data`.
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+    const DATA_ARG0: u32 = 16;
+
+    fn stmt(code: u16, k: u32) -> sock_filter {
+        sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter { code, jt, jf, k }
+    }
+
+    /// Returns a seccomp filter denying the creation of IPv4 and IPv6 sockets.
+    pub(super) fn network_filter() -> Vec<sock_filter> {
+        let deny = libc::SECCOMP_RET_ERRNO | EACCES;
+        vec![
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, deny),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+            jump(BPF_JGE_K, X32_SYSCALL_BIT, 6, 0),
+            jump(BPF_JEQ_K, SYS_IO_URING_SETUP, 5, 0),
+            jump(BPF_JEQ_K, SYS_SOCKET, 0, 5),
+            stmt(BPF_LD_W_ABS, DATA_ARG0),
+            jump(BPF_JEQ_K, AF_INET, 2, 0),
+            jump(BPF_JEQ_K, AF_INET6, 1, 0),
+            stmt(BPF_RET_K, libc::SECCOMP_RET_ALLOW),
+            stmt(BPF_RET_K, deny),
+            stmt(BPF_RET_K, libc::SECCOMP_RET_ALLOW),
+        ]
+    }
+
+    /// A path which stays writable, and whether it is a directory.
+    struct Rule(CString, bool);
+
+    pub(super) fn apply(
+        command: &mut Command,
+        writable: &[&Path],
+        network: bool,
+    ) -> Result<(), Error> {
+        let rules = writable
+            .iter()
+            .filter_map(|path| {
+                let is_dir = std::fs::metadata(path).ok()?.is_dir();
+                let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+                Some(Rule(path, is_dir))
+            })
+            .collect::<Vec<_>>();
+        let mut filter = if network {
+            Vec::new()
+        } else {
+            network_filter()
+        };
+        // SAFETY: the closure runs between fork and exec; it only makes system
+        // calls on memory allocated before the fork and does not allocate.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::prctl(
+                    libc::PR_SET_NO_NEW_PRIVS,
+                    1 as c_long,
+                    0 as c_long,
+                    0 as c_long,
+                    0 as c_long,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                restrict_filesystem(&rules)?;
+                if !filter.is_empty() {
+                    let prog = sock_fprog {
+                        len: filter.len() as u16,
+                        filter: filter.as_mut_ptr(),
+                    };
+                    if libc::prctl(
+                        libc::PR_SET_SECCOMP,
+                        libc::SECCOMP_MODE_FILTER as c_long,
+                        &prog as *const sock_fprog,
+                    ) != 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Denies modifications outside of the paths of `rules` with Landlock.
+    ///
+    /// # Safety
+    ///
+    /// Only makes system calls, and is safe to call between fork and exec.
+    unsafe fn restrict_filesystem(rules: &[Rule]) -> io::Result<()> {
+        let abi = libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        );
+        if abi < 1 {
+            return Err(io::Error::last_os_error());
+        }
+        let file_access = if abi >= 3 {
+            ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE
+        } else {
+            ACCESS_FS_WRITE_FILE
+        };
+        let attr = RulesetAttr {
+            handled_access_fs: file_access | ACCESS_FS_MODIFY_DIR,
+            handled_access_net: 0,
+        };
+        let ruleset = libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        );
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = ruleset as c_int;
+        for Rule(path, is_dir) in rules {
+            let fd = libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+            if fd < 0 {
+                continue;
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: if *is_dir {
+                    attr.handled_access_fs
+                } else {
+                    file_access
+                },
+                parent_fd: fd,
+            };
+            let added = libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0u32,
+            );
+            libc::close(fd);
+            if added != 0 {
+                let err = io::Error::last_os_error();
+                libc::close(ruleset);
+                return Err(err);
+            }
+        }
+        let restricted = libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32);
+        let err = io::Error::last_os_error();
+        libc::close(ruleset);
+        if restricted != 0 {
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod sys {
+    use std::path::Path;
+    use std::process::Command;
+
+    use crate::error::Error;
+
+    pub(super) fn apply(_: &mut Command, _: &[&Path], _: bool) -> Result<(), Error> {
+        Err(Error::IllegalConfig(
+            "sandboxing is not supported on this platform".to_string(),
+        ))
+    }
+}
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_filter() {
+        let filter = sys::network_filter();
+        // Every jump lands on an instruction, and the program ends with a return.
+        for (i, insn) in filter.iter().enumerate() {
+            if insn.code & 0x07 == 0x05 {
+                assert!(i + 1 + usize::from(insn.jt.max(insn.jf)) < filter.len());
+            }
+        }
+        assert_eq!(0x06, filter[filter.len() - 1].code);
+        assert_eq!(13, filter.len());
+
+        let mut command = Command::new("engine");
+        command.current_dir("/tmp");
+        assert!(Sandbox::new()
+            .writable("/var/cache")
+            .apply(&mut command)
+            .is_ok());
+    }
+
+    /// Directory the sandboxed child may not write to, set by `restrictions`.
+    const OUTSIDE_VAR: &str = "USI_SANDBOX_OUTSIDE";
+
+    /// Runs `restrictions_child` in a sandboxed copy of the test binary.
+    #[test]
+    fn restrictions() {
+        let base = std::env::temp_dir().join(format!("usi-sandbox-{}", std::process::id()));
+        let work_dir = base.join("work");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(&work_dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+
+        let mut command = Command::new(std::env::current_exe().unwrap());
+        command
+            .args(["--exact", "process::sandbox::tests::restrictions_child"])
+            .args(["--ignored", "--test-threads=1"])
+            .env(OUTSIDE_VAR, &outside)
+            .current_dir(&work_dir);
+        Sandbox::new().apply(&mut command).unwrap();
+        let output = command.output();
+        let unsupported = matches!(
+            output.as_ref().map_err(|err| err.raw_os_error()),
+            Err(Some(libc::ENOSYS | libc::EOPNOTSUPP))
+        );
+        let written = std::fs::read_dir(&outside).unwrap().count();
+        std::fs::remove_dir_all(&base).unwrap();
+        if unsupported {
+            eprintln!("skipped: Landlock is not supported by the kernel");
+            return;
+        }
+        let output = output.unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+        assert_eq!(0, written);
+    }
+
+    #[test]
+    #[ignore = "run in a sandbox by `restrictions`"]
+    fn restrictions_child() {
+        let outside = PathBuf::from(std::env::var_os(OUTSIDE_VAR).unwrap());
+        let err = std::fs::write(outside.join("file"), "x").unwrap_err();
+        assert_eq!(std::io::ErrorKind::PermissionDenied, err.kind());
+        std::fs::write("file", "x").unwrap();
+
+        let err = std::net::TcpListener::bind("127.0.0.1:0").unwrap_err();
+        assert_eq!(std::io::ErrorKind::PermissionDenied, err.kind());
+        assert!(std::net::UdpSocket::bind("[::1]:0").is_err());
+    }
+}
@@ -1,8 +1,32 @@
 use std::io::Write;
 
+use super::policy::WriteBuffering;
 use crate::error::Error;
 use crate::GuiCommand;
 
+/// The line terminator written after each command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    /// `\r\n`, for engines which mis-handle a bare `\n` on Windows.
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
 /// `GuiCommandWriter<W>` converts `GuiCommand`s and writes strings into the writer.
 ///
 /// # Examples
@@ -21,20 +45,72 @@ use crate::GuiCommand;
 #[derive(Debug)]
 pub struct GuiCommandWriter<W: Write> {
     writer: W,
+    buffering: WriteBuffering,
+    line_ending: LineEnding,
+    /// Commands held back by `WriteBuffering::Batch`.
+    pending: Vec<u8>,
 }
 
 impl<W: Write> GuiCommandWriter<W> {
     pub fn new(writer: W) -> Self {
-        GuiCommandWriter { writer }
+        GuiCommandWriter {
+            writer,
+            buffering: WriteBuffering::default(),
+            line_ending: LineEnding::default(),
+            pending: Vec::new(),
+        }
     }
 
-    pub fn send(&mut self, command: &GuiCommand) -> Result<(), Error> {
-        let s = format!("{command}\n");
-        self.writer.write_all(s.as_bytes())?;
+    /// Sets the line terminator written after each command.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    /// Sets when commands are flushed. Held back commands are written with the next command.
+    pub fn set_buffering(&mut self, buffering: WriteBuffering) {
+        self.buffering = buffering;
+    }
+
+    /// Writes the commands held back by `WriteBuffering::Batch`.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let pending = std::mem::take(&mut self.pending);
+        self.writer.write_all(&pending)?;
         self.writer.flush()?;
 
         Ok(())
     }
+
+    /// Writes a command followed by the line terminator.
+    ///
+    /// Returns `Error::IllegalSyntax` if the command contains a line break, e.g. in a
+    /// position or an option name or value, as it would split the command.
+    pub fn send(&mut self, command: &GuiCommand) -> Result<(), Error> {
+        let line = command.to_string();
+        if line.contains(['\n', '\r']) {
+            return Err(Error::IllegalSyntax);
+        }
+        self.pending.extend_from_slice(line.as_bytes());
+        self.pending
+            .extend_from_slice(self.line_ending.as_str().as_bytes());
+        let batched = matches!(command, GuiCommand::SetOption(..) | GuiCommand::Position(_));
+        if batched && self.buffering == WriteBuffering::Batch {
+            return Ok(());
+        }
+        self.flush().map_err(|err| err.with_command(&line))
+    }
+
+    /// Writes a single line as is, for commands not covered by `GuiCommand`.
+    ///
+    /// Returns `Error::IllegalSyntax` if `line` contains a line break.
+    pub fn send_raw(&mut self, line: &str) -> Result<(), Error> {
+        if line.contains(['\n', '\r']) {
+            return Err(Error::IllegalSyntax);
+        }
+        self.pending.extend_from_slice(line.as_bytes());
+        self.pending
+            .extend_from_slice(self.line_ending.as_str().as_bytes());
+        self.flush()
+    }
 }
 
 #[cfg(test)]
@@ -62,4 +138,72 @@ mod tests {
             std::str::from_utf8(&buf).unwrap()
         );
     }
+
+    #[test]
+    fn send_raw() {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut writer = GuiCommandWriter::new(&mut buf);
+        writer.send_raw("bench 64 1 10").unwrap();
+        assert!(matches!(
+            writer.send_raw("test\nquit"),
+            Err(Error::IllegalSyntax)
+        ));
+        assert!(matches!(
+            writer.send(&GuiCommand::SetOption(
+                "EvalDir".to_string(),
+                Some("eval\nquit".to_string())
+            )),
+            Err(Error::IllegalSyntax)
+        ));
+        for sfen in [
+            "startpos\nsetoption name EvalDir value /x",
+            "startpos\risready",
+        ] {
+            assert!(matches!(
+                writer.send(&GuiCommand::Position(sfen.to_string())),
+                Err(Error::IllegalSyntax)
+            ));
+        }
+        assert_eq!("bench 64 1 10\n", std::str::from_utf8(&buf).unwrap());
+    }
+
+    #[test]
+    fn line_ending() {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut writer = GuiCommandWriter::new(&mut buf);
+        writer.set_line_ending(LineEnding::CrLf);
+        writer.send(&GuiCommand::Usi).unwrap();
+        writer.send_raw("bench").unwrap();
+        assert_eq!("usi\r\nbench\r\n", std::str::from_utf8(&buf).unwrap());
+    }
+
+    #[test]
+    fn batch() {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut writer = GuiCommandWriter::new(&mut buf);
+        writer.set_buffering(WriteBuffering::Batch);
+        writer
+            .send(&GuiCommand::SetOption(
+                "USI_Hash".to_string(),
+                Some("256".to_string()),
+            ))
+            .unwrap();
+        writer
+            .send(&GuiCommand::Position("startpos".to_string()))
+            .unwrap();
+        assert!(writer.writer.is_empty());
+        writer.send(&GuiCommand::IsReady).unwrap();
+        assert_eq!(
+            "setoption name USI_Hash value 256\nposition sfen startpos\nisready\n",
+            std::str::from_utf8(&writer.writer[..]).unwrap()
+        );
+        writer
+            .send(&GuiCommand::Position("startpos moves 7g7f".to_string()))
+            .unwrap();
+        writer.flush().unwrap();
+        assert!(writer.pending.is_empty());
+        assert!(std::str::from_utf8(&buf)
+            .unwrap()
+            .ends_with("isready\nposition sfen startpos moves 7g7f\n"));
+    }
 }
@@ -1,6 +1,8 @@
+use std::fmt;
 use std::time::Duration;
 
 use super::parser::EngineCommandParser;
+use super::ParseDiagnostic;
 use crate::error::Error;
 
 /// Represents a kind of "option" command value.
@@ -29,6 +31,126 @@ pub enum OptionKind {
     },
 }
 
+impl OptionKind {
+    /// Returns the declared default value, if any.
+    pub fn default_value(&self) -> Option<OptionValue> {
+        match self {
+            OptionKind::Check { default } => default.map(OptionValue::Check),
+            OptionKind::Spin { default, .. } => default.map(OptionValue::Spin),
+            OptionKind::Combo { default, .. } => default.clone().map(OptionValue::Combo),
+            OptionKind::Button { .. } => Some(OptionValue::Button),
+            OptionKind::String { default } => default.clone().map(OptionValue::String),
+            OptionKind::Filename { default } => default.clone().map(OptionValue::Filename),
+        }
+    }
+
+    /// Parses a `setoption` value according to this option kind.
+    ///
+    /// Returns `None` if `s` is not a valid value of this kind.
+    /// `<empty>` is read as the empty string for `string` and `filename` options.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usi::{OptionKind, OptionValue};
+    ///
+    /// let kind = OptionKind::Spin { default: Some(16), min: Some(1), max: Some(1024) };
+    /// assert_eq!(Some(OptionValue::Spin(256)), kind.parse_value("256"));
+    /// assert_eq!(None, kind.parse_value("huge"));
+    /// ```
+    pub fn parse_value(&self, s: &str) -> Option<OptionValue> {
+        match self {
+            OptionKind::Check { .. } => s.parse().ok().map(OptionValue::Check),
+            OptionKind::Spin { .. } => s.parse().ok().map(OptionValue::Spin),
+            OptionKind::Combo { .. } => Some(OptionValue::Combo(s.to_string())),
+            OptionKind::Button { .. } => Some(OptionValue::Button),
+            OptionKind::String { .. } => Some(OptionValue::String(decode_empty(s))),
+            OptionKind::Filename { .. } => Some(OptionValue::Filename(decode_empty(s))),
+        }
+    }
+
+    /// Converts `s` into the text sent after `value` in `setoption`,
+    /// or `None` for a `button` option, which takes no value.
+    ///
+    /// Returns `Error::IllegalSyntax` if `s` is not a valid value of this kind
+    /// or contains a line break.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usi::OptionKind;
+    ///
+    /// let kind = OptionKind::Filename { default: None };
+    /// assert_eq!(Some("<empty>".to_string()), kind.encode_value("").unwrap());
+    /// assert_eq!(
+    ///     Some("C:\\My Engines\\book.db".to_string()),
+    ///     kind.encode_value("C:\\My Engines\\book.db").unwrap()
+    /// );
+    /// ```
+    pub fn encode_value(&self, s: &str) -> Result<Option<String>, Error> {
+        if s.contains(['\n', '\r']) {
+            return Err(Error::IllegalSyntax);
+        }
+        self.parse_value(s)
+            .map(|v| v.encode())
+            .ok_or(Error::IllegalSyntax)
+    }
+}
+
+fn decode_empty(s: &str) -> String {
+    if s == EMPTY_VALUE {
+        String::new()
+    } else {
+        s.to_string()
+    }
+}
+
+/// The value denoting an empty `string` or `filename` option.
+const EMPTY_VALUE: &str = "<empty>";
+
+/// Represents a typed value of an engine option.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OptionValue {
+    Check(bool),
+    Spin(i32),
+    Combo(String),
+    Button,
+    String(String),
+    Filename(String),
+}
+
+impl OptionValue {
+    /// Returns the text sent after `value` in `setoption`,
+    /// or `None` for a button, which takes no value.
+    ///
+    /// Empty strings and file names are sent as `<empty>`. Other values are sent
+    /// as is, as USI treats the rest of the line as the value, spaces included.
+    pub fn encode(&self) -> Option<String> {
+        match self {
+            OptionValue::Button => None,
+            OptionValue::String(s) | OptionValue::Filename(s) if s.is_empty() => {
+                Some(EMPTY_VALUE.to_string())
+            }
+            v => Some(v.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OptionValue::Check(b) => write!(f, "{b}"),
+            OptionValue::Spin(n) => write!(f, "{n}"),
+            OptionValue::Button => Ok(()),
+            OptionValue::Combo(ref s)
+            | OptionValue::String(ref s)
+            | OptionValue::Filename(ref s) => {
+                write!(f, "{s}")
+            }
+        }
+    }
+}
+
 /// Represents parameters of "option" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct OptionParams {
@@ -36,6 +158,59 @@ pub struct OptionParams {
     pub value: OptionKind,
 }
 
+impl fmt::Display for OptionParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "name {} type ", self.name)?;
+        let write_default = |f: &mut fmt::Formatter, d: &Option<String>| match d.as_deref() {
+            Some("") => write!(f, " default {EMPTY_VALUE}"),
+            Some(d) => write!(f, " default {d}"),
+            None => Ok(()),
+        };
+        match self.value {
+            OptionKind::Check { default } => {
+                write!(f, "check")?;
+                if let Some(d) = default {
+                    write!(f, " default {d}")?;
+                }
+                Ok(())
+            }
+            OptionKind::Spin { default, min, max } => {
+                write!(f, "spin")?;
+                if let Some(d) = default {
+                    write!(f, " default {d}")?;
+                }
+                if let Some(min) = min {
+                    write!(f, " min {min}")?;
+                }
+                if let Some(max) = max {
+                    write!(f, " max {max}")?;
+                }
+                Ok(())
+            }
+            OptionKind::Combo {
+                ref default,
+                ref vars,
+            } => {
+                write!(f, "combo")?;
+                write_default(f, default)?;
+                vars.iter().try_for_each(|v| write!(f, " var {v}"))
+            }
+            OptionKind::Button { ref default } => {
+                write!(f, "button")?;
+                write_default(f, default)
+            }
+            OptionKind::String { ref default } => {
+                write!(f, "string")?;
+                write_default(f, default)
+            }
+            OptionKind::Filename { ref default } => {
+                write!(f, "filename")?;
+                write_default(f, default)
+            }
+        }
+    }
+}
+
 /// Represents a kind of "score" parameter value in "info" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ScoreKind {
@@ -48,21 +223,90 @@ pub enum ScoreKind {
     MateUpperbound,
 }
 
+/// Whether a score is exact or a bound reported on a fail-high or fail-low.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ScoreBound {
+    Exact,
+    /// The score is at least the reported value (fail-high).
+    Lowerbound,
+    /// The score is at most the reported value (fail-low).
+    Upperbound,
+}
+
+impl ScoreKind {
+    /// Returns whether the score is exact or a bound.
+    ///
+    /// `score mate +` and `score mate -` are exact, only the distance to mate is unknown.
+    pub fn bound(&self) -> ScoreBound {
+        match self {
+            ScoreKind::CpExact | ScoreKind::MateExact | ScoreKind::MateSignOnly => {
+                ScoreBound::Exact
+            }
+            ScoreKind::CpLowerbound | ScoreKind::MateLowerbound => ScoreBound::Lowerbound,
+            ScoreKind::CpUpperbound | ScoreKind::MateUpperbound => ScoreBound::Upperbound,
+        }
+    }
+
+    /// Returns `true` if the score is exact rather than a bound.
+    pub fn is_exact(&self) -> bool {
+        self.bound() == ScoreBound::Exact
+    }
+
+    /// Returns `true` if the score is a number of moves to mate rather than centipawns.
+    pub fn is_mate(&self) -> bool {
+        !matches!(
+            self,
+            ScoreKind::CpExact | ScoreKind::CpLowerbound | ScoreKind::CpUpperbound
+        )
+    }
+}
+
 /// Represents parameters of "info" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum InfoParams {
+    CpuLoad(i32),
     CurrMove(String),
+    CurrMoveNumber(i32),
     Depth(i32, Option<i32>),
     HashFull(i32),
     MultiPv(i32),
-    Nodes(i32),
-    Nps(i32),
+    Nodes(u64),
+    Nps(u64),
     Pv(Vec<String>),
     Score(i32, ScoreKind),
     Text(String),
     Time(Duration),
 }
 
+impl fmt::Display for InfoParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InfoParams::CpuLoad(n) => write!(f, "cpuload {n}"),
+            InfoParams::CurrMove(ref m) => write!(f, "currmove {m}"),
+            InfoParams::CurrMoveNumber(n) => write!(f, "currmovenumber {n}"),
+            InfoParams::Depth(d, None) => write!(f, "depth {d}"),
+            InfoParams::Depth(d, Some(sd)) => write!(f, "depth {d} seldepth {sd}"),
+            InfoParams::HashFull(n) => write!(f, "hashfull {n}"),
+            InfoParams::MultiPv(n) => write!(f, "multipv {n}"),
+            InfoParams::Nodes(n) => write!(f, "nodes {n}"),
+            InfoParams::Nps(n) => write!(f, "nps {n}"),
+            InfoParams::Pv(ref moves) => write!(f, "pv {}", moves.join(" ")),
+            InfoParams::Score(v, ref kind) => match kind {
+                ScoreKind::CpExact => write!(f, "score cp {v}"),
+                ScoreKind::CpLowerbound => write!(f, "score cp {v} lowerbound"),
+                ScoreKind::CpUpperbound => write!(f, "score cp {v} upperbound"),
+                ScoreKind::MateExact => write!(f, "score mate {v}"),
+                ScoreKind::MateSignOnly if v < 0 => write!(f, "score mate -"),
+                ScoreKind::MateSignOnly => write!(f, "score mate +"),
+                ScoreKind::MateLowerbound => write!(f, "score mate {v} lowerbound"),
+                ScoreKind::MateUpperbound => write!(f, "score mate {v} upperbound"),
+            },
+            InfoParams::Text(ref s) => write!(f, "string {s}"),
+            InfoParams::Time(t) => write!(f, "time {}", t.as_millis()),
+        }
+    }
+}
+
 /// Represents parameters of "checkmate" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum CheckmateParams {
@@ -72,6 +316,17 @@ pub enum CheckmateParams {
     Timeout,
 }
 
+impl fmt::Display for CheckmateParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckmateParams::Mate(ref moves) => write!(f, "{}", moves.join(" ")),
+            CheckmateParams::NoMate => write!(f, "nomate"),
+            CheckmateParams::NotImplemented => write!(f, "notimplemented"),
+            CheckmateParams::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
 /// Represents parameters of "bestmove" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum BestMoveParams {
@@ -80,6 +335,17 @@ pub enum BestMoveParams {
     Win,
 }
 
+impl fmt::Display for BestMoveParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BestMoveParams::MakeMove(ref m, None) => write!(f, "{m}"),
+            BestMoveParams::MakeMove(ref m, Some(ref pm)) => write!(f, "{m} ponder {pm}"),
+            BestMoveParams::Resign => write!(f, "resign"),
+            BestMoveParams::Win => write!(f, "win"),
+        }
+    }
+}
+
 /// Represents parameters of "id" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum IdParams {
@@ -87,8 +353,21 @@ pub enum IdParams {
     Author(String),
 }
 
+impl fmt::Display for IdParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IdParams::Name(ref s) => write!(f, "name {s}"),
+            IdParams::Author(ref s) => write!(f, "author {s}"),
+        }
+    }
+}
+
 /// Represents a USI command sent from the engine.
 ///
+/// The `Display` implementation writes the command back in USI syntax,
+/// so a parsed line is reproduced as is, except for runs of whitespace,
+/// which are written as a single space.
+///
 /// # Examples
 ///
 /// ```
@@ -112,20 +391,57 @@ pub enum EngineCommand {
     Option(OptionParams),
     ReadyOk,
     UsiOk,
-    Unknown,
+    /// A command not defined by USI, holding the line with surrounding whitespace removed.
+    Unknown(String),
 }
 
 impl EngineCommand {
     /// Parses a USI command string into a new instance of `EngineCommand`.
     pub fn parse(cmd: &str) -> Result<EngineCommand, Error> {
         let parser = EngineCommandParser::new(cmd);
-        parser.parse()
+        parser.parse().map_err(|f| f.error)
+    }
+
+    /// Parses a USI command string like `parse`, explaining any failure
+    /// with the offending byte range of `cmd`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usi::{DiagnosticKind, EngineCommand};
+    ///
+    /// let diagnostic = EngineCommand::parse_with_diagnostics("info depth 3 nodez 100").unwrap_err();
+    /// assert_eq!(DiagnosticKind::UnknownKeyword("nodez".to_string()), diagnostic.kind);
+    /// assert_eq!(13..18, diagnostic.span);
+    /// ```
+    pub fn parse_with_diagnostics(cmd: &str) -> Result<EngineCommand, ParseDiagnostic> {
+        let parser = EngineCommandParser::new(cmd);
+        parser.parse().map_err(|f| f.diagnostic)
+    }
+}
+
+impl fmt::Display for EngineCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineCommand::Id(ref p) => write!(f, "id {p}"),
+            EngineCommand::BestMove(ref p) => write!(f, "bestmove {p}"),
+            EngineCommand::Checkmate(ref p) => write!(f, "checkmate {p}"),
+            EngineCommand::Info(ref params) => {
+                write!(f, "info")?;
+                params.iter().try_for_each(|p| write!(f, " {p}"))
+            }
+            EngineCommand::Option(ref p) => write!(f, "option {p}"),
+            EngineCommand::ReadyOk => write!(f, "readyok"),
+            EngineCommand::UsiOk => write!(f, "usiok"),
+            EngineCommand::Unknown(ref s) => write!(f, "{s}"),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::DiagnosticKind;
 
     #[test]
     fn parse() {
@@ -142,6 +458,7 @@ mod tests {
             "checkmate G*8f 9f9g 8f8g 9g9h 8g8h",
             "info time 1141 depth 3 seldepth 5 nodes 135125 score cp -1521 pv 3a3b L*4h 4c4d",
             "info nodes 120000 nps 116391 multipv 1 currmove 1 hashfull 104",
+            "info currmove 7g7f currmovenumber 3 cpuload 950",
             "info string 7g7f (70%)",
             "info score cp 100 lowerbound",
             "info score cp 100 upperbound",
@@ -169,6 +486,8 @@ mod tests {
             "info depth foo",
             "info depth 1 seldepth foo",
             "info multipv foo",
+            "info currmovenumber foo",
+            "info cpuload",
             "info score foo 1",
             "info foo bar",
             "option foo bar baz",
@@ -183,4 +502,196 @@ mod tests {
             assert!(EngineCommand::parse(c).is_err(), "failed at #{i}");
         }
     }
+
+    #[test]
+    fn diagnostics() {
+        let cases = [
+            ("", DiagnosticKind::MissingValue("a command"), 0..0),
+            ("bestmove", DiagnosticKind::MissingValue("a move"), 8..8),
+            (
+                "bestmove 7g7f 3c3d",
+                DiagnosticKind::UnexpectedToken("3c3d".to_string()),
+                14..18,
+            ),
+            (
+                "id foo bar",
+                DiagnosticKind::UnknownKeyword("foo".to_string()),
+                3..6,
+            ),
+            (
+                "info  depth x",
+                DiagnosticKind::InvalidNumber("x".to_string()),
+                12..13,
+            ),
+            (
+                "info score cp 1.5",
+                DiagnosticKind::InvalidNumber("1.5".to_string()),
+                14..17,
+            ),
+            (
+                "info depth 1 seldepth",
+                DiagnosticKind::MissingValue("a selective depth"),
+                21..21,
+            ),
+            (
+                "option name foo bar",
+                DiagnosticKind::UnexpectedToken("bar".to_string()),
+                16..19,
+            ),
+        ];
+
+        for (line, kind, span) in cases {
+            let diagnostic = EngineCommand::parse_with_diagnostics(line).unwrap_err();
+            assert_eq!(ParseDiagnostic { kind, span }, diagnostic, "{line}");
+        }
+        assert!(matches!(
+            EngineCommand::parse("info score cp 1.5"),
+            Err(Error::IllegalNumberFormat(_))
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let cases = [
+            "id name YaneuraOu NNUE 7.63 64AVX2",
+            "id author by yaneurao",
+            "bestmove 7g7f",
+            "bestmove 8h2b+ ponder 3a2b",
+            "bestmove resign",
+            "bestmove win",
+            "checkmate nomate",
+            "checkmate notimplemented",
+            "checkmate timeout",
+            "checkmate G*8f 9f9g 8f8g 9g9h 8g8h",
+            "info depth 3 seldepth 5 time 1141 nodes 135125 score cp -1521 pv 3a3b L*4h 4c4d",
+            "info nodes 120000 nps 116391 multipv 1 currmove 1g1f hashfull 104",
+            "info currmove 7g7f currmovenumber 3 cpuload 950",
+            "info string 7g7f (70%)",
+            "info score cp 100",
+            "info score cp 100 lowerbound",
+            "info score mate +",
+            "info score mate -",
+            "info score mate -5 upperbound",
+            "info depth 1",
+            "option name UseBook type check default true",
+            "option name Selectivity type spin default 2 min 0 max 4",
+            "option name Style type combo default Normal var Solid var Normal var Risky",
+            "option name ResetLearning type button",
+            "option name BookFile type string default public.bin",
+            "option name LearningFile type filename default <empty>",
+            "option name EvalDir type string",
+            "readyok",
+            "usiok",
+            "unknown command",
+        ];
+
+        for c in &cases {
+            assert_eq!(*c, EngineCommand::parse(c).unwrap().to_string());
+        }
+
+        assert_eq!(
+            EngineCommand::Option(OptionParams {
+                name: "Style".to_string(),
+                value: OptionKind::Combo {
+                    default: Some("Normal".to_string()),
+                    vars: vec!["Solid".to_string(), "Normal".to_string()],
+                },
+            }),
+            EngineCommand::parse(
+                "option name Style type combo default Normal var Solid var Normal"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            EngineCommand::Checkmate(CheckmateParams::NotImplemented),
+            EngineCommand::parse("checkmate notimplemented").unwrap()
+        );
+    }
+
+    #[test]
+    fn option_value() {
+        let check = OptionKind::Check {
+            default: Some(true),
+        };
+        assert_eq!(Some(OptionValue::Check(true)), check.default_value());
+        assert_eq!(Some(OptionValue::Check(false)), check.parse_value("false"));
+        assert_eq!(None, check.parse_value("yes"));
+
+        let spin = OptionKind::Spin {
+            default: None,
+            min: Some(0),
+            max: Some(4),
+        };
+        assert_eq!(None, spin.default_value());
+        assert_eq!(Some(OptionValue::Spin(-3)), spin.parse_value("-3"));
+
+        let filename = OptionKind::Filename {
+            default: Some("eval".to_string()),
+        };
+        assert_eq!(
+            Some(OptionValue::Filename("eval".to_string())),
+            filename.default_value()
+        );
+        assert_eq!("eval", filename.default_value().unwrap().to_string());
+        assert_eq!("true", OptionValue::Check(true).to_string());
+
+        assert_eq!(
+            Some(OptionValue::Filename(String::new())),
+            filename.parse_value("<empty>")
+        );
+        assert_eq!(
+            Some("<empty>".to_string()),
+            filename.encode_value("").unwrap()
+        );
+        assert_eq!(
+            Some("my book.db".to_string()),
+            filename.encode_value("my book.db").unwrap()
+        );
+        assert!(filename.encode_value("a\nquit").is_err());
+        assert!(check.encode_value("yes").is_err());
+        assert_eq!(
+            None,
+            OptionKind::Button { default: None }
+                .encode_value("")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn large_counts() {
+        assert_eq!(
+            EngineCommand::Info(vec![
+                InfoParams::Nodes(5_000_000_000),
+                InfoParams::Nps(4_294_967_296),
+            ]),
+            EngineCommand::parse("info nodes 5000000000 nps 4294967296").unwrap()
+        );
+        assert_eq!(
+            EngineCommand::Info(vec![InfoParams::Nodes(u64::MAX)]),
+            EngineCommand::parse("info nodes 123456789012345678901234567890").unwrap()
+        );
+        assert!(EngineCommand::parse("info nodes -1").is_err());
+        assert!(EngineCommand::parse("info nps 1e9").is_err());
+    }
+
+    #[test]
+    fn score_bounds() {
+        let bound = |line: &str| match EngineCommand::parse(line).unwrap() {
+            EngineCommand::Info(params) => match &params[0] {
+                InfoParams::Score(_, kind) => (kind.bound(), kind.is_mate()),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!((ScoreBound::Exact, false), bound("info score cp 100"));
+        assert_eq!(
+            (ScoreBound::Lowerbound, false),
+            bound("info score cp 100 lowerbound")
+        );
+        assert_eq!(
+            (ScoreBound::Upperbound, true),
+            bound("info score mate 5 upperbound")
+        );
+        assert_eq!((ScoreBound::Exact, true), bound("info score mate -"));
+    }
 }
@@ -0,0 +1,39 @@
+use std::fmt;
+use std::ops::Range;
+
+/// Explains why a line could not be parsed as an `EngineCommand`.
+/// Returned by `EngineCommand::parse_with_diagnostics`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ParseDiagnostic {
+    pub kind: DiagnosticKind,
+    /// Byte range of the offending token within the line.
+    /// Empty, at the end of the last token, if a value is missing.
+    pub span: Range<usize>,
+}
+
+/// The kind of problem found by `EngineCommand::parse_with_diagnostics`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DiagnosticKind {
+    /// A keyword not defined for the command, such as `info foo`.
+    UnknownKeyword(String),
+    /// A token where a number was expected.
+    InvalidNumber(String),
+    /// The line ended where the described value was expected.
+    MissingValue(&'static str),
+    /// A token not allowed at its position, such as `bestmove 7g7f 3c3d`.
+    UnexpectedToken(String),
+}
+
+impl fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            DiagnosticKind::UnknownKeyword(ref s) => write!(f, "unknown keyword `{s}`")?,
+            DiagnosticKind::InvalidNumber(ref s) => write!(f, "invalid number `{s}`")?,
+            DiagnosticKind::MissingValue(expected) => write!(f, "missing {expected}")?,
+            DiagnosticKind::UnexpectedToken(ref s) => write!(f, "unexpected `{s}`")?,
+        }
+        write!(f, " at {}..{}", self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for ParseDiagnostic {}
@@ -0,0 +1,154 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use super::EngineCommand;
+use crate::error::Error;
+
+/// A typed event produced by a `ProtocolExtension` from a line sent by the engine.
+pub type ExtensionEvent = Arc<dyn Any + Send + Sync>;
+
+/// A parser for engine-specific output lines not covered by USI,
+/// such as extended `info` fields or control lines of cluster engines.
+///
+/// Closures taking the line and returning `Option<ExtensionEvent>` implement this trait.
+pub trait ProtocolExtension: Send + Sync {
+    /// Parses `line`, returning `None` if the extension does not recognize it.
+    fn parse(&self, line: &str) -> Option<ExtensionEvent>;
+}
+
+impl<F> ProtocolExtension for F
+where
+    F: Fn(&str) -> Option<ExtensionEvent> + Send + Sync,
+{
+    fn parse(&self, line: &str) -> Option<ExtensionEvent> {
+        self(line)
+    }
+}
+
+/// A set of `ProtocolExtension`s consulted for lines the USI parser does not understand.
+///
+/// A line is offered to the extensions, in the order they were added, if it is
+/// not a USI command or fails to parse as one. The first event returned is
+/// available through `EngineOutput::extension`, and the line is reported as
+/// `EngineCommand::Unknown` instead of an error. Commands for the engine
+/// are sent with `UsiEngineHandler::send_raw`.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use usi::{EngineCommandReader, ExtensionEvent, ProtocolExtensions};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Visits(u64);
+///
+/// let extensions = ProtocolExtensions::new().with(|line: &str| -> Option<ExtensionEvent> {
+///     let visits = line.strip_prefix("info visits ")?.parse().ok()?;
+///     Some(Arc::new(Visits(visits)))
+/// });
+/// let mut reader = EngineCommandReader::new("info visits 1200\n".as_bytes());
+/// reader.set_extensions(extensions);
+/// let output = reader.next_command().unwrap();
+/// assert_eq!(Some(&Visits(1200)), output.extension::<Visits>());
+/// ```
+#[derive(Clone, Default)]
+pub struct ProtocolExtensions {
+    extensions: Vec<Arc<dyn ProtocolExtension>>,
+}
+
+impl ProtocolExtensions {
+    pub fn new() -> Self {
+        ProtocolExtensions::default()
+    }
+
+    /// Adds an extension, consulted after the ones already added.
+    #[must_use]
+    pub fn with<E: ProtocolExtension + 'static>(mut self, extension: E) -> Self {
+        self.extensions.push(Arc::new(extension));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.extensions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    /// Returns the event of the first extension recognizing `line`.
+    pub fn parse(&self, line: &str) -> Option<ExtensionEvent> {
+        self.extensions.iter().find_map(|e| e.parse(line))
+    }
+
+    /// Parses `line` as a USI command, falling back to the extensions.
+    pub(crate) fn parse_command(
+        &self,
+        line: &str,
+    ) -> Result<(EngineCommand, Option<ExtensionEvent>), Error> {
+        let parsed = EngineCommand::parse(line);
+        let understood = matches!(parsed, Ok(ref c) if !matches!(c, EngineCommand::Unknown(_)));
+        if understood || self.is_empty() {
+            return parsed.map(|c| (c, None));
+        }
+        match self.parse(line.trim()) {
+            Some(event) => Ok((EngineCommand::Unknown(line.trim().to_string()), Some(event))),
+            None => parsed.map(|c| (c, None)),
+        }
+    }
+}
+
+impl fmt::Debug for ProtocolExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProtocolExtensions")
+            .field("len", &self.extensions.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::InfoParams;
+
+    #[test]
+    fn fallback() {
+        let extensions = ProtocolExtensions::new()
+            .with(|line: &str| -> Option<ExtensionEvent> {
+                line.strip_prefix("info nodes 10 ")
+                    .map(|rest| Arc::new(rest.to_string()) as ExtensionEvent)
+            })
+            .with(|line: &str| -> Option<ExtensionEvent> {
+                (line == "cluster ready").then(|| Arc::new(1u8) as ExtensionEvent)
+            });
+
+        let (command, event) = extensions.parse_command("info nodes 10 qnodes 3").unwrap();
+        assert_eq!(
+            EngineCommand::Unknown("info nodes 10 qnodes 3".to_string()),
+            command
+        );
+        assert_eq!(
+            Some("qnodes 3"),
+            event.unwrap().downcast_ref::<String>().map(|s| s.as_str())
+        );
+
+        let (_, event) = extensions.parse_command(" cluster ready\n").unwrap();
+        assert_eq!(Some(&1u8), event.unwrap().downcast_ref::<u8>());
+
+        // Commands understood by the USI parser are not offered to the extensions.
+        assert_eq!(
+            (EngineCommand::Info(vec![InfoParams::Nodes(10)]), true),
+            extensions
+                .parse_command("info nodes 10")
+                .map(|(c, e)| (c, e.is_none()))
+                .unwrap()
+        );
+        assert!(extensions.parse_command("info qnodes 3").is_err());
+        assert!(ProtocolExtensions::new()
+            .parse_command("cluster ready")
+            .unwrap()
+            .1
+            .is_none());
+    }
+}
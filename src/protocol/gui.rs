@@ -1,6 +1,10 @@
 use std::fmt;
 use std::time::Duration;
 
+use super::OptionValue;
+use crate::error::Error;
+use crate::game::STARTPOS;
+
 /// Represents parameters of "gameover" command.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum GameOverKind {
@@ -20,22 +24,106 @@ impl fmt::Display for GameOverKind {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum MateParam {
-    Timeout(Duration),
+    Timeout(
+        #[cfg_attr(feature = "serde", serde(with = "crate::threaded::millis::required"))] Duration,
+    ),
     Infinite,
 }
 
+/// The longest time sent in a `go` command, about 24 days.
+///
+/// Longer times are clamped to it, as many engines read times as 32-bit integers.
+pub const MAX_THINK_TIME: Duration = Duration::from_millis(i32::MAX as u64);
+
 /// Represents parameters of "go" command.
+///
+/// Times are always sent as integral milliseconds, rounded down so that an
+/// engine is never given more time than allowed, and clamped to `MAX_THINK_TIME`.
+/// The `*_ms` accessors return the exact values sent.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use usi::ThinkParams;
+///
+/// let params = ThinkParams::new()
+///     .byoyomi(Duration::from_micros(10_000_500))
+///     .margin(Duration::from_millis(300));
+/// assert_eq!(Some(9700), params.byoyomi_ms());
+/// assert_eq!(" byoyomi 9700", params.to_string());
+/// ```
+///
+/// With the `serde` feature, times are serialized in milliseconds:
+///
+/// ```toml
+/// btime = 600000
+/// wtime = 600000
+/// byoyomi = 10000
+/// ```
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct ThinkParams {
     ponder: bool,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            with = "crate::threaded::millis",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
     btime: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            with = "crate::threaded::millis",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
     wtime: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            with = "crate::threaded::millis",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
     byoyomi: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            with = "crate::threaded::millis",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
     binc: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            with = "crate::threaded::millis",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
     winc: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    depth: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    nodes: Option<u64>,
     infinite: bool,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
     mate: Option<MateParam>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::threaded::millis::required"))]
+    margin: Duration,
 }
 
 impl ThinkParams {
@@ -43,6 +131,29 @@ impl ThinkParams {
         ThinkParams::default()
     }
 
+    /// One second per move.
+    pub fn blitz() -> Self {
+        ThinkParams::new().byoyomi(Duration::from_secs(1))
+    }
+
+    /// A search running until it is stopped.
+    pub fn analysis() -> Self {
+        ThinkParams::new().infinite()
+    }
+
+    /// A search to a fixed depth.
+    pub fn fixed_depth(depth: u32) -> Self {
+        ThinkParams::new().depth(depth)
+    }
+
+    /// A search with the remaining main times of both sides and a byoyomi.
+    pub fn tournament(btime: Duration, wtime: Duration, byoyomi: Duration) -> Self {
+        ThinkParams::new()
+            .btime(btime)
+            .wtime(wtime)
+            .byoyomi(byoyomi)
+    }
+
     #[must_use]
     pub fn ponder(mut self) -> Self {
         self.ponder = true;
@@ -79,6 +190,20 @@ impl ThinkParams {
         self
     }
 
+    /// Limits the search depth with `go depth`, an extension supported by most engines.
+    #[must_use]
+    pub fn depth(mut self, depth: u32) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Limits the number of searched nodes with `go nodes`, an extension supported by most engines.
+    #[must_use]
+    pub fn nodes(mut self, nodes: u64) -> Self {
+        self.nodes = Some(nodes);
+        self
+    }
+
     #[must_use]
     pub fn infinite(mut self) -> Self {
         self.infinite = true;
@@ -90,6 +215,136 @@ impl ThinkParams {
         self.mate = Some(t);
         self
     }
+
+    /// Sets a safety margin, such as the network overhead, subtracted from
+    /// `btime`, `wtime` and `byoyomi` when they are sent. Increments are sent as is.
+    #[must_use]
+    pub fn margin(mut self, t: Duration) -> Self {
+        self.margin = t;
+        self
+    }
+
+    /// Sets the safety margin to `t` unless a margin was set already.
+    pub(crate) fn default_margin(mut self, t: Duration) -> Self {
+        if self.margin.is_zero() {
+            self.margin = t;
+        }
+        self
+    }
+
+    /// Returns the `btime` value sent, in milliseconds.
+    pub fn btime_ms(&self) -> Option<u64> {
+        self.btime.map(|t| to_ms(t.saturating_sub(self.margin)))
+    }
+
+    /// Returns the `wtime` value sent, in milliseconds.
+    pub fn wtime_ms(&self) -> Option<u64> {
+        self.wtime.map(|t| to_ms(t.saturating_sub(self.margin)))
+    }
+
+    /// Returns the `byoyomi` value sent, in milliseconds.
+    pub fn byoyomi_ms(&self) -> Option<u64> {
+        self.byoyomi.map(|t| to_ms(t.saturating_sub(self.margin)))
+    }
+
+    /// Returns the `binc` value sent, in milliseconds.
+    pub fn binc_ms(&self) -> Option<u64> {
+        self.binc.map(to_ms)
+    }
+
+    /// Returns the `winc` value sent, in milliseconds.
+    pub fn winc_ms(&self) -> Option<u64> {
+        self.winc.map(to_ms)
+    }
+
+    /// Returns the `mate` time limit sent, in milliseconds,
+    /// or `None` unless the search is a mate search with a time limit.
+    pub fn mate_ms(&self) -> Option<u64> {
+        match self.mate {
+            Some(MateParam::Timeout(t)) => Some(to_ms(t)),
+            _ => None,
+        }
+    }
+
+    /// Parses the arguments of a `go` command.
+    fn parse(args: &str) -> Result<ThinkParams, Error> {
+        fn ms(token: Option<&str>) -> Result<Duration, Error> {
+            Ok(Duration::from_millis(
+                token.ok_or(Error::IllegalSyntax)?.parse()?,
+            ))
+        }
+
+        let mut params = ThinkParams::new();
+        let mut tokens = args.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "ponder" => params.ponder = true,
+                "btime" => params.btime = Some(ms(tokens.next())?),
+                "wtime" => params.wtime = Some(ms(tokens.next())?),
+                "byoyomi" => params.byoyomi = Some(ms(tokens.next())?),
+                "binc" => params.binc = Some(ms(tokens.next())?),
+                "winc" => params.winc = Some(ms(tokens.next())?),
+                "depth" => params.depth = Some(tokens.next().ok_or(Error::IllegalSyntax)?.parse()?),
+                "nodes" => params.nodes = Some(tokens.next().ok_or(Error::IllegalSyntax)?.parse()?),
+                "infinite" => params.infinite = true,
+                "mate" => {
+                    params.mate = Some(match tokens.next() {
+                        Some("infinite") | None => MateParam::Infinite,
+                        t => MateParam::Timeout(ms(t)?),
+                    })
+                }
+                _ => return Err(Error::IllegalSyntax),
+            }
+        }
+        Ok(params)
+    }
+
+    /// Returns the longest time the side to move may think for,
+    /// or `None` if the search is not limited in time.
+    pub(crate) fn time_limit(&self, black: bool) -> Option<Duration> {
+        if self.ponder || self.infinite {
+            return None;
+        }
+        match self.mate {
+            Some(MateParam::Timeout(t)) => return Some(t),
+            Some(MateParam::Infinite) => return None,
+            None => {}
+        }
+        let (time, inc) = if black {
+            (self.btime, self.binc)
+        } else {
+            (self.wtime, self.winc)
+        };
+        match (time, self.byoyomi) {
+            (None, None) => None,
+            (time, byoyomi) => Some(
+                time.unwrap_or_default() + byoyomi.unwrap_or_default() + inc.unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// Returns the parameters left after the side to move thought for `elapsed`,
+    /// which is taken from its main time first and then from the byoyomi.
+    pub(crate) fn after_elapsed(&self, black: bool, elapsed: Duration) -> ThinkParams {
+        let mut params = self.clone();
+        let time = if black {
+            &mut params.btime
+        } else {
+            &mut params.wtime
+        };
+        let mut overrun = elapsed;
+        if let Some(t) = time {
+            overrun = elapsed.saturating_sub(*t);
+            *t = t.saturating_sub(elapsed);
+        }
+        if let Some(b) = params.byoyomi.as_mut() {
+            *b = b.saturating_sub(overrun);
+        }
+        if let Some(MateParam::Timeout(t)) = params.mate.as_mut() {
+            *t = t.saturating_sub(elapsed);
+        }
+        params
+    }
 }
 
 impl fmt::Display for ThinkParams {
@@ -97,29 +352,34 @@ impl fmt::Display for ThinkParams {
         if self.ponder {
             write!(f, " ponder")?;
         }
-        if let Some(t) = self.btime {
-            write!(f, " btime {}", to_ms(t))?;
+        if let Some(t) = self.btime_ms() {
+            write!(f, " btime {t}")?;
+        }
+        if let Some(t) = self.wtime_ms() {
+            write!(f, " wtime {t}")?;
         }
-        if let Some(t) = self.wtime {
-            write!(f, " wtime {}", to_ms(t))?;
+        if let Some(t) = self.byoyomi_ms() {
+            write!(f, " byoyomi {t}")?;
         }
-        if let Some(t) = self.byoyomi {
-            write!(f, " byoyomi {}", to_ms(t))?;
+        if let Some(t) = self.binc_ms() {
+            write!(f, " binc {t}")?;
         }
-        if let Some(t) = self.binc {
-            write!(f, " binc {}", to_ms(t))?;
+        if let Some(t) = self.winc_ms() {
+            write!(f, " winc {t}")?;
         }
-        if let Some(t) = self.winc {
-            write!(f, " winc {}", to_ms(t))?;
+        if let Some(depth) = self.depth {
+            write!(f, " depth {depth}")?;
+        }
+        if let Some(nodes) = self.nodes {
+            write!(f, " nodes {nodes}")?;
         }
         if self.infinite {
             write!(f, " infinite")?;
         }
-        if let Some(mate_opts) = &self.mate {
-            match *mate_opts {
-                MateParam::Timeout(t) => write!(f, " mate {}", to_ms(t))?,
-                MateParam::Infinite => write!(f, " mate infinite")?,
-            }
+        if let Some(t) = self.mate_ms() {
+            write!(f, " mate {t}")?;
+        } else if self.mate == Some(MateParam::Infinite) {
+            write!(f, " mate infinite")?;
         }
 
         Ok(())
@@ -153,6 +413,90 @@ pub enum GuiCommand {
     Quit,
 }
 
+impl GuiCommand {
+    /// Returns a `setoption` command setting `name` to `value`,
+    /// encoding the value as `OptionValue::encode` does.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usi::{GuiCommand, OptionValue};
+    ///
+    /// let cmd = GuiCommand::set_option("BookFile", &OptionValue::Filename(String::new()));
+    /// assert_eq!("setoption name BookFile value <empty>", cmd.to_string());
+    /// ```
+    pub fn set_option(name: &str, value: &OptionValue) -> GuiCommand {
+        GuiCommand::SetOption(name.to_string(), value.encode())
+    }
+
+    /// Parses a command sent from a GUI, as an engine receives it.
+    ///
+    /// `position startpos` is turned into the SFEN of the initial position,
+    /// as `GuiCommand::Position` holds the argument of `position sfen`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usi::GuiCommand;
+    ///
+    /// let cmd = GuiCommand::parse("position startpos moves 7g7f").unwrap();
+    /// assert_eq!(
+    ///     "position sfen lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1 moves 7g7f",
+    ///     cmd.to_string()
+    /// );
+    /// ```
+    pub fn parse(cmd: &str) -> Result<GuiCommand, Error> {
+        let cmd = cmd.trim();
+        let (keyword, args) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+        let args = args.trim();
+        match keyword {
+            "gameover" => match args {
+                "win" => Ok(GuiCommand::GameOver(GameOverKind::Win)),
+                "lose" => Ok(GuiCommand::GameOver(GameOverKind::Lose)),
+                "draw" => Ok(GuiCommand::GameOver(GameOverKind::Draw)),
+                _ => Err(Error::IllegalSyntax),
+            },
+            "go" => ThinkParams::parse(args).map(GuiCommand::Go),
+            "position" => {
+                let mut tokens = args.split_whitespace();
+                let position = match tokens.next() {
+                    Some("startpos") => STARTPOS.to_string(),
+                    Some("sfen") => {
+                        let sfen: Vec<&str> = tokens.by_ref().take(4).collect();
+                        if sfen.len() < 4 {
+                            return Err(Error::IllegalSyntax);
+                        }
+                        sfen.join(" ")
+                    }
+                    _ => return Err(Error::IllegalSyntax),
+                };
+                Ok(GuiCommand::Position(match tokens.next() {
+                    Some("moves") => tokens.fold(position + " moves", |p, mv| p + " " + mv),
+                    Some(_) => return Err(Error::IllegalSyntax),
+                    None => position,
+                }))
+            }
+            "setoption" => {
+                let option = args.strip_prefix("name ").ok_or(Error::IllegalSyntax)?;
+                Ok(match option.split_once(" value ") {
+                    Some((name, value)) => {
+                        GuiCommand::SetOption(name.trim().to_string(), Some(value.to_string()))
+                    }
+                    None => GuiCommand::SetOption(option.trim().to_string(), None),
+                })
+            }
+            _ if !args.is_empty() => Err(Error::IllegalSyntax),
+            "isready" => Ok(GuiCommand::IsReady),
+            "ponderhit" => Ok(GuiCommand::Ponderhit),
+            "stop" => Ok(GuiCommand::Stop),
+            "usi" => Ok(GuiCommand::Usi),
+            "usinewgame" => Ok(GuiCommand::UsiNewGame),
+            "quit" => Ok(GuiCommand::Quit),
+            _ => Err(Error::IllegalSyntax),
+        }
+    }
+}
+
 impl fmt::Display for GuiCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -174,7 +518,7 @@ impl fmt::Display for GuiCommand {
 }
 
 fn to_ms(t: Duration) -> u64 {
-    1000 * t.as_secs() + (t.subsec_nanos() as u64) / 1_000_000
+    t.min(MAX_THINK_TIME).as_millis() as u64
 }
 
 #[cfg(test)]
@@ -218,6 +562,41 @@ mod tests {
                 GuiCommand::Go(ThinkParams::new().mate(MateParam::Infinite)),
             ),
             ("go ponder", GuiCommand::Go(ThinkParams::new().ponder())),
+            ("go byoyomi 1000", GuiCommand::Go(ThinkParams::blitz())),
+            ("go infinite", GuiCommand::Go(ThinkParams::analysis())),
+            ("go depth 12", GuiCommand::Go(ThinkParams::fixed_depth(12))),
+            (
+                "go byoyomi 1000 nodes 5000",
+                GuiCommand::Go(ThinkParams::blitz().nodes(5000)),
+            ),
+            (
+                "go btime 60000 wtime 50000 byoyomi 10000",
+                GuiCommand::Go(ThinkParams::tournament(
+                    Duration::from_secs(60),
+                    Duration::from_secs(50),
+                    Duration::from_secs(10),
+                )),
+            ),
+            (
+                "go btime 0 wtime 2147483647 byoyomi 1000",
+                GuiCommand::Go(
+                    ThinkParams::new()
+                        .btime(Duration::from_micros(999))
+                        .wtime(Duration::MAX)
+                        .byoyomi(Duration::from_nanos(1_000_999_999)),
+                ),
+            ),
+            (
+                "go btime 0 wtime 59500 byoyomi 9500 binc 1000",
+                GuiCommand::Go(
+                    ThinkParams::new()
+                        .btime(Duration::from_millis(200))
+                        .wtime(Duration::from_secs(60))
+                        .byoyomi(Duration::from_secs(10))
+                        .binc(Duration::from_secs(1))
+                        .margin(Duration::from_millis(500)),
+                ),
+            ),
             ("isready", GuiCommand::IsReady),
             ("ponderhit", GuiCommand::Ponderhit),
             (
@@ -236,6 +615,21 @@ mod tests {
                 "setoption name foo value bar",
                 GuiCommand::SetOption("foo".to_string(), Some("bar".to_string())),
             ),
+            (
+                "setoption name EvalDir value C:\\My Engines\\eval",
+                GuiCommand::set_option(
+                    "EvalDir",
+                    &OptionValue::Filename("C:\\My Engines\\eval".to_string()),
+                ),
+            ),
+            (
+                "setoption name BookFile value <empty>",
+                GuiCommand::set_option("BookFile", &OptionValue::String(String::new())),
+            ),
+            (
+                "setoption name ClearHash",
+                GuiCommand::set_option("ClearHash", &OptionValue::Button),
+            ),
             ("stop", GuiCommand::Stop),
             ("usi", GuiCommand::Usi),
             ("usinewgame", GuiCommand::UsiNewGame),
@@ -246,4 +640,105 @@ mod tests {
             assert_eq!(c.0, c.1.to_string());
         }
     }
+
+    #[test]
+    fn parse() {
+        let cases = [
+            "gameover lose",
+            "go btime 60000 wtime 50000 byoyomi 10000",
+            "go ponder btime 40000 wtime 50000 binc 10000 winc 10000",
+            "go infinite",
+            "go mate 60000",
+            "go mate infinite",
+            "go depth 12 nodes 5000",
+            "isready",
+            "ponderhit",
+            "position sfen lnsgkgsn1/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1",
+            "position sfen lnsgkgsn1/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1 moves 3c3d",
+            "setoption name USI_Hash value 256",
+            "setoption name EvalDir value C:\\My Engines\\eval",
+            "setoption name ClearHash",
+            "stop",
+            "usi",
+            "usinewgame",
+            "quit",
+        ];
+        for c in cases {
+            assert_eq!(c, GuiCommand::parse(c).unwrap().to_string());
+        }
+
+        assert_eq!(
+            GuiCommand::Position(format!("{STARTPOS} moves 7g7f 3c3d")),
+            GuiCommand::parse("position  startpos moves 7g7f  3c3d").unwrap()
+        );
+        for c in [
+            "",
+            "go btime",
+            "go btime x",
+            "go searchmoves 7g7f",
+            "position sfen 9/9 b",
+            "position startpos 7g7f",
+            "setoption USI_Hash",
+            "gameover",
+            "isready now",
+            "bestmove 7g7f",
+        ] {
+            assert!(GuiCommand::parse(c).is_err(), "{c}");
+        }
+    }
+
+    #[test]
+    fn time_limit() {
+        let secs = Duration::from_secs;
+        let params = ThinkParams::new()
+            .btime(secs(10))
+            .wtime(secs(20))
+            .byoyomi(secs(5));
+        assert_eq!(Some(secs(15)), params.time_limit(true));
+        assert_eq!(Some(secs(25)), params.time_limit(false));
+        assert_eq!(None, ThinkParams::new().depth(10).time_limit(true));
+        assert_eq!(None, params.clone().infinite().time_limit(true));
+        assert_eq!(
+            Some(secs(3)),
+            ThinkParams::new()
+                .mate(MateParam::Timeout(secs(3)))
+                .time_limit(false)
+        );
+
+        let remaining = params.after_elapsed(true, secs(12));
+        assert_eq!(Some(0), remaining.btime_ms());
+        assert_eq!(Some(20_000), remaining.wtime_ms());
+        assert_eq!(Some(3_000), remaining.byoyomi_ms());
+        let remaining = params.after_elapsed(false, secs(12));
+        assert_eq!(Some(8_000), remaining.wtime_ms());
+        assert_eq!(Some(5_000), remaining.byoyomi_ms());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn think_params_serde() {
+        let params: ThinkParams = toml::from_str(
+            r#"
+            btime = 600000
+            wtime = 590000
+            byoyomi = 10000
+            margin = 200
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            "go btime 599800 wtime 589800 byoyomi 9800",
+            GuiCommand::Go(params.clone()).to_string()
+        );
+        assert_eq!(
+            params,
+            toml::from_str(&toml::to_string(&params).unwrap()).unwrap()
+        );
+
+        let mate = ThinkParams::new().mate(MateParam::Timeout(Duration::from_secs(5)));
+        assert_eq!(
+            mate,
+            toml::from_str(&toml::to_string(&mate).unwrap()).unwrap()
+        );
+    }
 }
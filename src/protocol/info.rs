@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use super::{InfoParams, ScoreKind};
+
+/// A summary of the `info` commands received during a search.
+///
+/// Each field holds the latest value reported by the engine.
+/// Lines reporting secondary variations (`multipv` other than 1) are ignored,
+/// so the summary always describes the principal variation.
+/// `score` holds the latest score, which may be a bound reported on a fail-high
+/// or fail-low, while `exact_score` holds the latest exact one.
+///
+/// # Examples
+///
+/// ```
+/// use usi::{EngineCommand, ScoreKind, SearchInfo};
+///
+/// let mut info = SearchInfo::default();
+/// if let EngineCommand::Info(params) =
+///     EngineCommand::parse("info depth 10 seldepth 14 score cp 35 nodes 12000 pv 7g7f 3c3d").unwrap()
+/// {
+///     info.update(&params);
+/// }
+/// assert_eq!(Some(10), info.depth);
+/// assert_eq!(Some((35, ScoreKind::CpExact)), info.score);
+/// assert_eq!(vec!["7g7f", "3c3d"], info.pv);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SearchInfo {
+    pub depth: Option<i32>,
+    pub seldepth: Option<i32>,
+    pub score: Option<(i32, ScoreKind)>,
+    /// The latest score which is not a bound.
+    pub exact_score: Option<(i32, ScoreKind)>,
+    pub nodes: Option<u64>,
+    pub nps: Option<u64>,
+    pub time: Option<Duration>,
+    pub pv: Vec<String>,
+    /// The move currently searched.
+    pub currmove: Option<String>,
+    /// The number of the move currently searched, starting from 1.
+    pub currmovenumber: Option<i32>,
+    /// Usage of the hash table, in permill.
+    pub hashfull: Option<i32>,
+    /// CPU usage of the engine, in permill.
+    pub cpuload: Option<i32>,
+}
+
+impl SearchInfo {
+    /// Applies the parameters of an `info` command.
+    pub fn update(&mut self, params: &[InfoParams]) {
+        if params
+            .iter()
+            .any(|p| matches!(p, InfoParams::MultiPv(n) if *n != 1))
+        {
+            return;
+        }
+
+        for p in params {
+            match p {
+                InfoParams::Depth(depth, seldepth) => {
+                    self.depth = Some(*depth);
+                    self.seldepth = *seldepth;
+                }
+                InfoParams::Score(v, kind) => {
+                    self.score = Some((*v, kind.clone()));
+                    if kind.is_exact() {
+                        self.exact_score = Some((*v, kind.clone()));
+                    }
+                }
+                InfoParams::Nodes(n) => self.nodes = Some(*n),
+                InfoParams::Nps(n) => self.nps = Some(*n),
+                InfoParams::Time(t) => self.time = Some(*t),
+                InfoParams::Pv(pv) => self.pv = pv.clone(),
+                InfoParams::CurrMove(m) => self.currmove = Some(m.clone()),
+                InfoParams::CurrMoveNumber(n) => self.currmovenumber = Some(*n),
+                InfoParams::HashFull(n) => self.hashfull = Some(*n),
+                InfoParams::CpuLoad(n) => self.cpuload = Some(*n),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update() {
+        let mut info = SearchInfo::default();
+        info.update(&[InfoParams::Score(10, ScoreKind::CpExact)]);
+        info.update(&[
+            InfoParams::Depth(3, Some(5)),
+            InfoParams::Score(-20, ScoreKind::CpLowerbound),
+            InfoParams::Pv(vec!["2g2f".to_string()]),
+        ]);
+        info.update(&[
+            InfoParams::MultiPv(2),
+            InfoParams::Depth(3, None),
+            InfoParams::Pv(vec!["7g7f".to_string()]),
+        ]);
+        info.update(&[InfoParams::Nodes(1000), InfoParams::Nps(500)]);
+        info.update(&[
+            InfoParams::CurrMove("7g7f".to_string()),
+            InfoParams::CurrMoveNumber(3),
+            InfoParams::HashFull(104),
+            InfoParams::CpuLoad(950),
+        ]);
+
+        assert_eq!(Some(3), info.depth);
+        assert_eq!(Some(5), info.seldepth);
+        assert_eq!(Some((-20, ScoreKind::CpLowerbound)), info.score);
+        assert_eq!(Some((10, ScoreKind::CpExact)), info.exact_score);
+        assert_eq!(vec!["2g2f".to_string()], info.pv);
+        assert_eq!(Some(1000), info.nodes);
+        assert_eq!(Some(500), info.nps);
+        assert_eq!(None, info.time);
+        assert_eq!(Some("7g7f".to_string()), info.currmove);
+        assert_eq!(Some(3), info.currmovenumber);
+        assert_eq!(Some(104), info.hashfull);
+        assert_eq!(Some(950), info.cpuload);
+    }
+}
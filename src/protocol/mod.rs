@@ -1,6 +1,16 @@
 mod command;
+mod diagnostic;
+mod extension;
 mod gui;
+mod info;
+mod normalize;
 mod parser;
+mod warning;
 
 pub use self::command::*;
+pub use self::diagnostic::*;
+pub use self::extension::*;
 pub use self::gui::*;
+pub use self::info::*;
+pub use self::normalize::*;
+pub use self::warning::*;
@@ -0,0 +1,29 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Applies NFKC normalization, folding full-width ASCII, half-width katakana and
+/// the various Unicode spaces into their canonical forms.
+///
+/// # Examples
+///
+/// ```
+/// use usi::normalize_width;
+///
+/// assert_eq!("YaneuraOu NNUE 7.6", normalize_width("ＹａｎｅｕｒａＯｕ\u{3000}ＮＮＵＥ 7.6"));
+/// assert_eq!("技巧 2", normalize_width("技巧\u{00a0}２"));
+/// ```
+pub fn normalize_width(s: &str) -> String {
+    s.nfkc().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn width() {
+        assert_eq!("A~Z", normalize_width("Ａ～Ｚ"));
+        assert_eq!("a b c", normalize_width("a\u{3000}b\u{2003}c"));
+        assert_eq!("ショウギ", normalize_width("ｼｮｳｷﾞ"));
+        assert_eq!("将棋", normalize_width("将棋"));
+    }
+}
@@ -1,31 +1,38 @@
-use itertools::Itertools;
-use std::str::SplitWhitespace;
+use std::ops::Range;
+use std::str::{FromStr, SplitWhitespace};
 use std::time::Duration;
 
 use super::{
-    BestMoveParams, CheckmateParams, EngineCommand, IdParams, InfoParams, OptionKind, OptionParams,
-    ScoreKind,
+    BestMoveParams, CheckmateParams, DiagnosticKind, EngineCommand, IdParams, InfoParams,
+    OptionKind, OptionParams, ParseDiagnostic, ScoreKind,
 };
 use crate::error::Error;
 
+/// A parse failure, described both as the `Error` returned by `EngineCommand::parse`
+/// and as the diagnostic returned by `EngineCommand::parse_with_diagnostics`.
+pub struct Failure {
+    pub error: Error,
+    pub diagnostic: ParseDiagnostic,
+}
+
 pub struct EngineCommandParser<'a> {
+    line: &'a str,
     iter: SplitWhitespace<'a>,
+    /// Byte offset just past the last token taken.
+    end: usize,
 }
 
 impl<'a> EngineCommandParser<'a> {
-    pub fn new(cmd: &str) -> EngineCommandParser {
+    pub fn new(cmd: &str) -> EngineCommandParser<'_> {
         EngineCommandParser {
+            line: cmd,
             iter: cmd.split_whitespace(),
+            end: 0,
         }
     }
 
-    pub fn parse(mut self) -> Result<EngineCommand, Error> {
-        let command = self.iter.next();
-        if command.is_none() {
-            return Err(Error::IllegalSyntax);
-        }
-
-        let command = command.unwrap();
+    pub fn parse(mut self) -> Result<EngineCommand, Failure> {
+        let command = self.expect("a command")?;
         Ok(match command {
             "bestmove" => self.parse_bestmove()?,
             "checkmate" => self.parse_checkmate()?,
@@ -34,246 +41,303 @@ impl<'a> EngineCommandParser<'a> {
             "option" => self.parse_option()?,
             "readyok" => EngineCommand::ReadyOk,
             "usiok" => EngineCommand::UsiOk,
-            _ => EngineCommand::Unknown,
+            _ => EngineCommand::Unknown(self.line.trim().to_string()),
         })
     }
 
-    fn parse_bestmove(mut self) -> Result<EngineCommand, Error> {
-        match (self.iter.next(), self.iter.next(), self.iter.next()) {
-            (Some("resign"), None, None) => Ok(EngineCommand::BestMove(BestMoveParams::Resign)),
-            (Some("win"), None, None) => Ok(EngineCommand::BestMove(BestMoveParams::Win)),
-            (Some(m), None, None) => Ok(EngineCommand::BestMove(BestMoveParams::MakeMove(
-                m.to_string(),
-                None,
-            ))),
-            (Some(m), Some("ponder"), Some(pm)) => Ok(EngineCommand::BestMove(
-                BestMoveParams::MakeMove(m.to_string(), Some(pm.to_string())),
+    /// Takes the next token.
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.iter.next()?;
+        self.end = self.span(token).end;
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.iter.clone().next()
+    }
+
+    /// Takes the tokens up to the end of the line.
+    fn rest(&mut self) -> Vec<&'a str> {
+        let tokens: Vec<&str> = self.iter.by_ref().collect();
+        if let Some(last) = tokens.last() {
+            self.end = self.span(last).end;
+        }
+        tokens
+    }
+
+    /// Returns the byte range of `token` within the line.
+    fn span(&self, token: &str) -> Range<usize> {
+        let start = token.as_ptr() as usize - self.line.as_ptr() as usize;
+        start..start + token.len()
+    }
+
+    /// Takes the next token, failing if the line ends.
+    fn expect(&mut self, expected: &'static str) -> Result<&'a str, Failure> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => Err(self.failure(
+                DiagnosticKind::MissingValue(expected),
+                self.end..self.end,
+                Error::IllegalSyntax,
             )),
-            _ => Err(Error::IllegalSyntax),
         }
     }
 
-    fn parse_checkmate(mut self) -> Result<EngineCommand, Error> {
-        match self.iter.next() {
-            Some("notimplemented") => Ok(EngineCommand::Checkmate(CheckmateParams::NoMate)),
-            Some("timeout") => Ok(EngineCommand::Checkmate(CheckmateParams::Timeout)),
-            Some("nomate") => Ok(EngineCommand::Checkmate(CheckmateParams::NoMate)),
-            Some(s) => {
+    /// Takes the next token as a number.
+    fn number<T: FromStr>(&mut self, expected: &'static str) -> Result<T, Failure> {
+        let token = self.expect(expected)?;
+        token
+            .parse()
+            .map_err(|_| self.invalid_number(token, Error::IllegalSyntax))
+    }
+
+    /// Parses a count such as a node count, saturating at `u64::MAX`.
+    fn count(&mut self, expected: &'static str) -> Result<u64, Failure> {
+        let token = self.expect(expected)?;
+        match token.parse() {
+            Ok(n) => Ok(n),
+            Err(_) if token.bytes().all(|b| b.is_ascii_digit()) => Ok(u64::MAX),
+            Err(_) => Err(self.invalid_number(token, Error::IllegalSyntax)),
+        }
+    }
+
+    fn unknown_keyword(&self, token: &str) -> Failure {
+        self.failure(
+            DiagnosticKind::UnknownKeyword(token.to_string()),
+            self.span(token),
+            Error::IllegalSyntax,
+        )
+    }
+
+    fn unexpected_token(&self, token: &str) -> Failure {
+        self.failure(
+            DiagnosticKind::UnexpectedToken(token.to_string()),
+            self.span(token),
+            Error::IllegalSyntax,
+        )
+    }
+
+    fn invalid_number(&self, token: &str, error: Error) -> Failure {
+        self.failure(
+            DiagnosticKind::InvalidNumber(token.to_string()),
+            self.span(token),
+            error,
+        )
+    }
+
+    fn failure(&self, kind: DiagnosticKind, span: Range<usize>, error: Error) -> Failure {
+        Failure {
+            error,
+            diagnostic: ParseDiagnostic { kind, span },
+        }
+    }
+
+    fn parse_bestmove(mut self) -> Result<EngineCommand, Failure> {
+        let params = match (self.expect("a move")?, self.next()) {
+            ("resign", None) => BestMoveParams::Resign,
+            ("win", None) => BestMoveParams::Win,
+            (m, None) => BestMoveParams::MakeMove(m.to_string(), None),
+            (m, Some("ponder")) => {
+                let pm = self.expect("a ponder move")?;
+                BestMoveParams::MakeMove(m.to_string(), Some(pm.to_string()))
+            }
+            (_, Some(token)) => return Err(self.unexpected_token(token)),
+        };
+        Ok(EngineCommand::BestMove(params))
+    }
+
+    fn parse_checkmate(mut self) -> Result<EngineCommand, Failure> {
+        match self.expect("moves or a result")? {
+            "notimplemented" => Ok(EngineCommand::Checkmate(CheckmateParams::NotImplemented)),
+            "timeout" => Ok(EngineCommand::Checkmate(CheckmateParams::Timeout)),
+            "nomate" => Ok(EngineCommand::Checkmate(CheckmateParams::NoMate)),
+            s => {
                 let mut moves = vec![s.to_string()];
-                self.iter.for_each(|s| {
-                    moves.push(s.to_string());
-                });
+                moves.extend(self.rest().into_iter().map(|s| s.to_string()));
                 Ok(EngineCommand::Checkmate(CheckmateParams::Mate(moves)))
             }
-            _ => Err(Error::IllegalSyntax),
         }
     }
 
-    fn parse_id(mut self) -> Result<EngineCommand, Error> {
-        match self.iter.next() {
-            Some("name") => Ok(EngineCommand::Id(IdParams::Name(self.iter.join(" ")))),
-            Some("author") => Ok(EngineCommand::Id(IdParams::Author(self.iter.join(" ")))),
-            _ => Err(Error::IllegalSyntax),
+    fn parse_id(mut self) -> Result<EngineCommand, Failure> {
+        match self.expect("name or author")? {
+            "name" => Ok(EngineCommand::Id(IdParams::Name(self.rest().join(" ")))),
+            "author" => Ok(EngineCommand::Id(IdParams::Author(self.rest().join(" ")))),
+            token => Err(self.unknown_keyword(token)),
         }
     }
 
-    fn parse_info(self) -> Result<EngineCommand, Error> {
-        let mut iter = self.iter.peekable();
+    fn parse_info(mut self) -> Result<EngineCommand, Failure> {
         let mut entries = Vec::new();
 
-        while let Some(kind) = iter.next() {
+        while let Some(kind) = self.next() {
             match kind {
                 "depth" => {
-                    let depth: i32 = iter
-                        .next()
-                        .and_then(|s| s.parse().ok())
-                        .ok_or(Error::IllegalSyntax)?;
+                    let depth: i32 = self.number("a depth")?;
 
                     let mut sel_depth = None;
-                    if let Some(&peek_kind) = iter.peek() {
-                        if peek_kind == "seldepth" {
-                            iter.next();
-
-                            sel_depth = Some(
-                                iter.next()
-                                    .and_then(|s| s.parse().ok())
-                                    .ok_or(Error::IllegalSyntax)?,
-                            );
-                        }
+                    if self.peek() == Some("seldepth") {
+                        self.next();
+                        sel_depth = Some(self.number("a selective depth")?);
                     }
 
                     entries.push(InfoParams::Depth(depth, sel_depth));
                 }
                 "time" => {
-                    let ms: u64 = iter
-                        .next()
-                        .and_then(|s| s.parse().ok())
-                        .ok_or(Error::IllegalSyntax)?;
+                    let ms: u64 = self.number("a time")?;
                     entries.push(InfoParams::Time(Duration::from_millis(ms)));
                 }
                 "multipv" => {
-                    let multipv: i32 = iter
-                        .next()
-                        .and_then(|s| s.parse().ok())
-                        .ok_or(Error::IllegalSyntax)?;
+                    let multipv: i32 = self.number("a multipv index")?;
                     entries.push(InfoParams::MultiPv(multipv));
                 }
                 "nodes" => {
-                    let nodes: i32 = iter
-                        .next()
-                        .and_then(|s| s.parse().ok())
-                        .ok_or(Error::IllegalSyntax)?;
+                    let nodes = self.count("a node count")?;
                     entries.push(InfoParams::Nodes(nodes));
                 }
                 "pv" => {
-                    let pvs = iter.map(|v| v.to_string()).collect::<Vec<_>>();
+                    let pvs = self.rest().into_iter().map(|v| v.to_string()).collect();
                     entries.push(InfoParams::Pv(pvs));
                     // "pv" or "str" must be the final item.
                     break;
                 }
-                "score" => match (iter.next(), iter.next()) {
-                    (Some("cp"), Some(cp)) => {
-                        let cp: i32 = cp.parse()?;
-
-                        if let Some(&peek_kind) = iter.peek() {
-                            match peek_kind {
-                                "lowerbound" => {
-                                    iter.next();
-                                    entries.push(InfoParams::Score(cp, ScoreKind::CpLowerbound));
-                                }
-                                "upperbound" => {
-                                    iter.next();
-                                    entries.push(InfoParams::Score(cp, ScoreKind::CpUpperbound));
-                                }
-                                _ => {
-                                    entries.push(InfoParams::Score(cp, ScoreKind::CpExact));
-                                }
-                            }
-                        }
-                    }
-                    (Some("mate"), Some("+")) => {
-                        entries.push(InfoParams::Score(1, ScoreKind::MateSignOnly))
-                    }
-                    (Some("mate"), Some("-")) => {
-                        entries.push(InfoParams::Score(-1, ScoreKind::MateSignOnly))
-                    }
-                    (Some("mate"), Some(ply)) => {
-                        let ply: i32 = ply.parse()?;
-
-                        if let Some(&peek_kind) = iter.peek() {
-                            match peek_kind {
-                                "lowerbound" => {
-                                    iter.next();
-                                    entries.push(InfoParams::Score(ply, ScoreKind::MateLowerbound));
-                                }
-                                "upperbound" => {
-                                    iter.next();
-                                    entries.push(InfoParams::Score(ply, ScoreKind::MateUpperbound));
-                                }
-                                _ => {
-                                    entries.push(InfoParams::Score(ply, ScoreKind::MateExact));
-                                }
-                            }
-                        }
-                    }
-                    _ => return Err(Error::IllegalSyntax),
-                },
+                "score" => entries.push(self.parse_score()?),
                 "currmove" => {
-                    let currmove = iter.next().ok_or(Error::IllegalSyntax)?;
+                    let currmove = self.expect("a move")?;
                     entries.push(InfoParams::CurrMove(currmove.to_string()));
                 }
+                "currmovenumber" => {
+                    let number: i32 = self.number("a move number")?;
+                    entries.push(InfoParams::CurrMoveNumber(number));
+                }
                 "hashfull" => {
-                    let hashfull: i32 = iter
-                        .next()
-                        .and_then(|s| s.parse().ok())
-                        .ok_or(Error::IllegalSyntax)?;
+                    let hashfull: i32 = self.number("a hash usage")?;
                     entries.push(InfoParams::HashFull(hashfull));
                 }
+                "cpuload" => {
+                    let cpuload: i32 = self.number("a CPU usage")?;
+                    entries.push(InfoParams::CpuLoad(cpuload));
+                }
                 "nps" => {
-                    let nps: i32 = iter
-                        .next()
-                        .and_then(|s| s.parse().ok())
-                        .ok_or(Error::IllegalSyntax)?;
+                    let nps = self.count("a node rate")?;
                     entries.push(InfoParams::Nps(nps));
                 }
                 "string" => {
-                    entries.push(InfoParams::Text(iter.join(" ")));
+                    entries.push(InfoParams::Text(self.rest().join(" ")));
                     // "pv" or "str" must be the final item.
                     break;
                 }
-                _ => return Err(Error::IllegalSyntax),
+                token => return Err(self.unknown_keyword(token)),
             }
         }
 
         Ok(EngineCommand::Info(entries))
     }
 
-    fn parse_option(mut self) -> Result<EngineCommand, Error> {
-        let opt_name = match (self.iter.next(), self.iter.next(), self.iter.next()) {
-            (Some("name"), Some(opt_name), Some("type")) => opt_name,
-            _ => return Err(Error::IllegalSyntax),
+    fn parse_score(&mut self) -> Result<InfoParams, Failure> {
+        let [exact, lowerbound, upperbound] = match self.expect("cp or mate")? {
+            "cp" => [
+                ScoreKind::CpExact,
+                ScoreKind::CpLowerbound,
+                ScoreKind::CpUpperbound,
+            ],
+            "mate" => [
+                ScoreKind::MateExact,
+                ScoreKind::MateLowerbound,
+                ScoreKind::MateUpperbound,
+            ],
+            token => return Err(self.unknown_keyword(token)),
+        };
+
+        let value = self.expect("a score")?;
+        match (&exact, value) {
+            (ScoreKind::MateExact, "+") => {
+                return Ok(InfoParams::Score(1, ScoreKind::MateSignOnly))
+            }
+            (ScoreKind::MateExact, "-") => {
+                return Ok(InfoParams::Score(-1, ScoreKind::MateSignOnly))
+            }
+            _ => {}
+        }
+        let value: i32 = value
+            .parse()
+            .map_err(|e| self.invalid_number(value, Error::IllegalNumberFormat(e)))?;
+
+        let kind = match self.peek() {
+            Some("lowerbound") => {
+                self.next();
+                lowerbound
+            }
+            Some("upperbound") => {
+                self.next();
+                upperbound
+            }
+            _ => exact,
         };
+        Ok(InfoParams::Score(value, kind))
+    }
 
-        let opt_type = match self.iter.next() {
-            Some("check") => {
+    fn parse_option(mut self) -> Result<EngineCommand, Failure> {
+        match self.expect("name")? {
+            "name" => {}
+            token => return Err(self.unexpected_token(token)),
+        }
+        let opt_name = self.expect("an option name")?;
+        match self.expect("type")? {
+            "type" => {}
+            token => return Err(self.unexpected_token(token)),
+        }
+
+        let opt_type = match self.expect("an option type")? {
+            "check" => {
                 let default = self
-                    .iter
+                    .rest()
+                    .into_iter()
                     .find(|v| *v != "default")
                     .and_then(|s| s.parse().ok());
 
                 OptionKind::Check { default }
             }
-            Some("spin") => {
+            "spin" => {
                 let mut default = None;
                 let mut min = None;
                 let mut max = None;
 
-                while let Some(kind) = self.iter.next() {
+                while let Some(kind) = self.next() {
                     match kind {
-                        "default" => default = self.iter.next().and_then(|s| s.parse().ok()),
-                        "min" => min = self.iter.next().and_then(|s| s.parse().ok()),
-                        "max" => max = self.iter.next().and_then(|s| s.parse().ok()),
+                        "default" => default = self.next().and_then(|s| s.parse().ok()),
+                        "min" => min = self.next().and_then(|s| s.parse().ok()),
+                        "max" => max = self.next().and_then(|s| s.parse().ok()),
                         _ => {}
                     }
                 }
 
                 OptionKind::Spin { default, min, max }
             }
-            Some("combo") => {
+            "combo" => {
                 let mut default = None;
                 let mut vars = Vec::new();
 
-                while let Some(kind) = self.iter.next() {
+                while let Some(kind) = self.next() {
                     match kind {
-                        "default" => default = self.iter.next().map(parse_default),
-                        "var" => {
-                            self.iter.for_each(|v| {
-                                vars.push(v.to_string());
-                            });
-                            break;
-                        }
+                        "default" => default = self.next().map(parse_default),
+                        "var" => vars.extend(self.next().map(|v| v.to_string())),
                         _ => {}
                     }
                 }
 
                 OptionKind::Combo { default, vars }
             }
-            Some("button") => {
-                let default = self.iter.find(|v| *v != "default").map(parse_default);
-
-                OptionKind::Button { default }
-            }
-            Some("string") => {
-                let default = self.iter.find(|v| *v != "default").map(parse_default);
-
-                OptionKind::String { default }
-            }
-            Some("filename") => {
-                let default = self.iter.find(|v| *v != "default").map(parse_default);
-
-                OptionKind::Filename { default }
-            }
-            _ => return Err(Error::IllegalSyntax),
+            "button" => OptionKind::Button {
+                default: self.default_value(),
+            },
+            "string" => OptionKind::String {
+                default: self.default_value(),
+            },
+            "filename" => OptionKind::Filename {
+                default: self.default_value(),
+            },
+            token => return Err(self.unknown_keyword(token)),
         };
 
         Ok(EngineCommand::Option(OptionParams {
@@ -281,6 +345,14 @@ impl<'a> EngineCommandParser<'a> {
             value: opt_type,
         }))
     }
+
+    /// Takes the default value of a `button`, `string` or `filename` option.
+    fn default_value(&mut self) -> Option<String> {
+        self.rest()
+            .into_iter()
+            .find(|v| *v != "default")
+            .map(parse_default)
+    }
 }
 
 fn parse_default(s: &str) -> String {
@@ -0,0 +1,160 @@
+use super::{EngineCommand, InfoParams};
+
+const DEFAULT_PATTERNS: &[&str] = &[
+    "error",
+    "warning",
+    "failed",
+    "not found",
+    "no such",
+    "illegal",
+    "invalid",
+    "cannot",
+    "can't",
+    "couldn't",
+];
+
+/// A diagnostic reported by the engine through `info string`,
+/// such as a missing evaluation file or an unknown option.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EngineWarning {
+    /// The text following `info string`.
+    pub message: String,
+}
+
+/// `WarningPatterns` decides which `info string` lines are treated as warnings.
+///
+/// A line is a warning if it contains any of the patterns, ignoring case.
+/// The default patterns cover the wording commonly used by engines,
+/// such as "error", "failed" or "not found".
+///
+/// # Examples
+///
+/// ```
+/// use usi::{EngineCommand, WarningPatterns};
+///
+/// let patterns = WarningPatterns::default();
+/// let cmd = EngineCommand::parse("info string Error! : failed to read nn.bin").unwrap();
+/// assert_eq!(
+///     "Error! : failed to read nn.bin",
+///     patterns.classify(&cmd).unwrap().message
+/// );
+///
+/// let patterns = WarningPatterns::empty().pattern("fallback");
+/// assert!(patterns.matches("Using FALLBACK evaluation"));
+/// assert!(!patterns.matches("Error"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WarningPatterns {
+    patterns: Vec<String>,
+}
+
+impl Default for WarningPatterns {
+    fn default() -> Self {
+        WarningPatterns {
+            patterns: DEFAULT_PATTERNS.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl WarningPatterns {
+    /// Returns the default patterns.
+    pub fn new() -> Self {
+        WarningPatterns::default()
+    }
+
+    /// Returns patterns which match nothing.
+    pub fn empty() -> Self {
+        WarningPatterns {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Adds a pattern.
+    #[must_use]
+    pub fn pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.patterns.push(pattern.into().to_lowercase());
+        self
+    }
+
+    /// Returns `true` if `text` contains any of the patterns, ignoring case.
+    pub fn matches(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.patterns.iter().any(|p| text.contains(p.as_str()))
+    }
+
+    /// Returns a warning if `command` is an `info string` matching the patterns.
+    pub fn classify(&self, command: &EngineCommand) -> Option<EngineWarning> {
+        let params = match command {
+            EngineCommand::Info(params) => params,
+            _ => return None,
+        };
+        params.iter().find_map(|p| match p {
+            InfoParams::Text(text) if self.matches(text) => Some(EngineWarning {
+                message: text.clone(),
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Returns the name of the option mentioned by an engine rejecting a `setoption`
+/// command, as in `No such option: Hash` or `info string Unknown option Hash`.
+///
+/// The name is empty if the line does not mention it.
+///
+/// # Examples
+///
+/// ```
+/// use usi::rejected_option;
+///
+/// assert_eq!(Some("Bogus".to_string()), rejected_option("info string ERROR: No such option: Bogus"));
+/// assert_eq!(None, rejected_option("info string Hash 256MB"));
+/// ```
+pub fn rejected_option(line: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets valid for `line`.
+    let lower = line.to_ascii_lowercase();
+    let end = ["no such option", "unknown option"]
+        .iter()
+        .find_map(|phrase| lower.find(phrase).map(|pos| pos + phrase.len()))?;
+    let name = line[end..]
+        .trim_start_matches(|c: char| c == ':' || c.is_whitespace())
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'' || c == '.');
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let patterns = WarningPatterns::default();
+        let warning = |line: &str| patterns.classify(&EngineCommand::parse(line).unwrap());
+
+        assert!(warning("info string ERROR: No such option: Bogus").is_some());
+        assert!(warning("info string Eval file not found").is_some());
+        assert!(warning("info string Hash 256MB").is_none());
+        assert!(warning("info depth 1 score cp 0").is_none());
+        assert!(warning("readyok").is_none());
+
+        assert!(!WarningPatterns::empty().matches("error"));
+    }
+
+    #[test]
+    fn option_rejection() {
+        assert_eq!(
+            Some("Bogus".to_string()),
+            rejected_option("No such option: Bogus")
+        );
+        assert_eq!(
+            Some("EvalDir".to_string()),
+            rejected_option("info string Error! : Unknown option 'EvalDir'.")
+        );
+        assert_eq!(
+            Some(String::new()),
+            rejected_option("info string unknown option")
+        );
+        assert_eq!(None, rejected_option("option name Hash type spin"));
+    }
+}
@@ -0,0 +1,666 @@
+//! Named registry of engine configurations.
+//!
+//! `EngineRegistry` maps engine names and their aliases to `EngineConfig`s
+//! together with a default option profile, so applications can refer to
+//! engines such as `"yaneuraou-nnue"` instead of hard-coding paths.
+//!
+//! # Example
+//!
+//! ```
+//! use usi::registry::{EngineEntry, EngineRegistry, OptionProfile};
+//! use usi::EngineConfig;
+//!
+//! let mut registry = EngineRegistry::new();
+//! registry.insert(
+//!     "yaneuraou-nnue",
+//!     EngineEntry::new(EngineConfig {
+//!         path: "/opt/engines/YaneuraOu-NNUE".to_string(),
+//!         ..Default::default()
+//!     })
+//!     .alias("yaneuraou")
+//!     .options(OptionProfile::new().set("USI_Hash", "1024")),
+//! );
+//!
+//! let entry = registry.get("yaneuraou").unwrap();
+//! assert_eq!("/opt/engines/YaneuraOu-NNUE", entry.config.path);
+//! assert_eq!(Some("1024"), entry.options.get("USI_Hash"));
+//! ```
+//!
+//! With the `serde` feature enabled, a registry can be loaded from a TOML file:
+//!
+//! ```toml
+//! [engines.yaneuraou-nnue]
+//! path = "/opt/engines/YaneuraOu-NNUE"
+//! aliases = ["yaneuraou"]
+//!
+//! [engines.yaneuraou-nnue.options]
+//! USI_Hash = "1024"
+//! Threads = "4"
+//! ```
+//!
+//! Option names are matched regardless of case and of the `USI_` prefix,
+//! so a profile written with `USI_Hash` applies to an engine declaring `Hash`:
+//!
+//! ```
+//! use usi::registry::OptionProfile;
+//!
+//! let profile = OptionProfile::new().set("USI_Hash", "1024");
+//! assert_eq!(Some("1024"), profile.get("hash"));
+//! assert_eq!(
+//!     Some("1024"),
+//!     profile.resolve(["Hash", "Threads"]).get("Hash")
+//! );
+//! ```
+
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::fmt;
+#[cfg(feature = "serde")]
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use crate::error::Error;
+use crate::process::EngineInfo;
+use crate::protocol::{normalize_width, GuiCommand, OptionKind, OptionParams, OptionValue};
+use crate::threaded::EngineConfig;
+
+/// Returns the canonical form of an option name used for matching.
+///
+/// The name is NFKC-normalized and lowercased, and the `USI_` prefix as well
+/// as `_`, `-` and spaces are removed, so `USI_Hash`, `Hash`, `hash` and `Ｈａｓｈ`
+/// are all the same option.
+pub fn canonical_option_name(name: &str) -> String {
+    let lower = normalize_width(name).trim().to_ascii_lowercase();
+    let stripped = lower.strip_prefix("usi_").unwrap_or(&lower);
+    stripped
+        .chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .collect()
+}
+
+/// A set of option values to be applied to an engine via `setoption`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct OptionProfile {
+    options: BTreeMap<String, String>,
+}
+
+impl OptionProfile {
+    pub fn new() -> Self {
+        OptionProfile::default()
+    }
+
+    /// Adds or replaces an option value.
+    #[must_use]
+    pub fn set(mut self, name: &str, value: &str) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    /// Adds or replaces an option value in place.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.options.insert(name.to_string(), value.to_string());
+    }
+
+    /// Returns the value of the given option if present.
+    ///
+    /// Falls back to matching the canonical name if there is no exact match.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        if let Some(v) = self.options.get(name) {
+            return Some(v.as_str());
+        }
+        let canonical = canonical_option_name(name);
+        self.options
+            .iter()
+            .find(|(n, _)| canonical_option_name(n) == canonical)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns a profile with the option names replaced by the matching names
+    /// declared by an engine, e.g. from `EngineInfo::declared_options`.
+    ///
+    /// Options the engine does not declare keep their names.
+    pub fn resolve<'a, I>(&self, declared: I) -> OptionProfile
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let declared: Vec<&str> = declared.into_iter().collect();
+        let options = self
+            .options
+            .iter()
+            .map(|(n, v)| {
+                let name = if declared.contains(&n.as_str()) {
+                    n.as_str()
+                } else {
+                    let canonical = canonical_option_name(n);
+                    declared
+                        .iter()
+                        .copied()
+                        .find(|d| canonical_option_name(d) == canonical)
+                        .unwrap_or(n)
+                };
+                (name.to_string(), v.clone())
+            })
+            .collect();
+        OptionProfile { options }
+    }
+
+    /// Resolves the profile like `resolve`, leaving out the options the engine
+    /// does not declare.
+    ///
+    /// Options named with the `USI_` prefix, such as `USI_Hash` and `USI_Ponder`,
+    /// are set by GUIs without being declared and are always kept.
+    pub fn partition<'a, I>(&self, declared: I) -> AppliedOptions
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let declared: Vec<&str> = declared.into_iter().collect();
+        let mut applied = AppliedOptions::default();
+        for (name, value) in self.resolve(declared.iter().copied()).iter() {
+            let standard = name
+                .get(..4)
+                .is_some_and(|p| p.eq_ignore_ascii_case("usi_"));
+            if standard || declared.contains(&name.as_str()) {
+                applied.applied.insert(name, value);
+            } else {
+                applied.skipped.push(name.clone());
+            }
+        }
+        applied
+    }
+
+    /// Returns an iterator over `(name, value)` pairs.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, String> {
+        self.options.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.options.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    /// Returns `setoption` commands applying this profile.
+    pub fn to_commands(&self) -> Vec<GuiCommand> {
+        self.options
+            .iter()
+            .map(|(n, v)| GuiCommand::SetOption(n.clone(), Some(v.clone())))
+            .collect()
+    }
+}
+
+/// The options of profiles applied to an engine, see `OptionProfile::partition`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppliedOptions {
+    /// The options set, under the names declared by the engine.
+    pub applied: OptionProfile,
+    /// The options which were not set because the engine does not declare them.
+    pub skipped: Vec<String>,
+}
+
+impl AppliedOptions {
+    /// Adds the options of `other`, applied after these.
+    pub fn merge(&mut self, other: AppliedOptions) {
+        for (name, value) in other.applied.iter() {
+            self.applied.insert(name, value);
+        }
+        self.skipped.extend(other.skipped);
+    }
+}
+
+/// Differences between the options declared by two builds of an engine,
+/// e.g. to review saved option profiles after upgrading it.
+///
+/// Options are matched by their canonical names, see `canonical_option_name`.
+///
+/// # Examples
+///
+/// ```
+/// use usi::registry::{OptionDiff, OptionProfile};
+/// use usi::{OptionKind, OptionParams};
+///
+/// let spin = |name: &str, default, max| OptionParams {
+///     name: name.to_string(),
+///     value: OptionKind::Spin { default: Some(default), min: Some(1), max: Some(max) },
+/// };
+/// let old = [spin("Threads", 1, 512), spin("NodesLimit", 0, 1000)];
+/// let new = [spin("Threads", 4, 1024)];
+///
+/// let diff = OptionDiff::between(&old, &new);
+/// assert_eq!("NodesLimit", diff.removed[0].name);
+/// assert!(diff.changed[0].default_changed());
+///
+/// let profile = OptionProfile::new().set("Threads", "8").set("NodesLimit", "500");
+/// assert_eq!(vec!["NodesLimit".to_string()], diff.stale_options(&profile));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OptionDiff {
+    /// Options only declared by the new build.
+    pub added: Vec<OptionParams>,
+    /// Options only declared by the old build.
+    pub removed: Vec<OptionParams>,
+    /// Options declared by both builds with a different name, type, default or range.
+    pub changed: Vec<OptionChange>,
+}
+
+/// An option declared differently by two builds of an engine, see `OptionDiff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionChange {
+    pub old: OptionParams,
+    pub new: OptionParams,
+}
+
+impl OptionChange {
+    /// Returns `true` if the option was renamed, e.g. from `Hash` to `USI_Hash`.
+    pub fn renamed(&self) -> bool {
+        self.old.name != self.new.name
+    }
+
+    /// Returns `true` if the declared default value differs.
+    pub fn default_changed(&self) -> bool {
+        self.old.value.default_value() != self.new.value.default_value()
+    }
+}
+
+impl OptionDiff {
+    /// Compares the options declared by an old and a new build of an engine.
+    pub fn of(old: &EngineInfo, new: &EngineInfo) -> OptionDiff {
+        OptionDiff::between(old.declared_options(), new.declared_options())
+    }
+
+    /// Compares two lists of declared options.
+    pub fn between(old: &[OptionParams], new: &[OptionParams]) -> OptionDiff {
+        let find = |options: &[OptionParams], name: &str| {
+            let canonical = canonical_option_name(name);
+            options
+                .iter()
+                .find(|o| canonical_option_name(&o.name) == canonical)
+                .cloned()
+        };
+        let mut diff = OptionDiff::default();
+        for o in old {
+            match find(new, &o.name) {
+                Some(n) if n != *o => diff.changed.push(OptionChange {
+                    old: o.clone(),
+                    new: n,
+                }),
+                Some(_) => {}
+                None => diff.removed.push(o.clone()),
+            }
+        }
+        diff.added = new
+            .iter()
+            .filter(|n| find(old, &n.name).is_none())
+            .cloned()
+            .collect();
+        diff
+    }
+
+    /// Returns `true` if both builds declare the same options.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Returns the names of the options of `profile`, saved for the old build,
+    /// which the new build no longer declares or declares differently without
+    /// accepting the saved value, e.g. a combo option which lost the saved choice.
+    pub fn stale_options(&self, profile: &OptionProfile) -> Vec<String> {
+        let matches = |o: &OptionParams, name: &str| {
+            canonical_option_name(&o.name) == canonical_option_name(name)
+        };
+        profile
+            .iter()
+            .filter(|(name, value)| {
+                self.removed.iter().any(|o| matches(o, name))
+                    || self
+                        .changed
+                        .iter()
+                        .any(|c| matches(&c.new, name) && !accepts(&c.new.value, value))
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// Returns `true` if `value` is a valid value of an option of `kind`,
+/// within its range or among its choices.
+fn accepts(kind: &OptionKind, value: &str) -> bool {
+    match (kind, kind.parse_value(value)) {
+        (OptionKind::Spin { min, max, .. }, Some(OptionValue::Spin(n))) => {
+            min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max)
+        }
+        (OptionKind::Combo { vars, .. }, Some(OptionValue::Combo(v))) => {
+            vars.is_empty() || vars.contains(&v)
+        }
+        (_, parsed) => parsed.is_some(),
+    }
+}
+
+impl fmt::Display for OptionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for o in &self.added {
+            writeln!(f, "+ {o}")?;
+        }
+        for o in &self.removed {
+            writeln!(f, "- {o}")?;
+        }
+        for c in &self.changed {
+            writeln!(f, "~ {}", c.old)?;
+            writeln!(f, "  {}", c.new)?;
+        }
+        Ok(())
+    }
+}
+
+/// A registered engine: its spawn configuration, aliases and default options.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct EngineEntry {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub config: EngineConfig,
+    /// Alternative names this engine can be looked up by.
+    pub aliases: Vec<String>,
+    /// Options applied after the handshake.
+    pub options: OptionProfile,
+}
+
+impl EngineEntry {
+    pub fn new(config: EngineConfig) -> Self {
+        EngineEntry {
+            config,
+            ..Default::default()
+        }
+    }
+
+    #[must_use]
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn options(mut self, options: OptionProfile) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// A collection of named `EngineEntry`s.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct EngineRegistry {
+    engines: BTreeMap<String, EngineEntry>,
+}
+
+impl EngineRegistry {
+    pub fn new() -> Self {
+        EngineRegistry::default()
+    }
+
+    /// Registers an engine under the given name, replacing any previous entry.
+    pub fn insert(&mut self, name: &str, entry: EngineEntry) {
+        self.engines.insert(name.to_string(), entry);
+    }
+
+    /// Removes an engine by its name.
+    pub fn remove(&mut self, name: &str) -> Option<EngineEntry> {
+        self.engines.remove(name)
+    }
+
+    /// Looks up an engine by its name or one of its aliases.
+    pub fn get(&self, name: &str) -> Option<&EngineEntry> {
+        self.engines.get(name).or_else(|| {
+            self.engines
+                .values()
+                .find(|e| e.aliases.iter().any(|a| a == name))
+        })
+    }
+
+    /// Returns the spawn configuration of an engine by its name or alias.
+    pub fn config(&self, name: &str) -> Option<&EngineConfig> {
+        self.get(name).map(|e| &e.config)
+    }
+
+    /// Returns the registered engine names, excluding aliases.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.engines.keys().map(|k| k.as_str())
+    }
+
+    /// Returns an iterator over `(name, entry)` pairs.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, EngineEntry> {
+        self.engines.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.engines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engines.is_empty()
+    }
+
+    /// Parses a registry from a TOML document.
+    #[cfg(feature = "serde")]
+    pub fn from_toml_str(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+
+    /// Loads a registry from a TOML file.
+    #[cfg(feature = "serde")]
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(path)?;
+        Self::from_toml_str(&s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let mut registry = EngineRegistry::new();
+        registry.insert(
+            "yaneuraou-nnue",
+            EngineEntry::new(EngineConfig {
+                path: "/opt/yo".to_string(),
+                ..Default::default()
+            })
+            .alias("yo")
+            .options(OptionProfile::new().set("USI_Hash", "256")),
+        );
+
+        assert_eq!("/opt/yo", registry.config("yaneuraou-nnue").unwrap().path);
+        assert_eq!("/opt/yo", registry.config("yo").unwrap().path);
+        assert!(registry.get("unknown").is_none());
+        assert_eq!(vec!["yaneuraou-nnue"], registry.names().collect::<Vec<_>>());
+        assert_eq!(
+            vec![GuiCommand::SetOption(
+                "USI_Hash".to_string(),
+                Some("256".to_string())
+            )],
+            registry.get("yo").unwrap().options.to_commands()
+        );
+    }
+
+    #[test]
+    fn option_names() {
+        assert_eq!("hash", canonical_option_name("USI_Hash"));
+        assert_eq!("evaldir", canonical_option_name("Eval_Dir"));
+        assert_eq!("hash", canonical_option_name("ＵＳＩ＿Ｈａｓｈ"));
+        assert_eq!(
+            canonical_option_name("MultiPV"),
+            canonical_option_name("multi_pv")
+        );
+
+        let profile = OptionProfile::new()
+            .set("USI_Hash", "256")
+            .set("threads", "4")
+            .set("BookFile", "no_book");
+        assert_eq!(Some("256"), profile.get("Hash"));
+        assert_eq!(Some("4"), profile.get("Threads"));
+        assert_eq!(None, profile.get("Ponder"));
+
+        let resolved = profile.resolve(["Hash", "Threads", "USI_Ponder"]);
+        assert_eq!(
+            vec![("BookFile", "no_book"), ("Hash", "256"), ("Threads", "4")],
+            resolved
+                .iter()
+                .map(|(n, v)| (n.as_str(), v.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        let mut applied = profile.partition(["Hash", "Threads"]);
+        assert_eq!(
+            AppliedOptions {
+                applied: OptionProfile::new().set("Hash", "256").set("Threads", "4"),
+                skipped: vec!["BookFile".to_string()],
+            },
+            applied
+        );
+        applied.merge(
+            OptionProfile::new()
+                .set("USI_Ponder", "true")
+                .set("threads", "8")
+                .partition(["Threads"]),
+        );
+        assert_eq!(Some("8"), applied.applied.get("Threads"));
+        assert_eq!(Some("true"), applied.applied.get("USI_Ponder"));
+        assert_eq!(3, applied.applied.len());
+    }
+
+    #[test]
+    fn option_diff() {
+        let option = |name: &str, value| OptionParams {
+            name: name.to_string(),
+            value,
+        };
+        let old = [
+            option(
+                "Hash",
+                OptionKind::Spin {
+                    default: Some(16),
+                    min: Some(1),
+                    max: Some(1024),
+                },
+            ),
+            option(
+                "OwnBook",
+                OptionKind::Check {
+                    default: Some(true),
+                },
+            ),
+            option(
+                "BookFile",
+                OptionKind::Combo {
+                    default: Some("a.db".to_string()),
+                    vars: vec!["a.db".to_string(), "b.db".to_string()],
+                },
+            ),
+        ];
+        let new = [
+            option(
+                "USI_Hash",
+                OptionKind::Spin {
+                    default: Some(16),
+                    min: Some(1),
+                    max: Some(1024),
+                },
+            ),
+            option(
+                "BookFile",
+                OptionKind::Combo {
+                    default: Some("b.db".to_string()),
+                    vars: vec!["b.db".to_string()],
+                },
+            ),
+            option("EvalDir", OptionKind::String { default: None }),
+        ];
+
+        let diff = OptionDiff::between(&old, &new);
+        assert_eq!(vec![new[2].clone()], diff.added);
+        assert_eq!(vec![old[1].clone()], diff.removed);
+        assert_eq!(2, diff.changed.len());
+        assert!(diff.changed[0].renamed());
+        assert!(!diff.changed[0].default_changed());
+        assert!(diff.changed[1].default_changed());
+        assert!(OptionDiff::between(&old, &old).is_empty());
+        assert_eq!(
+            "+ name EvalDir type string\n\
+             - name OwnBook type check default true\n\
+             ~ name Hash type spin default 16 min 1 max 1024\n  \
+             name USI_Hash type spin default 16 min 1 max 1024\n\
+             ~ name BookFile type combo default a.db var a.db var b.db\n  \
+             name BookFile type combo default b.db var b.db\n",
+            diff.to_string()
+        );
+
+        let profile = OptionProfile::new()
+            .set("USI_Hash", "256")
+            .set("OwnBook", "false")
+            .set("BookFile", "a.db");
+        assert_eq!(
+            vec!["BookFile".to_string(), "OwnBook".to_string()],
+            diff.stale_options(&profile)
+        );
+        assert!(diff
+            .stale_options(&OptionProfile::new().set("Hash", "512"))
+            .is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_toml() {
+        use crate::threaded::StderrMode;
+
+        let registry = EngineRegistry::from_toml_str(
+            r#"
+            [engines.yaneuraou-nnue]
+            path = "/opt/yo"
+            args = ["--nnue"]
+            aliases = ["yo"]
+            pre_handshake_options = [["Protocol", "usi"]]
+            env = [["OMP_NUM_THREADS", "1"]]
+            stderr = "null"
+            ready_timeout = 60000
+
+            [engines.yaneuraou-nnue.options]
+            USI_Hash = "1024"
+            Threads = "4"
+            "#,
+        )
+        .expect("failed to parse the registry");
+
+        let entry = registry.get("yo").unwrap();
+        assert_eq!("/opt/yo", entry.config.path);
+        assert_eq!(vec!["--nnue".to_string()], entry.config.args);
+        assert_eq!(
+            vec![("Protocol".to_string(), Some("usi".to_string()))],
+            entry.config.pre_handshake_options
+        );
+        assert_eq!(StderrMode::Null, entry.config.stderr);
+        assert_eq!(
+            Some(std::time::Duration::from_secs(60)),
+            entry.config.ready_timeout
+        );
+        assert_eq!(None, entry.config.handshake_timeout);
+        assert_eq!(Some("1024"), entry.options.get("USI_Hash"));
+        assert_eq!(2, entry.options.len());
+
+        assert!(EngineRegistry::from_toml_str("engines = 1").is_err());
+    }
+}
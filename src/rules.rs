@@ -0,0 +1,674 @@
+//! Shogi rules for tracking positions and checking the moves played by engines.
+//!
+//! Only the legality of single moves is checked; repetitions and impasse
+//! declarations are left to the caller. `play_game` and `GameSession` enforce
+//! the rules on engine moves with the `shogi-rules` feature.
+//!
+//! # Example
+//!
+//! ```
+//! use usi::rules::Position;
+//!
+//! let mut position = Position::from_sfen("startpos moves 7g7f").unwrap();
+//! assert!(position.is_legal("3c3d"));
+//! assert!(!position.is_legal("3c3b"));
+//! assert!(position.play("3c3d"));
+//! assert!(position.is_legal("8h2b+"));
+//! ```
+
+use crate::error::Error;
+use crate::game::{Color, STARTPOS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum PieceKind {
+    Pawn,
+    Lance,
+    Knight,
+    Silver,
+    Gold,
+    Bishop,
+    Rook,
+    King,
+    ProPawn,
+    ProLance,
+    ProKnight,
+    ProSilver,
+    Horse,
+    Dragon,
+}
+
+/// Kinds of pieces in hand, in the order of `Position::hands`.
+const HAND_KINDS: [PieceKind; 7] = [
+    PieceKind::Pawn,
+    PieceKind::Lance,
+    PieceKind::Knight,
+    PieceKind::Silver,
+    PieceKind::Gold,
+    PieceKind::Bishop,
+    PieceKind::Rook,
+];
+
+const GOLD_STEPS: &[(i8, i8)] = &[(0, -1), (-1, -1), (1, -1), (-1, 0), (1, 0), (0, 1)];
+const SILVER_STEPS: &[(i8, i8)] = &[(0, -1), (-1, -1), (1, -1), (-1, 1), (1, 1)];
+const KING_STEPS: &[(i8, i8)] = &[
+    (0, -1),
+    (-1, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+const DIAGONALS: &[(i8, i8)] = &[(-1, -1), (1, -1), (-1, 1), (1, 1)];
+const ORTHOGONALS: &[(i8, i8)] = &[(0, -1), (-1, 0), (1, 0), (0, 1)];
+
+impl PieceKind {
+    fn from_letter(c: char) -> Option<PieceKind> {
+        Some(match c.to_ascii_uppercase() {
+            'P' => PieceKind::Pawn,
+            'L' => PieceKind::Lance,
+            'N' => PieceKind::Knight,
+            'S' => PieceKind::Silver,
+            'G' => PieceKind::Gold,
+            'B' => PieceKind::Bishop,
+            'R' => PieceKind::Rook,
+            'K' => PieceKind::King,
+            _ => return None,
+        })
+    }
+
+    fn promoted(self) -> Option<PieceKind> {
+        Some(match self {
+            PieceKind::Pawn => PieceKind::ProPawn,
+            PieceKind::Lance => PieceKind::ProLance,
+            PieceKind::Knight => PieceKind::ProKnight,
+            PieceKind::Silver => PieceKind::ProSilver,
+            PieceKind::Bishop => PieceKind::Horse,
+            PieceKind::Rook => PieceKind::Dragon,
+            _ => return None,
+        })
+    }
+
+    fn unpromoted(self) -> PieceKind {
+        match self {
+            PieceKind::ProPawn => PieceKind::Pawn,
+            PieceKind::ProLance => PieceKind::Lance,
+            PieceKind::ProKnight => PieceKind::Knight,
+            PieceKind::ProSilver => PieceKind::Silver,
+            PieceKind::Horse => PieceKind::Bishop,
+            PieceKind::Dragon => PieceKind::Rook,
+            kind => kind,
+        }
+    }
+
+    /// Returns the SFEN letter of an unpromoted piece, in uppercase.
+    fn letter(self) -> char {
+        match self.unpromoted() {
+            PieceKind::Pawn => 'P',
+            PieceKind::Lance => 'L',
+            PieceKind::Knight => 'N',
+            PieceKind::Silver => 'S',
+            PieceKind::Gold => 'G',
+            PieceKind::Bishop => 'B',
+            PieceKind::Rook => 'R',
+            _ => 'K',
+        }
+    }
+
+    fn hand_index(self) -> Option<usize> {
+        HAND_KINDS.iter().position(|k| *k == self)
+    }
+
+    /// Returns the single steps and the sliding directions of the piece,
+    /// for Black, i.e. with the forward direction being `(0, -1)`.
+    fn moves(self) -> (Offsets, Offsets) {
+        match self {
+            PieceKind::Pawn => (&[(0, -1)], &[]),
+            PieceKind::Lance => (&[], &[(0, -1)]),
+            PieceKind::Knight => (&[(-1, -2), (1, -2)], &[]),
+            PieceKind::Silver => (SILVER_STEPS, &[]),
+            PieceKind::Bishop => (&[], DIAGONALS),
+            PieceKind::Rook => (&[], ORTHOGONALS),
+            PieceKind::King => (KING_STEPS, &[]),
+            PieceKind::Horse => (KING_STEPS, DIAGONALS),
+            PieceKind::Dragon => (KING_STEPS, ORTHOGONALS),
+            _ => (GOLD_STEPS, &[]),
+        }
+    }
+
+    /// Returns the number of ranks from the far end on which the piece could
+    /// never move again, and therefore may not be dropped or left unpromoted.
+    fn dead_ranks(self) -> i8 {
+        match self {
+            PieceKind::Pawn | PieceKind::Lance => 1,
+            PieceKind::Knight => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// A square as `(file, rank)`, both from 1 to 9.
+type Square = (i8, i8);
+
+/// Offsets as `(file, rank)` from the square of a piece.
+type Offsets = &'static [(i8, i8)];
+
+fn index((file, rank): Square) -> usize {
+    (file as usize - 1) * 9 + rank as usize - 1
+}
+
+fn parse_square(file: u8, rank: u8) -> Option<Square> {
+    if !(b'1'..=b'9').contains(&file) || !(b'a'..=b'i').contains(&rank) {
+        return None;
+    }
+    Some(((file - b'0') as i8, (rank - b'a') as i8 + 1))
+}
+
+/// Returns the rank as counted from the far end for `color`, i.e. 1 for the last rank.
+fn relative_rank(color: Color, (_, rank): Square) -> i8 {
+    match color {
+        Color::Black => rank,
+        Color::White => 10 - rank,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Move {
+    Normal {
+        from: Square,
+        to: Square,
+        promote: bool,
+    },
+    Drop {
+        kind: PieceKind,
+        to: Square,
+    },
+}
+
+impl Move {
+    fn parse(mv: &str) -> Option<Move> {
+        let b = mv.as_bytes();
+        match b {
+            [piece, b'*', file, rank] => {
+                let kind = PieceKind::from_letter(*piece as char)
+                    .filter(|k| k.hand_index().is_some() && piece.is_ascii_uppercase())?;
+                Some(Move::Drop {
+                    kind,
+                    to: parse_square(*file, *rank)?,
+                })
+            }
+            [ff, fr, tf, tr, rest @ ..] if rest.is_empty() || rest == b"+" => Some(Move::Normal {
+                from: parse_square(*ff, *fr)?,
+                to: parse_square(*tf, *tr)?,
+                promote: !rest.is_empty(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the arguments of the `position` command into the initial position
+/// in SFEN, with `startpos` replaced by `STARTPOS`, and the moves played from there.
+pub(crate) fn split_moves(position: &str) -> (&str, std::str::SplitWhitespace<'_>) {
+    let position = position.trim();
+    let position = position.strip_prefix("sfen ").unwrap_or(position);
+    let (base, moves) = position.split_once("moves").unwrap_or((position, ""));
+    let base = match base.trim() {
+        "startpos" => STARTPOS,
+        base => base,
+    };
+    (base, moves.split_whitespace())
+}
+
+/// A shogi position, for tracking games and checking that moves are legal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    board: [Option<(Color, PieceKind)>; 81],
+    /// Pieces in hand of Black and White, indexed like `HAND_KINDS`.
+    hands: [[u8; 7]; 2],
+    side_to_move: Color,
+    /// The move number, starting from 1.
+    ply: u32,
+}
+
+impl Position {
+    /// Parses a position in the format of the `position` command arguments:
+    /// `startpos` or an SFEN string, optionally prefixed with `sfen` and
+    /// followed by `moves` and the moves played from there.
+    ///
+    /// Returns `Error::IllegalSyntax` if the position is malformed
+    /// or one of the moves is illegal.
+    pub fn from_sfen(sfen: &str) -> Result<Position, Error> {
+        let (base, moves) = split_moves(sfen);
+        let mut position = Position::parse_base(base).ok_or(Error::IllegalSyntax)?;
+        for mv in moves {
+            if !position.play(mv) {
+                return Err(Error::IllegalSyntax);
+            }
+        }
+        Ok(position)
+    }
+
+    fn parse_base(sfen: &str) -> Option<Position> {
+        let mut fields = sfen.split_whitespace();
+        let mut position = Position {
+            board: [None; 81],
+            hands: [[0; 7]; 2],
+            side_to_move: Color::Black,
+            ply: 1,
+        };
+
+        let ranks: Vec<&str> = fields.next()?.split('/').collect();
+        if ranks.len() != 9 {
+            return None;
+        }
+        for (rank, row) in (1..).zip(ranks) {
+            let mut file = 10;
+            let mut promoted = false;
+            for c in row.chars() {
+                if c == '+' {
+                    promoted = true;
+                    continue;
+                }
+                file -= c.to_digit(10).map_or(1, |n| n as i8);
+                if file < 1 {
+                    return None;
+                }
+                if c.is_ascii_digit() {
+                    continue;
+                }
+                let mut kind = PieceKind::from_letter(c)?;
+                if std::mem::take(&mut promoted) {
+                    kind = kind.promoted()?;
+                }
+                position.board[index((file, rank))] = Some((color_of(c), kind));
+            }
+            if file != 1 || promoted {
+                return None;
+            }
+        }
+
+        position.side_to_move = match fields.next()? {
+            "b" => Color::Black,
+            "w" => Color::White,
+            _ => return None,
+        };
+
+        let hands = fields.next()?;
+        if hands != "-" {
+            let mut count = 0u8;
+            for c in hands.chars() {
+                if let Some(n) = c.to_digit(10) {
+                    count = count.checked_mul(10)?.checked_add(n as u8)?;
+                    continue;
+                }
+                let slot = PieceKind::from_letter(c)?.hand_index()?;
+                let held = &mut position.hands[color_of(c).index()][slot];
+                *held = held.checked_add(count.max(1))?;
+                count = 0;
+            }
+        }
+
+        if let Some(ply) = fields.next() {
+            position.ply = ply.parse().ok()?;
+        }
+        Some(position)
+    }
+
+    /// Returns the position in SFEN.
+    pub fn to_sfen(&self) -> String {
+        let mut sfen = String::new();
+        for rank in 1..=9 {
+            if rank > 1 {
+                sfen.push('/');
+            }
+            let mut empty = 0;
+            for file in (1..=9).rev() {
+                let (color, kind) = match self.piece((file, rank)) {
+                    Some(piece) => piece,
+                    None => {
+                        empty += 1;
+                        continue;
+                    }
+                };
+                if empty > 0 {
+                    sfen.push_str(&empty.to_string());
+                    empty = 0;
+                }
+                if kind.unpromoted() != kind {
+                    sfen.push('+');
+                }
+                sfen.push(cased(kind.letter(), color));
+            }
+            if empty > 0 {
+                sfen.push_str(&empty.to_string());
+            }
+        }
+
+        sfen.push_str(match self.side_to_move {
+            Color::Black => " b ",
+            Color::White => " w ",
+        });
+
+        let mut hands = String::new();
+        for color in [Color::Black, Color::White] {
+            // Hands are written from the rook down to the pawn.
+            for (kind, n) in HAND_KINDS.iter().zip(self.hands[color.index()]).rev() {
+                if n > 1 {
+                    hands.push_str(&n.to_string());
+                }
+                if n > 0 {
+                    hands.push(cased(kind.letter(), color));
+                }
+            }
+        }
+        if hands.is_empty() {
+            hands.push('-');
+        }
+        sfen.push_str(&hands);
+        sfen.push(' ');
+        sfen.push_str(&self.ply.to_string());
+        sfen
+    }
+
+    /// Returns the side to move.
+    pub fn side_to_move(&self) -> Color {
+        self.side_to_move
+    }
+
+    /// Returns `true` if the side to move is in check.
+    pub fn in_check(&self) -> bool {
+        self.king_attacked(self.side_to_move)
+    }
+
+    /// Returns `true` if the side to move may declare a win by entering king,
+    /// following the 27-point rule.
+    ///
+    /// The king must be in the enemy camp and not in check, with at least
+    /// ten other pieces there. Counting those pieces and the pieces in hand,
+    /// with bishops and rooks worth five points and other pieces one point,
+    /// Black needs 28 points and White 27.
+    pub fn can_declare_win(&self) -> bool {
+        let color = self.side_to_move;
+        let king = squares().find(|s| self.piece(*s) == Some((color, PieceKind::King)));
+        if king.is_none_or(|k| relative_rank(color, k) > 3) || self.in_check() {
+            return false;
+        }
+
+        let value = |kind: PieceKind| match kind.unpromoted() {
+            PieceKind::Bishop | PieceKind::Rook => 5,
+            _ => 1,
+        };
+        let mut pieces = 0;
+        let mut points = 0;
+        for square in squares().filter(|s| relative_rank(color, *s) <= 3) {
+            match self.piece(square) {
+                Some((c, kind)) if c == color && kind != PieceKind::King => {
+                    pieces += 1;
+                    points += value(kind);
+                }
+                _ => {}
+            }
+        }
+        for (kind, n) in HAND_KINDS.iter().zip(self.hands[color.index()]) {
+            points += value(*kind) * u32::from(n);
+        }
+        let required = match color {
+            Color::Black => 28,
+            Color::White => 27,
+        };
+        pieces >= 10 && points >= required
+    }
+
+    /// Returns `true` if `mv`, in USI notation, is legal in this position.
+    pub fn is_legal(&self, mv: &str) -> bool {
+        Move::parse(mv).is_some_and(|m| self.is_legal_move(m, true))
+    }
+
+    /// Plays `mv`, in USI notation, returning `false` without changing
+    /// the position if the move is not legal.
+    pub fn play(&mut self, mv: &str) -> bool {
+        match Move::parse(mv).filter(|m| self.is_legal_move(*m, true)) {
+            Some(m) => {
+                self.apply(m);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn piece(&self, square: Square) -> Option<(Color, PieceKind)> {
+        self.board[index(square)]
+    }
+
+    /// Checks a move, rejecting pawn drops which checkmate if `check_drop_mate` is set.
+    fn is_legal_move(&self, m: Move, check_drop_mate: bool) -> bool {
+        let color = self.side_to_move;
+        let valid = match m {
+            Move::Normal { from, to, promote } => {
+                let kind = match self.piece(from) {
+                    Some((c, kind)) if c == color => kind,
+                    _ => return false,
+                };
+                if self.piece(to).is_some_and(|(c, _)| c == color)
+                    || !self.reaches(from, to, color, kind)
+                {
+                    return false;
+                }
+                if promote {
+                    kind.promoted().is_some()
+                        && (relative_rank(color, from) <= 3 || relative_rank(color, to) <= 3)
+                } else {
+                    relative_rank(color, to) > kind.dead_ranks()
+                }
+            }
+            Move::Drop { kind, to } => {
+                let in_hand = kind
+                    .hand_index()
+                    .is_some_and(|i| self.hands[color.index()][i] > 0);
+                in_hand
+                    && self.piece(to).is_none()
+                    && relative_rank(color, to) > kind.dead_ranks()
+                    && !(kind == PieceKind::Pawn && self.has_pawn_on_file(color, to.0))
+            }
+        };
+        if !valid {
+            return false;
+        }
+
+        let mut after = self.clone();
+        after.apply(m);
+        if after.king_attacked(color) {
+            return false;
+        }
+        if check_drop_mate && matches!(m, Move::Drop { kind, .. } if kind == PieceKind::Pawn) {
+            return !after.in_check() || after.has_legal_move();
+        }
+        true
+    }
+
+    fn has_pawn_on_file(&self, color: Color, file: i8) -> bool {
+        (1..=9).any(|rank| self.piece((file, rank)) == Some((color, PieceKind::Pawn)))
+    }
+
+    /// Returns `true` if the piece on `from` attacks `to`.
+    fn reaches(&self, from: Square, to: Square, color: Color, kind: PieceKind) -> bool {
+        // Directions are given for Black; White moves towards higher ranks.
+        let forward = match color {
+            Color::Black => 1,
+            Color::White => -1,
+        };
+        let df = to.0 - from.0;
+        let dr = (to.1 - from.1) * forward;
+        let (steps, slides) = kind.moves();
+        if steps.contains(&(df, dr)) {
+            return true;
+        }
+        slides.iter().any(|&(sf, sr)| {
+            let distance = df.abs().max(dr.abs());
+            if distance == 0 || (sf * distance, sr * distance) != (df, dr) {
+                return false;
+            }
+            (1..distance).all(|i| {
+                self.piece((from.0 + sf * i, from.1 + sr * i * forward))
+                    .is_none()
+            })
+        })
+    }
+
+    fn king_attacked(&self, color: Color) -> bool {
+        let king = squares().find(|s| self.piece(*s) == Some((color, PieceKind::King)));
+        let king = match king {
+            Some(king) => king,
+            // Mate problems may leave out the attacker's king.
+            None => return false,
+        };
+        squares().any(|s| match self.piece(s) {
+            Some((c, kind)) if c != color => self.reaches(s, king, c, kind),
+            _ => false,
+        })
+    }
+
+    fn has_legal_move(&self) -> bool {
+        let color = self.side_to_move;
+        let normal = squares()
+            .filter(|s| self.piece(*s).is_some_and(|(c, _)| c == color))
+            .flat_map(|from| {
+                squares().flat_map(move |to| {
+                    [false, true].map(|promote| Move::Normal { from, to, promote })
+                })
+            });
+        let drops = HAND_KINDS
+            .iter()
+            .zip(self.hands[color.index()])
+            .filter(|(_, n)| *n > 0)
+            .flat_map(|(kind, _)| squares().map(|to| Move::Drop { kind: *kind, to }));
+        normal.chain(drops).any(|m| self.is_legal_move(m, false))
+    }
+
+    /// Plays a move without checking it.
+    fn apply(&mut self, m: Move) {
+        let color = self.side_to_move;
+        match m {
+            Move::Normal { from, to, promote } => {
+                let Some((_, mut kind)) = self.board[index(from)].take() else {
+                    return;
+                };
+                if let Some((_, captured)) = self.board[index(to)] {
+                    if let Some(i) = captured.unpromoted().hand_index() {
+                        self.hands[color.index()][i] += 1;
+                    }
+                }
+                if promote {
+                    kind = kind.promoted().unwrap_or(kind);
+                }
+                self.board[index(to)] = Some((color, kind));
+            }
+            Move::Drop { kind, to } => {
+                if let Some(i) = kind.hand_index() {
+                    self.hands[color.index()][i] -= 1;
+                }
+                self.board[index(to)] = Some((color, kind));
+            }
+        }
+        self.side_to_move = color.opponent();
+        self.ply += 1;
+    }
+}
+
+fn squares() -> impl Iterator<Item = Square> {
+    (1..=9).flat_map(|file| (1..=9).map(move |rank| (file, rank)))
+}
+
+fn cased(letter: char, color: Color) -> char {
+    match color {
+        Color::Black => letter,
+        Color::White => letter.to_ascii_lowercase(),
+    }
+}
+
+fn color_of(c: char) -> Color {
+    if c.is_ascii_uppercase() {
+        Color::Black
+    } else {
+        Color::White
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves() {
+        let position = Position::from_sfen("startpos").unwrap();
+        assert_eq!(Color::Black, position.side_to_move());
+        assert!(position.is_legal("7g7f"));
+        assert!(position.is_legal("2h1h"));
+        assert!(!position.is_legal("7g7e"));
+        assert!(!position.is_legal("8h2b+"));
+        assert!(!position.is_legal("3c3d"));
+        assert!(!position.is_legal("P*5e"));
+        assert!(!position.is_legal("7g7f7"));
+
+        let mut position = Position::from_sfen("startpos moves 7g7f 3c3d").unwrap();
+        assert!(position.is_legal("8h2b"));
+        assert!(position.play("8h2b+"));
+        assert_eq!(Color::White, position.side_to_move());
+        assert!(position.play("3a2b"));
+        assert!(position.play("B*5e"));
+        assert!(!position.is_legal("B*5e"));
+        assert!(position.is_legal("B*4e"));
+        assert!(Position::from_sfen("startpos moves 7g7f 7g7f").is_err());
+        assert!(Position::from_sfen("9/9 b - 1").is_err());
+    }
+
+    #[test]
+    fn declaration() {
+        let board = "+R+BGGSS3/PPPPK4/PPPPP4/9/9/9/8k/9/9 b";
+        let declared = |hands: &str| {
+            Position::from_sfen(&format!("{board} {hands} 1"))
+                .unwrap()
+                .can_declare_win()
+        };
+        assert!(declared("RB"));
+        assert!(!declared("-"));
+        let outside = Position::from_sfen("+R+BGGSS3/PPPP5/PPPPP4/4K4/9/9/8k/9/9 b RB 1").unwrap();
+        assert!(!outside.can_declare_win());
+    }
+
+    #[test]
+    fn sfen() {
+        assert_eq!(STARTPOS, Position::from_sfen("startpos").unwrap().to_sfen());
+        let position = Position::from_sfen("startpos moves 7g7f 3c3d 8h2b+ 3a2b").unwrap();
+        assert_eq!(
+            "lnsgkg1nl/1r5s1/pppppp1pp/6p2/9/2P6/PP1PPPPPP/7R1/LNSGKGSNL b Bb 5",
+            position.to_sfen()
+        );
+        let hands = "8k/9/9/9/9/9/9/9/+R3K4 w RG2P3p 10";
+        assert_eq!(hands, Position::from_sfen(hands).unwrap().to_sfen());
+    }
+
+    #[test]
+    fn restrictions() {
+        // Moving the gold would expose the king to the rook.
+        let pinned = Position::from_sfen("4r4/9/9/9/9/9/9/4G4/4K4 b - 1").unwrap();
+        assert!(!pinned.is_legal("5h4h"));
+        assert!(pinned.is_legal("5h5g"));
+
+        // Pawns must promote on the last rank, and not be dropped on a file with a pawn.
+        let pawns = Position::from_sfen("k8/4P4/9/9/9/9/9/P8/4K4 b P 1").unwrap();
+        assert!(!pawns.is_legal("5b5a"));
+        assert!(!pawns.is_legal("P*9e"));
+        assert!(!pawns.is_legal("P*1a"));
+        assert!(pawns.is_legal("P*1e"));
+
+        // Checkmating by a pawn drop is illegal, unlike by other drops.
+        let mate = Position::from_sfen("7nk/7n1/8G/9/9/9/9/9/4K4 b PG 1").unwrap();
+        assert!(!mate.is_legal("P*1b"));
+        assert!(mate.is_legal("G*1b"));
+        let escape = Position::from_sfen("8k/7n1/8G/9/9/9/9/9/4K4 b P 1").unwrap();
+        assert!(escape.is_legal("P*1b"));
+    }
+}
@@ -0,0 +1,742 @@
+//! Serving backend engines to a GUI as a single USI engine on stdin and stdout.
+//!
+//! `UsiServer` reads `GuiCommand`s and answers with `EngineCommand`s, delegating
+//! the searches to a `Backend`, so that compositions like a `FailoverEngine`
+//! can be registered in any GUI as a regular engine.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use usi::failover::FailoverEngine;
+//! use usi::server::UsiServer;
+//! use usi::EngineConfig;
+//!
+//! let engine = FailoverEngine::spawn(
+//!     EngineConfig::new("/path/to/experimental-engine"),
+//!     EngineConfig::new("/path/to/stable-engine"),
+//! )
+//! .unwrap();
+//! UsiServer::new(engine).name("Failover").run().unwrap();
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::error::Error;
+use crate::game::STARTPOS;
+use crate::pool::EnginePool;
+use crate::protocol::{
+    BestMoveParams, EngineCommand, GameOverKind, GuiCommand, IdParams, InfoParams, OptionParams,
+    ThinkParams,
+};
+use crate::threaded::{
+    is_text_info, multipv_index, SearchHandle, SearchOutcome, SearchResult, ThreadedEngine,
+};
+
+/// How long backends may take to answer `isready`.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a running search checks for `stop` and `ponderhit`.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The `stop` and `ponderhit` commands received while a backend searches.
+#[derive(Clone, Debug, Default)]
+pub struct SearchControl {
+    stop: CancellationToken,
+    ponderhit: CancellationToken,
+}
+
+impl SearchControl {
+    pub fn new() -> Self {
+        SearchControl::default()
+    }
+
+    /// Requests the search to stop.
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    /// Signals that the opponent played the move pondered on.
+    pub fn ponderhit(&self) {
+        self.ponderhit.cancel();
+    }
+
+    pub fn is_ponderhit(&self) -> bool {
+        self.ponderhit.is_cancelled()
+    }
+}
+
+/// Something which searches positions on behalf of a `UsiServer`.
+pub trait Backend: Send {
+    /// Returns the name reported with `id name`.
+    fn name(&self) -> String;
+
+    /// Returns the options declared to the GUI.
+    fn options(&self) -> Vec<OptionParams>;
+
+    fn set_option(&mut self, name: &str, value: Option<&str>);
+
+    fn new_game(&mut self);
+
+    fn game_over(&mut self, result: GameOverKind);
+
+    /// Blocks until the backend is ready to search.
+    fn is_ready(&mut self) -> Result<(), Error>;
+
+    /// Searches `position`, given like the argument of `position sfen`, and waits for the result.
+    ///
+    /// `on_info` is called with each `info` command to pass on to the GUI.
+    fn search(
+        &mut self,
+        position: &str,
+        params: ThinkParams,
+        control: &SearchControl,
+        on_info: &mut dyn FnMut(&[InfoParams]),
+    ) -> Result<SearchOutcome, Error>;
+}
+
+impl Backend for ThreadedEngine {
+    fn name(&self) -> String {
+        ThreadedEngine::name(self).to_string()
+    }
+
+    fn options(&self) -> Vec<OptionParams> {
+        self.info()
+            .map(|info| info.declared_options().to_vec())
+            .unwrap_or_default()
+    }
+
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        ThreadedEngine::set_option(self, name, value);
+    }
+
+    fn new_game(&mut self) {
+        ThreadedEngine::new_game(self, None);
+    }
+
+    fn game_over(&mut self, result: GameOverKind) {
+        ThreadedEngine::game_over(self, result);
+    }
+
+    fn is_ready(&mut self) -> Result<(), Error> {
+        self.wait_ready(READY_TIMEOUT)
+    }
+
+    fn search(
+        &mut self,
+        position: &str,
+        params: ThinkParams,
+        control: &SearchControl,
+        on_info: &mut dyn FnMut(&[InfoParams]),
+    ) -> Result<SearchOutcome, Error> {
+        self.set_position(position);
+        let handle = self.go(params)?;
+        follow(&handle, None, control, on_info).map(|result| result.outcome)
+    }
+}
+
+/// Serves a GUI with the engines of a pool, each search running on an engine
+/// acquired for it; commands are sent to every engine.
+impl Backend for EnginePool {
+    fn name(&self) -> String {
+        EnginePool::name(self).to_string()
+    }
+
+    fn options(&self) -> Vec<OptionParams> {
+        EnginePool::options(self).to_vec()
+    }
+
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        EnginePool::set_option(self, name, value);
+    }
+
+    fn new_game(&mut self) {
+        EnginePool::new_game(self);
+    }
+
+    fn game_over(&mut self, result: GameOverKind) {
+        EnginePool::game_over(self, result);
+    }
+
+    fn is_ready(&mut self) -> Result<(), Error> {
+        if self.live_engines() == 0 {
+            return Err(Error::Terminated);
+        }
+        Ok(())
+    }
+
+    fn search(
+        &mut self,
+        position: &str,
+        params: ThinkParams,
+        control: &SearchControl,
+        on_info: &mut dyn FnMut(&[InfoParams]),
+    ) -> Result<SearchOutcome, Error> {
+        let mut engine = self.acquire()?;
+        Backend::search(&mut *engine, position, params, control, on_info)
+    }
+}
+
+/// Waits up to `timeout` for the result of `handle`, passing on its `info`
+/// commands and the requests of `control`. The search is stopped on errors.
+pub(crate) fn follow(
+    handle: &SearchHandle,
+    timeout: Option<Duration>,
+    control: &SearchControl,
+    on_info: &mut dyn FnMut(&[InfoParams]),
+) -> Result<SearchResult, Error> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut stopped = false;
+    let mut hit = false;
+    loop {
+        while let Some(info) = handle.try_next_info() {
+            on_info(&info);
+        }
+        if !stopped && control.is_stopped() {
+            handle.stop();
+            stopped = true;
+        }
+        if !hit && control.is_ponderhit() {
+            handle.ponderhit();
+            hit = true;
+        }
+        let wait = deadline.map_or(POLL_INTERVAL, |d| {
+            d.saturating_duration_since(Instant::now())
+                .min(POLL_INTERVAL)
+        });
+        match handle.wait(wait) {
+            Ok(result) => {
+                while let Some(info) = handle.try_next_info() {
+                    on_info(&info);
+                }
+                return Ok(result);
+            }
+            Err(Error::Timeout) if deadline.is_none_or(|d| Instant::now() < d) => {}
+            Err(err) => {
+                handle.stop();
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Limits the `info` commands a `UsiServer` forwards to the GUI,
+/// for GUIs which cannot keep up with fast engines.
+///
+/// `info string` commands are always forwarded.
+///
+/// # Examples
+///
+/// ```
+/// use usi::server::InfoThrottle;
+///
+/// let throttle = InfoThrottle::new().per_second(10).deduplicate();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct InfoThrottle {
+    interval: Option<Duration>,
+    deduplicate: bool,
+}
+
+impl InfoThrottle {
+    /// Creates a throttle forwarding every `info` command.
+    pub fn new() -> Self {
+        InfoThrottle::default()
+    }
+
+    /// Forwards at most `n` `info` commands per second for each `multipv` index.
+    ///
+    /// A command held back is replaced by the next one, and the latest one
+    /// is forwarded once allowed, at the latest before `bestmove`.
+    #[must_use]
+    pub fn per_second(mut self, n: u32) -> Self {
+        self.interval = Some(Duration::from_secs(1) / n.max(1));
+        self
+    }
+
+    /// Drops `info` commands which only update the counters (`nodes`, `nps`,
+    /// `time`, `hashfull` and `cpuload`) of the last one forwarded for their `multipv` index.
+    #[must_use]
+    pub fn deduplicate(mut self) -> Self {
+        self.deduplicate = true;
+        self
+    }
+
+    /// Returns `true` if the next command of `lane` may be forwarded at `now`.
+    fn allows(&self, lane: &Lane, now: Instant) -> bool {
+        match (self.interval, lane.sent_at) {
+            (Some(interval), Some(sent_at)) => now.duration_since(sent_at) >= interval,
+            _ => true,
+        }
+    }
+}
+
+/// Applies an `InfoThrottle` to the `info` commands of one search.
+#[derive(Debug, Default)]
+struct InfoForwarder {
+    throttle: InfoThrottle,
+    lanes: BTreeMap<i32, Lane>,
+}
+
+/// The state of one `multipv` index.
+#[derive(Debug, Default)]
+struct Lane {
+    sent_at: Option<Instant>,
+    sent: Vec<InfoParams>,
+    pending: Option<Vec<InfoParams>>,
+}
+
+impl InfoForwarder {
+    fn new(throttle: InfoThrottle) -> Self {
+        InfoForwarder {
+            throttle,
+            lanes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the commands to forward on receiving `info` at `now`.
+    fn push(&mut self, info: &[InfoParams], now: Instant) -> Vec<Vec<InfoParams>> {
+        if is_text_info(info) {
+            return vec![info.to_vec()];
+        }
+        let index = multipv_index(info);
+        let mut forward = Vec::new();
+        for (_, lane) in self.lanes.iter_mut().filter(|(i, _)| **i != index) {
+            if lane.pending.is_some() && self.throttle.allows(lane, now) {
+                forward.extend(lane.send(now));
+            }
+        }
+
+        let lane = self.lanes.entry(index).or_default();
+        if self.throttle.deduplicate && without_counters(info) == without_counters(&lane.sent) {
+            return forward;
+        }
+        lane.pending = Some(info.to_vec());
+        if self.throttle.allows(lane, now) {
+            forward.extend(lane.send(now));
+        }
+        forward
+    }
+
+    /// Returns the commands held back, to forward before the result.
+    fn finish(&mut self, now: Instant) -> Vec<Vec<InfoParams>> {
+        self.lanes
+            .values_mut()
+            .filter_map(|lane| lane.send(now))
+            .collect()
+    }
+}
+
+impl Lane {
+    fn send(&mut self, now: Instant) -> Option<Vec<InfoParams>> {
+        let info = self.pending.take()?;
+        self.sent_at = Some(now);
+        self.sent = info.clone();
+        Some(info)
+    }
+}
+
+fn without_counters(info: &[InfoParams]) -> Vec<&InfoParams> {
+    info.iter()
+        .filter(|p| {
+            !matches!(
+                p,
+                InfoParams::Nodes(_)
+                    | InfoParams::Nps(_)
+                    | InfoParams::Time(_)
+                    | InfoParams::HashFull(_)
+                    | InfoParams::CpuLoad(_)
+            )
+        })
+        .collect()
+}
+
+type Output = Arc<Mutex<dyn Write + Send>>;
+
+/// A search running on its own thread, which hands the backend back once finished.
+struct RunningSearch<B> {
+    control: SearchControl,
+    thread: JoinHandle<B>,
+}
+
+/// Speaks USI as an engine, delegating to a `Backend`.
+///
+/// Commands are handled one after another; commands other than `stop`,
+/// `ponderhit`, `isready` and `quit` wait for the running search to finish.
+/// Lines which are not valid GUI commands are ignored.
+#[derive(Debug)]
+pub struct UsiServer<B> {
+    backend: B,
+    name: Option<String>,
+    author: Option<String>,
+    info_throttle: InfoThrottle,
+}
+
+impl<B: Backend + 'static> UsiServer<B> {
+    pub fn new(backend: B) -> Self {
+        UsiServer {
+            backend,
+            name: None,
+            author: None,
+            info_throttle: InfoThrottle::default(),
+        }
+    }
+
+    /// Sets the name reported with `id name`, instead of the name of the backend.
+    #[must_use]
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the author reported with `id author`.
+    #[must_use]
+    pub fn author(mut self, author: &str) -> Self {
+        self.author = Some(author.to_string());
+        self
+    }
+
+    /// Sets how `info` commands of the backend are forwarded. By default, all of them are.
+    #[must_use]
+    pub fn info_throttle(mut self, throttle: InfoThrottle) -> Self {
+        self.info_throttle = throttle;
+        self
+    }
+
+    /// Serves commands from stdin until `quit` or the end of the input.
+    pub fn run(self) -> Result<(), Error> {
+        self.run_with(io::stdin().lock(), io::stdout())
+    }
+
+    /// Serves commands read from `input`, writing the responses to `output`.
+    ///
+    /// Returns the first error of writing the output or of the backend getting ready.
+    pub fn run_with<R, W>(self, input: R, output: W) -> Result<(), Error>
+    where
+        R: BufRead,
+        W: Write + Send + 'static,
+    {
+        let output: Output = Arc::new(Mutex::new(output));
+        let mut backend = Some(self.backend);
+        let mut running: Option<RunningSearch<B>> = None;
+        let mut position = STARTPOS.to_string();
+
+        for line in input.lines() {
+            let command = match GuiCommand::parse(&line?) {
+                Ok(command) => command,
+                Err(_) => continue,
+            };
+            match command {
+                GuiCommand::Stop => {
+                    if let Some(search) = &running {
+                        search.control.stop();
+                    }
+                    continue;
+                }
+                GuiCommand::Ponderhit => {
+                    if let Some(search) = &running {
+                        search.control.ponderhit();
+                    }
+                    continue;
+                }
+                // Backends are busy while searching, but must be ready once done.
+                GuiCommand::IsReady if running.is_some() => {
+                    send(&output, &EngineCommand::ReadyOk)?;
+                    continue;
+                }
+                GuiCommand::Quit => break,
+                _ => {}
+            }
+
+            if let Some(search) = running.take() {
+                backend = Some(join(search)?);
+            }
+            let Some(b) = backend.as_mut() else {
+                break;
+            };
+            match command {
+                GuiCommand::Usi => {
+                    let name = self.name.clone().unwrap_or_else(|| b.name());
+                    send(&output, &EngineCommand::Id(IdParams::Name(name)))?;
+                    if let Some(author) = &self.author {
+                        send(
+                            &output,
+                            &EngineCommand::Id(IdParams::Author(author.clone())),
+                        )?;
+                    }
+                    for option in b.options() {
+                        send(&output, &EngineCommand::Option(option))?;
+                    }
+                    send(&output, &EngineCommand::UsiOk)?;
+                }
+                GuiCommand::IsReady => {
+                    b.is_ready()?;
+                    send(&output, &EngineCommand::ReadyOk)?;
+                }
+                GuiCommand::SetOption(name, value) => b.set_option(&name, value.as_deref()),
+                GuiCommand::UsiNewGame => b.new_game(),
+                GuiCommand::GameOver(result) => b.game_over(result),
+                GuiCommand::Position(p) => position = p,
+                GuiCommand::Go(params) => {
+                    if let Some(b) = backend.take() {
+                        running = Some(start(
+                            b,
+                            position.clone(),
+                            params,
+                            self.info_throttle,
+                            Arc::clone(&output),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(search) = running.take() {
+            search.control.stop();
+            join(search)?;
+        }
+        Ok(())
+    }
+}
+
+fn start<B: Backend + 'static>(
+    mut backend: B,
+    position: String,
+    params: ThinkParams,
+    throttle: InfoThrottle,
+    output: Output,
+) -> RunningSearch<B> {
+    let control = SearchControl::new();
+    let thread_control = control.clone();
+    let thread = thread::spawn(move || {
+        let mut forwarder = InfoForwarder::new(throttle);
+        let mut on_info = |info: &[InfoParams]| {
+            for info in forwarder.push(info, Instant::now()) {
+                let _ = send(&output, &EngineCommand::Info(info));
+            }
+        };
+        let result = backend.search(&position, params, &thread_control, &mut on_info);
+        for info in forwarder.finish(Instant::now()) {
+            let _ = send(&output, &EngineCommand::Info(info));
+        }
+        let response = match result {
+            Ok(SearchOutcome::BestMove(params)) => EngineCommand::BestMove(params),
+            Ok(SearchOutcome::Checkmate(params)) => EngineCommand::Checkmate(params),
+            Ok(SearchOutcome::DeclareWin) => EngineCommand::BestMove(BestMoveParams::Win),
+            Err(err) => {
+                let text = InfoParams::Text(format!("backend error: {err}"));
+                let _ = send(&output, &EngineCommand::Info(vec![text]));
+                EngineCommand::BestMove(BestMoveParams::Resign)
+            }
+        };
+        let _ = send(&output, &response);
+        backend
+    });
+    RunningSearch { control, thread }
+}
+
+fn join<B>(search: RunningSearch<B>) -> Result<B, Error> {
+    search
+        .thread
+        .join()
+        .map_err(|_| Error::HandlerPanic("the search thread panicked".to_string()))
+}
+
+fn send(output: &Output, command: &EngineCommand) -> Result<(), Error> {
+    let mut output = output.lock().map_err(|_| Error::IllegalOperation)?;
+    writeln!(output, "{command}")?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{OptionKind, ScoreKind};
+    use crate::threaded::EngineConfig;
+
+    struct Echo {
+        options: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Backend for Echo {
+        fn name(&self) -> String {
+            "Echo".to_string()
+        }
+
+        fn options(&self) -> Vec<OptionParams> {
+            vec![OptionParams {
+                name: "Depth".to_string(),
+                value: OptionKind::Spin {
+                    default: Some(3),
+                    min: Some(1),
+                    max: Some(10),
+                },
+            }]
+        }
+
+        fn set_option(&mut self, name: &str, value: Option<&str>) {
+            self.options
+                .lock()
+                .unwrap()
+                .push(format!("{name}={}", value.unwrap_or("")));
+        }
+
+        fn new_game(&mut self) {}
+
+        fn game_over(&mut self, _result: GameOverKind) {}
+
+        fn is_ready(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn search(
+            &mut self,
+            position: &str,
+            params: ThinkParams,
+            control: &SearchControl,
+            on_info: &mut dyn FnMut(&[InfoParams]),
+        ) -> Result<SearchOutcome, Error> {
+            on_info(&[InfoParams::Score(1, ScoreKind::CpExact)]);
+            if params == ThinkParams::new().infinite() {
+                while !control.is_stopped() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            let mv = position.rsplit(' ').next().unwrap_or("").to_string();
+            Ok(SearchOutcome::BestMove(BestMoveParams::MakeMove(mv, None)))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn info_throttle() {
+        let info = |depth: i32, nodes: u64, multipv: i32| {
+            vec![
+                InfoParams::Depth(depth, None),
+                InfoParams::MultiPv(multipv),
+                InfoParams::Nodes(nodes),
+            ]
+        };
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut forwarder = InfoForwarder::new(InfoThrottle::new().per_second(10).deduplicate());
+
+        assert_eq!(vec![info(1, 10, 1)], forwarder.push(&info(1, 10, 1), at(0)));
+        assert_eq!(
+            vec![info(1, 10, 2)],
+            forwarder.push(&info(1, 10, 2), at(10))
+        );
+        // Held back, then replaced by a newer one.
+        assert!(forwarder.push(&info(2, 20, 1), at(20)).is_empty());
+        assert!(forwarder.push(&info(3, 30, 1), at(30)).is_empty());
+        let text = vec![InfoParams::Text("hello".to_string())];
+        assert_eq!(vec![text.clone()], forwarder.push(&text, at(40)));
+        // Only the counters changed, but the held back command is due.
+        assert_eq!(
+            vec![info(3, 30, 1)],
+            forwarder.push(&info(1, 40, 2), at(150))
+        );
+        assert_eq!(
+            vec![info(2, 50, 2)],
+            forwarder.push(&info(2, 50, 2), at(160))
+        );
+        assert!(forwarder.push(&info(4, 60, 1), at(170)).is_empty());
+        assert_eq!(vec![info(4, 60, 1)], forwarder.finish(at(180)));
+        assert!(forwarder.finish(at(190)).is_empty());
+    }
+
+    #[test]
+    fn serve() {
+        let options = Arc::new(Mutex::new(Vec::new()));
+        let backend = Echo {
+            options: Arc::clone(&options),
+        };
+        let output = Buffer::default();
+        let input = "usi\n\
+                     setoption name Depth value 5\n\
+                     isready\n\
+                     bogus\n\
+                     position startpos moves 7g7f 3c3d\n\
+                     go btime 1000 wtime 1000\n\
+                     position startpos moves 2g2f\n\
+                     go infinite\n\
+                     stop\n\
+                     quit\n";
+        UsiServer::new(backend)
+            .author("usi-rs")
+            .run_with(input.as_bytes(), output.clone())
+            .unwrap();
+
+        assert_eq!(vec!["Depth=5"], *options.lock().unwrap());
+        assert_eq!(
+            "id name Echo\n\
+             id author usi-rs\n\
+             option name Depth type spin default 3 min 1 max 10\n\
+             usiok\n\
+             readyok\n\
+             info score cp 1\n\
+             bestmove 3c3d\n\
+             info score cp 1\n\
+             bestmove 2g2f\n",
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serve_pool() {
+        let script = "while read -r cmd rest; do case $cmd in \
+            usi) echo id name Mock; echo usiok;; \
+            isready) echo readyok;; \
+            setoption) opt=$rest;; \
+            go) echo \"info string $opt\"; echo bestmove 7g7f;; \
+            quit) exit 0;; \
+            esac; done";
+        let config = EngineConfig::new("sh")
+            .working_dir(".")
+            .arg("-c")
+            .arg(script);
+        let pool = EnginePool::spawn(&config, 2).unwrap();
+        let output = Buffer::default();
+        let input = "usi\n\
+                     setoption name Depth value 5\n\
+                     isready\n\
+                     position startpos\n\
+                     go btime 1000 wtime 1000\n\
+                     quit\n";
+        UsiServer::new(pool)
+            .run_with(input.as_bytes(), output.clone())
+            .unwrap();
+
+        assert_eq!(
+            "id name Mock\n\
+             usiok\n\
+             readyok\n\
+             info string name Depth value 5\n\
+             bestmove 7g7f\n",
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap()
+        );
+    }
+}
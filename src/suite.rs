@@ -0,0 +1,338 @@
+//! Running an engine on problem suites, such as collections of tactics.
+//!
+//! Suites are read from an EPD-like text format with one problem per line:
+//! the four SFEN fields followed by `;`-terminated operations.
+//! `bm` lists the expected best moves, `am` moves to avoid and `id` names the problem.
+//! Empty lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # Mate in one.
+//! 4k4/9/4P4/9/9/9/9/9/9 b G 1 bm G*5b; id "drop";
+//! ```
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::suite::ProblemSuite;
+//! use usi::{EngineConfig, ThinkParams, ThreadedEngine};
+//!
+//! let suite = ProblemSuite::from_file("/path/to/tactics.epd").unwrap();
+//! let mut engine = ThreadedEngine::spawn(EngineConfig::new("/path/to/engine")).unwrap();
+//! let report = suite
+//!     .run(&mut engine, &ThinkParams::new().byoyomi(Duration::from_secs(5)))
+//!     .unwrap();
+//! println!("{}", report);
+//! ```
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::analysis::{analyze_position_with, Analysis};
+use crate::error::Error;
+use crate::protocol::ThinkParams;
+use crate::threaded::ThreadedEngine;
+
+/// A position with the moves that solve it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Problem {
+    /// Name of the problem, from the `id` operation.
+    pub id: Option<String>,
+    /// The position in SFEN notation.
+    pub position: String,
+    /// Moves solving the problem. Any of them is accepted.
+    pub best_moves: Vec<String>,
+    /// Moves failing the problem. Any other move is accepted.
+    pub avoid_moves: Vec<String>,
+}
+
+impl Problem {
+    /// Returns `true` if playing `mv` solves the problem.
+    pub fn is_solution(&self, mv: &str) -> bool {
+        (self.best_moves.is_empty() || self.best_moves.iter().any(|m| m == mv))
+            && !self.avoid_moves.iter().any(|m| m == mv)
+    }
+}
+
+impl FromStr for Problem {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(Error::IllegalConfig(format!("incomplete SFEN: {}", s)));
+        }
+
+        let mut problem = Problem {
+            position: fields[..4].join(" "),
+            ..Default::default()
+        };
+        for operation in fields[4..].join(" ").split(';') {
+            let mut operands = operation.split_whitespace();
+            match operands.next() {
+                Some("bm") => problem.best_moves.extend(operands.map(str::to_string)),
+                Some("am") => problem.avoid_moves.extend(operands.map(str::to_string)),
+                Some("id") => {
+                    let id = operation.trim().trim_start_matches("id").trim();
+                    problem.id = Some(id.trim_matches('"').to_string());
+                }
+                _ => {}
+            }
+        }
+        if problem.best_moves.is_empty() && problem.avoid_moves.is_empty() {
+            return Err(Error::IllegalConfig(format!(
+                "no bm or am operation: {}",
+                s
+            )));
+        }
+        Ok(problem)
+    }
+}
+
+/// A collection of problems.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProblemSuite {
+    pub problems: Vec<Problem>,
+}
+
+impl ProblemSuite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a problem.
+    #[must_use]
+    pub fn problem(mut self, problem: Problem) -> Self {
+        self.problems.push(problem);
+        self
+    }
+
+    /// Parses a suite, one problem per line.
+    ///
+    /// Errors name the line of the first problem which could not be parsed.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut suite = ProblemSuite::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let problem = line.parse().map_err(|err| match err {
+                Error::IllegalConfig(msg) => {
+                    Error::IllegalConfig(format!("line {}: {}", i + 1, msg))
+                }
+                err => err,
+            })?;
+            suite.problems.push(problem);
+        }
+        Ok(suite)
+    }
+
+    /// Loads a suite from a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(path)?;
+        Self::parse(&s)
+    }
+
+    pub fn len(&self) -> usize {
+        self.problems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Searches every problem with `limits` and checks the moves played.
+    ///
+    /// Returns the first error of a search.
+    pub fn run(
+        &self,
+        engine: &mut ThreadedEngine,
+        limits: &ThinkParams,
+    ) -> Result<SuiteReport, Error> {
+        let mut results = Vec::with_capacity(self.problems.len());
+        for problem in &self.problems {
+            let mut found = None;
+            let analysis = analyze_position_with(
+                engine,
+                &problem.position,
+                limits,
+                || false,
+                |info, elapsed| {
+                    if let Some(mv) = info.pv.first() {
+                        if !problem.is_solution(mv) {
+                            found = None;
+                        } else if found.is_none() {
+                            found = Some(elapsed);
+                        }
+                    }
+                },
+            )?;
+            results.push(ProblemResult::new(problem.clone(), analysis, found));
+        }
+        Ok(SuiteReport { results })
+    }
+}
+
+/// The outcome of one problem of a suite.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProblemResult {
+    pub problem: Problem,
+    pub analysis: Analysis,
+    /// Time from `go` until the engine settled on a solution, if it played one.
+    ///
+    /// This is when the principal variation last started with a solving move,
+    /// or the time of the `bestmove` if no principal variation was reported.
+    pub time_to_solution: Option<Duration>,
+}
+
+impl ProblemResult {
+    fn new(problem: Problem, analysis: Analysis, found: Option<Duration>) -> Self {
+        let solved = analysis
+            .outcome
+            .move_str()
+            .is_some_and(|mv| problem.is_solution(&mv));
+        ProblemResult {
+            time_to_solution: solved.then(|| found.unwrap_or(analysis.elapsed)),
+            problem,
+            analysis,
+        }
+    }
+
+    pub fn is_solved(&self) -> bool {
+        self.time_to_solution.is_some()
+    }
+}
+
+/// Results of `ProblemSuite::run`, displayed with one line per problem and a summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SuiteReport {
+    pub results: Vec<ProblemResult>,
+}
+
+impl SuiteReport {
+    pub fn solved(&self) -> impl Iterator<Item = &ProblemResult> {
+        self.results.iter().filter(|r| r.is_solved())
+    }
+
+    pub fn unsolved(&self) -> impl Iterator<Item = &ProblemResult> {
+        self.results.iter().filter(|r| !r.is_solved())
+    }
+
+    /// Returns the total time to solution of the solved problems.
+    pub fn total_time(&self) -> Duration {
+        self.solved().filter_map(|r| r.time_to_solution).sum()
+    }
+}
+
+impl fmt::Display for SuiteReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, result) in self.results.iter().enumerate() {
+            let id = result
+                .problem
+                .id
+                .clone()
+                .unwrap_or_else(|| (i + 1).to_string());
+            let played = result
+                .analysis
+                .outcome
+                .move_str()
+                .unwrap_or_else(|| "win".to_string());
+            match result.time_to_solution {
+                Some(time) => writeln!(f, "{}: solved {} in {}ms", id, played, time.as_millis())?,
+                None => writeln!(
+                    f,
+                    "{}: unsolved, played {}, expected {}",
+                    id,
+                    played,
+                    expected(&result.problem)
+                )?,
+            }
+        }
+        write!(
+            f,
+            "solved {}/{} in {}ms",
+            self.solved().count(),
+            self.results.len(),
+            self.total_time().as_millis()
+        )
+    }
+}
+
+fn expected(problem: &Problem) -> String {
+    if problem.best_moves.is_empty() {
+        format!("not {}", problem.avoid_moves.join(" "))
+    } else {
+        problem.best_moves.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{BestMoveParams, SearchInfo};
+    use crate::threaded::SearchOutcome;
+
+    #[test]
+    fn parse() {
+        let suite = ProblemSuite::parse(
+            "# comment\n\
+             \n\
+             4k4/9/4P4/9/9/9/9/9/9 b G 1 bm G*5b; id \"mate 1\";\n\
+             lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1 bm 7g7f 2g2f; am 5i5h;",
+        )
+        .unwrap();
+        assert_eq!(2, suite.len());
+        assert_eq!(Some("mate 1".to_string()), suite.problems[0].id);
+        assert_eq!("4k4/9/4P4/9/9/9/9/9/9 b G 1", suite.problems[0].position);
+        assert_eq!(vec!["G*5b"], suite.problems[0].best_moves);
+        assert_eq!(vec!["7g7f", "2g2f"], suite.problems[1].best_moves);
+        assert_eq!(vec!["5i5h"], suite.problems[1].avoid_moves);
+        assert!(suite.problems[1].is_solution("2g2f"));
+        assert!(!suite.problems[1].is_solution("5i5h"));
+
+        let err = ProblemSuite::parse("\n4k4/9/4P4/9/9/9/9/9/9 b G 1; id \"x\";").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        assert!(ProblemSuite::parse("4k4/9/4P4/9/9/9/9/9/9 b").is_err());
+    }
+
+    #[test]
+    fn report() {
+        let analysis = |mv: &str| Analysis {
+            outcome: SearchOutcome::BestMove(BestMoveParams::MakeMove(mv.to_string(), None)),
+            info: SearchInfo::default(),
+            elapsed: Duration::from_millis(900),
+        };
+        let problem = "4k4/9/4P4/9/9/9/9/9/9 b G 1 bm G*5b; id \"drop\";"
+            .parse::<Problem>()
+            .unwrap();
+        let report = SuiteReport {
+            results: vec![
+                ProblemResult::new(
+                    problem.clone(),
+                    analysis("G*5b"),
+                    Some(Duration::from_millis(120)),
+                ),
+                ProblemResult::new(problem.clone(), analysis("G*5b"), None),
+                ProblemResult::new(
+                    Problem {
+                        id: None,
+                        ..problem
+                    },
+                    analysis("5c5b+"),
+                    Some(Duration::from_millis(50)),
+                ),
+            ],
+        };
+        assert_eq!(2, report.solved().count());
+        assert_eq!(
+            "drop: solved G*5b in 120ms\n\
+             drop: solved G*5b in 900ms\n\
+             3: unsolved, played 5c5b+, expected G*5b\n\
+             solved 2/3 in 1020ms",
+            report.to_string()
+        );
+    }
+}
@@ -9,12 +9,9 @@
 //! use usi::threaded::{ThreadedEngine, EngineConfig};
 //! use std::time::Duration;
 //!
-//! let config = EngineConfig {
-//!     path: "/path/to/engine".to_string(),
-//!     args: vec![],
-//!     working_dir: Some("/path/to/working/dir".to_string()),
-//!     pre_handshake_options: vec![],
-//! };
+//! let config = EngineConfig::new("/path/to/engine")
+//!     .working_dir("/path/to/working/dir")
+//!     .handshake_timeout(Duration::from_secs(30));
 //!
 //! let mut engine = ThreadedEngine::spawn(config).unwrap();
 //!
@@ -22,7 +19,7 @@
 //! engine.set_position("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1");
 //!
 //! // Start search with byoyomi
-//! engine.go_byoyomi(Duration::from_secs(5));
+//! engine.go_byoyomi(Duration::from_secs(5)).unwrap();
 //!
 //! // Poll for move (non-blocking)
 //! loop {
@@ -34,27 +31,520 @@
 //! }
 //! ```
 
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::analysis::BatchAnalysis;
+use crate::cancel::CancellationToken;
 use crate::error::Error;
+use crate::game::STARTPOS;
+use crate::memory::check_hash_size;
+#[cfg(feature = "sandbox")]
+use crate::process::Sandbox;
+use crate::process::{
+    check_eval_path, check_option_path, resolve_option_path, Capabilities, DropPolicy, EngineInfo,
+    LineEnding, OutputLimits, RetryPolicy, StartupCallback, StartupEvent, StdioBuffers,
+    UsiEngineHandler,
+};
 use crate::protocol::*;
-use crate::process::UsiEngineHandler;
+use crate::registry::{AppliedOptions, OptionProfile};
+use crate::rules::{split_moves, Position};
+
+/// How long `spawn` waits for the handshake when no handshake timeout is configured.
+const DEFAULT_HANDSHAKE_WAIT: Duration = Duration::from_secs(10);
+
+/// How long before the deadline `go_with_deadline` sends `stop` when no margin is configured.
+const DEFAULT_DEADLINE_MARGIN: Duration = Duration::from_millis(100);
+
+/// How often waiting for a search checks for cancellation.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time limit of the warm-up search, for engines ignoring `go nodes`.
+const WARM_UP_TIME: Duration = Duration::from_secs(1);
+
+/// How long `warm_up` waits for the warm-up search, including the initialization it triggers.
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A mate in one (G*5b), probing whether an engine supports mate searches.
+const MATE_PROBE: &str = "4k4/9/4P4/9/9/9/9/9/9 b G2r2b3g4s4n4l17p 1";
+
+/// Time allowed beyond its limit for an internal search to be answered.
+const PROBE_MARGIN: Duration = Duration::from_secs(1);
+
+/// Process creation flag preventing a console window from being created.
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// Identifier of the next search started by any `ThreadedEngine` of the process.
+static NEXT_SEARCH_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Suffix of the next temporary working directory created by the process.
+static NEXT_TEMP_DIR: AtomicU64 = AtomicU64::new(1);
 
 /// Configuration for spawning a threaded USI engine
+///
+/// The fields can be set directly or through the builder methods.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use usi::registry::OptionProfile;
+/// use usi::{EngineConfig, StderrMode};
+///
+/// let config = EngineConfig::new("/path/to/engine")
+///     .arg("--threads=4")
+///     .env("OMP_NUM_THREADS", "1")
+///     .stderr(StderrMode::Null)
+///     .ready_timeout(Duration::from_secs(60))
+///     .option_profile(OptionProfile::new().set("USI_Hash", "1024"));
+/// assert_eq!(vec!["--threads=4".to_string()], config.args);
+/// ```
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct EngineConfig {
     /// Path to the engine executable
     pub path: String,
+    /// Executables tried in order if `path` cannot be spawned or fails the handshake,
+    /// e.g. builds for older instruction sets
+    pub fallback_paths: Vec<String>,
     /// Arguments to pass to the engine executable
     pub args: Vec<String>,
     /// Working directory for the engine (defaults to engine's parent directory)
     pub working_dir: Option<String>,
     /// Options to send before the USI handshake (for engines like Fairy-Stockfish)
     pub pre_handshake_options: Vec<(String, Option<String>)>,
+    /// Environment variables set for the engine in addition to the inherited ones
+    pub env: Vec<(String, String)>,
+    /// Where the engine's stderr goes
+    pub stderr: StderrMode,
+    /// How long to wait for `usiok` (waits up to 10 seconds for the engine name otherwise)
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "millis", skip_serializing_if = "Option::is_none")
+    )]
+    pub handshake_timeout: Option<Duration>,
+    /// How long to wait for `readyok` after the handshake (waits indefinitely otherwise)
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "millis", skip_serializing_if = "Option::is_none")
+    )]
+    pub ready_timeout: Option<Duration>,
+    /// Maximum number of `info` commands queued per search; the oldest are dropped
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub info_capacity: Option<usize>,
+    /// Send `isready` after this long without commands while the engine is idle,
+    /// keeping remote transports open; the engine is considered dead and shut down
+    /// if it does not answer before the next interval elapses
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "millis", skip_serializing_if = "Option::is_none")
+    )]
+    pub keep_alive: Option<Duration>,
+    /// Option profiles applied in order after the handshake, matched against
+    /// the declared option names like `OptionProfile::partition`, which skips
+    /// the options the engine does not declare
+    pub option_profiles: Vec<OptionProfile>,
+    /// Evaluation options such as `EvalFile`, checked by `spawn` like
+    /// `ThreadedEngine::set_eval_option` and set before the first `isready`
+    pub eval_options: Vec<(String, String)>,
+    /// Parsers for engine-specific output lines, whose events are
+    /// delivered through `ThreadedEngine::poll_extension_event`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub protocol_extensions: ProtocolExtensions,
+    /// Number of nodes of the warm-up search run by `spawn`, see `ThreadedEngine::warm_up`
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub warm_up_nodes: Option<u64>,
+    /// Run the engine in a new temporary directory, removed once the engine is
+    /// shut down, instead of `working_dir`, so that concurrent instances don't
+    /// share their log, book or learning files
+    pub temp_working_dir: bool,
+    /// Check the `USI_Hash` size set by the option profiles against the available
+    /// memory before spawning, see `ThreadedEngine::set_hash`
+    pub check_memory: bool,
+    /// Number of attempts to spawn the engine and complete the handshake when
+    /// failing with an `Error::is_transient` error (values below 1 are treated as 1)
+    pub spawn_attempts: u32,
+    /// Delay before the first respawn, doubling after every attempt
+    #[cfg_attr(feature = "serde", serde(with = "millis::required"))]
+    pub spawn_backoff: Duration,
+    /// Buffering of the pipes to the engine
+    pub stdio_buffers: StdioBuffers,
+    /// Line terminator written after each command
+    pub line_ending: LineEnding,
+    /// Spawn the engine without a console window on Windows, so that GUI applications
+    /// don't flash one; ignored on other platforms
+    pub hide_console: bool,
+    /// Timeouts and retries of the requests awaiting an answer from the engine,
+    /// see `ThreadedEngine::set_command_policy`. An `IsReady` policy replaces
+    /// `ready_timeout` for the first `isready`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub command_policies: Vec<(RequestKind, RetryPolicy)>,
+    /// How long before its deadline a search started by `ThreadedEngine::go_with_deadline`
+    /// is stopped (100 milliseconds otherwise)
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "millis", skip_serializing_if = "Option::is_none")
+    )]
+    pub deadline_margin: Option<Duration>,
+    /// Safety margin subtracted from the `btime`, `wtime` and `byoyomi` sent to the
+    /// engine, covering the latency of the process and channels, unless the
+    /// `ThinkParams` set one, see `ThreadedEngine::measure_overhead`
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "millis", skip_serializing_if = "Option::is_none")
+    )]
+    pub time_margin: Option<Duration>,
+    /// Restrictions applied to the engine process
+    #[cfg(feature = "sandbox")]
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub sandbox: Option<Sandbox>,
+}
+
+impl EngineConfig {
+    pub fn new(path: &str) -> Self {
+        EngineConfig {
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds an executable to try if the previous ones fail to start.
+    #[must_use]
+    pub fn fallback_path(mut self, path: &str) -> Self {
+        self.fallback_paths.push(path.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    #[must_use]
+    pub fn working_dir(mut self, dir: &str) -> Self {
+        self.working_dir = Some(dir.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn pre_handshake_option(mut self, name: &str, value: Option<&str>) -> Self {
+        self.pre_handshake_options
+            .push((name.to_string(), value.map(|v| v.to_string())));
+        self
+    }
+
+    #[must_use]
+    pub fn stderr(mut self, mode: StderrMode) -> Self {
+        self.stderr = mode;
+        self
+    }
+
+    #[must_use]
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    #[must_use]
+    pub fn info_capacity(mut self, n: usize) -> Self {
+        self.info_capacity = Some(n);
+        self
+    }
+
+    #[must_use]
+    pub fn option_profile(mut self, profile: OptionProfile) -> Self {
+        self.option_profiles.push(profile);
+        self
+    }
+
+    #[must_use]
+    pub fn eval_option(mut self, name: &str, path: &str) -> Self {
+        self.eval_options.push((name.to_string(), path.to_string()));
+        self
+    }
+
+    #[must_use]
+    pub fn protocol_extension<E: ProtocolExtension + 'static>(mut self, extension: E) -> Self {
+        self.protocol_extensions = mem::take(&mut self.protocol_extensions).with(extension);
+        self
+    }
+
+    #[must_use]
+    pub fn warm_up_nodes(mut self, nodes: u64) -> Self {
+        self.warm_up_nodes = Some(nodes);
+        self
+    }
+
+    #[must_use]
+    pub fn temp_working_dir(mut self) -> Self {
+        self.temp_working_dir = true;
+        self
+    }
+
+    #[must_use]
+    pub fn check_memory(mut self) -> Self {
+        self.check_memory = true;
+        self
+    }
+
+    /// Retries spawning the engine up to `attempts` times in total on transient
+    /// failures, sleeping `backoff` before the first retry and doubling it afterwards.
+    #[must_use]
+    pub fn spawn_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.spawn_attempts = attempts;
+        self.spawn_backoff = backoff;
+        self
+    }
+
+    /// Sets the timeout and retries of `kind` requests, see `ThreadedEngine::set_command_policy`.
+    #[must_use]
+    pub fn command_policy(mut self, kind: RequestKind, policy: RetryPolicy) -> Self {
+        self.command_policies.retain(|(k, _)| *k != kind);
+        self.command_policies.push((kind, policy));
+        self
+    }
+
+    /// Sets how long before its deadline `ThreadedEngine::go_with_deadline` sends `stop`.
+    #[must_use]
+    pub fn deadline_margin(mut self, margin: Duration) -> Self {
+        self.deadline_margin = Some(margin);
+        self
+    }
+
+    /// Sets the safety margin subtracted from the times sent with `go`, see `ThinkParams::margin`.
+    #[must_use]
+    pub fn time_margin(mut self, margin: Duration) -> Self {
+        self.time_margin = Some(margin);
+        self
+    }
+
+    #[must_use]
+    pub fn stdio_buffers(mut self, buffers: StdioBuffers) -> Self {
+        self.stdio_buffers = buffers;
+        self
+    }
+
+    #[must_use]
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    #[must_use]
+    pub fn hide_console(mut self) -> Self {
+        self.hide_console = true;
+        self
+    }
+
+    /// Runs the engine with the restrictions of `sandbox`.
+    #[cfg(feature = "sandbox")]
+    #[must_use]
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    fn ready_policy(&self) -> RetryPolicy {
+        self.command_policies
+            .iter()
+            .find(|(kind, _)| *kind == RequestKind::IsReady)
+            .map(|(_, policy)| policy.clone())
+            .or_else(|| self.ready_timeout.map(|t| RetryPolicy::new().timeout(t)))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn spawn_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new()
+            .attempts(self.spawn_attempts)
+            .backoff(self.spawn_backoff)
+    }
+
+    /// Checks that `instances` engines with the `USI_Hash` size of the option
+    /// profiles fit into the available memory, if `check_memory` is set.
+    pub(crate) fn verify_memory(&self, instances: usize) -> Result<(), Error> {
+        let hash = self
+            .option_profiles
+            .iter()
+            .rev()
+            .find_map(|p| p.get("USI_Hash"))
+            .and_then(|v| v.trim().parse().ok());
+        match hash {
+            Some(hash) if self.check_memory => check_hash_size(hash, instances),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses a configuration from a TOML document.
+    ///
+    /// ```
+    /// use usi::EngineConfig;
+    ///
+    /// let config = EngineConfig::from_toml_str(
+    ///     r#"
+    ///     path = "/opt/engines/fairy-stockfish"
+    ///     pre_handshake_options = [["Protocol", "usi"]]
+    ///
+    ///     [[option_profiles]]
+    ///     USI_Hash = "1024"
+    ///     "#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(Some("1024"), config.option_profiles[0].get("USI_Hash"));
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn from_toml_str(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+
+    /// Loads a configuration from a TOML file.
+    #[cfg(feature = "serde")]
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(path)?;
+        Self::from_toml_str(&s)
+    }
+
+    /// Parses a configuration from a JSON document with the same fields as
+    /// `from_toml_str`.
+    ///
+    /// ```
+    /// use usi::EngineConfig;
+    ///
+    /// let config = EngineConfig::from_json_str(
+    ///     r#"{
+    ///         "path": "/opt/engines/fairy-stockfish",
+    ///         "pre_handshake_options": [["Protocol", "usi"]],
+    ///         "option_profiles": [{"USI_Hash": "1024"}]
+    ///     }"#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(Some("1024"), config.option_profiles[0].get("USI_Hash"));
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn from_json_str(s: &str) -> Result<Self, Error> {
+        serde_json::from_str(s).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+
+    /// Loads a configuration from a JSON file.
+    #[cfg(feature = "serde")]
+    pub fn from_json_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(path)?;
+        Self::from_json_str(&s)
+    }
+
+    /// Returns `path` followed by the fallback paths.
+    fn candidates(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.path.as_str()).chain(self.fallback_paths.iter().map(String::as_str))
+    }
+
+    /// Builds the command spawning the executable at `path` in `work_dir`.
+    fn command(&self, path: &str, work_dir: &Path) -> Result<Command, Error> {
+        let mut command = Command::new(path);
+        command
+            .args(&self.args)
+            .current_dir(work_dir)
+            .envs(self.env.iter().map(|(k, v)| (k, v)));
+        match &self.stderr {
+            StderrMode::Inherit => {}
+            StderrMode::Null => {
+                command.stderr(Stdio::null());
+            }
+            StderrMode::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                command.stderr(file);
+            }
+        }
+        #[cfg(windows)]
+        if self.hide_console {
+            use std::os::windows::process::CommandExt;
+
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        #[cfg(feature = "sandbox")]
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.apply(&mut command)?;
+        }
+        Ok(command)
+    }
+}
+
+/// Where the stderr of an engine spawned by `ThreadedEngine` goes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum StderrMode {
+    /// Share the stderr of the current process.
+    #[default]
+    Inherit,
+    /// Discard the output.
+    Null,
+    /// Append the output to a file, relative to the current directory of the process.
+    File(PathBuf),
+}
+
+/// Serializes optional durations as milliseconds, e.g. `ready_timeout = 60000` in TOML.
+#[cfg(feature = "serde")]
+pub(crate) mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        d.map(|d| d.as_millis() as u64).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(d)?.map(Duration::from_millis))
+    }
+
+    /// Serializes durations which are not optional as milliseconds.
+    pub mod required {
+        use std::time::Duration;
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+            (d.as_millis() as u64).serialize(s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+            Ok(Duration::from_millis(u64::deserialize(d)?))
+        }
+    }
 }
 
 /// Commands that can be sent to a threaded USI engine.
@@ -73,259 +563,3390 @@ pub enum EngineRequest {
     Go(ThinkParams),
     /// Stop the current search (stop)
     Stop,
+    /// The opponent played the expected move during `go ponder` (ponderhit)
+    PonderHit,
+    /// Notify the engine of the result of the game (gameover X)
+    GameOver(GameOverKind),
+    /// Start a new game (usinewgame)
+    NewGame,
     /// Quit the engine (quit)
     Quit,
+    /// Send an engine-specific command line as is
+    Raw(String),
 }
 
-/// A threaded wrapper around `UsiEngineHandler` that provides non-blocking access.
-///
-/// This spawns the engine in a background thread and uses channels for communication,
-/// allowing the caller to send commands and poll for moves without blocking.
-pub struct ThreadedEngine {
-    command_sender: Sender<EngineRequest>,
-    move_receiver: Arc<Mutex<Receiver<String>>>,
-    engine_name: String,
+impl EngineRequest {
+    /// Returns the kind of the request.
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            EngineRequest::SetOption { .. } => RequestKind::SetOption,
+            EngineRequest::IsReady => RequestKind::IsReady,
+            EngineRequest::Position { .. } => RequestKind::Position,
+            EngineRequest::Go(_) => RequestKind::Go,
+            EngineRequest::Stop => RequestKind::Stop,
+            EngineRequest::PonderHit => RequestKind::PonderHit,
+            EngineRequest::GameOver(_) => RequestKind::GameOver,
+            EngineRequest::NewGame => RequestKind::NewGame,
+            EngineRequest::Quit => RequestKind::Quit,
+            EngineRequest::Raw(_) => RequestKind::Raw,
+        }
+    }
 }
 
-impl ThreadedEngine {
-    /// Spawn a new threaded USI engine.
-    ///
-    /// This spawns the engine process and performs the USI handshake in a background thread.
-    /// Returns immediately with a handle for sending commands and receiving moves.
-    pub fn spawn(config: EngineConfig) -> Result<Self, Error> {
-        let path = PathBuf::from(&config.path);
-        let work_dir = config
-            .working_dir
-            .map(PathBuf::from)
-            .or_else(|| path.parent().map(|p| p.to_path_buf()))
-            .ok_or_else(|| Error::EngineIo(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Could not determine engine working directory",
-            )))?;
+/// The kind of an `EngineRequest`, without its arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    SetOption,
+    IsReady,
+    Position,
+    Go,
+    Stop,
+    PonderHit,
+    GameOver,
+    NewGame,
+    Quit,
+    Raw,
+}
 
-        // Create channels for communication
-        let (command_sender, command_receiver) = channel::<EngineRequest>();
-        let (move_sender, move_receiver) = channel::<String>();
-        let (name_sender, name_receiver) = channel::<String>();
-        let move_receiver = Arc::new(Mutex::new(move_receiver));
+/// Internal messages processed by the engine thread.
+#[derive(Debug)]
+enum ThreadCommand {
+    /// A request issued by the caller.
+    Request(EngineRequest),
+    /// The listen thread received the result of the search of a generation.
+    SearchFinished(u64),
+    /// New limits on the output read from the engine.
+    OutputLimits(OutputLimits),
+    /// New patterns classifying `info string` lines as warnings.
+    WarningPatterns(WarningPatterns),
+    /// The search of `generation` has to be stopped at `stop_at`.
+    Deadline { generation: u64, stop_at: Instant },
+    /// The caller stopped the search of a generation through its `SearchHandle`.
+    StopSearch(u64),
+    /// The engine closed its output, so no more search results will arrive.
+    EngineClosed,
+    /// The caller dropped the `ThreadedEngine` with `DropPolicy::Detach`.
+    Detach,
+}
 
-        let engine_path = config.path.clone();
-        let engine_args = config.args.clone();
-        let pre_handshake_options = config.pre_handshake_options.clone();
+/// Outcome of the startup reported by the engine thread.
+#[derive(Debug)]
+struct Startup {
+    name: String,
+    /// The full engine information, if the handshake succeeded.
+    info: Option<EngineInfo>,
+    applied_options: AppliedOptions,
+    /// The executable which was spawned.
+    path: PathBuf,
+}
 
-        thread::spawn(move || {
-            Self::engine_thread(
-                engine_path,
-                engine_args,
-                work_dir,
-                pre_handshake_options,
-                command_receiver,
-                move_sender,
-                name_sender,
-            );
-        });
+impl Startup {
+    fn failed(name: &str, path: PathBuf) -> Self {
+        Startup {
+            name: name.to_string(),
+            info: None,
+            applied_options: AppliedOptions::default(),
+            path,
+        }
+    }
+}
 
-        // Wait for engine name (with timeout)
-        let engine_name = name_receiver
-            .recv_timeout(Duration::from_secs(10))
-            .unwrap_or_else(|_| "Unknown Engine".to_string());
+/// Endpoints of the channels used by the engine thread.
+struct ThreadChannels {
+    commands: Receiver<ThreadCommand>,
+    wake: Sender<ThreadCommand>,
+    moves: Sender<SearchResult>,
+    info: Sender<Startup>,
+    errors: Sender<Error>,
+    warnings: Sender<EngineWarning>,
+    extensions: Sender<ExtensionEvent>,
+}
 
-        Ok(Self {
-            command_sender,
-            move_receiver,
-            engine_name,
-        })
-    }
+/// Determines what `ThreadedEngine::go` does while a previous search is still running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum GoPolicy {
+    /// Return `Error::SearchInProgress` without sending anything.
+    Error,
+    /// Send `stop` to end the running search, then start the new one.
+    /// The result of the stopped search is reported as stale.
+    #[default]
+    StopThenGo,
+    /// Hold the new search, and every request issued after it, until the running search finishes.
+    Queue,
+}
 
-    /// Returns the engine name reported during the USI handshake.
-    pub fn name(&self) -> &str {
-        &self.engine_name
-    }
+/// The final response of the engine to a `go` request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SearchOutcome {
+    /// A `bestmove` response to a regular search.
+    BestMove(BestMoveParams),
+    /// A `checkmate` response to a mate search.
+    Checkmate(CheckmateParams),
+    /// A `bestmove win` response, declaring a win by entering king (nyūgyoku).
+    ///
+    /// `ThreadedEngine` reports `bestmove win` as this variant rather than `BestMove`.
+    DeclareWin,
+}
 
-    /// Set the current position using SFEN notation.
-    pub fn set_position(&mut self, sfen: &str) {
-        let _ = self.command_sender.send(EngineRequest::Position {
-            sfen: sfen.to_string(),
-        });
+impl SearchOutcome {
+    /// Returns the move to play as a string, using `"resign"` when the engine
+    /// gave up or found no mate. Returns `None` for a win declaration.
+    pub fn move_str(&self) -> Option<String> {
+        match self {
+            SearchOutcome::BestMove(BestMoveParams::MakeMove(mv, _)) => Some(mv.clone()),
+            SearchOutcome::BestMove(BestMoveParams::Resign) => Some("resign".to_string()),
+            SearchOutcome::BestMove(BestMoveParams::Win) | SearchOutcome::DeclareWin => None,
+            SearchOutcome::Checkmate(CheckmateParams::Mate(moves)) => moves.first().cloned(),
+            SearchOutcome::Checkmate(_) => Some("resign".to_string()),
+        }
     }
+}
 
-    /// Start a search with the given parameters.
-    pub fn go(&mut self, params: ThinkParams) {
-        let _ = self.command_sender.send(EngineRequest::Go(params));
-    }
+/// The result of a search, tagged with the generation of its `go` request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SearchResult {
+    /// Generation of the `go` request which produced this result, starting from 1.
+    pub generation: u64,
+    /// Identifier of the search, see `SearchHandle::search_id`.
+    pub search_id: u64,
+    /// `true` if another `go` request was issued before this result arrived.
+    pub stale: bool,
+    pub outcome: SearchOutcome,
+    /// Time from the `go` request to the arrival of this result.
+    pub elapsed: Duration,
+    /// Time from sending `go` to the engine to the arrival of this result,
+    /// which excludes the time the request was held, e.g. by `GoPolicy::Queue`.
+    pub engine_elapsed: Duration,
+    /// The latest `time` reported by the engine in the `info` commands of the search.
+    pub reported_time: Option<Duration>,
+}
 
-    /// Start a search with byoyomi time control.
-    pub fn go_byoyomi(&mut self, time: Duration) {
-        self.go(ThinkParams::new().byoyomi(time));
+/// An `info` command of a search, returned by `SearchHandle::try_next_timed_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimedInfo {
+    /// Identifier of the search, see `SearchHandle::search_id`.
+    pub search_id: u64,
+    /// Time from the `go` request to the arrival of the command.
+    /// For coalesced commands, this is when the first of them arrived.
+    pub elapsed: Duration,
+    pub params: Vec<InfoParams>,
+}
+
+/// A snapshot of the latest search state, returned by `ThreadedEngine::current_search`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchSnapshot {
+    /// Generation of the search.
+    pub generation: u64,
+    /// Identifier of the search, see `SearchHandle::search_id`.
+    pub search_id: u64,
+    /// Latest information reported for the principal variation.
+    pub info: SearchInfo,
+    /// Time since the search was sent to the engine, up to its result once finished.
+    pub elapsed: Duration,
+    /// `true` once the result of the search has arrived.
+    pub finished: bool,
+}
+
+/// The search most recently sent to the engine.
+#[derive(Debug)]
+struct CurrentSearch {
+    generation: u64,
+    search_id: u64,
+    started: Instant,
+    finished: Option<Instant>,
+    info: SearchInfo,
+}
+
+impl CurrentSearch {
+    fn snapshot(&self) -> SearchSnapshot {
+        SearchSnapshot {
+            generation: self.generation,
+            search_id: self.search_id,
+            info: self.info.clone(),
+            elapsed: self
+                .finished
+                .unwrap_or_else(Instant::now)
+                .duration_since(self.started),
+            finished: self.finished.is_some(),
+        }
     }
+}
 
-    /// Start an infinite search.
-    pub fn go_infinite(&mut self) {
-        self.go(ThinkParams::new().infinite());
+/// State shared between a `ThreadedEngine` and its engine thread.
+#[derive(Debug, Default)]
+struct Shared {
+    /// Kinds of the requests issued by the caller but not sent to the engine yet.
+    queued: Mutex<Vec<RequestKind>>,
+    /// Number of `readyok` responses received after the startup handshake,
+    /// excluding the answers to keep-alive `isready`s.
+    ready_acks: Mutex<u64>,
+    /// `true` while a keep-alive `isready` has not been answered.
+    keep_alive_pending: AtomicBool,
+    ready_cond: Condvar,
+    /// Generation of the most recent `go` request issued by the caller.
+    latest_generation: AtomicU64,
+    /// Generations of `go` requests forwarded to the engine but not answered yet.
+    pending_searches: Mutex<VecDeque<u64>>,
+    /// Number of search results received.
+    completed_searches: AtomicU64,
+    go_policy: Mutex<GoPolicy>,
+    /// Per-search state observed through `SearchHandle`s, keyed by generation.
+    searches: Mutex<HashMap<u64, SearchState>>,
+    search_cond: Condvar,
+    current: Mutex<Option<CurrentSearch>>,
+    /// Window within which `info` commands are coalesced, if enabled.
+    info_window: Mutex<Option<Duration>>,
+    /// Set once the engine has quit or closed its output.
+    terminated: AtomicBool,
+    /// Process identifier of the engine, or 0 if it could not be spawned.
+    pid: AtomicU32,
+    /// Name of the option most recently set by the caller.
+    last_option: Mutex<Option<String>>,
+    /// Names of options the engine reported as unknown.
+    rejected_options: Mutex<Vec<String>>,
+    /// Maximum number of `info` commands queued per search.
+    info_capacity: Option<usize>,
+    /// The result of the most recent search.
+    latest_result: Mutex<Option<SearchResult>>,
+    /// Generation of a ponder search abandoned after a miss, whose result is stale.
+    abandoned_generation: AtomicU64,
+    /// Identifiers of the searches requested but not answered yet, keyed by generation.
+    search_ids: Mutex<HashMap<u64, u64>>,
+    /// Delays between the elapsed time of searches and the `time` reported by the engine.
+    lag: Mutex<Overhead>,
+}
+
+/// Information collected for a single search.
+#[derive(Debug)]
+struct SearchState {
+    search_id: u64,
+    started: Instant,
+    finished: Option<Instant>,
+    infos: VecDeque<QueuedInfo>,
+    result: Option<SearchResult>,
+    /// Set if the search finished without a result.
+    abandoned: Option<Abandoned>,
+}
+
+/// Why a search finished without a result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Abandoned {
+    /// The search was stopped while queued by `GoPolicy::Queue`.
+    Stopped,
+    /// The engine terminated before answering.
+    Terminated,
+}
+
+impl Abandoned {
+    fn error(self) -> Error {
+        match self {
+            Abandoned::Stopped => Error::Cancelled,
+            Abandoned::Terminated => Error::Terminated,
+        }
     }
+}
 
-    /// Start a mate search.
-    pub fn go_mate(&mut self, timeout: Option<Duration>) {
-        let params = match timeout {
-            Some(t) => ThinkParams::new().mate(MateParam::Timeout(t)),
-            None => ThinkParams::new().mate(MateParam::Infinite),
-        };
-        self.go(params);
+/// An `info` command waiting to be taken through a `SearchHandle`.
+#[derive(Debug)]
+struct QueuedInfo {
+    received: Instant,
+    params: Vec<InfoParams>,
+}
+
+impl SearchState {
+    fn new(search_id: u64) -> Self {
+        SearchState {
+            search_id,
+            started: Instant::now(),
+            finished: None,
+            infos: VecDeque::new(),
+            result: None,
+            abandoned: None,
+        }
     }
 
-    /// Poll for a move result (non-blocking).
+    /// Queues an `info` command.
     ///
-    /// Returns `Some(move_string)` if the engine has produced a move,
-    /// `None` if still thinking or no move available.
-    pub fn poll_move(&mut self) -> Option<String> {
-        if let Ok(receiver) = self.move_receiver.lock() {
-            match receiver.try_recv() {
-                Ok(mv) => Some(mv),
-                Err(TryRecvError::Empty) => None,
-                Err(TryRecvError::Disconnected) => None,
+    /// With a coalescing `window`, the command replaces the latest queued command
+    /// of the same `multipv` index if that one arrived within the window. It takes
+    /// the place of the replaced command, unless an `info string` command was queued
+    /// in between, in which case it is queued last to keep the order of arrival.
+    /// `info string` commands are never coalesced.
+    fn push_info(&mut self, info: &[InfoParams], window: Option<Duration>) {
+        let now = Instant::now();
+        if let Some(window) = window.filter(|_| !is_text_info(info)) {
+            let index = multipv_index(info);
+            let queued = self
+                .infos
+                .iter()
+                .rposition(|q| !is_text_info(&q.params) && multipv_index(&q.params) == index)
+                .filter(|&i| now.duration_since(self.infos[i].received) < window);
+            if let Some(i) = queued {
+                if !self
+                    .infos
+                    .iter()
+                    .skip(i + 1)
+                    .any(|q| is_text_info(&q.params))
+                {
+                    self.infos[i].params = info.to_vec();
+                    return;
+                }
+                self.infos.remove(i);
             }
-        } else {
-            None
         }
+        self.infos.push_back(QueuedInfo {
+            received: now,
+            params: info.to_vec(),
+        });
     }
 
-    /// Stop the current search.
-    pub fn stop(&mut self) {
-        let _ = self.command_sender.send(EngineRequest::Stop);
+    fn pop_info(&mut self) -> Option<Vec<InfoParams>> {
+        self.infos.pop_front().map(|q| q.params)
     }
 
-    /// Set an engine option.
-    ///
-    /// Sends a `setoption` command to the engine. Call `is_ready()` after
-    /// setting options to ensure the engine has processed them.
-    pub fn set_option(&mut self, name: &str, value: Option<&str>) {
-        let _ = self.command_sender.send(EngineRequest::SetOption {
-            name: name.to_string(),
-            value: value.map(|v| v.to_string()),
-        });
+    fn pop_timed_info(&mut self) -> Option<TimedInfo> {
+        let started = self.started;
+        let search_id = self.search_id;
+        self.infos.pop_front().map(|q| TimedInfo {
+            search_id,
+            elapsed: q.received.saturating_duration_since(started),
+            params: q.params,
+        })
     }
+}
 
-    /// Wait for the engine to be ready.
-    ///
-    /// Sends an `isready` command to ensure the engine has processed
-    /// all previous commands.
-    pub fn is_ready(&mut self) {
-        let _ = self.command_sender.send(EngineRequest::IsReady);
+pub(crate) fn multipv_index(info: &[InfoParams]) -> i32 {
+    info.iter()
+        .find_map(|p| match p {
+            InfoParams::MultiPv(n) => Some(*n),
+            _ => None,
+        })
+        .unwrap_or(1)
+}
+
+/// Returns the search time reported by an `info` command.
+fn reported_time(info: &[InfoParams]) -> Option<Duration> {
+    info.iter().find_map(|p| match p {
+        InfoParams::Time(t) => Some(*t),
+        _ => None,
+    })
+}
+
+pub(crate) fn is_text_info(info: &[InfoParams]) -> bool {
+    info.iter().any(|p| matches!(p, InfoParams::Text(_)))
+}
+
+impl Shared {
+    /// Returns `true` if a `go` request has not been answered yet.
+    fn is_searching(&self) -> bool {
+        self.latest_generation.load(Ordering::SeqCst)
+            > self.completed_searches.load(Ordering::SeqCst)
     }
 
-    /// Engine thread that manages the USI engine process
-    fn engine_thread(
-        engine_path: String,
-        engine_args: Vec<String>,
-        work_dir: PathBuf,
-        pre_handshake_options: Vec<(String, Option<String>)>,
-        command_receiver: Receiver<EngineRequest>,
-        move_sender: Sender<String>,
-        name_sender: Sender<String>,
-    ) {
-        // Spawn the engine process
-        let mut handler = match UsiEngineHandler::spawn(&engine_path, &work_dir, &engine_args) {
-            Ok(h) => h,
-            Err(_) => {
-                let _ = name_sender.send("Engine Failed".to_string());
-                return;
+    /// Records an option rejected by the engine, attributing rejections
+    /// which do not name the option to the one set last.
+    fn record_rejection(&self, line: &str) {
+        let Some(mut name) = rejected_option(line) else {
+            return;
+        };
+        if name.is_empty() {
+            match self.last_option.lock().ok().and_then(|last| last.clone()) {
+                Some(last) => name = last,
+                None => return,
             }
+        }
+        if let Ok(mut rejected) = self.rejected_options.lock() {
+            rejected.push(name);
+        }
+    }
+
+    /// Removes `name` from the rejected options, returning `true` if it was there.
+    fn take_rejection(&self, name: &str) -> bool {
+        let Ok(mut rejected) = self.rejected_options.lock() else {
+            return false;
         };
+        let before = rejected.len();
+        rejected.retain(|n| !n.eq_ignore_ascii_case(name));
+        rejected.len() != before
+    }
 
-        // Send pre-handshake options (for Fairy-Stockfish, etc.)
-        for (name, value) in pre_handshake_options {
-            let _ = handler.send_command_before_handshake(&GuiCommand::SetOption(name, value));
-        }
+    fn go_policy(&self) -> GoPolicy {
+        self.go_policy.lock().map(|p| *p).unwrap_or_default()
+    }
 
-        // Get engine info (initiates handshake)
-        let engine_name = if let Ok(info) = handler.get_info() {
-            info.name().to_string()
-        } else {
-            "Unknown".to_string()
+    /// Tags a search outcome with the generation of the oldest unanswered `go`.
+    fn tag(&self, outcome: SearchOutcome) -> SearchResult {
+        let generation = self
+            .pending_searches
+            .lock()
+            .ok()
+            .and_then(|mut pending| pending.pop_front())
+            .unwrap_or(0);
+        self.completed_searches.fetch_add(1, Ordering::SeqCst);
+        let now = Instant::now();
+        let mut forwarded = None;
+        let mut reported_time = None;
+        if let Ok(mut current) = self.current.lock() {
+            if let Some(c) = current.as_mut().filter(|c| c.generation == generation) {
+                c.finished = Some(now);
+                forwarded = Some(c.started);
+                reported_time = c.info.time;
+            }
+        }
+        let search_id = self
+            .search_ids
+            .lock()
+            .ok()
+            .and_then(|mut ids| ids.remove(&generation))
+            .unwrap_or(0);
+        let engine_elapsed = forwarded.map_or(Duration::ZERO, |s| now.duration_since(s));
+        let mut result = SearchResult {
+            generation,
+            search_id,
+            stale: generation < self.latest_generation.load(Ordering::SeqCst)
+                || generation == self.abandoned_generation.load(Ordering::SeqCst),
+            outcome,
+            elapsed: engine_elapsed,
+            engine_elapsed,
+            reported_time,
         };
-        let _ = name_sender.send(engine_name);
+        if let Ok(mut searches) = self.searches.lock() {
+            // Prefer the time of the request, as `SearchHandle::elapsed` does.
+            if let Some(state) = searches.get_mut(&generation) {
+                result.elapsed = now.duration_since(state.started);
+                state.finished = Some(now);
+                state.result = Some(result.clone());
+            }
+            self.search_cond.notify_all();
+        }
+        if let Ok(mut latest) = self.latest_result.lock() {
+            *latest = Some(result.clone());
+        }
+        result
+    }
 
-        // Prepare engine
-        if handler.prepare().is_err() {
-            return;
+    /// Starts tracking a search forwarded to the engine.
+    fn start_search(&self, generation: u64) {
+        if let Ok(mut pending) = self.pending_searches.lock() {
+            pending.push_back(generation);
         }
+        let search_id = self
+            .search_ids
+            .lock()
+            .ok()
+            .and_then(|ids| ids.get(&generation).copied())
+            .unwrap_or(0);
+        if let Ok(mut current) = self.current.lock() {
+            *current = Some(CurrentSearch {
+                generation,
+                search_id,
+                started: Instant::now(),
+                finished: None,
+                info: SearchInfo::default(),
+            });
+        }
+    }
 
-        // Send usinewgame
-        if handler.send_command(&GuiCommand::UsiNewGame).is_err() {
-            return;
+    /// Finishes the search of `generation` without a result, e.g. because it was
+    /// never sent to the engine.
+    fn abandon_search(&self, generation: u64, reason: Abandoned) {
+        self.completed_searches.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut ids) = self.search_ids.lock() {
+            ids.remove(&generation);
+        }
+        if let Ok(mut searches) = self.searches.lock() {
+            if let Some(state) = searches.get_mut(&generation) {
+                state.finished = Some(Instant::now());
+                state.abandoned = Some(reason);
+            }
+            self.search_cond.notify_all();
         }
+    }
 
-        // Start listening to engine output
-        let output_sender = move_sender.clone();
-        if handler
-            .listen(move |output| -> Result<(), std::io::Error> {
-                match output.response() {
-                    Some(EngineCommand::BestMove(params)) => {
-                        match params {
-                            BestMoveParams::MakeMove(mv, _ponder) => {
-                                let _ = output_sender.send(mv.clone());
-                            }
-                            BestMoveParams::Resign => {
-                                let _ = output_sender.send("resign".to_string());
-                            }
-                            BestMoveParams::Win => {
-                                // Engine claims win, no move to send
-                            }
-                        }
+    /// Sends a request to the engine thread, recording it as queued until it is sent.
+    fn submit(&self, sender: &Sender<ThreadCommand>, request: EngineRequest) -> Result<(), Error> {
+        let kind = request.kind();
+        if let Ok(mut queued) = self.queued.lock() {
+            queued.push(kind);
+        }
+        sender.send(ThreadCommand::Request(request)).map_err(|_| {
+            self.dequeue(kind);
+            Error::Terminated
+        })
+    }
+
+    /// Records that the oldest queued request of `kind` was sent to the engine.
+    fn dequeue(&self, kind: RequestKind) {
+        if let Ok(mut queued) = self.queued.lock() {
+            if let Some(i) = queued.iter().position(|k| *k == kind) {
+                queued.remove(i);
+            }
+        }
+    }
+
+    /// Marks the engine as terminated and wakes up threads waiting for search results.
+    ///
+    /// Searches without a result are finished with `Error::Terminated`, before the
+    /// engine is marked as terminated so that waiting threads observe both at once.
+    fn terminate(&self) {
+        if let Ok(mut queued) = self.queued.lock() {
+            queued.clear();
+        }
+        let mut searches = self.searches.lock().ok();
+        if let Some(searches) = &mut searches {
+            let now = Instant::now();
+            for state in searches.values_mut() {
+                if state.result.is_none() && state.abandoned.is_none() {
+                    state.finished = Some(now);
+                    state.abandoned = Some(Abandoned::Terminated);
+                }
+            }
+        }
+        self.terminated.store(true, Ordering::SeqCst);
+        self.search_cond.notify_all();
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.terminated.load(Ordering::SeqCst)
+    }
+
+    /// Records an `info` command for the search currently running in the engine.
+    fn record_info(&self, info: &[InfoParams]) {
+        let generation = match self.pending_searches.lock() {
+            Ok(pending) => match pending.front() {
+                Some(g) => *g,
+                None => return,
+            },
+            Err(_) => return,
+        };
+        if let Ok(mut current) = self.current.lock() {
+            if let Some(c) = current.as_mut().filter(|c| c.generation == generation) {
+                c.info.update(info);
+                if let Some(time) = reported_time(info) {
+                    let lag = c.started.elapsed().saturating_sub(time);
+                    if let Ok(mut stats) = self.lag.lock() {
+                        stats.record(lag);
+                    }
+                }
+            }
+        }
+        let window = self.info_window.lock().ok().and_then(|w| *w);
+        if let Ok(mut searches) = self.searches.lock() {
+            if let Some(state) = searches.get_mut(&generation) {
+                state.push_info(info, window);
+                if let Some(capacity) = self.info_capacity {
+                    while state.infos.len() > capacity {
+                        state.infos.pop_front();
+                    }
+                }
+                self.search_cond.notify_all();
+            }
+        }
+    }
+}
+
+/// A token returned by `ThreadedEngine::is_ready` which completes
+/// once the engine answers the corresponding `isready` with `readyok`.
+#[derive(Debug, Clone)]
+pub struct ReadyToken {
+    target: u64,
+    shared: Arc<Shared>,
+}
+
+impl ReadyToken {
+    /// Returns `true` if the engine has already answered `readyok`.
+    pub fn is_acknowledged(&self) -> bool {
+        self.shared
+            .ready_acks
+            .lock()
+            .map(|acks| *acks >= self.target)
+            .unwrap_or(false)
+    }
+
+    /// Blocks until the engine answers `readyok` or `timeout` elapses.
+    ///
+    /// Returns `Error::Timeout` if no answer arrived in time.
+    pub fn wait(&self, timeout: Duration) -> Result<(), Error> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    /// Blocks until the engine answers `readyok` or `deadline` passes, if given.
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<(), Error> {
+        let mut acks = self
+            .shared
+            .ready_acks
+            .lock()
+            .map_err(|_| Error::IllegalOperation)?;
+        while *acks < self.target {
+            acks = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    self.shared
+                        .ready_cond
+                        .wait_timeout(acks, deadline - now)
+                        .map_err(|_| Error::IllegalOperation)?
+                        .0
+                }
+                None => self
+                    .shared
+                    .ready_cond
+                    .wait(acks)
+                    .map_err(|_| Error::IllegalOperation)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// A handle to a single search started by `ThreadedEngine::go`.
+///
+/// The handle observes the `info` commands and the final result of its own
+/// search only, independently from other searches and from `poll_move`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use usi::{EngineConfig, ThinkParams, ThreadedEngine};
+///
+/// let mut engine = ThreadedEngine::spawn(EngineConfig {
+///     path: "/path/to/engine".to_string(),
+///     ..Default::default()
+/// })
+/// .unwrap();
+///
+/// let search = engine.go(ThinkParams::new().byoyomi(Duration::from_secs(3))).unwrap();
+/// for info in search.infos() {
+///     println!("{:?} after {:?}", info, search.elapsed());
+/// }
+/// let result = search.wait(Duration::from_secs(5)).unwrap();
+/// println!("{:?}", result.outcome);
+/// ```
+#[derive(Debug)]
+pub struct SearchHandle {
+    generation: u64,
+    search_id: u64,
+    shared: Arc<Shared>,
+    command_sender: Sender<ThreadCommand>,
+}
+
+impl SearchHandle {
+    /// Returns the generation of this search.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the identifier of this search, which is unique among the searches
+    /// of all engines of the process and increases with each `go` request.
+    ///
+    /// It is included in the results, infos and snapshots of the search,
+    /// e.g. to correlate the logs of concurrent games or of an `EnginePool`.
+    /// With the `tracing` feature, the engine thread also records it in a `search`
+    /// span lasting from the `go` command to the best move.
+    pub fn search_id(&self) -> u64 {
+        self.search_id
+    }
+
+    /// Returns the time elapsed since the search was requested,
+    /// up to the arrival of its result once finished.
+    pub fn elapsed(&self) -> Duration {
+        self.with_state(|state| {
+            state
+                .finished
+                .map(|f| f.duration_since(state.started))
+                .unwrap_or_else(|| state.started.elapsed())
+        })
+        .unwrap_or_default()
+    }
+
+    /// Returns `true` once the result of this search has arrived,
+    /// or once it was abandoned without a result.
+    pub fn is_finished(&self) -> bool {
+        self.with_state(|state| state.result.is_some() || state.abandoned.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Returns the result of this search if it has arrived (non-blocking).
+    pub fn poll(&self) -> Option<SearchResult> {
+        self.with_state(|state| state.result.clone()).flatten()
+    }
+
+    /// Blocks until the result of this search arrives or `timeout` elapses.
+    ///
+    /// Returns `Error::Timeout` if the result did not arrive in time,
+    /// `Error::Terminated` if the engine terminated before answering,
+    /// or `Error::Cancelled` if the search was stopped while queued.
+    pub fn wait(&self, timeout: Duration) -> Result<SearchResult, Error> {
+        self.wait_until(Instant::now() + timeout, None)
+    }
+
+    /// Like `wait`, but stops the search and returns `Error::Cancelled`
+    /// once `cancel` is cancelled.
+    pub fn wait_or_cancel(
+        &self,
+        timeout: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<SearchResult, Error> {
+        let result = self.wait_until(Instant::now() + timeout, Some(cancel));
+        if let Err(Error::Cancelled) = result {
+            self.stop();
+        }
+        result
+    }
+
+    fn wait_until(
+        &self,
+        deadline: Instant,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<SearchResult, Error> {
+        let mut searches = self
+            .shared
+            .searches
+            .lock()
+            .map_err(|_| Error::IllegalOperation)?;
+        loop {
+            if let Some(state) = searches.get(&self.generation) {
+                if let Some(result) = &state.result {
+                    return Ok(result.clone());
+                }
+                if let Some(abandoned) = state.abandoned {
+                    return Err(abandoned.error());
+                }
+            }
+            if self.shared.is_terminated() {
+                return Err(Error::Terminated);
+            }
+            if cancel.is_some_and(CancellationToken::is_cancelled) {
+                return Err(Error::Cancelled);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            let wait = match cancel {
+                Some(_) => (deadline - now).min(CANCEL_POLL_INTERVAL),
+                None => deadline - now,
+            };
+            searches = self
+                .shared
+                .search_cond
+                .wait_timeout(searches, wait)
+                .map_err(|_| Error::IllegalOperation)?
+                .0;
+        }
+    }
+
+    /// Takes the next `info` command received for this search (non-blocking).
+    pub fn try_next_info(&self) -> Option<Vec<InfoParams>> {
+        self.shared
+            .searches
+            .lock()
+            .ok()?
+            .get_mut(&self.generation)?
+            .pop_info()
+    }
+
+    /// Takes the next `info` command received for this search together with
+    /// the time it arrived at, relative to the `go` request (non-blocking).
+    pub fn try_next_timed_info(&self) -> Option<TimedInfo> {
+        self.shared
+            .searches
+            .lock()
+            .ok()?
+            .get_mut(&self.generation)?
+            .pop_timed_info()
+    }
+
+    /// Returns a blocking iterator over the `info` commands of this search,
+    /// which ends once the search has finished and all its infos were taken.
+    pub fn infos(&self) -> SearchInfos<'_> {
+        SearchInfos { handle: self }
+    }
+
+    /// Stops this search if it has not finished yet.
+    ///
+    /// A search queued by `GoPolicy::Queue` is removed from the queue instead,
+    /// and finishes without a result. Other searches are not affected.
+    pub fn stop(&self) {
+        if !self.is_finished() {
+            let _ = self
+                .command_sender
+                .send(ThreadCommand::StopSearch(self.generation));
+        }
+    }
+
+    /// Turns this search into a regular one if it was started with `go ponder`
+    /// and has not finished yet.
+    pub fn ponderhit(&self) {
+        if !self.is_finished() {
+            let _ = self
+                .shared
+                .submit(&self.command_sender, EngineRequest::PonderHit);
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&SearchState) -> T) -> Option<T> {
+        self.shared
+            .searches
+            .lock()
+            .ok()?
+            .get(&self.generation)
+            .map(f)
+    }
+}
+
+/// A temporary working directory, removed with its contents when dropped.
+#[derive(Debug)]
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn create() -> Result<Self, Error> {
+        let name = format!(
+            "usi-{}-{}",
+            std::process::id(),
+            NEXT_TEMP_DIR.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir(&path)?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Marks the engine as terminated when dropped, i.e. when the thread owning it exits.
+struct TerminationGuard(Arc<Shared>);
+
+impl Drop for TerminationGuard {
+    fn drop(&mut self) {
+        self.0.terminate();
+    }
+}
+
+/// Owned by the `listen` hook, marks the engine as terminated like `TerminationGuard`
+/// and wakes up the engine thread once the engine has closed its output.
+struct ListenGuard {
+    shared: Arc<Shared>,
+    wake: Sender<ThreadCommand>,
+}
+
+impl Drop for ListenGuard {
+    fn drop(&mut self) {
+        self.shared.terminate();
+        let _ = self.wake.send(ThreadCommand::EngineClosed);
+    }
+}
+
+impl Drop for SearchHandle {
+    fn drop(&mut self) {
+        if let Ok(mut searches) = self.shared.searches.lock() {
+            searches.remove(&self.generation);
+        }
+    }
+}
+
+/// A blocking iterator over the `info` commands of a search.
+/// See `SearchHandle::infos`.
+#[derive(Debug)]
+pub struct SearchInfos<'a> {
+    handle: &'a SearchHandle,
+}
+
+impl Iterator for SearchInfos<'_> {
+    type Item = Vec<InfoParams>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let shared = &self.handle.shared;
+        let mut searches = shared.searches.lock().ok()?;
+        loop {
+            let state = searches.get_mut(&self.handle.generation)?;
+            if let Some(info) = state.pop_info() {
+                return Some(info);
+            }
+            if state.result.is_some() || state.abandoned.is_some() || shared.is_terminated() {
+                return None;
+            }
+            searches = shared.search_cond.wait(searches).ok()?;
+        }
+    }
+}
+
+/// A game position tracked by `ThreadedEngine`.
+#[derive(Clone, Debug)]
+struct TrackedPosition {
+    /// The initial position in SFEN.
+    start: String,
+    initial: Position,
+    moves: Vec<String>,
+    /// Moves taken back by `undo`, the most recent last.
+    undone: Vec<String>,
+    current: Position,
+}
+
+impl TrackedPosition {
+    fn new(position: &str) -> Result<Self, Error> {
+        let (start, moves) = split_moves(position);
+        let initial = Position::from_sfen(start)?;
+        let mut tracked = TrackedPosition {
+            start: start.to_string(),
+            initial: initial.clone(),
+            moves: Vec::new(),
+            undone: Vec::new(),
+            current: initial,
+        };
+        for mv in moves {
+            tracked.push(mv)?;
+        }
+        Ok(tracked)
+    }
+
+    fn push(&mut self, mv: &str) -> Result<(), Error> {
+        if !self.current.play(mv) {
+            return Err(Error::IllegalMove(mv.to_string()));
+        }
+        self.moves.push(mv.to_string());
+        self.undone.clear();
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Option<String> {
+        let mv = self.moves.pop()?;
+        // Positions cannot be unplayed, so the remaining moves are replayed.
+        self.current = self.initial.clone();
+        for m in &self.moves {
+            self.current.play(m);
+        }
+        self.undone.push(mv.clone());
+        Some(mv)
+    }
+
+    fn redo(&mut self) -> Option<String> {
+        let mv = self.undone.pop()?;
+        self.current.play(&mv);
+        self.moves.push(mv.clone());
+        Some(mv)
+    }
+
+    /// Returns the argument of `position sfen`.
+    fn argument(&self) -> String {
+        if self.moves.is_empty() {
+            return self.start.clone();
+        }
+        format!("{} moves {}", self.start, self.moves.join(" "))
+    }
+
+    /// Returns the argument of `position sfen` after `mv`,
+    /// or `None` if `mv` is illegal.
+    fn argument_after(&self, mv: &str) -> Option<String> {
+        if !self.current.is_legal(mv) {
+            return None;
+        }
+        let mut argument = self.argument();
+        argument.push_str(if self.moves.is_empty() {
+            " moves "
+        } else {
+            " "
+        });
+        argument.push_str(mv);
+        Some(argument)
+    }
+}
+
+/// A `go ponder` search started by the automatic ponder manager.
+#[derive(Debug)]
+struct PonderSearch {
+    /// The opponent move the engine expects.
+    expected: String,
+    handle: SearchHandle,
+    /// `true` once `ponderhit` was sent.
+    hit: bool,
+}
+
+/// A threaded wrapper around `UsiEngineHandler` that provides non-blocking access.
+///
+/// This spawns the engine in a background thread and uses channels for communication,
+/// allowing the caller to send commands and poll for moves without blocking.
+#[derive(Debug)]
+pub struct ThreadedEngine {
+    command_sender: Sender<ThreadCommand>,
+    move_receiver: Arc<Mutex<Receiver<SearchResult>>>,
+    error_receiver: Arc<Mutex<Receiver<Error>>>,
+    warning_receiver: Arc<Mutex<Receiver<EngineWarning>>>,
+    warning_sender: Sender<EngineWarning>,
+    extension_receiver: Arc<Mutex<Receiver<ExtensionEvent>>>,
+    engine_name: String,
+    info: Option<EngineInfo>,
+    applied_options: AppliedOptions,
+    path: PathBuf,
+    work_dir: PathBuf,
+    shared: Arc<Shared>,
+    ready_requested: u64,
+    drop_policy: DropPolicy,
+    tracked: Option<TrackedPosition>,
+    auto_ponder: bool,
+    /// Limits of the most recent regular search, reused by ponder searches.
+    ponder_params: Option<ThinkParams>,
+    ponder: Option<PonderSearch>,
+    /// Settings of the engine thread, applied again by `replace`.
+    output_limits: Option<OutputLimits>,
+    warning_patterns: Option<WarningPatterns>,
+    command_policies: Vec<(RequestKind, RetryPolicy)>,
+    deadline_margin: Duration,
+    time_margin: Option<Duration>,
+}
+
+/// Statistics of the latency of an engine, returned by `ThreadedEngine::measure_overhead`
+/// and `ThreadedEngine::lag_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Overhead {
+    /// Number of measurements.
+    pub samples: u64,
+    /// Sum of the measurements.
+    pub total: Duration,
+    /// Smallest measurement.
+    pub min: Duration,
+    /// Largest measurement.
+    pub max: Duration,
+    /// Most recent measurement.
+    pub latest: Duration,
+}
+
+impl Overhead {
+    fn record(&mut self, latency: Duration) {
+        self.min = if self.samples == 0 {
+            latency
+        } else {
+            self.min.min(latency)
+        };
+        self.max = self.max.max(latency);
+        self.latest = latency;
+        self.total = self.total.saturating_add(latency);
+        self.samples += 1;
+    }
+
+    /// Returns the average measurement, or zero if none was made.
+    pub fn average(&self) -> Duration {
+        match u32::try_from(self.samples) {
+            Ok(0) => Duration::ZERO,
+            Ok(n) => self.total / n,
+            Err(_) => self.total.div_f64(self.samples as f64),
+        }
+    }
+}
+
+impl ThreadedEngine {
+    /// Spawn a new threaded USI engine.
+    ///
+    /// This spawns the engine process and performs the USI handshake in a background thread.
+    /// Returns immediately with a handle for sending commands and receiving moves.
+    pub fn spawn(config: EngineConfig) -> Result<Self, Error> {
+        Self::spawn_inner(config, None)
+    }
+
+    /// Spawn a new threaded USI engine, reporting startup progress.
+    ///
+    /// `progress` is called from the engine thread for each `StartupEvent`
+    /// reached while spawning the process and performing the handshake.
+    pub fn spawn_with_progress<F>(config: EngineConfig, progress: F) -> Result<Self, Error>
+    where
+        F: FnMut(&StartupEvent) + Send + 'static,
+    {
+        Self::spawn_inner(config, Some(Box::new(progress)))
+    }
+
+    fn spawn_inner(config: EngineConfig, progress: Option<StartupCallback>) -> Result<Self, Error> {
+        config.verify_memory(1)?;
+        let path = PathBuf::from(&config.path);
+        let temp_dir = if config.temp_working_dir {
+            Some(TempDir::create()?)
+        } else {
+            None
+        };
+        let work_dir = temp_dir
+            .as_ref()
+            .map(|d| d.0.clone())
+            .or_else(|| config.working_dir.clone().map(PathBuf::from))
+            .or_else(|| path.parent().map(|p| p.to_path_buf()))
+            .ok_or_else(|| {
+                Error::EngineIo(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "Could not determine engine working directory",
+                ))
+            })?;
+        for (name, path) in &config.eval_options {
+            check_eval_path(name, &resolve_option_path(&work_dir, path))?;
+        }
+
+        // Create channels for communication
+        let (command_sender, command_receiver) = channel::<ThreadCommand>();
+        let (move_sender, move_receiver) = channel::<SearchResult>();
+        let (info_sender, info_receiver) = channel::<Startup>();
+        let (error_sender, error_receiver) = channel::<Error>();
+        let (warning_sender, warning_receiver) = channel::<EngineWarning>();
+        let (extension_sender, extension_receiver) = channel::<ExtensionEvent>();
+        let move_receiver = Arc::new(Mutex::new(move_receiver));
+        let error_receiver = Arc::new(Mutex::new(error_receiver));
+        let warning_receiver = Arc::new(Mutex::new(warning_receiver));
+        let extension_receiver = Arc::new(Mutex::new(extension_receiver));
+        let shared = Arc::new(Shared {
+            info_capacity: config.info_capacity,
+            ..Default::default()
+        });
+        let warm_up_nodes = config.warm_up_nodes;
+        let command_policies = config.command_policies.clone();
+        let deadline_margin = config.deadline_margin.unwrap_or(DEFAULT_DEADLINE_MARGIN);
+        let time_margin = config.time_margin;
+        let retry = config.spawn_retry_policy();
+        let backoff: Duration = (1..retry.max_attempts())
+            .filter_map(|n| retry.delay_before(n))
+            .sum();
+        let handshake_wait = config
+            .handshake_timeout
+            .map_or(DEFAULT_HANDSHAKE_WAIT, |t| t + DEFAULT_HANDSHAKE_WAIT)
+            .saturating_mul(retry.max_attempts())
+            .saturating_add(backoff)
+            .saturating_mul(config.candidates().count() as u32);
+
+        let channels = ThreadChannels {
+            commands: command_receiver,
+            wake: command_sender.clone(),
+            moves: move_sender,
+            info: info_sender,
+            errors: error_sender,
+            warnings: warning_sender.clone(),
+            extensions: extension_sender,
+        };
+        let thread_shared = Arc::clone(&shared);
+        let thread_work_dir = work_dir.clone();
+        thread::spawn(move || {
+            Self::engine_thread(
+                config,
+                thread_work_dir,
+                temp_dir,
+                progress,
+                channels,
+                thread_shared,
+            );
+        });
+
+        // Wait for engine info (with timeout)
+        let startup = info_receiver
+            .recv_timeout(handshake_wait)
+            .unwrap_or_else(|_| Startup::failed("Unknown Engine", path));
+
+        let mut engine = Self {
+            command_sender,
+            move_receiver,
+            error_receiver,
+            warning_receiver,
+            warning_sender,
+            extension_receiver,
+            engine_name: startup.name,
+            info: startup.info,
+            applied_options: startup.applied_options,
+            path: startup.path,
+            work_dir,
+            shared,
+            ready_requested: 0,
+            drop_policy: DropPolicy::default(),
+            tracked: None,
+            auto_ponder: false,
+            ponder_params: None,
+            ponder: None,
+            output_limits: None,
+            warning_patterns: None,
+            command_policies,
+            deadline_margin,
+            time_margin,
+        };
+        if let Some(nodes) = warm_up_nodes.filter(|_| engine.info.is_some()) {
+            engine.warm_up(nodes)?;
+        }
+        Ok(engine)
+    }
+
+    /// Returns the process identifier of the engine,
+    /// or `None` if the process could not be spawned.
+    pub fn pid(&self) -> Option<u32> {
+        match self.shared.pid.load(Ordering::SeqCst) {
+            0 => None,
+            pid => Some(pid),
+        }
+    }
+
+    /// Returns the engine name reported during the USI handshake.
+    pub fn name(&self) -> &str {
+        &self.engine_name
+    }
+
+    /// Returns the options of `EngineConfig::option_profiles` which were set,
+    /// and the ones skipped because the engine does not declare them.
+    ///
+    /// A warning is also reported through `poll_warning` for each skipped option.
+    pub fn applied_options(&self) -> &AppliedOptions {
+        &self.applied_options
+    }
+
+    /// Returns the executable which was spawned, `EngineConfig::path` or one
+    /// of the fallback paths.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the directory the engine runs in.
+    pub fn working_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Returns the information reported during the USI handshake, including
+    /// the author and the declared options, or `None` if the handshake failed
+    /// or did not complete within the startup timeout.
+    pub fn info(&self) -> Option<&EngineInfo> {
+        self.info.as_ref()
+    }
+
+    /// Set the current position using SFEN notation.
+    ///
+    /// Stops tracking the position if `track_position` was called.
+    pub fn set_position(&mut self, sfen: &str) {
+        self.cancel_ponder();
+        self.tracked = None;
+        let _ = self.send_request(EngineRequest::Position {
+            sfen: sfen.to_string(),
+        });
+    }
+
+    /// Start a search with the given parameters.
+    ///
+    /// Each call starts a new search generation; results of earlier
+    /// searches arriving afterwards are considered stale.
+    /// If a search is still running, the behavior is determined by the `GoPolicy`.
+    ///
+    /// The returned `SearchHandle` tracks this particular search.
+    /// If the position is tracked, it is sent before `go`.
+    ///
+    /// With automatic pondering, if the engine ponders on the position and
+    /// `ponderhit` was sent, the handle of the ponder search is returned instead,
+    /// and the search keeps the limits it was started with.
+    ///
+    /// The time margin set with `set_time_margin` is subtracted from the times
+    /// sent unless `params` sets a margin.
+    pub fn go(&mut self, params: ThinkParams) -> Result<SearchHandle, Error> {
+        let params = match self.time_margin {
+            Some(margin) => params.default_margin(margin),
+            None => params,
+        };
+        match self.ponder.take() {
+            Some(ponder) if ponder.hit => {
+                self.ponder_params = Some(params);
+                return Ok(ponder.handle);
+            }
+            Some(ponder) => self.abandon(ponder),
+            None => {}
+        }
+        let abandoned =
+            self.shared.abandoned_generation.load(Ordering::SeqCst) == self.generation();
+        if self.shared.go_policy() == GoPolicy::Error && self.shared.is_searching() && !abandoned {
+            return Err(Error::SearchInProgress);
+        }
+        let position = self.tracked.as_ref().map(TrackedPosition::argument);
+        self.ponder_params = Some(params.clone());
+        self.send_go(position, params)
+    }
+
+    /// Starts a search like `go` and sends `stop` once the deadline margin
+    /// before `deadline` is reached, so that engines ignoring their time
+    /// limits, e.g. byoyomi, still answer by `deadline`.
+    ///
+    /// The margin is set by `EngineConfig::deadline_margin` or `set_deadline_margin`.
+    /// If the search is held by `GoPolicy::Queue` past its stop time,
+    /// it is stopped as soon as it is sent to the engine.
+    pub fn go_with_deadline(
+        &mut self,
+        params: ThinkParams,
+        deadline: Instant,
+    ) -> Result<SearchHandle, Error> {
+        let handle = self.go(params)?;
+        let stop_at = deadline
+            .checked_sub(self.deadline_margin)
+            .unwrap_or(deadline);
+        self.command_sender
+            .send(ThreadCommand::Deadline {
+                generation: handle.generation(),
+                stop_at,
+            })
+            .map_err(|_| Error::Terminated)?;
+        Ok(handle)
+    }
+
+    /// Sets the safety margin subtracted from the `btime`, `wtime` and `byoyomi`
+    /// sent by `go`, or removes it with `None`. See `measure_overhead` to tune it.
+    pub fn set_time_margin(&mut self, margin: Option<Duration>) {
+        self.time_margin = margin;
+    }
+
+    /// Returns the safety margin subtracted from the times sent by `go`.
+    pub fn time_margin(&self) -> Option<Duration> {
+        self.time_margin
+    }
+
+    /// Measures the round trip of `samples` `isready` commands, i.e. the latency
+    /// added by the process, its pipes and the engine thread to every `go`
+    /// and `bestmove`, e.g. to choose a time margin for short byoyomi.
+    ///
+    /// The measurement is only meaningful while the engine is idle.
+    /// Returns `Error::Timeout` if an `isready` is not answered within `timeout`.
+    pub fn measure_overhead(&mut self, samples: u32, timeout: Duration) -> Result<Overhead, Error> {
+        let mut overhead = Overhead::default();
+        for _ in 0..samples {
+            let sent = Instant::now();
+            self.is_ready().wait(timeout)?;
+            overhead.record(sent.elapsed());
+        }
+        Ok(overhead)
+    }
+
+    /// Returns how far the time the engine reports in `info time` lags behind the
+    /// time elapsed since `go` was sent, over the searches since the engine was
+    /// spawned or `reset_lag_stats` was called.
+    ///
+    /// Each `info` command with a `time` is a measurement. A growing lag reveals
+    /// time lost outside of the engine's own accounting, e.g. to a loaded machine,
+    /// slow pipes, or an engine starting its clock late.
+    pub fn lag_stats(&self) -> Overhead {
+        self.shared
+            .lag
+            .lock()
+            .map(|stats| *stats)
+            .unwrap_or_default()
+    }
+
+    /// Clears the statistics returned by `lag_stats`.
+    pub fn reset_lag_stats(&mut self) {
+        if let Ok(mut stats) = self.shared.lag.lock() {
+            *stats = Overhead::default();
+        }
+    }
+
+    /// Sets how long before its deadline a search started by `go_with_deadline` is stopped.
+    pub fn set_deadline_margin(&mut self, margin: Duration) {
+        self.deadline_margin = margin;
+    }
+
+    /// Returns how long before its deadline a search started by `go_with_deadline` is stopped.
+    pub fn deadline_margin(&self) -> Duration {
+        self.deadline_margin
+    }
+
+    fn send_go(
+        &mut self,
+        position: Option<String>,
+        params: ThinkParams,
+    ) -> Result<SearchHandle, Error> {
+        if let Some(sfen) = position {
+            self.send_request(EngineRequest::Position { sfen })?;
+        }
+        let generation = self.shared.latest_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let search_id = NEXT_SEARCH_ID.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut ids) = self.shared.search_ids.lock() {
+            ids.insert(generation, search_id);
+        }
+        if let Ok(mut searches) = self.shared.searches.lock() {
+            searches.insert(generation, SearchState::new(search_id));
+        }
+        self.send_request(EngineRequest::Go(params))?;
+        Ok(SearchHandle {
+            generation,
+            search_id,
+            shared: Arc::clone(&self.shared),
+            command_sender: self.command_sender.clone(),
+        })
+    }
+
+    /// Runs a search of `nodes` nodes on the initial position and waits for
+    /// its result, so that the first search of a game does not pay for the
+    /// initialization the engine defers to it, such as loading a network.
+    ///
+    /// The search is also limited to a second for engines ignoring `go nodes`.
+    /// The warm-up move is not reported by `poll_move` or `poll_result`,
+    /// and results of earlier searches not polled yet are discarded.
+    /// The engine is left on the initial position: a position set with
+    /// `set_position` has to be set again, a tracked position is sent by `go`.
+    ///
+    /// Returns the duration of the search. It is run by `spawn`
+    /// when `EngineConfig::warm_up_nodes` is set.
+    pub fn warm_up(&mut self, nodes: u64) -> Result<Duration, Error> {
+        let params = ThinkParams::new().nodes(nodes).byoyomi(WARM_UP_TIME);
+        self.internal_search(STARTPOS, params, WARM_UP_TIMEOUT)
+            .map(|result| result.elapsed)
+    }
+
+    /// Returns the capabilities derived from the declared options, and probes
+    /// whether the engine answers `go mate` on a mate in one within `timeout`.
+    ///
+    /// The probe leaves the engine on its position like `warm_up` does.
+    pub fn probe_capabilities(&mut self, timeout: Duration) -> Result<Capabilities, Error> {
+        let mut capabilities = self
+            .info
+            .as_ref()
+            .map(EngineInfo::capabilities)
+            .unwrap_or_default();
+        let params = ThinkParams::new().mate(MateParam::Timeout(timeout));
+        let answered = match self.internal_search(MATE_PROBE, params, timeout + PROBE_MARGIN) {
+            Ok(result) => matches!(
+                result.outcome,
+                SearchOutcome::Checkmate(ref c) if *c != CheckmateParams::NotImplemented
+            ),
+            Err(Error::Timeout) => false,
+            Err(err) => return Err(err),
+        };
+        capabilities.mate_search = Some(answered);
+        Ok(capabilities)
+    }
+
+    /// Runs a search on `position` and waits up to `timeout` for its result,
+    /// which is not reported by `poll_move` or `poll_result`, nor pondered on.
+    /// The search is stopped if it did not finish in time.
+    fn internal_search(
+        &mut self,
+        position: &str,
+        params: ThinkParams,
+        timeout: Duration,
+    ) -> Result<SearchResult, Error> {
+        self.cancel_ponder();
+        let handle = self.send_go(Some(position.to_string()), params)?;
+        let result = handle.wait(timeout);
+        if result.is_err() {
+            handle.stop();
+            let _ = handle.wait(PROBE_MARGIN);
+        }
+        if let Ok(receiver) = self.move_receiver.lock() {
+            while let Ok(r) = receiver.recv_timeout(PROBE_MARGIN) {
+                if r.generation == handle.generation() {
+                    break;
+                }
+            }
+        }
+        if let Ok(mut latest) = self.shared.latest_result.lock() {
+            *latest = None;
+        }
+        result
+    }
+
+    /// Starts tracking the game from `position`, given like the arguments of
+    /// `position`: `startpos` or an SFEN string, optionally followed by `moves`.
+    ///
+    /// While the position is tracked, moves are added with `push_move` and
+    /// every `go` sends the tracked position first, so that the engine cannot
+    /// search a stale position. `set_position` stops the tracking.
+    ///
+    /// Returns `Error::IllegalSyntax` if the position is malformed
+    /// or one of its moves is illegal.
+    pub fn track_position(&mut self, position: &str) -> Result<(), Error> {
+        let tracked = TrackedPosition::new(position)?;
+        self.cancel_ponder();
+        self.tracked = Some(tracked);
+        Ok(())
+    }
+
+    /// Plays a move, in USI notation, in the tracked position.
+    ///
+    /// With automatic pondering, playing the move the engine expected sends
+    /// `ponderhit`, and playing another one stops the ponder search.
+    /// Playing the best move of the latest search starts pondering.
+    ///
+    /// Returns `Error::IllegalOperation` if the position is not tracked,
+    /// and `Error::IllegalMove` without playing it if the move is illegal.
+    pub fn push_move(&mut self, mv: &str) -> Result<(), Error> {
+        self.tracked
+            .as_mut()
+            .ok_or(Error::IllegalOperation)?
+            .push(mv)?;
+        match self.ponder.take() {
+            Some(ponder) if ponder.hit && ponder.handle.is_finished() => {}
+            Some(mut ponder) if !ponder.hit && ponder.expected == mv => {
+                ponder.handle.ponderhit();
+                ponder.hit = true;
+                self.ponder = Some(ponder);
+                return Ok(());
+            }
+            Some(ponder) => {
+                self.abandon(ponder);
+                return Ok(());
+            }
+            None => {}
+        }
+        if self.auto_ponder {
+            self.start_ponder(mv);
+        }
+        Ok(())
+    }
+
+    /// Enables the automatic ponder manager, which is disabled by default.
+    ///
+    /// While the position is tracked, playing the best move of a search with
+    /// `push_move` starts a `go ponder` search on the position after the ponder
+    /// move suggested by the engine, with the limits of the previous `go`.
+    /// Playing the expected move then sends `ponderhit` and the next `go`
+    /// returns the ponder search; on a miss, the ponder search is stopped and
+    /// its result is reported as stale. Requires `USI_Ponder` to be enabled.
+    pub fn set_auto_ponder(&mut self, enabled: bool) {
+        self.auto_ponder = enabled;
+        if !enabled {
+            self.cancel_ponder();
+        }
+    }
+
+    /// Returns the opponent move the engine is pondering on,
+    /// or `None` if no ponder search is running.
+    pub fn pondering(&self) -> Option<&str> {
+        self.ponder
+            .as_ref()
+            .filter(|p| !p.hit)
+            .map(|p| p.expected.as_str())
+    }
+
+    /// Starts pondering if `mv` is the best move of the latest search.
+    fn start_ponder(&mut self, mv: &str) {
+        let Some(params) = self.ponder_params.clone() else {
+            return;
+        };
+        let latest = self
+            .shared
+            .latest_result
+            .lock()
+            .ok()
+            .and_then(|r| r.clone());
+        let expected = match latest {
+            Some(SearchResult {
+                generation,
+                stale: false,
+                outcome: SearchOutcome::BestMove(BestMoveParams::MakeMove(best, Some(expected))),
+                ..
+            }) if generation == self.generation() && best == mv => expected,
+            _ => return,
+        };
+        let Some(position) = self
+            .tracked
+            .as_ref()
+            .and_then(|t| t.argument_after(&expected))
+        else {
+            return;
+        };
+        if let Ok(handle) = self.send_go(Some(position), params.ponder()) {
+            self.ponder = Some(PonderSearch {
+                expected,
+                handle,
+                hit: false,
+            });
+        }
+    }
+
+    /// Stops a ponder search on a miss, so that its result is reported as stale.
+    fn abandon(&mut self, ponder: PonderSearch) {
+        self.shared
+            .abandoned_generation
+            .store(ponder.handle.generation(), Ordering::SeqCst);
+        ponder.handle.stop();
+    }
+
+    /// Abandons the ponder search unless `ponderhit` was sent.
+    fn cancel_ponder(&mut self) {
+        if let Some(ponder) = self.ponder.take().filter(|p| !p.hit) {
+            self.abandon(ponder);
+        }
+    }
+
+    /// Returns the tracked position in SFEN, or `None` if the position is not tracked.
+    pub fn current_sfen(&self) -> Option<String> {
+        self.tracked.as_ref().map(|t| t.current.to_sfen())
+    }
+
+    /// Takes back the last move of the tracked position and returns it.
+    ///
+    /// Returns `None` if the position is not tracked or no move was played.
+    /// The next `go` sends the position without the move.
+    pub fn undo(&mut self) -> Option<String> {
+        self.cancel_ponder();
+        self.tracked.as_mut()?.undo()
+    }
+
+    /// Plays again the move most recently taken back by `undo` and returns it.
+    ///
+    /// Returns `None` if there is no such move; `push_move` discards the moves
+    /// taken back, like starting a new variation.
+    pub fn redo(&mut self) -> Option<String> {
+        self.cancel_ponder();
+        self.tracked.as_mut()?.redo()
+    }
+
+    /// Returns the moves played in the tracked position since its initial position.
+    pub fn tracked_moves(&self) -> &[String] {
+        self.tracked.as_ref().map_or(&[], |t| &t.moves)
+    }
+
+    /// Sets the policy applied when `go` is called while a search is running.
+    pub fn set_go_policy(&mut self, policy: GoPolicy) {
+        if let Ok(mut p) = self.shared.go_policy.lock() {
+            *p = policy;
+        }
+    }
+
+    /// Returns `false` once the engine has quit or closed its output.
+    pub fn is_alive(&self) -> bool {
+        !self.shared.is_terminated()
+    }
+
+    /// Sets what happens to the engine process when this `ThreadedEngine` is dropped.
+    ///
+    /// With `DropPolicy::Detach`, the engine thread finishes without sending
+    /// `quit`, leaving the process running.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// Sets limits on the output read from the engine.
+    ///
+    /// Lines discarded because of the limits are ignored.
+    /// See `UsiEngineHandler::set_output_limits`.
+    pub fn set_output_limits(&mut self, limits: OutputLimits) {
+        self.output_limits = Some(limits);
+        let _ = self
+            .command_sender
+            .send(ThreadCommand::OutputLimits(limits));
+    }
+
+    /// Enables coalescing of `info` commands delivered through `SearchHandle`s.
+    ///
+    /// While `info` commands of a search are not taken, those arriving within
+    /// `window` of each other are coalesced, keeping only the latest command per
+    /// `multipv` index. `None` disables coalescing, which is the default.
+    pub fn set_info_coalescing(&mut self, window: Option<Duration>) {
+        if let Ok(mut w) = self.shared.info_window.lock() {
+            *w = window;
+        }
+    }
+
+    /// Returns the kinds of the requests issued but not sent to the engine yet,
+    /// in the order they were issued.
+    ///
+    /// Requests wait in the engine thread while it is busy, and `go` requests
+    /// together with the requests issued after them wait for the running search
+    /// with `GoPolicy::Queue`. `stop`, `ponderhit` and `quit` are sent right away.
+    pub fn pending_requests(&self) -> Vec<RequestKind> {
+        self.shared
+            .queued
+            .lock()
+            .map(|q| q.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if a search was started and its result has not arrived yet.
+    pub fn is_searching(&self) -> bool {
+        self.shared.is_searching()
+    }
+
+    /// Returns a snapshot of the search most recently sent to the engine,
+    /// or `None` if no search was sent yet.
+    ///
+    /// Unlike `SearchHandle::infos`, this does not consume any `info` commands,
+    /// so it can be called at any rate, e.g. once per frame.
+    pub fn current_search(&self) -> Option<SearchSnapshot> {
+        self.shared
+            .current
+            .lock()
+            .ok()?
+            .as_ref()
+            .map(CurrentSearch::snapshot)
+    }
+
+    /// Returns the generation of the most recent search, or 0 if none was started.
+    pub fn generation(&self) -> u64 {
+        self.shared.latest_generation.load(Ordering::SeqCst)
+    }
+
+    /// Start a search with byoyomi time control.
+    pub fn go_byoyomi(&mut self, time: Duration) -> Result<SearchHandle, Error> {
+        self.go(ThinkParams::new().byoyomi(time))
+    }
+
+    /// Start an infinite search.
+    pub fn go_infinite(&mut self) -> Result<SearchHandle, Error> {
+        self.go(ThinkParams::new().infinite())
+    }
+
+    /// Start a mate search.
+    pub fn go_mate(&mut self, timeout: Option<Duration>) -> Result<SearchHandle, Error> {
+        let params = match timeout {
+            Some(t) => ThinkParams::new().mate(MateParam::Timeout(t)),
+            None => ThinkParams::new().mate(MateParam::Infinite),
+        };
+        self.go(params)
+    }
+
+    /// Poll for a move result (non-blocking).
+    ///
+    /// Returns `Some(move_string)` if the engine has produced a move,
+    /// `None` if still thinking or no move available.
+    /// Results of superseded searches are discarded.
+    pub fn poll_move(&mut self) -> Option<String> {
+        while let Some(result) = self.poll_result() {
+            if result.stale {
+                continue;
+            }
+            if let Some(mv) = result.outcome.move_str() {
+                return Some(mv);
+            }
+        }
+        None
+    }
+
+    /// Poll for a search result (non-blocking), including results of superseded searches.
+    ///
+    /// Returns `None` if no result is available.
+    pub fn poll_result(&mut self) -> Option<SearchResult> {
+        if let Ok(receiver) = self.move_receiver.lock() {
+            match receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Poll for an error which occurred in the background threads (non-blocking).
+    ///
+    /// This includes failures to spawn or prepare the engine, to send commands,
+    /// and errors of the thread reading the engine output.
+    /// Returns `None` if no error is available.
+    pub fn poll_error(&mut self) -> Option<Error> {
+        let err = self.error_receiver.lock().ok()?.try_recv().ok()?;
+        match self.info {
+            Some(_) => Some(err.with_engine(&self.engine_name)),
+            None => Some(err),
+        }
+    }
+
+    /// Poll for an `info string` line classified as a warning (non-blocking),
+    /// such as a report of a missing evaluation file.
+    ///
+    /// Returns `None` if no warning is available.
+    pub fn poll_warning(&mut self) -> Option<EngineWarning> {
+        self.warning_receiver.lock().ok()?.try_recv().ok()
+    }
+
+    /// Poll for an event produced by one of the `protocol_extensions` of the
+    /// `EngineConfig` (non-blocking), for lines received after the handshake.
+    ///
+    /// Returns `None` if no event is available.
+    pub fn poll_extension_event(&mut self) -> Option<ExtensionEvent> {
+        self.extension_receiver.lock().ok()?.try_recv().ok()
+    }
+
+    /// Sets the patterns classifying `info string` lines as warnings.
+    /// Lines received during startup are classified with the default patterns.
+    pub fn set_warning_patterns(&mut self, patterns: WarningPatterns) {
+        self.warning_patterns = Some(patterns.clone());
+        let _ = self
+            .command_sender
+            .send(ThreadCommand::WarningPatterns(patterns));
+    }
+
+    /// Replaces the engine with one spawned from `config`, e.g. to switch to a
+    /// stronger engine for the endgame.
+    ///
+    /// The tracked position with its moves, automatic pondering, the last search
+    /// limits and the settings made through this `ThreadedEngine` are kept.
+    /// A ponder search is abandoned, the previous engine is shut down according
+    /// to the `DropPolicy`, and waiting on its searches returns `Error::Terminated`.
+    ///
+    /// If the new engine cannot be spawned or does not complete the handshake,
+    /// the current engine is kept and the error is returned.
+    pub fn replace(&mut self, config: EngineConfig) -> Result<(), Error> {
+        let mut engine = ThreadedEngine::spawn(config)?;
+        if engine.info.is_none() {
+            return Err(engine.poll_error().unwrap_or(Error::Terminated));
+        }
+        self.cancel_ponder();
+
+        engine.set_go_policy(self.shared.go_policy());
+        engine.set_info_coalescing(self.shared.info_window.lock().ok().and_then(|w| *w));
+        if let Some(limits) = self.output_limits {
+            engine.set_output_limits(limits);
+        }
+        if let Some(patterns) = self.warning_patterns.take() {
+            engine.set_warning_patterns(patterns);
+        }
+        engine.drop_policy = self.drop_policy;
+        engine.tracked = self.tracked.take();
+        engine.auto_ponder = self.auto_ponder;
+        engine.ponder_params = self.ponder_params.take();
+        engine.command_policies = std::mem::take(&mut self.command_policies);
+        engine.deadline_margin = self.deadline_margin;
+        engine.time_margin = self.time_margin;
+        *self = engine;
+        Ok(())
+    }
+
+    /// Analyze `positions` one after another with the same search limits.
+    ///
+    /// The returned iterator runs one search per call to `next`, yielding each
+    /// position with its `Analysis`. `limits` must let searches terminate on their own.
+    pub fn analyze_batch<I, S>(&mut self, positions: I, limits: ThinkParams) -> BatchAnalysis<'_>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let positions = positions.into_iter().map(Into::into).collect();
+        BatchAnalysis::new(self, positions, limits)
+    }
+
+    /// Stop the current search.
+    ///
+    /// A ponder search started by the automatic ponder manager is abandoned.
+    pub fn stop(&mut self) {
+        self.cancel_ponder();
+        let _ = self.send_request(EngineRequest::Stop);
+    }
+
+    /// Tell the engine that the opponent played the expected move.
+    ///
+    /// The running `go ponder` search continues as a regular search, under the
+    /// time limits it was started with, and its result is delivered to the
+    /// same `SearchHandle`. Like `stop`, it is sent even while requests are queued.
+    pub fn ponderhit(&mut self) {
+        let _ = self.send_request(EngineRequest::PonderHit);
+    }
+
+    /// Send an engine-specific command line as is, e.g. YaneuraOu's `bench`.
+    ///
+    /// Replies are processed like any other engine output, so `info` commands
+    /// sent during a search are delivered to its `SearchHandle`.
+    /// Lines containing line breaks are rejected and reported through `poll_error`.
+    pub fn send_raw(&mut self, line: &str) {
+        let _ = self.send_request(EngineRequest::Raw(line.to_string()));
+    }
+
+    /// Set an engine option.
+    ///
+    /// Sends a `setoption` command to the engine. Call `is_ready()` after
+    /// setting options to ensure the engine has processed them.
+    pub fn set_option(&mut self, name: &str, value: Option<&str>) {
+        if let Ok(mut last) = self.shared.last_option.lock() {
+            *last = Some(name.to_string());
+        }
+        let _ = self.send_request(EngineRequest::SetOption {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        });
+    }
+
+    /// Set `USI_Hash` to `mb` MiB after checking that it fits into the available memory.
+    ///
+    /// Returns `Error::HashTooLarge` without setting the option if it does not,
+    /// rather than letting the engine be killed for lack of memory during a game.
+    /// The memory used by running engines, including this one, is already
+    /// accounted for. The check passes if the available memory is unknown.
+    pub fn set_hash(&mut self, mb: u64) -> Result<(), Error> {
+        check_hash_size(mb, 1)?;
+        self.set_option("USI_Hash", Some(&mb.to_string()));
+        Ok(())
+    }
+
+    /// Set an engine option and wait up to `timeout` for `readyok`,
+    /// checking that the engine accepted it.
+    ///
+    /// Returns `Error::UnknownOption` if the engine reported the option as unknown,
+    /// and `Error::Timeout` if the engine did not answer in time.
+    /// Rejections are also reported through `poll_error`.
+    pub fn set_option_checked(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        self.shared.take_rejection(name);
+        self.set_option(name, value);
+        self.wait_ready(timeout)?;
+        if self.shared.take_rejection(name) {
+            return Err(Error::UnknownOption(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Set a `filename` or `string` option holding a path, such as `EvalDir` or `BookFile`.
+    ///
+    /// Relative paths are resolved against the engine's working directory.
+    /// If the path does not exist, a warning is reported through `poll_warning`
+    /// and the option is set anyway. Returns the resolved path.
+    pub fn set_path_option(&mut self, name: &str, path: &str) -> PathBuf {
+        let resolved = resolve_option_path(&self.work_dir, path);
+        if let Some(warning) = check_option_path(name, path, &resolved) {
+            let _ = self.warning_sender.send(warning);
+        }
+        self.set_option(
+            name,
+            OptionValue::Filename(path.to_string()).encode().as_deref(),
+        );
+        resolved
+    }
+
+    /// Set an evaluation option, such as `EvalDir`, `EvalFile` or `DNN_Model`,
+    /// after checking that it refers to a file of plausible size,
+    /// or to a directory containing one.
+    ///
+    /// Relative paths are resolved against the engine's working directory.
+    /// Returns `Error::InvalidEvalFile` without setting the option if the
+    /// check fails, instead of the engine failing on `isready`.
+    /// Otherwise returns the resolved path.
+    pub fn set_eval_option(&mut self, name: &str, path: &str) -> Result<PathBuf, Error> {
+        let resolved = resolve_option_path(&self.work_dir, path);
+        check_eval_path(name, &resolved)?;
+        self.set_option(
+            name,
+            OptionValue::Filename(path.to_string()).encode().as_deref(),
+        );
+        Ok(resolved)
+    }
+
+    /// Notify the engine of the result of the game.
+    ///
+    /// Sends `gameover win`, `gameover lose` or `gameover draw`, from the
+    /// engine's point of view. Engines use it to stop pondering and to
+    /// finalize per-game state such as search trees or learning data.
+    pub fn game_over(&mut self, result: GameOverKind) {
+        let _ = self.send_request(EngineRequest::GameOver(result));
+    }
+
+    /// Start a new game, so that one engine can play several games.
+    ///
+    /// Stops the current search if any, sends `gameover` with `previous_result`
+    /// if given, then `usinewgame` and `isready`. The returned token completes
+    /// once the engine is ready for the new game. `usinewgame` is already sent
+    /// at startup, so this is only needed from the second game on.
+    ///
+    /// A tracked position goes back to its initial position.
+    pub fn new_game(&mut self, previous_result: Option<GameOverKind>) -> ReadyToken {
+        if self.is_searching() {
+            self.stop();
+        }
+        if let Some(tracked) = &mut self.tracked {
+            if let Ok(initial) = TrackedPosition::new(&tracked.start) {
+                *tracked = initial;
+            }
+        }
+        if let Some(result) = previous_result {
+            self.game_over(result);
+        }
+        let _ = self.send_request(EngineRequest::NewGame);
+        self.is_ready()
+    }
+
+    /// Ask the engine whether it is ready.
+    ///
+    /// Sends an `isready` command to ensure the engine has processed
+    /// all previous commands. The returned token can be used to wait
+    /// for the corresponding `readyok`.
+    pub fn is_ready(&mut self) -> ReadyToken {
+        self.ready_requested += 1;
+        let _ = self.send_request(EngineRequest::IsReady);
+        ReadyToken {
+            target: self.ready_requested,
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Sends `isready` and blocks until the engine answers `readyok` or `timeout` elapses.
+    ///
+    /// Returns `Error::Timeout` if no answer arrived in time.
+    pub fn wait_ready(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.is_ready().wait(timeout)
+    }
+
+    /// Sets the timeout and retries of `kind` requests awaiting an answer from the engine.
+    ///
+    /// `RequestKind::IsReady` applies to `ready`, and `RequestKind::SetOption` to
+    /// the `isready` confirming `set_option_confirmed`, which falls back to the
+    /// `IsReady` policy. Each retry sends `isready` again and keeps waiting for
+    /// the first answer. Without a policy or a timeout, these wait indefinitely.
+    pub fn set_command_policy(&mut self, kind: RequestKind, policy: RetryPolicy) {
+        self.command_policies.retain(|(k, _)| *k != kind);
+        self.command_policies.push((kind, policy));
+    }
+
+    /// Returns the policy set for `kind` requests.
+    pub fn command_policy(&self, kind: RequestKind) -> Option<&RetryPolicy> {
+        self.command_policies
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, policy)| policy)
+    }
+
+    /// Sends `isready` and blocks until the engine answers `readyok`,
+    /// as configured by the `RequestKind::IsReady` policy.
+    ///
+    /// Returns `Error::Timeout` with the `isready` command attached if no attempt was answered.
+    pub fn ready(&mut self) -> Result<(), Error> {
+        let policy = self
+            .command_policy(RequestKind::IsReady)
+            .cloned()
+            .unwrap_or_default();
+        self.await_ready(&policy)
+            .map_err(|err| err.with_command("isready"))
+    }
+
+    /// Sets an option and waits until the engine has processed it, as configured
+    /// by the `RequestKind::SetOption` policy, e.g. for options loading large files.
+    ///
+    /// Returns `Error::UnknownOption` if the engine reported the option as unknown,
+    /// or `Error::Timeout` with the `setoption` command attached if it did not answer.
+    pub fn set_option_confirmed(&mut self, name: &str, value: Option<&str>) -> Result<(), Error> {
+        let policy = self
+            .command_policy(RequestKind::SetOption)
+            .or_else(|| self.command_policy(RequestKind::IsReady))
+            .cloned()
+            .unwrap_or_default();
+        self.shared.take_rejection(name);
+        self.set_option(name, value);
+        self.await_ready(&policy).map_err(|err| {
+            let command = GuiCommand::SetOption(name.to_string(), value.map(str::to_string));
+            err.with_command(&command.to_string())
+        })?;
+        if self.shared.take_rejection(name) {
+            return Err(Error::UnknownOption(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Sends `isready` and waits for the answer, retrying according to `policy`.
+    fn await_ready(&mut self, policy: &RetryPolicy) -> Result<(), Error> {
+        let token = self.is_ready();
+        for attempt in 0..policy.max_attempts() {
+            if let Some(delay) = policy.delay_before(attempt) {
+                thread::sleep(delay);
+                if token.is_acknowledged() {
+                    return Ok(());
+                }
+                self.is_ready();
+            }
+            let deadline = policy.attempt_timeout().map(|t| Instant::now() + t);
+            match token.wait_until(deadline) {
+                Err(Error::Timeout) if self.is_alive() => {}
+                Err(Error::Timeout) => return Err(Error::Terminated),
+                result => return result,
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn send_request(&self, request: EngineRequest) -> Result<(), Error> {
+        self.shared.submit(&self.command_sender, request)
+    }
+
+    /// Engine thread that manages the USI engine process
+    /// Describes the failure of the zero-based spawn `attempt`, which is
+    /// retried if the failure is transient and attempts remain.
+    fn spawn_failure(
+        retry: &RetryPolicy,
+        path: &str,
+        attempt: u32,
+        err: &Error,
+    ) -> (StartupEvent, Option<Duration>) {
+        let retry_in = retry
+            .delay_before(attempt + 1)
+            .filter(|_| err.is_transient());
+        let reason = match err {
+            Error::EngineIo(err) => err.to_string(),
+            err => err.to_string(),
+        };
+        let event = StartupEvent::SpawnFailed {
+            path: PathBuf::from(path),
+            attempt: attempt + 1,
+            reason,
+            retry_in,
+        };
+        (event, retry_in)
+    }
+
+    fn engine_thread(
+        config: EngineConfig,
+        work_dir: PathBuf,
+        mut temp_dir: Option<TempDir>,
+        progress: Option<StartupCallback>,
+        channels: ThreadChannels,
+        shared: Arc<Shared>,
+    ) {
+        let _guard = TerminationGuard(Arc::clone(&shared));
+
+        let errors = channels.errors;
+        let retry = config.spawn_retry_policy();
+        let candidates: Vec<&str> = config.candidates().collect();
+        let mut progress = progress;
+        let mut candidate = 0;
+        let mut attempt = 0;
+        let (mut handler, info) = loop {
+            let path = candidates[candidate];
+            let last = candidate + 1 == candidates.len();
+            // Spawn the engine process
+            let spawned = config.command(path, &work_dir).and_then(|mut command| {
+                UsiEngineHandler::spawn_command_with_buffers(&mut command, config.stdio_buffers)
+            });
+            let mut handler = match spawned {
+                Ok(h) => h,
+                Err(err) => {
+                    let (event, retry_in) = Self::spawn_failure(&retry, path, attempt, &err);
+                    if let Some(p) = &mut progress {
+                        p(&event);
+                    }
+                    if let Some(delay) = retry_in {
+                        thread::sleep(delay);
+                        attempt += 1;
+                        continue;
                     }
-                    Some(EngineCommand::Checkmate(params)) => {
-                        use crate::protocol::CheckmateParams;
-                        match params {
-                            CheckmateParams::Mate(moves) => {
-                                if let Some(first_move) = moves.first() {
-                                    let _ = output_sender.send(first_move.clone());
-                                }
+                    if !last {
+                        candidate += 1;
+                        attempt = 0;
+                        continue;
+                    }
+                    let _ = errors.send(err);
+                    let _ = channels
+                        .info
+                        .send(Startup::failed("Engine Failed", PathBuf::from(path)));
+                    return;
+                }
+            };
+            if let Some(p) = progress.take() {
+                handler.set_progress(p);
+            }
+            shared.pid.store(handler.pid(), Ordering::SeqCst);
+            handler.set_line_ending(config.line_ending);
+            handler.set_warning_sender(channels.warnings.clone());
+            handler.set_protocol_extensions(config.protocol_extensions.clone());
+
+            // Send pre-handshake options (for Fairy-Stockfish, etc.)
+            for (name, value) in &config.pre_handshake_options {
+                let command = GuiCommand::SetOption(name.clone(), value.clone());
+                if let Err(err) = handler.send_command_before_handshake(&command) {
+                    let _ = errors.send(err);
+                }
+            }
+
+            // Get engine info (initiates handshake)
+            let info = match config.handshake_timeout {
+                Some(timeout) => handler.get_info_with_timeout(timeout),
+                None => handler.get_info(),
+            };
+            match info {
+                Ok(info) => {
+                    break (
+                        handler,
+                        Startup {
+                            name: info.name().to_string(),
+                            info: Some(info),
+                            applied_options: AppliedOptions::default(),
+                            path: PathBuf::from(path),
+                        },
+                    )
+                }
+                Err(err) => {
+                    let (event, retry_in) = Self::spawn_failure(&retry, path, attempt, &err);
+                    handler.notify_progress(event);
+                    if retry_in.is_some() || !last {
+                        progress = handler.take_progress();
+                        drop(handler);
+                        match retry_in {
+                            Some(delay) => {
+                                thread::sleep(delay);
+                                attempt += 1;
                             }
-                            CheckmateParams::NoMate
-                            | CheckmateParams::NotImplemented
-                            | CheckmateParams::Timeout => {
-                                let _ = output_sender.send("resign".to_string());
+                            None => {
+                                candidate += 1;
+                                attempt = 0;
                             }
                         }
+                        continue;
                     }
-                    _ => {}
+                    // Preparing a handler whose handshake failed would wait
+                    // for a `readyok` which never comes.
+                    let _ = errors.send(err);
+                    let _ = channels
+                        .info
+                        .send(Startup::failed("Unknown", PathBuf::from(path)));
+                    return;
                 }
-                Ok(())
-            })
-            .is_err()
-        {
+            }
+        };
+
+        // Apply option profiles under the names declared by the engine,
+        // skipping the options it does not declare
+        let declared: Vec<&str> = info
+            .info
+            .iter()
+            .flat_map(|i| i.declared_options())
+            .map(|o| o.name.as_str())
+            .collect();
+        let mut applied = AppliedOptions::default();
+        for profile in &config.option_profiles {
+            applied.merge(profile.partition(declared.iter().copied()));
+        }
+        for command in applied.applied.to_commands() {
+            if let Err(err) = handler.send_command(&command) {
+                let _ = errors.send(err);
+            }
+        }
+        for name in &applied.skipped {
+            let _ = channels.warnings.send(EngineWarning {
+                message: format!("{name} is not declared by the engine and was not set"),
+            });
+        }
+        for (name, path) in &config.eval_options {
+            let value = OptionValue::Filename(path.clone());
+            if let Err(err) = handler.send_command(&GuiCommand::set_option(name, &value)) {
+                let _ = errors.send(err);
+            }
+        }
+        let _ = channels.info.send(Startup {
+            applied_options: applied,
+            ..info
+        });
+
+        // Prepare engine and send usinewgame
+        let prepared = handler
+            .prepare_with_policy(&config.ready_policy())
+            .and_then(|_| handler.send_command(&GuiCommand::UsiNewGame));
+        if let Err(err) = prepared {
+            let _ = errors.send(err);
+            return;
+        }
+
+        // Start listening to engine output
+        let output_sender = channels.moves.clone();
+        let extension_sender = channels.extensions;
+        // The hook is dropped when the engine closes its output.
+        let listen_guard = ListenGuard {
+            shared: Arc::clone(&shared),
+            wake: channels.wake.clone(),
+        };
+        handler.set_error_sender(errors.clone());
+        if let Err(err) = handler.listen(move |output| -> Result<(), std::io::Error> {
+            let shared = &listen_guard.shared;
+            let wake_sender = &listen_guard.wake;
+            shared.record_rejection(output.raw_str());
+            if let Some(event) = output.extension_event() {
+                let _ = extension_sender.send(Arc::clone(event));
+            }
+            match output.response() {
+                Some(EngineCommand::BestMove(params)) => {
+                    let outcome = match params {
+                        BestMoveParams::Win => SearchOutcome::DeclareWin,
+                        params => SearchOutcome::BestMove(params.clone()),
+                    };
+                    let result = shared.tag(outcome);
+                    let generation = result.generation;
+                    let _ = output_sender.send(result);
+                    let _ = wake_sender.send(ThreadCommand::SearchFinished(generation));
+                }
+                Some(EngineCommand::Info(params)) => {
+                    shared.record_info(params);
+                }
+                Some(EngineCommand::ReadyOk) => {
+                    // A keep-alive is only sent while no other `isready` is unanswered.
+                    let keep_alive = shared.keep_alive_pending.compare_exchange(
+                        true,
+                        false,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    );
+                    if keep_alive.is_ok() {
+                        return Ok(());
+                    }
+                    if let Ok(mut acks) = shared.ready_acks.lock() {
+                        *acks += 1;
+                        shared.ready_cond.notify_all();
+                    }
+                }
+                Some(EngineCommand::Checkmate(params)) => {
+                    let result = shared.tag(SearchOutcome::Checkmate(params.clone()));
+                    let generation = result.generation;
+                    let _ = output_sender.send(result);
+                    let _ = wake_sender.send(ThreadCommand::SearchFinished(generation));
+                }
+                _ => {}
+            }
+            Ok(())
+        }) {
+            let _ = errors.send(err);
             return;
         }
+        drop(channels.wake);
 
         // Process commands from the caller
-        while let Ok(cmd) = command_receiver.recv() {
-            match cmd {
-                EngineRequest::SetOption { name, value } => {
-                    let _ = handler.send_command(&GuiCommand::SetOption(name, value));
+        let mut dispatcher = Dispatcher {
+            handler,
+            errors,
+            shared,
+            received_generation: 0,
+            forwarded_generation: 0,
+            forwarded_ready: 0,
+            deferred: VecDeque::new(),
+            deadlines: Deadlines::default(),
+            #[cfg(feature = "tracing")]
+            search_spans: VecDeque::new(),
+        };
+        loop {
+            dispatcher.enforce_deadlines();
+            let until_deadline = dispatcher.until_deadline();
+            let timeout = match (config.keep_alive, until_deadline) {
+                (Some(interval), Some(wait)) => Some(interval.min(wait)),
+                (interval, wait) => interval.or(wait),
+            };
+            let received = match timeout {
+                Some(timeout) => channels.commands.recv_timeout(timeout),
+                None => channels
+                    .commands
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let running = match received {
+                Ok(ThreadCommand::Request(request)) => dispatcher.dispatch(request),
+                Ok(ThreadCommand::SearchFinished(generation)) => {
+                    dispatcher.search_finished(generation)
                 }
-                EngineRequest::IsReady => {
-                    let _ = handler.send_command(&GuiCommand::IsReady);
+                Ok(ThreadCommand::OutputLimits(limits)) => {
+                    dispatcher.handler.set_output_limits(limits);
+                    true
                 }
-                EngineRequest::Position { sfen } => {
-                    let _ = handler.send_command(&GuiCommand::Position(sfen));
+                Ok(ThreadCommand::WarningPatterns(patterns)) => {
+                    dispatcher.handler.set_warning_patterns(patterns);
+                    true
                 }
-                EngineRequest::Go(params) => {
-                    let _ = handler.send_command(&GuiCommand::Go(params));
+                Ok(ThreadCommand::Deadline {
+                    generation,
+                    stop_at,
+                }) => {
+                    dispatcher.deadlines.insert(generation, stop_at);
+                    true
                 }
-                EngineRequest::Stop => {
-                    let _ = handler.send_command(&GuiCommand::Stop);
+                Ok(ThreadCommand::StopSearch(generation)) => dispatcher.stop_search(generation),
+                Ok(ThreadCommand::EngineClosed) => dispatcher.engine_closed(),
+                Ok(ThreadCommand::Detach) => {
+                    dispatcher.handler.set_drop_policy(DropPolicy::Detach);
+                    // The detached engine keeps using its working directory.
+                    if let Some(dir) = temp_dir.take() {
+                        mem::forget(dir);
+                    }
+                    false
                 }
-                EngineRequest::Quit => {
-                    let _ = handler.send_command(&GuiCommand::Quit);
-                    break;
+                // The deadline is enforced at the start of the next iteration.
+                Err(RecvTimeoutError::Timeout) if timeout == until_deadline => true,
+                Err(RecvTimeoutError::Timeout) => dispatcher.keep_alive(),
+                Err(RecvTimeoutError::Disconnected) => false,
+            };
+            if !running {
+                break;
+            }
+        }
+    }
+}
+
+/// Forwards requests from the caller to the engine, applying the `GoPolicy`.
+struct Dispatcher {
+    handler: UsiEngineHandler,
+    errors: Sender<Error>,
+    shared: Arc<Shared>,
+    /// Generation of the most recent `go` request received from the caller.
+    received_generation: u64,
+    forwarded_generation: u64,
+    /// Number of `isready` requests forwarded to the engine.
+    forwarded_ready: u64,
+    /// Requests held back by `GoPolicy::Queue` until the running search finishes,
+    /// with the generation of the search started by a `go` request.
+    deferred: VecDeque<(EngineRequest, u64)>,
+    deadlines: Deadlines,
+    /// `search` spans of the searches sent to the engine, closed when their result arrives.
+    #[cfg(feature = "tracing")]
+    search_spans: VecDeque<(u64, tracing::Span)>,
+}
+
+/// Times at which searches started by `ThreadedEngine::go_with_deadline` are stopped.
+#[derive(Debug, Default)]
+struct Deadlines(Vec<(u64, Instant)>);
+
+impl Deadlines {
+    fn insert(&mut self, generation: u64, stop_at: Instant) {
+        self.0.push((generation, stop_at));
+    }
+
+    /// Returns the stop time of the `running` search.
+    fn get(&self, running: Option<u64>) -> Option<Instant> {
+        self.0
+            .iter()
+            .find(|(g, _)| Some(*g) == running)
+            .map(|(_, stop_at)| *stop_at)
+    }
+
+    /// Removes the deadlines of the searches sent to the engine up to `forwarded`
+    /// which are either past due or no longer running. Returns `true` if the
+    /// `running` search is past due and has to be stopped.
+    fn expire(&mut self, forwarded: u64, running: Option<u64>, now: Instant) -> bool {
+        let mut due = false;
+        self.0.retain(|&(generation, stop_at)| {
+            if generation > forwarded {
+                return true;
+            }
+            if Some(generation) != running {
+                return false;
+            }
+            due = stop_at <= now;
+            !due
+        });
+        due
+    }
+}
+
+impl Dispatcher {
+    fn is_searching(&self) -> bool {
+        self.shared
+            .pending_searches
+            .lock()
+            .map(|pending| !pending.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Returns the search running in the engine, i.e. the last one sent and not answered yet.
+    fn running_search(&self) -> Option<u64> {
+        self.shared
+            .pending_searches
+            .lock()
+            .ok()
+            .and_then(|pending| pending.back().copied())
+    }
+
+    /// Sends `stop` if the running search has reached its deadline.
+    fn enforce_deadlines(&mut self) {
+        let running = self.running_search();
+        if self
+            .deadlines
+            .expire(self.forwarded_generation, running, Instant::now())
+        {
+            self.send(&GuiCommand::Stop);
+        }
+    }
+
+    /// Returns the time left until the running search has to be stopped.
+    fn until_deadline(&self) -> Option<Duration> {
+        self.deadlines
+            .get(self.running_search())
+            .map(|stop_at| stop_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Sends a keep-alive `isready` if the engine is idle. Returns `false` if
+    /// the previous one was not answered, reporting `Error::Timeout`.
+    fn keep_alive(&mut self) -> bool {
+        if self.shared.keep_alive_pending.load(Ordering::SeqCst) {
+            let _ = self.errors.send(Error::Timeout);
+            return false;
+        }
+        let ready_pending = self
+            .shared
+            .ready_acks
+            .lock()
+            .map_or(true, |acks| *acks < self.forwarded_ready);
+        if self.is_searching() || !self.deferred.is_empty() || ready_pending {
+            return true;
+        }
+        self.shared.keep_alive_pending.store(true, Ordering::SeqCst);
+        self.send(&GuiCommand::IsReady);
+        true
+    }
+
+    /// Handles a request from the caller. Returns `false` once the engine has quit.
+    fn dispatch(&mut self, request: EngineRequest) -> bool {
+        let generation = match request {
+            EngineRequest::Go(_) => {
+                self.received_generation += 1;
+                self.received_generation
+            }
+            _ => 0,
+        };
+        match request {
+            EngineRequest::Stop | EngineRequest::PonderHit => self.forward(request, generation),
+            EngineRequest::Quit => {
+                self.forward(request, generation);
+                return false;
+            }
+            request if !self.deferred.is_empty() => self.deferred.push_back((request, generation)),
+            EngineRequest::Go(params) if self.is_searching() => match self.shared.go_policy() {
+                GoPolicy::Queue => self
+                    .deferred
+                    .push_back((EngineRequest::Go(params), generation)),
+                GoPolicy::Error | GoPolicy::StopThenGo => {
+                    self.send(&GuiCommand::Stop);
+                    self.forward(EngineRequest::Go(params), generation);
+                }
+            },
+            request => self.forward(request, generation),
+        }
+        true
+    }
+
+    /// Forwards deferred requests after a search finished, up to the next `go`.
+    /// Closes the `search` span of `generation` and sends the deferred requests.
+    fn search_finished(&mut self, generation: u64) -> bool {
+        #[cfg(feature = "tracing")]
+        if let Some(i) = self.search_spans.iter().position(|(g, _)| *g == generation) {
+            if let Some((_, span)) = self.search_spans.remove(i) {
+                span.in_scope(|| tracing::debug!("bestmove"));
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = generation;
+        self.resume()
+    }
+
+    fn resume(&mut self) -> bool {
+        while !self.is_searching() {
+            match self.deferred.pop_front() {
+                Some((request, generation)) => self.forward(request, generation),
+                None => break,
+            }
+        }
+        true
+    }
+
+    /// Drops the deferred requests once the engine has closed its output,
+    /// and counts every unanswered search as completed.
+    /// `Shared::terminate` has already finished their handles.
+    fn engine_closed(&mut self) -> bool {
+        self.deferred.clear();
+        #[cfg(feature = "tracing")]
+        self.search_spans.clear();
+        if let Ok(mut pending) = self.shared.pending_searches.lock() {
+            pending.clear();
+        }
+        let latest = self.shared.latest_generation.load(Ordering::SeqCst);
+        self.shared
+            .completed_searches
+            .fetch_max(latest, Ordering::SeqCst);
+        true
+    }
+
+    /// Stops the search of `generation` if it is running in the engine,
+    /// or removes it from the deferred requests if it has not started yet.
+    fn stop_search(&mut self, generation: u64) -> bool {
+        let deferred = self
+            .deferred
+            .iter()
+            .position(|(r, g)| matches!(r, EngineRequest::Go(_)) && *g == generation);
+        if let Some(i) = deferred {
+            self.deferred.remove(i);
+            self.shared.dequeue(RequestKind::Go);
+            self.shared.abandon_search(generation, Abandoned::Stopped);
+        } else if self.is_searching() && self.forwarded_generation == generation {
+            self.send(&GuiCommand::Stop);
+        }
+        true
+    }
+
+    /// Sends `request` to the engine. `generation` is the one of the search
+    /// started by a `go` request.
+    fn forward(&mut self, request: EngineRequest, generation: u64) {
+        self.shared.dequeue(request.kind());
+        let command = match request {
+            EngineRequest::Raw(line) => {
+                if let Err(err) = self.handler.send_raw(&line) {
+                    let _ = self.errors.send(err);
                 }
+                return;
+            }
+            EngineRequest::SetOption { name, value } => GuiCommand::SetOption(name, value),
+            EngineRequest::IsReady => {
+                self.forwarded_ready += 1;
+                GuiCommand::IsReady
+            }
+            EngineRequest::Position { sfen } => GuiCommand::Position(sfen),
+            EngineRequest::Go(params) => {
+                self.forwarded_generation = generation;
+                self.shared.start_search(generation);
+                #[cfg(feature = "tracing")]
+                {
+                    let span = self.search_span(generation);
+                    span.in_scope(|| tracing::debug!("go"));
+                    self.search_spans.push_back((generation, span));
+                }
+                GuiCommand::Go(params)
             }
+            EngineRequest::Stop => GuiCommand::Stop,
+            EngineRequest::PonderHit => GuiCommand::Ponderhit,
+            EngineRequest::GameOver(result) => GuiCommand::GameOver(result),
+            EngineRequest::NewGame => GuiCommand::UsiNewGame,
+            EngineRequest::Quit => GuiCommand::Quit,
+        };
+        self.send(&command);
+    }
+
+    /// Opens the `search` span of `generation`, carrying its process-wide search ID.
+    #[cfg(feature = "tracing")]
+    fn search_span(&self, generation: u64) -> tracing::Span {
+        let search_id = self
+            .shared
+            .search_ids
+            .lock()
+            .ok()
+            .and_then(|ids| ids.get(&generation).copied())
+            .unwrap_or(0);
+        tracing::info_span!("search", search_id, generation)
+    }
+
+    fn send(&mut self, command: &GuiCommand) {
+        if let Err(err) = self.handler.send_command(command) {
+            let _ = self.errors.send(err);
         }
     }
 }
 
 impl Drop for ThreadedEngine {
     fn drop(&mut self) {
-        let _ = self.command_sender.send(EngineRequest::Quit);
+        match self.drop_policy {
+            DropPolicy::Kill => {
+                let _ = self.send_request(EngineRequest::Quit);
+            }
+            DropPolicy::Detach => {
+                let _ = self.command_sender.send(ThreadCommand::Detach);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mate_probe() {
+        let mut position = Position::from_sfen(MATE_PROBE).unwrap();
+        assert_eq!(MATE_PROBE, position.to_sfen());
+        assert!(position.play("G*5b"));
+        assert!(position.in_check());
+        for escape in ["5a4a", "5a6a", "5a4b", "5a6b", "5a5b"] {
+            assert!(!position.is_legal(escape), "{escape}");
+        }
+    }
+
+    #[test]
+    fn spawn_retry() {
+        let retry = EngineConfig::new("engine").spawn_retry_policy();
+        assert_eq!(1, retry.max_attempts());
+
+        let config = EngineConfig::new("engine").spawn_retry(3, Duration::from_millis(100));
+        let retry = config.spawn_retry_policy();
+        let busy = Error::EngineIo(std::io::Error::from(std::io::ErrorKind::ResourceBusy));
+        let (event, retry_in) = ThreadedEngine::spawn_failure(&retry, "engine", 1, &busy);
+        assert_eq!(Some(Duration::from_millis(200)), retry_in);
+        assert!(matches!(
+            event,
+            StartupEvent::SpawnFailed {
+                attempt: 2,
+                retry_in: Some(_),
+                ..
+            }
+        ));
+        assert_eq!(
+            None,
+            ThreadedEngine::spawn_failure(&retry, "engine", 2, &busy).1
+        );
+
+        let missing = Error::EngineIo(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(!missing.is_transient());
+        assert_eq!(
+            None,
+            ThreadedEngine::spawn_failure(&retry, "engine", 0, &missing).1
+        );
+        assert!(Error::Timeout.is_transient());
+    }
+
+    #[test]
+    fn fallback_paths() {
+        let config = EngineConfig::new("engine-avx512")
+            .fallback_path("engine-avx2")
+            .fallback_path("engine-sse42");
+        assert_eq!(
+            vec!["engine-avx512", "engine-avx2", "engine-sse42"],
+            config.candidates().collect::<Vec<_>>()
+        );
+        assert_eq!(1, EngineConfig::new("engine").candidates().count());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fallback_on_exit() {
+        // `false` stands in for a build which dies before the handshake, e.g. with SIGILL.
+        let config = EngineConfig::new("false")
+            .fallback_path("sh")
+            .working_dir(".")
+            .arg("-c")
+            .arg(MOCK_ENGINE);
+        let engine = ThreadedEngine::spawn(config).unwrap();
+        assert_eq!(Path::new("sh"), engine.path());
+        assert_eq!("Mock", engine.name());
+        assert!(engine.info().is_some());
+
+        // Without a working candidate, the startup fails instead of hanging.
+        let mut engine =
+            ThreadedEngine::spawn(EngineConfig::new("false").working_dir(".")).unwrap();
+        assert!(engine.info().is_none());
+        assert!(engine.poll_error().is_some());
+    }
+
+    #[test]
+    fn temp_dir() {
+        let dir = TempDir::create().unwrap();
+        let other = TempDir::create().unwrap();
+        assert_ne!(dir.0, other.0);
+        let path = dir.0.clone();
+        std::fs::write(path.join("learn.bin"), b"data").unwrap();
+        drop(dir);
+        assert!(!path.exists());
+        assert!(other.0.is_dir());
+    }
+
+    #[test]
+    fn ready_token() {
+        let shared = Arc::new(Shared::default());
+        let token = ReadyToken {
+            target: 2,
+            shared: Arc::clone(&shared),
+        };
+
+        *shared.ready_acks.lock().unwrap() = 1;
+        assert!(!token.is_acknowledged());
+        assert!(matches!(
+            token.wait(Duration::from_millis(10)),
+            Err(Error::Timeout)
+        ));
+
+        let waiter = token.clone();
+        let handle = thread::spawn(move || waiter.wait_until(None));
+        *shared.ready_acks.lock().unwrap() = 2;
+        shared.ready_cond.notify_all();
+        assert!(handle.join().unwrap().is_ok());
+        assert!(token.is_acknowledged());
+    }
+
+    #[test]
+    fn command_policies() {
+        let config = EngineConfig::new("engine").ready_timeout(Duration::from_secs(60));
+        assert_eq!(
+            RetryPolicy::new().timeout(Duration::from_secs(60)),
+            config.ready_policy()
+        );
+        let retried = RetryPolicy::new()
+            .attempts(3)
+            .timeout(Duration::from_secs(5));
+        let config = config
+            .command_policy(RequestKind::IsReady, RetryPolicy::new())
+            .command_policy(RequestKind::IsReady, retried.clone());
+        assert_eq!(1, config.command_policies.len());
+        assert_eq!(retried, config.ready_policy());
+        assert_eq!(
+            RetryPolicy::new(),
+            EngineConfig::new("engine").ready_policy()
+        );
+    }
+
+    #[test]
+    fn overhead() {
+        let mut overhead = Overhead::default();
+        assert_eq!(Duration::ZERO, overhead.average());
+        for ms in [3, 1, 5] {
+            overhead.record(Duration::from_millis(ms));
+        }
+        assert_eq!(3, overhead.samples);
+        assert_eq!(Duration::from_millis(1), overhead.min);
+        assert_eq!(Duration::from_millis(5), overhead.max);
+        assert_eq!(Duration::from_millis(3), overhead.average());
+
+        let params = ThinkParams::blitz().default_margin(Duration::from_millis(50));
+        assert_eq!(Some(950), params.byoyomi_ms());
+        let params = params.margin(Duration::from_millis(200));
+        assert_eq!(
+            Some(800),
+            params
+                .default_margin(Duration::from_millis(50))
+                .byoyomi_ms()
+        );
+    }
+
+    #[test]
+    fn deadlines() {
+        let now = Instant::now();
+        let mut deadlines = Deadlines::default();
+        deadlines.insert(1, now + Duration::from_secs(1));
+        deadlines.insert(2, now);
+        deadlines.insert(3, now);
+
+        // The search of generation 3 is still held back.
+        assert_eq!(Some(now + Duration::from_secs(1)), deadlines.get(Some(1)));
+        assert!(!deadlines.expire(2, Some(1), now));
+        assert!(deadlines.expire(2, Some(1), now + Duration::from_secs(1)));
+        assert_eq!(None, deadlines.get(Some(1)));
+        assert!(deadlines.expire(3, Some(3), now));
+        assert!(deadlines.0.is_empty());
+    }
+
+    #[test]
+    fn search_generation() {
+        let shared = Shared::default();
+        shared.latest_generation.store(2, Ordering::SeqCst);
+        shared.pending_searches.lock().unwrap().extend([1, 2]);
+
+        let first = shared.tag(SearchOutcome::BestMove(BestMoveParams::Resign));
+        assert_eq!(1, first.generation);
+        assert!(first.stale);
+
+        let second = shared.tag(SearchOutcome::Checkmate(CheckmateParams::Mate(vec![
+            "G*5b".to_string(),
+        ])));
+        assert_eq!(2, second.generation);
+        assert!(!second.stale);
+        assert_eq!(Some("G*5b".to_string()), second.outcome.move_str());
+        assert_eq!(Some(second), *shared.latest_result.lock().unwrap());
+
+        // A ponder search abandoned after a miss.
+        shared.latest_generation.store(3, Ordering::SeqCst);
+        shared.abandoned_generation.store(3, Ordering::SeqCst);
+        shared.pending_searches.lock().unwrap().push_back(3);
+        assert!(
+            shared
+                .tag(SearchOutcome::BestMove(BestMoveParams::Resign))
+                .stale
+        );
+
+        assert_eq!(
+            None,
+            SearchOutcome::BestMove(BestMoveParams::Win).move_str()
+        );
+        assert_eq!(None, SearchOutcome::DeclareWin.move_str());
+        assert_eq!(
+            Some("resign".to_string()),
+            SearchOutcome::Checkmate(CheckmateParams::NoMate).move_str()
+        );
+    }
+
+    #[test]
+    fn current_search() {
+        let shared = Shared::default();
+        shared
+            .searches
+            .lock()
+            .unwrap()
+            .insert(1, SearchState::new(1));
+        thread::sleep(Duration::from_millis(10));
+        shared.start_search(1);
+        shared.record_info(&[InfoParams::Depth(4, None), InfoParams::Nodes(100)]);
+        shared.record_info(&[InfoParams::Depth(5, None), InfoParams::Time(Duration::ZERO)]);
+
+        let snapshot = shared.current.lock().unwrap().as_ref().unwrap().snapshot();
+        assert_eq!(1, snapshot.generation);
+        assert_eq!(Some(5), snapshot.info.depth);
+        assert_eq!(Some(100), snapshot.info.nodes);
+        assert!(!snapshot.finished);
+
+        let result = shared.tag(SearchOutcome::BestMove(BestMoveParams::Resign));
+        assert!(result.elapsed >= result.engine_elapsed + Duration::from_millis(10));
+        assert_eq!(Some(Duration::ZERO), result.reported_time);
+        let snapshot = shared.current.lock().unwrap().as_ref().unwrap().snapshot();
+        assert!(snapshot.finished);
+    }
+
+    #[test]
+    fn lag_stats() {
+        let shared = Shared::default();
+        shared.record_info(&[InfoParams::Time(Duration::ZERO)]);
+        assert_eq!(0, shared.lag.lock().unwrap().samples);
+
+        shared.start_search(1);
+        thread::sleep(Duration::from_millis(10));
+        shared.record_info(&[InfoParams::Depth(1, None)]);
+        shared.record_info(&[InfoParams::Time(Duration::ZERO)]);
+        shared.record_info(&[InfoParams::Time(Duration::from_secs(60))]);
+        let lag = *shared.lag.lock().unwrap();
+        assert_eq!(2, lag.samples);
+        assert!(lag.max >= Duration::from_millis(10));
+        assert_eq!(Duration::ZERO, lag.min);
+        assert_eq!(Duration::ZERO, lag.latest);
+    }
+
+    #[test]
+    fn info_coalescing() {
+        let mut state = SearchState::new(1);
+        let window = Some(Duration::from_secs(60));
+        state.push_info(
+            &[InfoParams::Depth(1, None), InfoParams::Pv(vec![])],
+            window,
+        );
+        state.push_info(
+            &[InfoParams::MultiPv(2), InfoParams::Depth(1, None)],
+            window,
+        );
+        state.push_info(&[InfoParams::Text("hello".to_string())], window);
+        state.push_info(&[InfoParams::Depth(2, None), InfoParams::Nodes(10)], window);
+        state.push_info(
+            &[InfoParams::MultiPv(2), InfoParams::Depth(2, None)],
+            window,
+        );
+
+        // Lines queued before the `info string` are replaced, and moved after it.
+        assert_eq!(
+            Some(vec![InfoParams::Text("hello".to_string())]),
+            state.pop_info()
+        );
+        assert_eq!(
+            Some(vec![InfoParams::Depth(2, None), InfoParams::Nodes(10)]),
+            state.pop_info()
+        );
+        assert_eq!(
+            Some(vec![InfoParams::MultiPv(2), InfoParams::Depth(2, None)]),
+            state.pop_info()
+        );
+        assert_eq!(None, state.pop_info());
+
+        // The pv and score always come from the same line.
+        let first = vec![
+            InfoParams::Depth(1, None),
+            InfoParams::Score(10, ScoreKind::CpExact),
+            InfoParams::Pv(vec!["7g7f".to_string()]),
+        ];
+        let second = vec![InfoParams::Depth(2, None), InfoParams::Nodes(100)];
+        let third = vec![
+            InfoParams::Depth(2, None),
+            InfoParams::Score(-5, ScoreKind::CpExact),
+            InfoParams::Pv(vec!["2g2f".to_string()]),
+        ];
+        state.push_info(&first, window);
+        state.push_info(
+            &[InfoParams::MultiPv(2), InfoParams::Depth(1, None)],
+            window,
+        );
+        state.push_info(&second, window);
+        assert_eq!(Some(second), state.pop_info());
+        state.push_info(&third, window);
+        assert_eq!(
+            Some(vec![InfoParams::MultiPv(2), InfoParams::Depth(1, None)]),
+            state.pop_info()
+        );
+        assert_eq!(Some(third), state.pop_info());
+        assert_eq!(None, state.pop_info());
+
+        state.push_info(&[InfoParams::Depth(3, None)], None);
+        state.push_info(&[InfoParams::Depth(4, None)], None);
+        assert_eq!(2, state.infos.len());
+    }
+
+    #[test]
+    fn search_handle() {
+        let shared = Arc::new(Shared::default());
+        let (command_sender, command_receiver) = channel();
+        shared.latest_generation.store(1, Ordering::SeqCst);
+        shared
+            .searches
+            .lock()
+            .unwrap()
+            .insert(1, SearchState::new(7));
+        shared.search_ids.lock().unwrap().insert(1, 7);
+        shared.pending_searches.lock().unwrap().push_back(1);
+        let handle = SearchHandle {
+            generation: 1,
+            search_id: 7,
+            shared: Arc::clone(&shared),
+            command_sender,
+        };
+
+        shared.record_info(&[InfoParams::Depth(1, None)]);
+        assert_eq!(
+            Some(vec![InfoParams::Depth(1, None)]),
+            handle.try_next_info()
+        );
+        assert!(handle.poll().is_none());
+
+        handle.stop();
+        assert!(matches!(
+            command_receiver.try_recv(),
+            Ok(ThreadCommand::StopSearch(1))
+        ));
+        handle.ponderhit();
+        assert!(matches!(
+            command_receiver.try_recv(),
+            Ok(ThreadCommand::Request(EngineRequest::PonderHit))
+        ));
+        assert_eq!(vec![RequestKind::PonderHit], *shared.queued.lock().unwrap());
+        shared.dequeue(RequestKind::PonderHit);
+        assert!(shared.queued.lock().unwrap().is_empty());
+
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        assert!(matches!(
+            handle.wait_or_cancel(Duration::from_secs(10), &cancel),
+            Err(Error::Cancelled)
+        ));
+        assert!(matches!(
+            command_receiver.try_recv(),
+            Ok(ThreadCommand::StopSearch(1))
+        ));
+
+        shared.record_info(&[InfoParams::Depth(2, None)]);
+        let timed = handle.try_next_timed_info().unwrap();
+        assert_eq!(vec![InfoParams::Depth(2, None)], timed.params);
+        assert_eq!(7, timed.search_id);
+        assert!(timed.elapsed <= handle.elapsed());
+
+        shared.record_info(&[InfoParams::Depth(3, None)]);
+        shared.tag(SearchOutcome::BestMove(BestMoveParams::Resign));
+        assert_eq!(
+            vec![vec![InfoParams::Depth(3, None)]],
+            handle.infos().collect::<Vec<_>>()
+        );
+        assert!(handle.is_finished());
+        let result = handle.wait(Duration::ZERO).unwrap();
+        assert_eq!(1, result.generation);
+        assert_eq!(handle.elapsed(), result.elapsed);
+        assert_eq!(7, result.search_id);
+        assert_eq!(7, handle.search_id());
+
+        drop(handle);
+        assert!(shared.searches.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_from_toml() {
+        let path = std::env::temp_dir().join(format!("usi-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            path = "/opt/yo"
+            args = ["--nnue"]
+            pre_handshake_options = [["Protocol", "usi"]]
+            handshake_timeout = 30000
+            keep_alive = 15000
+
+            [[option_profiles]]
+            USI_Hash = "1024"
+
+            [[option_profiles]]
+            Threads = "4"
+            "#,
+        )
+        .unwrap();
+        let config = EngineConfig::from_toml_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!("/opt/yo", config.path);
+        assert_eq!(vec!["--nnue".to_string()], config.args);
+        assert_eq!(
+            vec![("Protocol".to_string(), Some("usi".to_string()))],
+            config.pre_handshake_options
+        );
+        assert_eq!(Some(Duration::from_secs(30)), config.handshake_timeout);
+        assert_eq!(Some(Duration::from_secs(15)), config.keep_alive);
+        assert_eq!(2, config.option_profiles.len());
+        assert_eq!(Some("4"), config.option_profiles[1].get("Threads"));
+
+        assert!(matches!(
+            EngineConfig::from_toml_str("path = 1"),
+            Err(Error::IllegalConfig(_))
+        ));
+        assert!(
+            EngineConfig::from_toml_file(std::env::temp_dir().join("usi-missing.toml")).is_err()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_from_json() {
+        let path = std::env::temp_dir().join(format!("usi-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{
+                "path": "/opt/yo",
+                "args": ["--nnue"],
+                "pre_handshake_options": [["Protocol", "usi"]],
+                "handshake_timeout": 30000,
+                "option_profiles": [{"USI_Hash": "1024"}, {"Threads": "4"}]
+            }"#,
+        )
+        .unwrap();
+        let config = EngineConfig::from_json_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!("/opt/yo", config.path);
+        assert_eq!(vec!["--nnue".to_string()], config.args);
+        assert_eq!(
+            vec![("Protocol".to_string(), Some("usi".to_string()))],
+            config.pre_handshake_options
+        );
+        assert_eq!(Some(Duration::from_secs(30)), config.handshake_timeout);
+        assert_eq!(2, config.option_profiles.len());
+        assert_eq!(Some("4"), config.option_profiles[1].get("Threads"));
+
+        assert!(matches!(
+            EngineConfig::from_json_str(r#"{"path": 1}"#),
+            Err(Error::IllegalConfig(_))
+        ));
+    }
+
+    #[test]
+    fn abandoned_search() {
+        let shared = Arc::new(Shared::default());
+        let (command_sender, _command_receiver) = channel();
+        shared.latest_generation.store(2, Ordering::SeqCst);
+        shared
+            .searches
+            .lock()
+            .unwrap()
+            .insert(2, SearchState::new(2));
+        let handle = SearchHandle {
+            generation: 2,
+            search_id: 2,
+            shared: Arc::clone(&shared),
+            command_sender,
+        };
+
+        shared.abandon_search(2, Abandoned::Stopped);
+        assert!(handle.is_finished());
+        assert!(matches!(handle.wait(Duration::ZERO), Err(Error::Cancelled)));
+        assert_eq!(None, handle.infos().next());
+    }
+
+    /// A shell script answering the handshake, `isready`, and `stop` with a best move.
+    #[cfg(unix)]
+    const MOCK_ENGINE: &str = "while read -r cmd rest; do case $cmd in \
+        usi) echo 'id name Mock'; echo usiok;; \
+        isready) echo readyok;; \
+        stop) echo 'bestmove 7g7f';; \
+        quit) exit 0;; \
+        esac; done";
+
+    #[cfg(unix)]
+    fn mock_engine(script: &str) -> EngineConfig {
+        EngineConfig {
+            path: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            working_dir: Some(".".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_queued_search() {
+        let mut engine = ThreadedEngine::spawn(mock_engine(MOCK_ENGINE)).unwrap();
+        engine.set_go_policy(GoPolicy::Queue);
+        let running = engine.go(ThinkParams::new().infinite()).unwrap();
+        let queued = engine.go(ThinkParams::new().infinite()).unwrap();
+        queued.stop();
+        assert!(matches!(
+            queued.wait(Duration::from_secs(10)),
+            Err(Error::Cancelled)
+        ));
+        assert!(queued.is_finished());
+
+        // The running search is not stopped by the handle of the queued one.
+        engine.wait_ready(Duration::from_secs(10)).unwrap();
+        assert!(!running.is_finished());
+        running.stop();
+        assert_eq!(1, running.wait(Duration::from_secs(10)).unwrap().generation);
+
+        // The queued search is never sent to the engine.
+        engine.wait_ready(Duration::from_secs(10)).unwrap();
+        assert!(!engine.is_searching());
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    #[test]
+    fn engine_exit_finishes_searches() {
+        // Exits once a search is sent to it.
+        let script = "while read -r cmd rest; do case $cmd in \
+            usi) echo usiok;; \
+            isready) echo readyok;; \
+            go) exit 0;; \
+            esac; done";
+        let mut engine = ThreadedEngine::spawn(mock_engine(script)).unwrap();
+        engine.set_go_policy(GoPolicy::Queue);
+        let running = engine.go(ThinkParams::new().infinite()).unwrap();
+        let queued = engine.go(ThinkParams::new().infinite()).unwrap();
+
+        for search in [&running, &queued] {
+            assert!(matches!(
+                search.wait(Duration::from_secs(10)),
+                Err(Error::Terminated)
+            ));
+            assert!(search.is_finished());
+            assert_eq!(None, search.infos().next());
+        }
+    }
+
+    #[cfg(all(unix, feature = "tracing"))]
+    #[test]
+    fn search_span() {
+        use std::cell::RefCell;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Records the `search` spans with their fields, and the events logged in them.
+        #[derive(Default)]
+        struct Recorder {
+            spans: Mutex<Vec<HashMap<&'static str, String>>>,
+            events: Mutex<Vec<(u64, String)>>,
+            closed: Mutex<Vec<u64>>,
+        }
+
+        #[derive(Default)]
+        struct Fields(HashMap<&'static str, String>);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name(), format!("{value:?}"));
+            }
+        }
+
+        thread_local! {
+            static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+        }
+
+        impl Subscriber for &'static Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                fields.0.insert("name", span.metadata().name().to_string());
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(fields.0);
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                if let Some(span) = ENTERED.with(|e| e.borrow().last().copied()) {
+                    let message = fields.0.remove("message").unwrap_or_default();
+                    self.events.lock().unwrap().push((span, message));
+                }
+            }
+            fn enter(&self, span: &Id) {
+                ENTERED.with(|e| e.borrow_mut().push(span.into_u64()));
+            }
+            fn exit(&self, _: &Id) {
+                ENTERED.with(|e| e.borrow_mut().pop());
+            }
+            fn try_close(&self, span: Id) -> bool {
+                self.closed.lock().unwrap().push(span.into_u64());
+                true
+            }
+        }
+
+        let recorder: &'static Recorder = Box::leak(Box::default());
+        tracing::subscriber::set_global_default(recorder).unwrap();
+
+        let mut engine = ThreadedEngine::spawn(mock_engine(MOCK_ENGINE)).unwrap();
+        let search = engine.go(ThinkParams::new().infinite()).unwrap();
+        search.stop();
+        search.wait(Duration::from_secs(10)).unwrap();
+
+        let span = recorder
+            .spans
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|fields| {
+                fields.get("name").map(String::as_str) == Some("search")
+                    && fields.get("search_id") == Some(&search.search_id().to_string())
+            })
+            .map(|i| i as u64 + 1)
+            .unwrap();
+        assert_eq!(
+            Some("1"),
+            recorder.spans.lock().unwrap()[span as usize - 1]
+                .get("generation")
+                .map(String::as_str)
+        );
+        // The span is closed by the engine thread once the result has arrived.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !recorder.closed.lock().unwrap().contains(&span) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let events: Vec<_> = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(s, _)| *s == span)
+            .map(|(_, message)| message.clone())
+            .collect();
+        assert_eq!(vec!["go", "bestmove"], events);
+    }
+
+    #[test]
+    fn tracked_position() {
+        let mut tracked = TrackedPosition::new("startpos moves 7g7f").unwrap();
+        assert_eq!(
+            format!("{} moves 7g7f", crate::game::STARTPOS),
+            tracked.argument()
+        );
+        tracked.push("3c3d").unwrap();
+        assert!(matches!(tracked.push("3c3d"), Err(Error::IllegalMove(_))));
+        assert_eq!(vec!["7g7f", "3c3d"], tracked.moves);
+        assert_eq!(
+            "lnsgkgsnl/1r5b1/pppppp1pp/6p2/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL b - 3",
+            tracked.current.to_sfen()
+        );
+        assert!(TrackedPosition::new("startpos moves 7g7e").is_err());
+
+        let after = tracked.current.clone();
+        assert_eq!(Some("3c3d".to_string()), tracked.undo());
+        assert_eq!(Some("7g7f".to_string()), tracked.undo());
+        assert_eq!(None, tracked.undo());
+        assert_eq!(crate::game::STARTPOS, tracked.argument());
+        assert_eq!(Some("7g7f".to_string()), tracked.redo());
+        assert_eq!(Some("3c3d".to_string()), tracked.redo());
+        assert_eq!(after, tracked.current);
+        tracked.undo();
+        tracked.push("8c8d").unwrap();
+        assert_eq!(None, tracked.redo());
+        assert_eq!(
+            format!("{} moves 7g7f 8c8d", crate::game::STARTPOS),
+            tracked.argument()
+        );
+        assert_eq!(
+            Some(format!("{} moves 7g7f 8c8d 2g2f", crate::game::STARTPOS)),
+            tracked.argument_after("2g2f")
+        );
+        assert_eq!(None, tracked.argument_after("2g2e"));
+        assert_eq!(
+            Some(format!("{} moves 7g7f", crate::game::STARTPOS)),
+            TrackedPosition::new("startpos")
+                .unwrap()
+                .argument_after("7g7f")
+        );
+    }
+}
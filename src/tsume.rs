@@ -0,0 +1,398 @@
+//! Solving collections of mate problems (tsume shogi) with `go mate`.
+//!
+//! Problems are read one per line: the four SFEN fields, optionally followed by
+//! `;`-terminated operations, `id` naming the problem and `dm` giving the
+//! length of the expected mate in plies. Empty lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! 4k4/9/4P4/9/9/9/9/9/9 b G 1 id "drop"; dm 1;
+//! ```
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::tsume::{TsumeSet, TsumeSolver};
+//! use usi::{EngineConfig, ThreadedEngine};
+//!
+//! let problems = TsumeSet::from_file("/path/to/tsume.txt").unwrap();
+//! let mut engine = ThreadedEngine::spawn(EngineConfig::new("/path/to/engine")).unwrap();
+//! let report = TsumeSolver::new(Duration::from_secs(10))
+//!     .solve(&mut engine, &problems)
+//!     .unwrap();
+//! println!("{}", report);
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::protocol::CheckmateParams;
+use crate::threaded::{SearchOutcome, ThreadedEngine};
+
+/// How long to wait for an answer after the mate search should have timed out.
+const ANSWER_MARGIN: Duration = Duration::from_secs(2);
+
+/// A mate problem.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TsumeProblem {
+    /// Name of the problem, from the `id` operation.
+    pub id: Option<String>,
+    /// The position in SFEN notation.
+    pub position: String,
+    /// Length of the mate in plies, from the `dm` operation.
+    pub length: Option<usize>,
+}
+
+impl FromStr for TsumeProblem {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(Error::IllegalConfig(format!("incomplete SFEN: {}", s)));
+        }
+
+        let mut problem = TsumeProblem {
+            position: fields[..4].join(" "),
+            ..Default::default()
+        };
+        for operation in fields[4..].join(" ").split(';') {
+            let operation = operation.trim();
+            match operation.split_whitespace().next() {
+                Some("id") => {
+                    let id = operation.trim_start_matches("id").trim();
+                    problem.id = Some(id.trim_matches('"').to_string());
+                }
+                Some("dm") => {
+                    let length = operation.trim_start_matches("dm").trim();
+                    problem.length = Some(length.parse().map_err(|_| {
+                        Error::IllegalConfig(format!("invalid mate length: {}", length))
+                    })?);
+                }
+                _ => {}
+            }
+        }
+        Ok(problem)
+    }
+}
+
+/// A collection of mate problems.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TsumeSet {
+    pub problems: Vec<TsumeProblem>,
+}
+
+impl TsumeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a problem.
+    #[must_use]
+    pub fn problem(mut self, problem: TsumeProblem) -> Self {
+        self.problems.push(problem);
+        self
+    }
+
+    /// Parses problems, one per line.
+    ///
+    /// Errors name the line of the first problem which could not be parsed.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut set = TsumeSet::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let problem = line.parse().map_err(|err| match err {
+                Error::IllegalConfig(msg) => {
+                    Error::IllegalConfig(format!("line {}: {}", i + 1, msg))
+                }
+                err => err,
+            })?;
+            set.problems.push(problem);
+        }
+        Ok(set)
+    }
+
+    /// Loads problems from a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(path)?;
+        Self::parse(&s)
+    }
+
+    pub fn len(&self) -> usize {
+        self.problems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Runs `go mate` on every problem of a `TsumeSet`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TsumeSolver {
+    timeout: Duration,
+}
+
+impl TsumeSolver {
+    /// Creates a solver giving the engine `timeout` per problem.
+    pub fn new(timeout: Duration) -> Self {
+        TsumeSolver { timeout }
+    }
+
+    /// Solves the problems one after another.
+    ///
+    /// Engines not answering in time are stopped, and the problem counts as timed out.
+    /// Returns the first other error of a search.
+    pub fn solve(&self, engine: &mut ThreadedEngine, set: &TsumeSet) -> Result<TsumeReport, Error> {
+        let mut results = Vec::with_capacity(set.len());
+        for problem in &set.problems {
+            engine.set_position(&problem.position);
+            let search = engine.go_mate(Some(self.timeout))?;
+            let outcome = match search.wait(self.timeout + ANSWER_MARGIN) {
+                Ok(result) => Some(result.outcome),
+                Err(Error::Timeout) => {
+                    search.stop();
+                    search.wait(ANSWER_MARGIN).ok().map(|result| result.outcome)
+                }
+                Err(err) => return Err(err),
+            };
+            results.push(TsumeResult::new(problem, outcome, search.elapsed()));
+        }
+        Ok(TsumeReport { results })
+    }
+}
+
+/// How a mate search ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TsumeStatus {
+    /// The engine found a mate.
+    Solved,
+    /// The engine proved there is no mate.
+    NoMate,
+    /// The engine gave up or did not answer in time.
+    Timeout,
+    /// The engine does not support `go mate`.
+    NotImplemented,
+}
+
+/// The outcome of one problem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TsumeResult {
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub id: Option<String>,
+    pub position: String,
+    pub status: TsumeStatus,
+    /// The mating sequence, if solved.
+    pub moves: Vec<String>,
+    /// The expected length of the mate, if known.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub expected_length: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::threaded::millis::required"))]
+    pub elapsed: Duration,
+}
+
+impl TsumeResult {
+    fn new(problem: &TsumeProblem, outcome: Option<SearchOutcome>, elapsed: Duration) -> Self {
+        let (status, moves) = match outcome {
+            Some(SearchOutcome::Checkmate(CheckmateParams::Mate(moves))) => {
+                (TsumeStatus::Solved, moves)
+            }
+            Some(SearchOutcome::Checkmate(CheckmateParams::NoMate)) => {
+                (TsumeStatus::NoMate, Vec::new())
+            }
+            Some(SearchOutcome::Checkmate(CheckmateParams::Timeout)) | None => {
+                (TsumeStatus::Timeout, Vec::new())
+            }
+            // Engines without mate search may answer `go mate` with a regular `bestmove`.
+            Some(SearchOutcome::Checkmate(CheckmateParams::NotImplemented))
+            | Some(SearchOutcome::BestMove(_))
+            | Some(SearchOutcome::DeclareWin) => (TsumeStatus::NotImplemented, Vec::new()),
+        };
+        TsumeResult {
+            id: problem.id.clone(),
+            position: problem.position.clone(),
+            status,
+            moves,
+            expected_length: problem.length,
+            elapsed,
+        }
+    }
+
+    /// Returns the length of the mate found in plies.
+    pub fn mate_length(&self) -> Option<usize> {
+        (self.status == TsumeStatus::Solved).then_some(self.moves.len())
+    }
+
+    /// Returns `true` if a mate was found and its length is the expected one, if known.
+    pub fn is_correct(&self) -> bool {
+        self.mate_length().is_some()
+            && self
+                .expected_length
+                .is_none_or(|n| Some(n) == self.mate_length())
+    }
+}
+
+/// Results of `TsumeSolver::solve`, displayed with one line per problem and a summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TsumeReport {
+    pub results: Vec<TsumeResult>,
+}
+
+impl TsumeReport {
+    /// Returns the number of results with `status`.
+    pub fn count(&self, status: TsumeStatus) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+
+    /// Returns the number of problems solved with the expected mate length.
+    pub fn correct(&self) -> usize {
+        self.results.iter().filter(|r| r.is_correct()).count()
+    }
+
+    /// Returns how many mates of each length were found.
+    pub fn mate_lengths(&self) -> BTreeMap<usize, usize> {
+        let mut lengths = BTreeMap::new();
+        for length in self.results.iter().filter_map(TsumeResult::mate_length) {
+            *lengths.entry(length).or_insert(0) += 1;
+        }
+        lengths
+    }
+
+    /// Parses a report saved with `to_toml_string`.
+    #[cfg(feature = "serde")]
+    pub fn from_toml_str(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_toml_string(&self) -> Result<String, Error> {
+        toml::to_string(self).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+}
+
+impl fmt::Display for TsumeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, result) in self.results.iter().enumerate() {
+            let id = result.id.clone().unwrap_or_else(|| (i + 1).to_string());
+            write!(f, "{}: ", id)?;
+            match result.status {
+                TsumeStatus::Solved => write!(f, "mate in {}", result.moves.len())?,
+                TsumeStatus::NoMate => write!(f, "no mate")?,
+                TsumeStatus::Timeout => write!(f, "timeout")?,
+                TsumeStatus::NotImplemented => write!(f, "not implemented")?,
+            }
+            if let Some(expected) = result.expected_length.filter(|_| !result.is_correct()) {
+                write!(f, ", expected mate in {}", expected)?;
+            }
+            writeln!(f, " ({}ms)", result.elapsed.as_millis())?;
+        }
+        write!(
+            f,
+            "solved {}/{} ({} with the expected length), no mate {}, timeout {}",
+            self.count(TsumeStatus::Solved),
+            self.results.len(),
+            self.correct(),
+            self.count(TsumeStatus::NoMate),
+            self.count(TsumeStatus::Timeout)
+        )?;
+        let lengths = self.mate_lengths();
+        if !lengths.is_empty() {
+            let lengths: Vec<String> = lengths
+                .iter()
+                .map(|(length, count)| format!("{}: {}", length, count))
+                .collect();
+            write!(f, "\nmate lengths: {}", lengths.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::BestMoveParams;
+
+    #[test]
+    fn parse() {
+        let set = TsumeSet::parse(
+            "# comment\n\
+             4k4/9/4P4/9/9/9/9/9/9 b G 1 id \"drop\"; dm 1;\n\
+             \n\
+             4k4/9/9/9/9/9/9/9/9 b G 1",
+        )
+        .unwrap();
+        assert_eq!(2, set.len());
+        assert_eq!(Some("drop".to_string()), set.problems[0].id);
+        assert_eq!(Some(1), set.problems[0].length);
+        assert_eq!("4k4/9/9/9/9/9/9/9/9 b G 1", set.problems[1].position);
+        assert_eq!(None, set.problems[1].length);
+
+        let err = TsumeSet::parse("\n4k4/9/4P4/9/9/9/9/9/9 b G 1 dm x;").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn report() {
+        let problem = TsumeProblem {
+            id: Some("a".to_string()),
+            position: "4k4/9/4P4/9/9/9/9/9/9 b G 1".to_string(),
+            length: Some(1),
+        };
+        let mate = |moves: &[&str]| {
+            Some(SearchOutcome::Checkmate(CheckmateParams::Mate(
+                moves.iter().map(|m| m.to_string()).collect(),
+            )))
+        };
+        let ms = Duration::from_millis;
+        let report = TsumeReport {
+            results: vec![
+                TsumeResult::new(&problem, mate(&["G*5b"]), ms(10)),
+                TsumeResult::new(&problem, mate(&["G*4b", "5a6a", "4b5b"]), ms(20)),
+                TsumeResult::new(
+                    &problem,
+                    Some(SearchOutcome::Checkmate(CheckmateParams::NoMate)),
+                    ms(30),
+                ),
+                TsumeResult::new(&problem, None, ms(40)),
+                TsumeResult::new(
+                    &TsumeProblem {
+                        id: None,
+                        length: None,
+                        ..problem.clone()
+                    },
+                    Some(SearchOutcome::BestMove(BestMoveParams::Resign)),
+                    ms(50),
+                ),
+            ],
+        };
+        assert_eq!(2, report.count(TsumeStatus::Solved));
+        assert_eq!(1, report.correct());
+        assert_eq!(
+            "a: mate in 1 (10ms)\n\
+             a: mate in 3, expected mate in 1 (20ms)\n\
+             a: no mate, expected mate in 1 (30ms)\n\
+             a: timeout, expected mate in 1 (40ms)\n\
+             5: not implemented (50ms)\n\
+             solved 2/5 (1 with the expected length), no mate 1, timeout 1\n\
+             mate lengths: 1: 1, 3: 1",
+            report.to_string()
+        );
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            report,
+            TsumeReport::from_toml_str(&report.to_toml_string().unwrap()).unwrap()
+        );
+    }
+}
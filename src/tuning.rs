@@ -0,0 +1,676 @@
+//! Matches over a grid of engine option values.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use usi::game::TimeControl;
+//! use usi::tuning::Sweep;
+//! use usi::EngineConfig;
+//!
+//! let time_control = TimeControl::new().byoyomi(Duration::from_millis(500));
+//! let sweep = Sweep::new(
+//!     EngineConfig::new("/path/to/engine"),
+//!     EngineConfig::new("/path/to/engine"),
+//!     time_control,
+//! )
+//! .option("SlowMover", &["80", "100", "120"])
+//! .games(100);
+//!
+//! for result in sweep.run().unwrap() {
+//!     let score = result.score;
+//!     println!(
+//!         "{:?}: {:+.0} +/- {:.0} Elo over {} games",
+//!         result.options,
+//!         score.elo().unwrap(),
+//!         score.elo_margin().unwrap(),
+//!         score.games()
+//!     );
+//! }
+//! ```
+
+use crate::error::Error;
+use crate::game::{play_game, Color, GameResult, TimeControl, STARTPOS};
+use crate::registry::OptionProfile;
+use crate::threaded::{EngineConfig, ThreadedEngine};
+
+/// Quantile of the normal distribution for a 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+/// Exponent of the decay of the SPSA learning rate.
+const SPSA_ALPHA: f64 = 0.602;
+
+/// Exponent of the decay of the SPSA perturbation.
+const SPSA_GAMMA: f64 = 0.101;
+
+/// Stability constant of the SPSA learning rate, relative to the number of iterations.
+const SPSA_STABILITY: f64 = 0.1;
+
+const DEFAULT_SPSA_RATE: f64 = 0.002;
+
+/// Wins, losses and draws of an engine against an opponent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchScore {
+    pub wins: usize,
+    pub losses: usize,
+    pub draws: usize,
+}
+
+impl MatchScore {
+    /// Adds the result of a game played as `color`.
+    pub fn record(&mut self, result: &GameResult, color: Color) {
+        match result.winner {
+            None => self.draws += 1,
+            Some(w) if w == color => self.wins += 1,
+            Some(_) => self.losses += 1,
+        }
+    }
+
+    pub fn games(&self) -> usize {
+        self.wins + self.losses + self.draws
+    }
+
+    /// Returns the fraction of points scored, counting draws as half a point,
+    /// or `None` if no game was played.
+    pub fn score(&self) -> Option<f64> {
+        let games = self.games();
+        if games == 0 {
+            return None;
+        }
+        Some((self.wins as f64 + self.draws as f64 / 2.0) / games as f64)
+    }
+
+    /// Returns the Elo difference to the opponent, or `None` if no game was played.
+    ///
+    /// The difference is infinite if all games were won or lost.
+    pub fn elo(&self) -> Option<f64> {
+        self.score().map(elo)
+    }
+
+    /// Returns the half width of the 95% confidence interval of `elo`,
+    /// or `None` if no game was played.
+    pub fn elo_margin(&self) -> Option<f64> {
+        let score = self.score()?;
+        let games = self.games() as f64;
+        let variance = (self.wins as f64 * (1.0 - score).powi(2)
+            + self.losses as f64 * score.powi(2)
+            + self.draws as f64 * (0.5 - score).powi(2))
+            / games;
+        let delta = Z_95 * (variance / games).sqrt();
+        let upper = elo((score + delta).min(1.0));
+        let lower = elo((score - delta).max(0.0));
+        Some((upper - lower) / 2.0)
+    }
+}
+
+fn elo(score: f64) -> f64 {
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+/// The score of one configuration of a `Sweep`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SweepResult {
+    /// The option values of the configuration.
+    pub options: OptionProfile,
+    /// The score of the configured engine against the opponent.
+    pub score: MatchScore,
+}
+
+/// Matches of an engine against a fixed opponent, for each combination
+/// of a grid of option values.
+///
+/// For each configuration, the engine is spawned with the option values
+/// applied as an `OptionProfile` and plays `games` games with `play_game`,
+/// alternating colors and cycling through the openings, each opening
+/// being played once with each color.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    candidate: EngineConfig,
+    opponent: EngineConfig,
+    time_control: TimeControl,
+    grid: Vec<(String, Vec<String>)>,
+    games: usize,
+    openings: Vec<String>,
+}
+
+impl Sweep {
+    pub fn new(candidate: EngineConfig, opponent: EngineConfig, time_control: TimeControl) -> Self {
+        Sweep {
+            candidate,
+            opponent,
+            time_control,
+            grid: Vec::new(),
+            games: 2,
+            openings: Vec::new(),
+        }
+    }
+
+    /// Adds an option and the values it takes in the grid.
+    #[must_use]
+    pub fn option(mut self, name: &str, values: &[&str]) -> Self {
+        let values = values.iter().map(|v| v.to_string()).collect();
+        self.grid.push((name.to_string(), values));
+        self
+    }
+
+    /// Sets the number of games played by each configuration, 2 by default.
+    #[must_use]
+    pub fn games(mut self, games: usize) -> Self {
+        self.games = games;
+        self
+    }
+
+    /// Adds an opening position in SFEN. The initial position is used if none was added.
+    #[must_use]
+    pub fn opening(mut self, sfen: &str) -> Self {
+        self.openings.push(sfen.to_string());
+        self
+    }
+
+    /// Returns every combination of the option values, varying the last option fastest.
+    pub fn configurations(&self) -> Vec<OptionProfile> {
+        self.grid
+            .iter()
+            .fold(vec![OptionProfile::new()], |profiles, (name, values)| {
+                profiles
+                    .iter()
+                    .flat_map(|p| values.iter().map(move |v| p.clone().set(name, v)))
+                    .collect()
+            })
+    }
+
+    /// Plays the games of every configuration and returns their scores,
+    /// in the order of `configurations`.
+    ///
+    /// Returns an error if an engine could not be spawned or prepared.
+    pub fn run(&self) -> Result<Vec<SweepResult>, Error> {
+        let mut opponent = ThreadedEngine::spawn(self.opponent.clone())?;
+        let mut first_game = true;
+        self.configurations()
+            .into_iter()
+            .map(|options| {
+                let config = self.candidate.clone().option_profile(options.clone());
+                let mut candidate = ThreadedEngine::spawn(config)?;
+                let mut score = MatchScore::default();
+                for game in 0..self.games {
+                    if !first_game {
+                        opponent.new_game(None);
+                    }
+                    if game > 0 {
+                        candidate.new_game(None);
+                    }
+                    first_game = false;
+                    let (color, opening) = self.pairing(game);
+                    let (black, white) = match color {
+                        Color::Black => (&mut candidate, &mut opponent),
+                        Color::White => (&mut opponent, &mut candidate),
+                    };
+                    let record = play_game(black, white, self.time_control, opening)?;
+                    score.record(&record.result, color);
+                }
+                Ok(SweepResult { options, score })
+            })
+            .collect()
+    }
+
+    /// Returns the color of the candidate and the opening of a game.
+    fn pairing(&self, game: usize) -> (Color, &str) {
+        let color = if game % 2 == 0 {
+            Color::Black
+        } else {
+            Color::White
+        };
+        let opening = match self.openings.len() {
+            0 => STARTPOS,
+            n => &self.openings[game / 2 % n],
+        };
+        (color, opening)
+    }
+}
+
+/// A numeric option tuned by `Spsa`.
+///
+/// Values are sent rounded to the nearest integer, as USI `spin` options are integers.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpsaParam {
+    pub name: String,
+    /// The current estimate of the best value.
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Perturbation applied to the value at the end of the run.
+    pub step: f64,
+    /// Learning rate at the end of the run, relative to `step`.
+    pub rate: f64,
+}
+
+impl SpsaParam {
+    /// A parameter starting at `value` within `min..=max`, perturbed by `step`
+    /// at the end of the run, with a learning rate of 0.002.
+    pub fn new(name: &str, value: f64, min: f64, max: f64, step: f64) -> Self {
+        SpsaParam {
+            name: name.to_string(),
+            value,
+            min,
+            max,
+            step,
+            rate: DEFAULT_SPSA_RATE,
+        }
+    }
+
+    #[must_use]
+    pub fn rate(mut self, rate: f64) -> Self {
+        self.rate = rate;
+        self
+    }
+}
+
+/// The progress of an `Spsa` run, which can be saved and passed to
+/// `Spsa::resume` to continue the run.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpsaState {
+    pub params: Vec<SpsaParam>,
+    /// Number of completed iterations.
+    pub iteration: u64,
+    /// Number of iterations of the run, which determines the decay of the gains.
+    pub iterations: u64,
+    /// Seed of the perturbations, which only depend on the seed and the iteration.
+    pub seed: u64,
+    /// Results of the engine with the values perturbed upwards against the other.
+    pub score: MatchScore,
+}
+
+impl SpsaState {
+    pub fn is_finished(&self) -> bool {
+        self.iteration >= self.iterations
+    }
+
+    /// Returns the current values, rounded as they are sent to the engine.
+    pub fn values(&self) -> OptionProfile {
+        self.profile(|p| p.value)
+    }
+
+    /// Parses a state saved with `to_toml_string`.
+    #[cfg(feature = "serde")]
+    pub fn from_toml_str(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_toml_string(&self) -> Result<String, Error> {
+        toml::to_string(self).map_err(|e| Error::IllegalConfig(e.to_string()))
+    }
+
+    /// Returns the direction of the perturbation of each parameter, 1 or -1,
+    /// for the next iteration.
+    fn directions(&self) -> Vec<f64> {
+        (0..self.params.len() as u64)
+            .map(|i| {
+                let bits = splitmix64(
+                    self.seed
+                        .wrapping_add(self.iteration.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+                        .wrapping_add(i),
+                );
+                if bits & 1 == 0 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the perturbation and the learning rate of `param` for the next iteration.
+    fn gains(&self, param: &SpsaParam) -> (f64, f64) {
+        let n = self.iterations.max(1) as f64;
+        let k = (self.iteration + 1) as f64;
+        let stability = SPSA_STABILITY * n;
+        let c = param.step * n.powf(SPSA_GAMMA) / k.powf(SPSA_GAMMA);
+        let a = param.rate * param.step.powi(2) * (stability + n).powf(SPSA_ALPHA)
+            / (stability + k).powf(SPSA_ALPHA);
+        (c, a)
+    }
+
+    /// Returns the values of the engines perturbed upwards and downwards along `directions`.
+    fn perturbed(&self, directions: &[f64]) -> (OptionProfile, OptionProfile) {
+        let shifted = |sign: f64| {
+            let mut i = 0;
+            self.profile(|p| {
+                let (c, _) = self.gains(p);
+                let value = p.value + sign * c * directions[i];
+                i += 1;
+                value.clamp(p.min, p.max)
+            })
+        };
+        (shifted(1.0), shifted(-1.0))
+    }
+
+    /// Moves the values along `directions` by `result`, the points of the engine
+    /// perturbed upwards minus the points of the other, and ends the iteration.
+    fn update(&mut self, directions: &[f64], result: f64) {
+        let gains: Vec<_> = self.params.iter().map(|p| self.gains(p)).collect();
+        for ((param, (c, a)), direction) in self.params.iter_mut().zip(gains).zip(directions) {
+            param.value = (param.value + a / c * result * direction).clamp(param.min, param.max);
+        }
+        self.iteration += 1;
+    }
+
+    fn profile<F: FnMut(&SpsaParam) -> f64>(&self, mut value: F) -> OptionProfile {
+        let mut profile = OptionProfile::new();
+        for p in &self.params {
+            profile.insert(&p.name, &(value(p).round() as i64).to_string());
+        }
+        profile
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// A stochastic tuner of numeric engine options (SPSA).
+///
+/// Each iteration perturbs all parameters at once in random directions,
+/// plays a pair of games between an engine with the values perturbed upwards
+/// and one with the values perturbed downwards, each color once on the same
+/// opening, and moves the values towards the side which scored better.
+/// The perturbations and learning rates decrease over the iterations,
+/// following the schedule used by fishtest.
+///
+/// Both engines are spawned from the same configuration on the first
+/// iteration and receive the perturbed values with `setoption` before each pair.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use usi::game::TimeControl;
+/// use usi::tuning::{Spsa, SpsaParam};
+/// use usi::EngineConfig;
+///
+/// let mut spsa = Spsa::new(
+///     EngineConfig::new("/path/to/engine"),
+///     TimeControl::new().byoyomi(Duration::from_millis(200)),
+///     1000,
+/// )
+/// .param(SpsaParam::new("SlowMover", 100.0, 10.0, 500.0, 10.0));
+///
+/// while spsa.step().unwrap() {
+///     // Save `spsa.state()` to resume the run later.
+/// }
+/// println!("{:?}", spsa.state().values());
+/// ```
+#[derive(Debug)]
+pub struct Spsa {
+    config: EngineConfig,
+    time_control: TimeControl,
+    openings: Vec<String>,
+    state: SpsaState,
+    engines: Option<(ThreadedEngine, ThreadedEngine)>,
+}
+
+impl Spsa {
+    pub fn new(config: EngineConfig, time_control: TimeControl, iterations: u64) -> Self {
+        Spsa::resume(
+            config,
+            time_control,
+            SpsaState {
+                iterations,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Continues a run from a saved state.
+    pub fn resume(config: EngineConfig, time_control: TimeControl, state: SpsaState) -> Self {
+        Spsa {
+            config,
+            time_control,
+            openings: Vec::new(),
+            state,
+            engines: None,
+        }
+    }
+
+    #[must_use]
+    pub fn param(mut self, param: SpsaParam) -> Self {
+        self.state.params.push(param);
+        self
+    }
+
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.state.seed = seed;
+        self
+    }
+
+    /// Adds an opening position in SFEN, used in turn by the iterations.
+    /// The initial position is used if none was added.
+    #[must_use]
+    pub fn opening(mut self, sfen: &str) -> Self {
+        self.openings.push(sfen.to_string());
+        self
+    }
+
+    pub fn state(&self) -> &SpsaState {
+        &self.state
+    }
+
+    /// Runs the next iteration, returning `false` without playing if the run is finished.
+    ///
+    /// Returns an error if an engine could not be spawned or prepared.
+    /// The state is only updated once both games were played.
+    pub fn step(&mut self) -> Result<bool, Error> {
+        if self.state.is_finished() {
+            return Ok(false);
+        }
+        let (plus, minus) = match &mut self.engines {
+            Some(engines) => engines,
+            engines => engines.insert((
+                ThreadedEngine::spawn(self.config.clone())?,
+                ThreadedEngine::spawn(self.config.clone())?,
+            )),
+        };
+        let directions = self.state.directions();
+        let (plus_values, minus_values) = self.state.perturbed(&directions);
+        for (engine, values) in [(&mut *plus, &plus_values), (&mut *minus, &minus_values)] {
+            for (name, value) in values.iter() {
+                engine.set_option(name, Some(value));
+            }
+        }
+        let opening = match self.openings.len() {
+            0 => STARTPOS,
+            n => &self.openings[(self.state.iteration % n as u64) as usize],
+        };
+
+        let mut score = MatchScore::default();
+        for color in [Color::Black, Color::White] {
+            plus.new_game(None);
+            minus.new_game(None);
+            let (black, white) = match color {
+                Color::Black => (&mut *plus, &mut *minus),
+                Color::White => (&mut *minus, &mut *plus),
+            };
+            let record = play_game(black, white, self.time_control, opening)?;
+            score.record(&record.result, color);
+        }
+        self.state.score.wins += score.wins;
+        self.state.score.losses += score.losses;
+        self.state.score.draws += score.draws;
+        let result = score.wins as f64 - score.losses as f64;
+        self.state.update(&directions, result);
+        Ok(true)
+    }
+
+    /// Runs the remaining iterations and returns the final state.
+    pub fn run(&mut self) -> Result<&SpsaState, Error> {
+        while self.step()? {}
+        Ok(&self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::GameEndReason;
+
+    #[test]
+    fn match_score() {
+        let mut score = MatchScore::default();
+        assert_eq!(None, score.elo());
+        assert_eq!(None, score.elo_margin());
+
+        let won = GameResult {
+            winner: Some(Color::White),
+            reason: GameEndReason::Resign,
+        };
+        score.record(&won, Color::White);
+        score.record(&won, Color::Black);
+        assert_eq!((1, 1, 0), (score.wins, score.losses, score.draws));
+        assert_eq!(Some(0.0), score.elo());
+
+        let score = MatchScore {
+            wins: 6,
+            losses: 4,
+            draws: 0,
+        };
+        assert!((score.elo().unwrap() - 70.44).abs() < 0.01);
+        assert!((score.elo_margin().unwrap() - 269.53).abs() < 0.01);
+        assert_eq!(
+            Some(f64::INFINITY),
+            MatchScore {
+                wins: 2,
+                ..Default::default()
+            }
+            .elo()
+        );
+    }
+
+    #[test]
+    fn configurations() {
+        let sweep = Sweep::new(
+            EngineConfig::new("a"),
+            EngineConfig::new("b"),
+            TimeControl::new(),
+        )
+        .option("SlowMover", &["80", "120"])
+        .option("Temperature", &["0", "1", "2"])
+        .opening("a")
+        .opening("b");
+        let configurations = sweep.configurations();
+        assert_eq!(6, configurations.len());
+        assert_eq!(
+            OptionProfile::new()
+                .set("SlowMover", "80")
+                .set("Temperature", "2"),
+            configurations[2]
+        );
+        assert_eq!(Some("120"), configurations[3].get("SlowMover"));
+
+        let pairings: Vec<_> = (0..5).map(|g| sweep.pairing(g)).collect();
+        assert_eq!(
+            vec![
+                (Color::Black, "a"),
+                (Color::White, "a"),
+                (Color::Black, "b"),
+                (Color::White, "b"),
+                (Color::Black, "a"),
+            ],
+            pairings
+        );
+        assert_eq!(
+            1,
+            Sweep::new(
+                EngineConfig::new("a"),
+                EngineConfig::new("b"),
+                TimeControl::new()
+            )
+            .configurations()
+            .len()
+        );
+    }
+
+    #[test]
+    fn spsa() {
+        let mut state = SpsaState {
+            params: vec![
+                SpsaParam::new("SlowMover", 100.0, 10.0, 500.0, 10.0),
+                SpsaParam::new("Contempt", 0.0, -5.0, 5.0, 10.0).rate(0.01),
+            ],
+            iterations: 100,
+            seed: 3,
+            ..Default::default()
+        };
+        let directions = state.directions();
+        assert_eq!(2, directions.len());
+        assert!(directions.iter().all(|d| d.abs() == 1.0));
+        assert_eq!(directions, state.clone().directions());
+
+        // The perturbation decays to `step` at the end of the run.
+        let (c, a) = state.gains(&state.params[0]);
+        assert!((c - 10.0 * 100f64.powf(SPSA_GAMMA)).abs() < 1e-9);
+        let last = SpsaState {
+            iteration: 99,
+            ..state.clone()
+        };
+        let (c_end, a_end) = last.gains(&last.params[0]);
+        assert!((c_end - 10.0).abs() < 1e-9);
+        assert!((a_end - 0.2).abs() < 1e-9);
+        assert!(a > a_end);
+
+        let (plus, minus) = state.perturbed(&[1.0, -1.0]);
+        assert_eq!(
+            Some((100.0 + c).round().to_string().as_str()),
+            plus.get("SlowMover")
+        );
+        assert_eq!(Some("-5"), plus.get("Contempt"));
+        assert_eq!(
+            Some((100.0 - c).round().to_string().as_str()),
+            minus.get("SlowMover")
+        );
+        assert_eq!(Some("5"), minus.get("Contempt"));
+
+        state.update(&[1.0, -1.0], 2.0);
+        assert_eq!(1, state.iteration);
+        assert!((state.params[0].value - (100.0 + 2.0 * a / c)).abs() < 1e-9);
+        assert!(state.params[1].value < 0.0);
+        let upwards = (0..16)
+            .filter(|&i| {
+                let later = SpsaState {
+                    iteration: i,
+                    ..state.clone()
+                };
+                later.directions()[0] > 0.0
+            })
+            .count();
+        assert!(0 < upwards && upwards < 16);
+
+        state.iteration = 100;
+        assert!(state.is_finished());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn spsa_state_toml() {
+        let state = SpsaState {
+            params: vec![SpsaParam::new("SlowMover", 104.5, 10.0, 500.0, 10.0)],
+            iteration: 12,
+            iterations: 100,
+            seed: 7,
+            score: MatchScore {
+                wins: 10,
+                losses: 8,
+                draws: 6,
+            },
+        };
+        let saved = state.to_toml_string().unwrap();
+        assert_eq!(state, SpsaState::from_toml_str(&saved).unwrap());
+        assert_eq!(Some("105"), state.values().get("SlowMover"));
+    }
+}